@@ -13,14 +13,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(feature = "encryption")]
-use std::collections::BTreeMap;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
+#[cfg(feature = "encryption")]
+use std::io::{Cursor, Read};
 use std::result::Result as StdResult;
 use std::sync::Arc;
 
 use matrix_sdk_common::instant::{Duration, Instant};
+#[cfg(feature = "encryption")]
+use matrix_sdk_common::locks::Mutex;
 use matrix_sdk_common::locks::RwLock;
 use matrix_sdk_common::uuid::Uuid;
 
@@ -32,7 +34,8 @@ use tracing::{info, instrument, trace};
 
 use http::Method as HttpMethod;
 use http::Response as HttpResponse;
-use reqwest::header::{HeaderValue, InvalidHeaderValue, AUTHORIZATION};
+use reqwest::header::{HeaderValue, InvalidHeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use serde::Serialize;
 use url::Url;
 
 use crate::events::room::message::MessageEventContent;
@@ -40,17 +43,29 @@ use crate::events::EventType;
 use crate::identifiers::{EventId, RoomId, RoomIdOrAliasId, UserId};
 use crate::Endpoint;
 
+#[cfg(feature = "encryption")]
+use crate::events::room::encrypted::EncryptedEventContent;
 #[cfg(feature = "encryption")]
 use crate::identifiers::DeviceId;
 
 use crate::api;
+use crate::http_client::{DefaultHttpClient, HttpMiddleware, HttpSend};
 #[cfg(not(target_arch = "wasm32"))]
 use crate::VERSION;
-use crate::{Error, EventEmitter, Result};
+use crate::{Error, EventEmitter, MatrixError, Result};
 use matrix_sdk_base::BaseClient;
+#[cfg(feature = "encryption")]
+use matrix_sdk_base::Error as BaseError;
 use matrix_sdk_base::Room;
 use matrix_sdk_base::Session;
 use matrix_sdk_base::StateStore;
+#[cfg(feature = "encryption")]
+use matrix_sdk_base::{
+    decrypt_attachment, encrypt_attachment, BackupVersion, Device, GroupSessionSharePolicy,
+    IncomingResponse, KeysQueryCrossSigningKeys, MediaEncryptionInfo, MegolmError,
+    MegolmV1BackupKey, OutgoingRequest, OutgoingRequests, RecoveryKey, RoomKeyBackup, Sas,
+    TrustState, UnusedFallbackKeyTypes, UserIdentity,
+};
 
 const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
 
@@ -61,10 +76,18 @@ const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
 pub struct Client {
     /// The URL of the homeserver to connect to.
     homeserver: Url,
-    /// The underlying HTTP client.
-    http_client: reqwest::Client,
+    /// The underlying HTTP transport.
+    http_client: Arc<dyn HttpSend>,
     /// User session data.
     pub(crate) base_client: BaseClient,
+    /// The policy deciding if failed requests are retried.
+    retry_policy: Option<RetryPolicy>,
+    /// The middleware that sees every request and response.
+    middleware: Vec<Arc<dyn HttpMiddleware>>,
+    /// Locks making sure that only one group session share is in flight per
+    /// room.
+    #[cfg(feature = "encryption")]
+    group_session_locks: Arc<Mutex<HashMap<RoomId, Arc<Mutex<()>>>>>,
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -105,6 +128,9 @@ pub struct ClientConfig {
     user_agent: Option<HeaderValue>,
     disable_ssl_verification: bool,
     state_store: Option<Box<dyn StateStore>>,
+    retry_policy: Option<RetryPolicy>,
+    http_client: Option<Arc<dyn HttpSend>>,
+    middleware: Vec<Arc<dyn HttpMiddleware>>,
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -117,6 +143,9 @@ impl std::fmt::Debug for ClientConfig {
 
         res.field("user_agent", &self.user_agent)
             .field("disable_ssl_verification", &self.disable_ssl_verification)
+            .field("retry_policy", &self.retry_policy)
+            .field("http_client", &self.http_client)
+            .field("middleware", &self.middleware)
             .finish()
     }
 }
@@ -169,6 +198,145 @@ impl ClientConfig {
         self.state_store = Some(store);
         self
     }
+
+    /// Retry rate limited requests and requests that failed with a server
+    /// error according to the given policy.
+    ///
+    /// By default requests aren't retried.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The policy deciding how often and after how long a
+    /// request is retried.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use matrix_sdk::{ClientConfig, RetryPolicy};
+    /// let client_config = ClientConfig::new()
+    ///     .retry_policy(RetryPolicy::new().max_attempts(10));
+    /// ```
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// Use a custom HTTP transport to send the requests.
+    ///
+    /// The proxy, user agent and SSL verification settings only configure the
+    /// default reqwest based transport, a custom transport needs to handle
+    /// those itself.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The transport that should send all the requests of the
+    /// `Client`.
+    pub fn http_client(mut self, client: Arc<dyn HttpSend>) -> Self {
+        self.http_client = Some(client);
+        self
+    }
+
+    /// Add a middleware that sees every request the client sends and every
+    /// response it receives.
+    ///
+    /// Middleware is called in the order it was added.
+    ///
+    /// # Arguments
+    ///
+    /// * `middleware` - The middleware that should be added.
+    pub fn middleware(mut self, middleware: Arc<dyn HttpMiddleware>) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+/// Policy deciding if and when a failed request should be retried.
+///
+/// Requests that were rate limited are retried after the time the homeserver
+/// told us to wait, requests that failed with a server error use an
+/// exponential backoff which never exceeds the maximum backoff.
+///
+/// Requests that failed with a server error are only retried if their HTTP
+/// method is idempotent, the server might have processed a `POST` request
+/// before failing.
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Create a new default retry policy.
+    ///
+    /// A request is sent at most 5 times, the backoff starts at 500
+    /// milliseconds and is capped at 30 seconds.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the maximum number of times a request is sent, including the
+    /// first attempt.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_attempts` - The maximum number of attempts.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Set the time to wait before the first retry, the time doubles with
+    /// every further retry.
+    ///
+    /// # Arguments
+    ///
+    /// * `backoff` - The initial backoff.
+    pub fn initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    /// Set the maximum time to wait before a retry.
+    ///
+    /// # Arguments
+    ///
+    /// * `backoff` - The maximum backoff.
+    pub fn max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    /// Get the time to wait before the next attempt.
+    ///
+    /// # Arguments
+    ///
+    /// * `attempt` - The number of the attempt that just failed.
+    ///
+    /// * `retry_after` - The time the homeserver told us to wait, if any, we
+    /// never wait less than that.
+    fn backoff(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after;
+        }
+
+        let backoff = self
+            .initial_backoff
+            .checked_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .unwrap_or(self.max_backoff);
+
+        std::cmp::min(backoff, self.max_backoff)
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -220,7 +388,7 @@ impl SyncSettings {
 }
 
 #[cfg(feature = "encryption")]
-use api::r0::keys::{claim_keys, get_keys, upload_keys, KeyAlgorithm};
+use api::r0::keys::{get_keys, upload_keys};
 use api::r0::membership::{
     ban_user, forget_room,
     invite_user::{self, InvitationRecipient},
@@ -232,8 +400,7 @@ use api::r0::receipt::create_receipt;
 use api::r0::room::create_room;
 use api::r0::session::login;
 use api::r0::sync::sync_events;
-#[cfg(feature = "encryption")]
-use api::r0::to_device::send_event_to_device;
+use api::r0::to_device::{send_event_to_device, DeviceIdOrAllDevices};
 use api::r0::typing::create_typing_event;
 
 impl Client {
@@ -260,7 +427,7 @@ impl Client {
     pub fn new_with_config<U: TryInto<Url>>(
         homeserver_url: U,
         session: Option<Session>,
-        config: ClientConfig,
+        mut config: ClientConfig,
     ) -> Result<Self> {
         #[allow(clippy::match_wild_err_arm)]
         let homeserver: Url = match homeserver_url.try_into() {
@@ -268,6 +435,36 @@ impl Client {
             Err(_e) => panic!("Error parsing homeserver url"),
         };
 
+        let http_client: Arc<dyn HttpSend> = match config.http_client.take() {
+            Some(client) => client,
+            None => Arc::new(DefaultHttpClient::with_client(Self::reqwest_client(
+                &config,
+            )?)),
+        };
+
+        let retry_policy = config.retry_policy;
+        let middleware = config.middleware;
+
+        let base_client = if let Some(store) = config.state_store {
+            BaseClient::new_with_state_store(session, store)?
+        } else {
+            BaseClient::new(session)?
+        };
+
+        Ok(Self {
+            homeserver,
+            http_client,
+            base_client,
+            retry_policy,
+            middleware,
+            #[cfg(feature = "encryption")]
+            group_session_locks: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Build the reqwest client of the default HTTP transport.
+    #[cfg_attr(target_arch = "wasm32", allow(unused_variables))]
+    fn reqwest_client(config: &ClientConfig) -> Result<reqwest::Client> {
         let http_client = reqwest::Client::builder();
 
         #[cfg(not(target_arch = "wasm32"))]
@@ -278,15 +475,15 @@ impl Client {
                 http_client
             };
 
-            let http_client = match config.proxy {
-                Some(p) => http_client.proxy(p),
+            let http_client = match &config.proxy {
+                Some(p) => http_client.proxy(p.clone()),
                 None => http_client,
             };
 
             let mut headers = reqwest::header::HeaderMap::new();
 
-            let user_agent = match config.user_agent {
-                Some(a) => a,
+            let user_agent = match &config.user_agent {
+                Some(a) => a.clone(),
                 None => HeaderValue::from_str(&format!("matrix-rust-sdk {}", VERSION)).unwrap(),
             };
 
@@ -295,19 +492,7 @@ impl Client {
             http_client.default_headers(headers)
         };
 
-        let http_client = http_client.build()?;
-
-        let base_client = if let Some(store) = config.state_store {
-            BaseClient::new_with_state_store(session, store)?
-        } else {
-            BaseClient::new(session)?
-        };
-
-        Ok(Self {
-            homeserver,
-            http_client,
-            base_client,
-        })
+        Ok(http_client.build()?)
     }
 
     /// Is the client logged in.
@@ -782,8 +967,27 @@ impl Client {
             timeout: sync_settings.timeout,
         };
 
+        #[cfg(not(feature = "encryption"))]
         let mut response = self.send(request).await?;
 
+        #[cfg(feature = "encryption")]
+        let mut response = {
+            // The unused fallback key types aren't part of the typed response,
+            // parse them out of the raw response body.
+            let http_response = self.send_request(request).await?;
+
+            if http_response.status().is_success() {
+                let unused_fallback_keys: UnusedFallbackKeyTypes =
+                    serde_json::from_slice(http_response.body())?;
+
+                self.base_client
+                    .receive_unused_fallback_key_types(&unused_fallback_keys)
+                    .await?;
+            }
+
+            sync_events::Response::try_from(http_response)?
+        };
+
         self.base_client
             .receive_sync_response(&mut response)
             .await?;
@@ -869,24 +1073,12 @@ impl Client {
                 continue;
             };
 
-            // TODO send out to-device messages here
-
             #[cfg(feature = "encryption")]
             {
-                if self.base_client.should_upload_keys().await {
-                    let response = self.keys_upload().await;
+                self.send_outgoing_requests().await;
 
-                    if let Err(e) = response {
-                        warn!("Error while uploading E2EE keys {:?}", e);
-                    }
-                }
-
-                if self.base_client.should_query_keys().await {
-                    let response = self.keys_query().await;
-
-                    if let Err(e) = response {
-                        warn!("Error while querying device keys {:?}", e);
-                    }
+                if let Err(e) = self.backup_room_keys().await {
+                    warn!("Error while backing up room keys {:?}", e);
                 }
             }
 
@@ -916,69 +1108,334 @@ impl Client {
         }
     }
 
-    async fn send<Request: Endpoint<ResponseError = crate::api::Error> + std::fmt::Debug>(
+    /// Send an arbitrary request to the homeserver.
+    ///
+    /// This can be used to call any endpoint of ruma-client-api, including
+    /// the ones that don't have a dedicated method on the `Client`. The
+    /// request is sent to the configured homeserver and the access token is
+    /// attached if the endpoint requires authentication.
+    ///
+    /// Returns the parsed response from the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request of the endpoint that should be called.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # use ruma_identifiers::UserId;
+    /// # use std::convert::TryFrom;
+    /// use matrix_sdk::api::r0::profile::get_display_name;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver, None).unwrap();
+    /// let user_id = UserId::try_from("@example:localhost").unwrap();
+    /// let request = get_display_name::Request { user_id };
+    ///
+    /// let response = client.send(request).await.unwrap();
+    /// println!("The display name is {:?}", response.displayname);
+    /// # })
+    /// ```
+    pub async fn send<Request: Endpoint<ResponseError = crate::api::Error> + std::fmt::Debug>(
         &self,
         request: Request,
     ) -> Result<Request::Response> {
+        let http_response = self.send_request(request).await?;
+
+        Ok(<Request::Response>::try_from(http_response)?)
+    }
+
+    /// Send a request to the homeserver and return the raw HTTP response
+    /// without parsing it.
+    async fn send_request<
+        Request: Endpoint<ResponseError = crate::api::Error> + std::fmt::Debug,
+    >(
+        &self,
+        request: Request,
+    ) -> Result<HttpResponse<Vec<u8>>> {
         let request: http::Request<Vec<u8>> = request.try_into()?;
-        let url = request.uri();
-        let path_and_query = url.path_and_query().unwrap();
+        let (parts, body) = request.into_parts();
+        let path_and_query = parts.uri.path_and_query().unwrap();
         let mut url = self.homeserver.clone();
 
         url.set_path(path_and_query.path());
         url.set_query(path_and_query.query());
 
+        let (content_type, body) = match parts.method {
+            HttpMethod::GET | HttpMethod::HEAD | HttpMethod::OPTIONS => (None, Vec::new()),
+            // DELETE requests may carry a body as well, e.g. the
+            // interactive authentication data when deleting devices.
+            HttpMethod::POST | HttpMethod::PUT | HttpMethod::PATCH | HttpMethod::DELETE => {
+                (Some("application/json"), body)
+            }
+            method => return Err(Error::UnsupportedMethod(method)),
+        };
+
+        let request = self
+            .build_request(
+                parts.method,
+                url,
+                content_type,
+                body,
+                Request::METADATA.requires_authentication,
+            )
+            .await?;
+
+        self.send_http(request).await
+    }
+
+    /// Build a request to the homeserver.
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - The HTTP method of the request.
+    ///
+    /// * `url` - The full URL of the request.
+    ///
+    /// * `content_type` - The content type of the body, if there is one.
+    ///
+    /// * `body` - The body of the request.
+    ///
+    /// * `requires_authentication` - Should the access token be attached to
+    /// the request.
+    async fn build_request(
+        &self,
+        method: HttpMethod,
+        url: Url,
+        content_type: Option<&str>,
+        body: Vec<u8>,
+        requires_authentication: bool,
+    ) -> Result<http::Request<Vec<u8>>> {
         trace!("Doing request {:?}", url);
 
-        let request_builder = match Request::METADATA.method {
-            HttpMethod::GET => self.http_client.get(url),
-            HttpMethod::POST => {
-                let body = request.body().clone();
-                self.http_client
-                    .post(url)
-                    .body(body)
-                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+        let mut request_builder = http::Request::builder().method(method).uri(url.as_str());
+
+        if let Some(content_type) = content_type {
+            request_builder = request_builder.header(CONTENT_TYPE, content_type);
+        }
+
+        if requires_authentication {
+            match self.base_client.session().read().await.as_ref() {
+                Some(s) => {
+                    let header_value = format!("Bearer {}", &s.access_token);
+                    request_builder = request_builder.header(AUTHORIZATION, header_value);
+                }
+                None => return Err(Error::AuthenticationRequired),
             }
-            HttpMethod::PUT => {
-                let body = request.body().clone();
-                self.http_client
-                    .put(url)
-                    .body(body)
-                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+        }
+
+        Ok(request_builder.body(body)?)
+    }
+
+    /// Send a request using the HTTP transport of the client.
+    ///
+    /// Rate limited requests and requests that failed with a server error are
+    /// retried according to the retry policy of the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request that should be sent.
+    async fn send_http(
+        &self,
+        mut request: http::Request<Vec<u8>>,
+    ) -> Result<HttpResponse<Vec<u8>>> {
+        for middleware in &self.middleware {
+            middleware.on_request(&mut request).await;
+        }
+
+        let mut attempt = 1;
+
+        loop {
+            // The transport consumes the request, we keep the original around
+            // for the middleware and for further attempts.
+            let start = Instant::now();
+            let result = self
+                .http_client
+                .send_request(clone_request(&request)?)
+                .await;
+            let elapsed = start.elapsed();
+
+            for middleware in &self.middleware {
+                match &result {
+                    Ok(r) => middleware.on_response(&request, r, elapsed).await,
+                    Err(e) => middleware.on_error(&request, e, elapsed).await,
+                }
             }
-            HttpMethod::DELETE => unimplemented!(),
-            _ => panic!("Unsuported method"),
-        };
 
-        let request_builder = if Request::METADATA.requires_authentication {
-            let session = self.base_client.session().read().await;
+            let http_response = result?;
 
-            if let Some(session) = session.as_ref() {
-                let header_value = format!("Bearer {}", &session.access_token);
-                request_builder.header(AUTHORIZATION, header_value)
-            } else {
-                return Err(Error::AuthenticationRequired);
+            let status = http_response.status();
+            let matrix_error = MatrixError::from_response(&http_response);
+            let rate_limited = status == http::StatusCode::TOO_MANY_REQUESTS
+                || matrix_error
+                    .as_ref()
+                    .map_or(false, |e| e.is_limit_exceeded());
+
+            // Rate limited requests weren't processed by the server, other
+            // failures are only safe to repeat if the request is idempotent.
+            let retryable =
+                rate_limited || (status.is_server_error() && request.method().is_idempotent());
+
+            match self.retry_policy {
+                Some(policy) if retryable && attempt < policy.max_attempts => {
+                    let backoff = policy
+                        .backoff(attempt, matrix_error.as_ref().and_then(|e| e.retry_after()));
+
+                    info!(
+                        "Request failed with status {} {:?}, retrying in {:?}",
+                        status, matrix_error, backoff
+                    );
+
+                    sleep::new(backoff).await;
+                    attempt += 1;
+                }
+                _ => {
+                    return match matrix_error {
+                        Some(e) if e.is_limit_exceeded() => Err(Error::RateLimited(e)),
+                        _ => Ok(http_response),
+                    };
+                }
             }
-        } else {
-            request_builder
+        }
+    }
+
+    /// Send a JSON request to an endpoint that isn't covered by
+    /// ruma-client-api.
+    ///
+    /// Returns the JSON body of the response.
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - The HTTP method of the request.
+    ///
+    /// * `path` - The path of the endpoint.
+    ///
+    /// * `query` - The query parameters of the request.
+    ///
+    /// * `body` - The JSON body of the request.
+    ///
+    /// * `requires_authentication` - Should the access token be attached to
+    /// the request, fails with `Error::AuthenticationRequired` if the client
+    /// isn't logged in.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_sdk::{Client, HttpMethod};
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver, None).unwrap();
+    /// let response = client
+    ///     .send_json(
+    ///         HttpMethod::GET,
+    ///         "/_matrix/client/unstable/org.matrix.msc2432/rooms/!room:localhost/aliases",
+    ///         &[],
+    ///         None,
+    ///         true,
+    ///     )
+    ///     .await
+    ///     .unwrap();
+    /// # })
+    /// ```
+    pub async fn send_json(
+        &self,
+        method: HttpMethod,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<&serde_json::Value>,
+        requires_authentication: bool,
+    ) -> Result<serde_json::Value> {
+        let mut url = self.homeserver.clone();
+        url.set_path(path);
+
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+
+        let (content_type, body) = match body {
+            Some(body) => (Some("application/json"), serde_json::to_vec(body)?),
+            None => (None, Vec::new()),
         };
-        let mut response = request_builder.send().await?;
 
-        trace!("Got response: {:?}", response);
+        let request = self
+            .build_request(method, url, content_type, body, requires_authentication)
+            .await?;
+        let response = self.send_http(request).await?;
 
-        let status = response.status();
-        let mut http_builder = HttpResponse::builder().status(status);
-        let headers = http_builder.headers_mut().unwrap();
+        if !response.status().is_success() {
+            return Err(Error::from_response(&response));
+        }
 
-        for (k, v) in response.headers_mut().drain() {
-            if let Some(key) = k {
-                headers.insert(key, v);
-            }
+        Ok(serde_json::from_slice(response.body())?)
+    }
+
+    /// Upload some media to the homeserver's content repository.
+    ///
+    /// Returns the `mxc://` URI of the uploaded content.
+    ///
+    /// # Arguments
+    ///
+    /// * `content_type` - The MIME type of the content, e.g. `image/png`.
+    ///
+    /// * `data` - The raw content that should be uploaded.
+    pub async fn upload(&self, content_type: &str, data: Vec<u8>) -> Result<String> {
+        let mut url = self.homeserver.clone();
+        url.set_path("/_matrix/media/r0/upload");
+
+        let request = self
+            .build_request(HttpMethod::POST, url, Some(content_type), data, true)
+            .await?;
+        let response = self.send_http(request).await?;
+
+        if !response.status().is_success() {
+            return Err(Error::from_response(&response));
         }
-        let body = response.bytes().await?.as_ref().to_owned();
-        let http_response = http_builder.body(body).unwrap();
 
-        Ok(<Request::Response>::try_from(http_response)?)
+        let body: serde_json::Value = serde_json::from_slice(response.body())?;
+
+        body.get("content_uri")
+            .and_then(|u| u.as_str())
+            .map(|u| u.to_owned())
+            .ok_or_else(|| Error::InvalidContentUri(body.to_string()))
+    }
+
+    /// Download some media from the homeserver's content repository.
+    ///
+    /// Returns the raw content.
+    ///
+    /// # Arguments
+    ///
+    /// * `content_uri` - The `mxc://` URI of the content.
+    pub async fn download(&self, content_uri: &str) -> Result<Vec<u8>> {
+        let mxc = Url::parse(content_uri)
+            .map_err(|_| Error::InvalidContentUri(content_uri.to_owned()))?;
+
+        let server_name = match mxc.host_str() {
+            Some(h) if mxc.scheme() == "mxc" && mxc.path().len() > 1 => h,
+            _ => return Err(Error::InvalidContentUri(content_uri.to_owned())),
+        };
+
+        let mut url = self.homeserver.clone();
+        url.set_path(&format!(
+            "/_matrix/media/r0/download/{}{}",
+            server_name,
+            mxc.path()
+        ));
+
+        let request = self
+            .build_request(HttpMethod::GET, url, None, Vec::new(), false)
+            .await?;
+        let response = self.send_http(request).await?;
+
+        if !response.status().is_success() {
+            return Err(Error::from_response(&response));
+        }
+
+        Ok(response.into_body())
     }
 
     /// Send a room message to the homeserver.
@@ -1047,34 +1504,8 @@ impl Client {
             };
 
             if encrypted {
-                let missing_sessions = {
-                    let room = self.base_client.get_joined_room(room_id).await;
-                    let room = room.as_ref().unwrap().read().await;
-                    let users = room.members.keys();
-                    self.base_client.get_missing_sessions(users).await?
-                };
-
-                if !missing_sessions.is_empty() {
-                    self.claim_one_time_keys(missing_sessions).await?;
-                }
-
-                if self.base_client.should_share_group_session(room_id).await {
-                    // TODO we need to make sure that only one such request is
-                    // in flight per room at a time.
-                    let response = self.share_group_session(room_id).await;
-
-                    // If one of the responses failed invalidate the group
-                    // session as using it would end up in undecryptable
-                    // messages.
-                    if let Err(r) = response {
-                        self.base_client.invalidate_group_session(room_id).await;
-                        return Err(r);
-                    }
-                }
-
-                raw_content = serde_json::value::to_raw_value(
-                    &self.base_client.encrypt(room_id, content).await?,
-                )?;
+                let encrypted_content = self.encrypt_room_message(room_id, content).await?;
+                raw_content = serde_json::value::to_raw_value(&encrypted_content)?;
                 event_type = EventType::RoomEncrypted;
             }
         }
@@ -1090,34 +1521,280 @@ impl Client {
         Ok(response)
     }
 
-    /// Claim one-time keys creating new Olm sessions.
+    /// Encrypt a room message, sharing a group session with the members of the
+    /// room first if none is shared or the current one expired.
     ///
-    /// # Arguments
+    /// The group session lock of the room is held from the share check until
+    /// the message is encrypted. Concurrent callers wait for it and reuse the
+    /// shared session.
     ///
-    /// * `users` - The list of user/device pairs that we should claim keys for.
+    /// # Arguments
     ///
-    /// # Panics
+    /// * `room_id` - The ID of the room the message will be sent to.
     ///
-    /// Panics if the client isn't logged in, or if no encryption keys need to
-    /// be uploaded.
+    /// * `content` - The content of the message that should be encrypted.
     #[cfg(feature = "encryption")]
     #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
-    #[instrument]
-    async fn claim_one_time_keys(
+    async fn encrypt_room_message(
         &self,
-        one_time_keys: BTreeMap<UserId, BTreeMap<DeviceId, KeyAlgorithm>>,
-    ) -> Result<claim_keys::Response> {
-        let request = claim_keys::Request {
-            timeout: None,
-            one_time_keys,
-        };
+        room_id: &RoomId,
+        content: MessageEventContent,
+    ) -> Result<EncryptedEventContent> {
+        let lock = self
+            .group_session_locks
+            .lock()
+            .await
+            .entry(room_id.clone())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone();
 
-        let response = self.send(request).await?;
-        self.base_client
-            .receive_keys_claim_response(&response)
-            .await?;
-        Ok(response)
-    }
+        let guard = lock.lock().await;
+        let result = self.share_and_encrypt(room_id, content).await;
+        drop(guard);
+
+        // Forget the lock if nobody else is waiting for it, the map lock is
+        // held so nobody can grab a new copy meanwhile.
+        let mut locks = self.group_session_locks.lock().await;
+
+        if Arc::strong_count(&lock) == 2 {
+            locks.remove(room_id);
+        }
+
+        result
+    }
+
+    /// Encrypt a room message, sharing a new group session first if needed.
+    ///
+    /// This needs to be called while holding the group session lock of the
+    /// room.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    async fn share_and_encrypt(
+        &self,
+        room_id: &RoomId,
+        content: MessageEventContent,
+    ) -> Result<EncryptedEventContent> {
+        self.share_group_session_if_needed(room_id).await?;
+
+        match self.base_client.encrypt(room_id, content.clone()).await {
+            // The session may expire on its own between the check and the
+            // encryption, share a fresh one and try once more.
+            Err(BaseError::MegolmError(MegolmError::ExpiredOutboundSession)) => {
+                self.share_group_session_if_needed(room_id).await?;
+                Ok(self.base_client.encrypt(room_id, content).await?)
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Share a group session with the members of the room, claiming one-time
+    /// keys for devices that we don't share an Olm session with first.
+    ///
+    /// This needs to be called while holding the group session lock of the
+    /// room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room for which we want to share a group
+    /// session.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    async fn share_group_session_if_needed(&self, room_id: &RoomId) -> Result<()> {
+        // Another send to this room might have shared a group session while we
+        // were waiting for the lock.
+        if !self.base_client.should_share_group_session(room_id).await {
+            return Ok(());
+        }
+
+        let claim_request = {
+            let room = self
+                .base_client
+                .get_joined_room(room_id)
+                .await
+                .ok_or_else(|| Error::UnknownRoom(room_id.clone()))?;
+            let room = room.read().await;
+            let users = room.encryption_recipients();
+            self.base_client.claim_missing_sessions(users).await?
+        };
+
+        if let Some(request) = claim_request {
+            self.send_outgoing_request(&request).await?;
+        }
+
+        let response = self.share_group_session(room_id).await;
+
+        // If one of the responses failed invalidate the group session as using
+        // it would end up in undecryptable messages.
+        if let Err(r) = response {
+            self.base_client.invalidate_group_session(room_id).await;
+            return Err(r);
+        }
+
+        Ok(())
+    }
+
+    /// Send a to-device event to the given devices.
+    ///
+    /// The event content can be any serializable type, e.g. a typed event
+    /// content or a raw `serde_json::Value`.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_type` - The type of the event.
+    ///
+    /// * `messages` - The event content for each user and device that should
+    /// receive the event.
+    pub async fn send_to_device<C: Serialize>(
+        &self,
+        event_type: EventType,
+        messages: &BTreeMap<UserId, BTreeMap<DeviceIdOrAllDevices, C>>,
+    ) -> Result<()> {
+        let mut raw_messages = BTreeMap::new();
+
+        for (user_id, user_messages) in messages {
+            let mut raw_user_messages = BTreeMap::new();
+
+            for (device_id, content) in user_messages {
+                raw_user_messages
+                    .insert(device_id.clone(), serde_json::value::to_raw_value(content)?);
+            }
+
+            raw_messages.insert(user_id.clone(), raw_user_messages);
+        }
+
+        let request = send_event_to_device::Request {
+            event_type,
+            txn_id: Uuid::new_v4().to_string(),
+            messages: raw_messages,
+        };
+
+        let _response: send_event_to_device::Response = self.send(request).await?;
+
+        Ok(())
+    }
+
+    /// Send an Olm encrypted to-device event to the given devices.
+    ///
+    /// One-time keys are claimed for devices that we don't share an Olm session
+    /// with yet. The devices of the users need to be known, i.e. we need to
+    /// share an encrypted room with them, devices that we can't establish an
+    /// Olm session with won't receive the event.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_type` - The type of the event that will be encrypted.
+    ///
+    /// * `messages` - The event content for each user and device that should
+    /// receive the event.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn send_encrypted_to_device<C: Serialize>(
+        &self,
+        event_type: EventType,
+        messages: &BTreeMap<UserId, BTreeMap<DeviceIdOrAllDevices, C>>,
+    ) -> Result<()> {
+        let mut json_messages = BTreeMap::new();
+
+        for (user_id, user_messages) in messages {
+            let mut json_user_messages = BTreeMap::new();
+
+            for (device_id, content) in user_messages {
+                json_user_messages.insert(device_id.clone(), serde_json::to_value(content)?);
+            }
+
+            json_messages.insert(user_id.clone(), json_user_messages);
+        }
+
+        if let Some(request) = self
+            .base_client
+            .claim_missing_sessions(messages.keys())
+            .await?
+        {
+            self.send_outgoing_request(&request).await?;
+        }
+
+        let requests = self
+            .base_client
+            .encrypt_to_device(event_type, &json_messages)
+            .await?;
+
+        for request in requests {
+            let _response: send_event_to_device::Response = self.send(request).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Encrypt and upload an attachment for an encrypted room.
+    ///
+    /// Returns the `m.image` or `m.file` message content referencing the
+    /// encrypted attachment, ready to be sent using `room_send()`. Images are
+    /// recognized by their content type.
+    ///
+    /// # Arguments
+    ///
+    /// * `body` - The textual description of the attachment, usually the file
+    /// name.
+    ///
+    /// * `content_type` - The MIME type of the attachment, e.g. `image/png`.
+    ///
+    /// * `reader` - The reader of the plaintext attachment.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn upload_encrypted_attachment<R: Read>(
+        &self,
+        body: &str,
+        content_type: &str,
+        reader: &mut R,
+    ) -> Result<MessageEventContent> {
+        let (ciphertext, info) = encrypt_attachment(reader)?;
+        let size = ciphertext.len();
+
+        let url = self.upload("application/octet-stream", ciphertext).await?;
+
+        let mut file = serde_json::to_value(info)?;
+        file["url"] = url.into();
+
+        let msgtype = if content_type.starts_with("image/") {
+            "m.image"
+        } else {
+            "m.file"
+        };
+
+        Ok(serde_json::from_value(serde_json::json!({
+            "msgtype": msgtype,
+            "body": body,
+            "file": file,
+            "info": {
+                "mimetype": content_type,
+                "size": size,
+            },
+        }))?)
+    }
+
+    /// Download and decrypt an encrypted attachment.
+    ///
+    /// Returns the plaintext of the attachment, the hash of the ciphertext is
+    /// checked before it is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `content_uri` - The `mxc://` URI of the encrypted attachment, the
+    /// `url` field of the encrypted file.
+    ///
+    /// * `info` - The encryption info of the attachment, the rest of the
+    /// encrypted file.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn download_encrypted_attachment(
+        &self,
+        content_uri: &str,
+        info: &MediaEncryptionInfo,
+    ) -> Result<Vec<u8>> {
+        let ciphertext = self.download(content_uri).await?;
+
+        Ok(decrypt_attachment(&mut Cursor::new(ciphertext), info)?)
+    }
 
     /// Share a group session for a room.
     ///
@@ -1146,41 +1823,409 @@ impl Client {
         Ok(())
     }
 
-    /// Upload the E2E encryption keys.
+    /// Send out all the queued up to-device requests of the crypto machine,
+    /// e.g. the events of interactive verification flows.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    #[instrument]
+    async fn send_to_device_requests(&self) -> Result<()> {
+        for request in self.base_client.outgoing_requests().await {
+            if let OutgoingRequests::ToDeviceRequest(_) = request.request() {
+                self.send_outgoing_request(&request).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the latest room key backup version from the server.
+    ///
+    /// Returns None if no backup exists.
+    #[cfg(feature = "encryption")]
+    async fn latest_backup_version(&self) -> Result<Option<BackupVersion>> {
+        let response = self
+            .send_json(
+                HttpMethod::GET,
+                "/_matrix/client/r0/room_keys/version",
+                &[],
+                None,
+                true,
+            )
+            .await;
+
+        match response {
+            Ok(r) => Ok(Some(serde_json::from_value(r)?)),
+            Err(Error::Matrix(_, e)) if e.errcode == "M_NOT_FOUND" => Ok(None),
+            Err(Error::HttpStatus(http::StatusCode::NOT_FOUND)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Create a new room key backup on the server and start backing up our
+    /// room keys to it.
     ///
-    /// This uploads the long lived device keys as well as the required amount
-    /// of one-time keys.
+    /// Returns the recovery key of the new backup. The recovery key is needed
+    /// to restore room keys from the backup and should be shown to the user
+    /// in its base58 encoded form.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn create_backup(&self) -> Result<RecoveryKey> {
+        let recovery_key = RecoveryKey::new();
+        let public_key = recovery_key.public_key();
+        let body = self.base_client.backup_auth_data(&public_key).await?;
+
+        let response = self
+            .send_json(
+                HttpMethod::POST,
+                "/_matrix/client/r0/room_keys/version",
+                &[],
+                Some(&body),
+                true,
+            )
+            .await?;
+
+        let version: String = serde_json::from_value(response["version"].clone())?;
+        self.base_client.enable_backup(public_key, version).await;
+
+        Ok(recovery_key)
+    }
+
+    /// Start backing up our room keys to the latest backup version on the
+    /// server.
     ///
-    /// # Panics
+    /// The backup is only enabled if its auth data was signed by our own
+    /// device or by one of our verified devices.
     ///
-    /// Panics if the client isn't logged in, or if no encryption keys need to
-    /// be uploaded.
+    /// Returns true if the backup was enabled, false if no trusted backup
+    /// exists.
     #[cfg(feature = "encryption")]
     #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
-    #[instrument]
-    async fn keys_upload(&self) -> Result<upload_keys::Response> {
-        let (device_keys, one_time_keys) = self
-            .base_client
-            .keys_for_upload()
-            .await
-            .expect("Keys don't need to be uploaded");
+    pub async fn enable_backup(&self) -> Result<bool> {
+        let backup = if let Some(b) = self.latest_backup_version().await? {
+            b
+        } else {
+            self.base_client.disable_backup().await;
+            return Ok(false);
+        };
 
-        debug!(
-            "Uploading encryption keys device keys: {}, one-time-keys: {}",
-            device_keys.is_some(),
-            one_time_keys.as_ref().map_or(0, |k| k.len())
-        );
+        if !self.base_client.verify_backup(&backup).await {
+            warn!("Not enabling untrusted room key backup {}", backup.version);
+            return Ok(false);
+        }
+
+        let key = MegolmV1BackupKey::from_base64(&backup.auth_data.public_key)?;
+        self.base_client.enable_backup(key, backup.version).await;
 
-        let request = upload_keys::Request {
-            device_keys,
-            one_time_keys,
+        Ok(true)
+    }
+
+    /// Download and import all the room keys of the latest backup version on
+    /// the server.
+    ///
+    /// Returns the number of room keys that were imported.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The recovery key of the backup.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn restore_backup(&self, recovery_key: &RecoveryKey) -> Result<usize> {
+        let backup = if let Some(b) = self.latest_backup_version().await? {
+            b
+        } else {
+            return Ok(0);
         };
 
-        let response = self.send(request).await?;
-        self.base_client
-            .receive_keys_upload_response(&response)
+        recovery_key.check_backup(&backup)?;
+
+        let mut response = self
+            .send_json(
+                HttpMethod::GET,
+                "/_matrix/client/r0/room_keys/keys",
+                &[("version", backup.version.as_str())],
+                None,
+                true,
+            )
             .await?;
-        Ok(response)
+
+        let rooms: BTreeMap<RoomId, RoomKeyBackup> = match response.get_mut("rooms") {
+            Some(rooms) => serde_json::from_value(rooms.take())?,
+            None => BTreeMap::new(),
+        };
+
+        Ok(self
+            .base_client
+            .import_backed_up_room_keys(recovery_key, &backup.version, rooms)
+            .await?)
+    }
+
+    /// Upload the room keys that aren't yet backed up to the currently
+    /// enabled backup.
+    ///
+    /// This is done automatically in `sync_forever()`, nothing is uploaded if
+    /// no backup is enabled.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn backup_room_keys(&self) -> Result<()> {
+        while let Some(request) = self.base_client.backup_request().await? {
+            let body = serde_json::json!({ "rooms": request.rooms });
+
+            self.send_json(
+                HttpMethod::PUT,
+                "/_matrix/client/r0/room_keys/keys",
+                &[("version", request.version.as_str())],
+                Some(&body),
+                true,
+            )
+            .await?;
+
+            self.base_client.mark_backup_as_sent(&request).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get a specific device of an user.
+    ///
+    /// Returns None if the device is unknown.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the device belongs to.
+    ///
+    /// * `device_id` - The unique id of the device.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    #[allow(clippy::ptr_arg)]
+    pub async fn get_device(&self, user_id: &UserId, device_id: &DeviceId) -> Option<Device> {
+        self.base_client.get_device(user_id, device_id).await
+    }
+
+    /// Set the trust state of a device.
+    ///
+    /// The new trust state is persisted in the crypto store. Blacklisted
+    /// devices won't receive any of our future room keys.
+    ///
+    /// Returns false if the device is unknown, true otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the device belongs to.
+    ///
+    /// * `device_id` - The unique id of the device.
+    ///
+    /// * `trust_state` - The new trust state of the device.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    #[allow(clippy::ptr_arg)]
+    pub async fn set_device_trust_state(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        trust_state: TrustState,
+    ) -> Result<bool> {
+        Ok(self
+            .base_client
+            .set_device_trust_state(user_id, device_id, trust_state)
+            .await?)
+    }
+
+    /// Get the cross signing identity of an user.
+    ///
+    /// Returns None if the user has no known cross signing identity.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn get_user_identity(&self, user_id: &UserId) -> Option<UserIdentity> {
+        self.base_client.get_user_identity(user_id).await
+    }
+
+    /// Create our own cross signing identity, if we don't already have one,
+    /// and upload its public keys to the server.
+    ///
+    /// Our own device gets signed by the new self-signing key and the master
+    /// key gets signed by our device.
+    ///
+    /// # Arguments
+    ///
+    /// * `auth` - The user-interactive authentication data the server requires
+    /// for uploading cross signing keys.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn bootstrap_cross_signing(&self, auth: Option<serde_json::Value>) -> Result<()> {
+        let (keys, signatures) = self.base_client.bootstrap_cross_signing().await?;
+
+        let mut body = serde_json::to_value(&keys)?;
+
+        if let Some(auth) = auth {
+            body["auth"] = auth;
+        }
+
+        self.send_json(
+            HttpMethod::POST,
+            "/_matrix/client/unstable/keys/device_signing/upload",
+            &[],
+            Some(&body),
+            true,
+        )
+        .await?;
+
+        self.send_json(
+            HttpMethod::POST,
+            "/_matrix/client/unstable/keys/signatures/upload",
+            &[],
+            Some(&serde_json::to_value(&signatures)?),
+            true,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Mark the cross signing identity of an user as verified.
+    ///
+    /// The master key of the user gets signed with our user-signing key and
+    /// the signature is uploaded to the server. All the devices the user
+    /// signed with its self-signing key will be considered verified.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that should be verified.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn verify_user_identity(&self, user_id: &UserId) -> Result<()> {
+        let signatures = self.base_client.sign_user_identity(user_id).await?;
+
+        self.send_json(
+            HttpMethod::POST,
+            "/_matrix/client/unstable/keys/signatures/upload",
+            &[],
+            Some(&serde_json::to_value(&signatures)?),
+            true,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Set the policy that decides which devices get our room keys when a
+    /// group session is shared.
+    ///
+    /// By default room keys are shared with all devices that aren't
+    /// blacklisted.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The policy that should be used when sharing group
+    /// sessions.
+    ///
+    /// # Panics
+    /// Panics if the client hasn't been logged in.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn set_group_session_share_policy(&self, policy: GroupSessionSharePolicy) {
+        self.base_client
+            .set_group_session_share_policy(policy)
+            .await
+    }
+
+    /// Start an interactive SAS verification with the given device.
+    ///
+    /// The emojis or decimals of the returned `Sas` object can be shown to
+    /// the user once the other side accepted the verification.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device that should be verified.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the client isn't logged in.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn start_verification(&self, device: Device) -> Result<Sas> {
+        let sas = self.base_client.start_verification(device).await;
+        self.send_to_device_requests().await?;
+        Ok(sas)
+    }
+
+    /// Accept a verification request that another device sent us.
+    ///
+    /// Returns the newly started SAS verification flow, None if no request
+    /// with the given flow id is known.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The transaction id of the verification request.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn accept_verification_request(&self, flow_id: &str) -> Result<Option<Sas>> {
+        let sas = self.base_client.accept_verification_request(flow_id).await;
+        self.send_to_device_requests().await?;
+        Ok(sas)
+    }
+
+    /// Get a SAS verification flow with the given flow id.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The unique id of the verification flow.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn get_verification(&self, flow_id: &str) -> Option<Sas> {
+        self.base_client.get_verification(flow_id).await
+    }
+
+    /// Accept a SAS verification flow that the other side started.
+    ///
+    /// Returns true if the flow was accepted.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The unique id of the verification flow.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn accept_verification(&self, flow_id: &str) -> Result<bool> {
+        let accepted = self.base_client.accept_verification(flow_id).await;
+        self.send_to_device_requests().await?;
+        Ok(accepted)
+    }
+
+    /// Confirm that the short authentication strings of a SAS verification
+    /// flow match.
+    ///
+    /// The other device will be marked as verified once both sides confirmed
+    /// the match.
+    ///
+    /// Returns true if the flow was confirmed.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The unique id of the verification flow.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn confirm_verification(&self, flow_id: &str) -> Result<bool> {
+        let confirmed = self.base_client.confirm_verification(flow_id).await?;
+        self.send_to_device_requests().await?;
+        Ok(confirmed)
+    }
+
+    /// Cancel a SAS verification flow.
+    ///
+    /// Returns true if the flow was canceled.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The unique id of the verification flow.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn cancel_verification(&self, flow_id: &str) -> Result<bool> {
+        let canceled = self.base_client.cancel_verification(flow_id).await;
+        self.send_to_device_requests().await?;
+        Ok(canceled)
     }
 
     /// Get the current, if any, sync token of the client.
@@ -1189,45 +2234,114 @@ impl Client {
         self.base_client.sync_token().await
     }
 
-    /// Query the server for users device keys.
+    /// Send out all the requests that the crypto machine queued up.
     ///
-    /// # Panics
+    /// This uploads our keys, queries and claims the keys of other devices
+    /// and sends out to-device messages. A failed request is logged and
+    /// will be retried the next time this is called.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    async fn send_outgoing_requests(&self) {
+        for request in self.base_client.outgoing_requests().await {
+            if let Err(e) = self.send_outgoing_request(&request).await {
+                warn!(
+                    "Error while sending out E2EE request {}: {:?}",
+                    request.request_id(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Send out a single request of the crypto machine and pass the response
+    /// back to it.
     ///
-    /// Panics if no key query needs to be done.
+    /// # Arguments
+    ///
+    /// * `request` - The request that should be sent out.
     #[cfg(feature = "encryption")]
     #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
     #[instrument]
-    async fn keys_query(&self) -> Result<get_keys::Response> {
-        let mut users_for_query = self
-            .base_client
-            .users_for_key_query()
-            .await
-            .expect("Keys don't need to be uploaded");
-
-        debug!(
-            "Querying device keys device for users: {:?}",
-            users_for_query
-        );
+    async fn send_outgoing_request(&self, request: &OutgoingRequest) -> Result<()> {
+        let request_id = request.request_id();
+
+        match request.request() {
+            OutgoingRequests::KeysUpload(upload) => {
+                debug!(
+                    "Uploading encryption keys device keys: {}, one-time-keys: {}",
+                    upload.device_keys.is_some(),
+                    upload.one_time_keys.as_ref().map_or(0, |k| k.len())
+                );
 
-        let mut device_keys: BTreeMap<UserId, Vec<DeviceId>> = BTreeMap::new();
+                // Ruma doesn't know about fallback keys, send our own request
+                // body and convert the response.
+                let body = serde_json::to_value(upload)?;
+                let response = self
+                    .send_json(
+                        HttpMethod::POST,
+                        "/_matrix/client/r0/keys/upload",
+                        &[],
+                        Some(&body),
+                        true,
+                    )
+                    .await?;
+                let response = upload_keys::Response::try_from(HttpResponse::new(
+                    serde_json::to_vec(&response)?,
+                ))?;
+
+                self.base_client
+                    .mark_request_as_sent(request_id, IncomingResponse::KeysUpload(&response))
+                    .await?;
+            }
+            OutgoingRequests::KeysQuery(query) => {
+                debug!(
+                    "Querying device keys device for users: {:?}",
+                    query.device_keys.keys()
+                );
 
-        for user in users_for_query.drain() {
-            device_keys.insert(user, Vec::new());
+                // The cross signing keys aren't part of the typed response,
+                // parse them out of the raw response body.
+                let http_response = self.send_request(query.clone()).await?;
+                let body = http_response.body().clone();
+                let response = get_keys::Response::try_from(http_response)?;
+                let cross_signing_keys: KeysQueryCrossSigningKeys = serde_json::from_slice(&body)?;
+
+                self.base_client
+                    .mark_request_as_sent(
+                        request_id,
+                        IncomingResponse::KeysQuery(&response, &cross_signing_keys),
+                    )
+                    .await?;
+            }
+            OutgoingRequests::KeysClaim(claim) => {
+                let response = self.send(claim.clone()).await?;
+                self.base_client
+                    .mark_request_as_sent(request_id, IncomingResponse::KeysClaim(&response))
+                    .await?;
+            }
+            OutgoingRequests::ToDeviceRequest(to_device) => {
+                let response = self.send(to_device.clone()).await?;
+                self.base_client
+                    .mark_request_as_sent(request_id, IncomingResponse::ToDevice(&response))
+                    .await?;
+            }
         }
 
-        let request = get_keys::Request {
-            timeout: None,
-            device_keys,
-            token: None,
-        };
+        Ok(())
+    }
+}
 
-        let response = self.send(request).await?;
-        self.base_client
-            .receive_keys_query_response(&response)
-            .await?;
+/// Create a copy of the given request.
+fn clone_request(request: &http::Request<Vec<u8>>) -> Result<http::Request<Vec<u8>>> {
+    let mut request_builder = http::Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone());
 
-        Ok(response)
+    if let Some(headers) = request_builder.headers_mut() {
+        *headers = request.headers().clone();
     }
+
+    Ok(request_builder.body(request.body().clone())?)
 }
 
 #[cfg(test)]
@@ -1236,16 +2350,18 @@ mod test {
         ban_user, create_receipt, create_typing_event, forget_room, invite_user, kick_user,
         leave_room, Invite3pid, MessageEventContent,
     };
-    use super::{Client, ClientConfig, Session, SyncSettings, Url};
+    use super::{Client, ClientConfig, DeviceIdOrAllDevices, Session, SyncSettings, Url};
     use crate::events::collections::all::RoomEvent;
     use crate::events::room::member::MembershipState;
     use crate::events::room::message::TextMessageEventContent;
+    use crate::events::EventType;
     use crate::identifiers::{EventId, RoomId, RoomIdOrAliasId, UserId};
 
     use matrix_sdk_base::JsonStore;
     use matrix_sdk_test::{EventBuilder, EventsFile};
 
     use mockito::{mock, Matcher};
+    use std::collections::BTreeMap;
     use std::convert::TryFrom;
     use std::str::FromStr;
     use std::time::Duration;
@@ -1346,78 +2462,352 @@ mod test {
                 );
             }
         } else {
-            panic!("this request should return an `Err` variant")
+            panic!("this request should return an `Err` variant")
+        }
+    }
+
+    #[tokio::test]
+    async fn join_room_by_id() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: UserId::try_from("@example:localhost").unwrap(),
+            device_id: "DEVICEID".to_owned(),
+        };
+
+        let _m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/join".to_string()),
+        )
+        .with_status(200)
+        .with_body_from_file("../test_data/room_id.json")
+        .create();
+
+        let client = Client::new(homeserver, Some(session)).unwrap();
+        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
+
+        assert_eq!(
+            // this is the `join_by_room_id::Response` but since no PartialEq we check the RoomId field
+            client.join_room_by_id(&room_id).await.unwrap().room_id,
+            room_id
+        );
+    }
+
+    // TODO enable this once Ruma supports proper serialization of the query
+    // string.
+    #[tokio::test]
+    async fn join_room_by_id_or_alias() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: UserId::try_from("@example:localhost").unwrap(),
+            device_id: "DEVICEID".to_owned(),
+        };
+
+        let _m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/client/r0/join/".to_string()),
+        )
+        .with_status(200)
+        .with_body_from_file("../test_data/room_id.json")
+        .create();
+
+        let client = Client::new(homeserver, Some(session)).unwrap();
+        let room_id = RoomIdOrAliasId::try_from("!testroom:example.org").unwrap();
+
+        assert_eq!(
+            // this is the `join_by_room_id::Response` but since no PartialEq we check the RoomId field
+            client
+                .join_room_by_id_or_alias(&room_id, &["server.com".to_string()])
+                .await
+                .unwrap()
+                .room_id,
+            RoomId::try_from("!testroom:example.org").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    #[allow(irrefutable_let_patterns)]
+    async fn invite_user_by_id() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let user = UserId::try_from("@example:localhost").unwrap();
+        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user.clone(),
+            device_id: "DEVICEID".to_owned(),
+        };
+
+        let _m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/invite".to_string()),
+        )
+        .with_status(200)
+        .with_body_from_file("../test_data/logout_response.json")
+        .create();
+
+        let client = Client::new(homeserver, Some(session)).unwrap();
+
+        if let invite_user::Response = client.invite_user_by_id(&room_id, &user).await.unwrap() {}
+    }
+
+    #[tokio::test]
+    #[allow(irrefutable_let_patterns)]
+    async fn invite_user_by_3pid() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let user = UserId::try_from("@example:localhost").unwrap();
+        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user.clone(),
+            device_id: "DEVICEID".to_owned(),
+        };
+
+        let _m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/invite".to_string()),
+        )
+        .with_status(200)
+        .with_body_from_file("../test_data/logout_response.json")
+        .create();
+
+        let client = Client::new(homeserver, Some(session)).unwrap();
+
+        if let invite_user::Response = client
+            .invite_user_by_3pid(
+                &room_id,
+                &Invite3pid {
+                    id_server: "example.org".to_string(),
+                    id_access_token: "IdToken".to_string(),
+                    medium: crate::api::r0::thirdparty::Medium::Email,
+                    address: "address".to_string(),
+                },
+            )
+            .await
+            .unwrap()
+        {}
+    }
+
+    #[tokio::test]
+    #[allow(irrefutable_let_patterns)]
+    async fn leave_room() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: UserId::try_from("@example:localhost").unwrap(),
+            device_id: "DEVICEID".to_owned(),
+        };
+
+        let _m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/leave".to_string()),
+        )
+        .with_status(200)
+        // this is an empty JSON object
+        .with_body_from_file("../test_data/logout_response.json")
+        .create();
+
+        let client = Client::new(homeserver, Some(session)).unwrap();
+        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
+
+        let response = client.leave_room(&room_id).await.unwrap();
+        if let leave_room::Response = response {
+        } else {
+            panic!(
+                "expected `ruma_client_api::leave_room::Response` found {:?}",
+                response
+            )
+        }
+    }
+
+    #[tokio::test]
+    #[allow(irrefutable_let_patterns)]
+    async fn ban_user() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let user = UserId::try_from("@example:localhost").unwrap();
+        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user.clone(),
+            device_id: "DEVICEID".to_owned(),
+        };
+
+        let _m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/ban".to_string()),
+        )
+        .with_status(200)
+        // this is an empty JSON object
+        .with_body_from_file("../test_data/logout_response.json")
+        .create();
+
+        let client = Client::new(homeserver, Some(session)).unwrap();
+
+        let response = client.ban_user(&room_id, &user, None).await.unwrap();
+        if let ban_user::Response = response {
+        } else {
+            panic!(
+                "expected `ruma_client_api::ban_user::Response` found {:?}",
+                response
+            )
+        }
+    }
+
+    #[tokio::test]
+    #[allow(irrefutable_let_patterns)]
+    async fn kick_user() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let user = UserId::try_from("@example:localhost").unwrap();
+        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user.clone(),
+            device_id: "DEVICEID".to_owned(),
+        };
+
+        let _m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/kick".to_string()),
+        )
+        .with_status(200)
+        // this is an empty JSON object
+        .with_body_from_file("../test_data/logout_response.json")
+        .create();
+
+        let client = Client::new(homeserver, Some(session)).unwrap();
+
+        let response = client.kick_user(&room_id, &user, None).await.unwrap();
+        if let kick_user::Response = response {
+        } else {
+            panic!(
+                "expected `ruma_client_api::kick_user::Response` found {:?}",
+                response
+            )
+        }
+    }
+
+    #[tokio::test]
+    #[allow(irrefutable_let_patterns)]
+    async fn forget_room() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let user = UserId::try_from("@example:localhost").unwrap();
+        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user.clone(),
+            device_id: "DEVICEID".to_owned(),
+        };
+
+        let _m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/forget".to_string()),
+        )
+        .with_status(200)
+        // this is an empty JSON object
+        .with_body_from_file("../test_data/logout_response.json")
+        .create();
+
+        let client = Client::new(homeserver, Some(session)).unwrap();
+
+        let response = client.forget_room_by_id(&room_id).await.unwrap();
+        if let forget_room::Response = response {
+        } else {
+            panic!(
+                "expected `ruma_client_api::forget_room::Response` found {:?}",
+                response
+            )
         }
     }
 
     #[tokio::test]
-    async fn join_room_by_id() {
+    #[allow(irrefutable_let_patterns)]
+    async fn read_receipt() {
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let user_id = UserId::try_from("@example:localhost").unwrap();
+        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
+        let event_id = EventId::new("example.org").unwrap();
 
         let session = Session {
             access_token: "1234".to_owned(),
-            user_id: UserId::try_from("@example:localhost").unwrap(),
+            user_id,
             device_id: "DEVICEID".to_owned(),
         };
 
         let _m = mock(
             "POST",
-            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/join".to_string()),
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/receipt".to_string()),
         )
         .with_status(200)
-        .with_body_from_file("../test_data/room_id.json")
+        // this is an empty JSON object
+        .with_body_from_file("../test_data/logout_response.json")
         .create();
 
         let client = Client::new(homeserver, Some(session)).unwrap();
-        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
 
-        assert_eq!(
-            // this is the `join_by_room_id::Response` but since no PartialEq we check the RoomId field
-            client.join_room_by_id(&room_id).await.unwrap().room_id,
-            room_id
-        );
+        let response = client.read_receipt(&room_id, &event_id).await.unwrap();
+        if let create_receipt::Response = response {
+        } else {
+            panic!(
+                "expected `ruma_client_api::create_receipt::Response` found {:?}",
+                response
+            )
+        }
     }
 
-    // TODO enable this once Ruma supports proper serialization of the query
-    // string.
     #[tokio::test]
-    async fn join_room_by_id_or_alias() {
+    #[allow(irrefutable_let_patterns)]
+    async fn typing_notice() {
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let user = UserId::try_from("@example:localhost").unwrap();
+        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
 
         let session = Session {
             access_token: "1234".to_owned(),
-            user_id: UserId::try_from("@example:localhost").unwrap(),
+            user_id: user.clone(),
             device_id: "DEVICEID".to_owned(),
         };
 
         let _m = mock(
-            "POST",
-            Matcher::Regex(r"^/_matrix/client/r0/join/".to_string()),
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/typing".to_string()),
         )
         .with_status(200)
-        .with_body_from_file("../test_data/room_id.json")
+        // this is an empty JSON object
+        .with_body_from_file("../test_data/logout_response.json")
         .create();
 
         let client = Client::new(homeserver, Some(session)).unwrap();
-        let room_id = RoomIdOrAliasId::try_from("!testroom:example.org").unwrap();
 
-        assert_eq!(
-            // this is the `join_by_room_id::Response` but since no PartialEq we check the RoomId field
-            client
-                .join_room_by_id_or_alias(&room_id, &["server.com".to_string()])
-                .await
-                .unwrap()
-                .room_id,
-            RoomId::try_from("!testroom:example.org").unwrap()
-        );
+        let response = client
+            .typing_notice(
+                &room_id,
+                &user,
+                true,
+                Some(std::time::Duration::from_secs(1)),
+            )
+            .await
+            .unwrap();
+        if let create_typing_event::Response = response {
+        } else {
+            panic!(
+                "expected `ruma_client_api::create_typing_event::Response` found {:?}",
+                response
+            )
+        }
     }
 
     #[tokio::test]
-    #[allow(irrefutable_let_patterns)]
-    async fn invite_user_by_id() {
+    async fn send_to_device() {
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
         let user = UserId::try_from("@example:localhost").unwrap();
-        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
 
         let session = Session {
             access_token: "1234".to_owned(),
@@ -1426,21 +2816,41 @@ mod test {
         };
 
         let _m = mock(
-            "POST",
-            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/invite".to_string()),
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/sendToDevice/org.example.ping/.*".to_string()),
         )
+        .match_body(Matcher::Json(serde_json::json!({
+            "messages": {
+                "@example:localhost": {
+                    "*": { "ping": "pong" }
+                }
+            }
+        })))
         .with_status(200)
+        // this is an empty JSON object
         .with_body_from_file("../test_data/logout_response.json")
         .create();
 
         let client = Client::new(homeserver, Some(session)).unwrap();
 
-        if let invite_user::Response = client.invite_user_by_id(&room_id, &user).await.unwrap() {}
+        let mut user_messages = BTreeMap::new();
+        user_messages.insert(
+            DeviceIdOrAllDevices::AllDevices,
+            serde_json::json!({ "ping": "pong" }),
+        );
+        let mut messages = BTreeMap::new();
+        messages.insert(user, user_messages);
+
+        client
+            .send_to_device(EventType::Custom("org.example.ping".to_owned()), &messages)
+            .await
+            .unwrap();
     }
 
     #[tokio::test]
-    #[allow(irrefutable_let_patterns)]
-    async fn invite_user_by_3pid() {
+    async fn room_message_send() {
+        use matrix_sdk_common::uuid::Uuid;
+
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
         let user = UserId::try_from("@example:localhost").unwrap();
         let room_id = RoomId::try_from("!testroom:example.org").unwrap();
@@ -1452,282 +2862,674 @@ mod test {
         };
 
         let _m = mock(
-            "POST",
-            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/invite".to_string()),
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/".to_string()),
         )
         .with_status(200)
-        .with_body_from_file("../test_data/logout_response.json")
+        .with_body_from_file("../test_data/event_id.json")
         .create();
 
         let client = Client::new(homeserver, Some(session)).unwrap();
 
-        if let invite_user::Response = client
-            .invite_user_by_3pid(
-                &room_id,
-                &Invite3pid {
-                    id_server: "example.org".to_string(),
-                    id_access_token: "IdToken".to_string(),
-                    medium: crate::api::r0::thirdparty::Medium::Email,
-                    address: "address".to_string(),
-                },
+        let content = MessageEventContent::Text(TextMessageEventContent {
+            body: "Hello world".to_owned(),
+            format: None,
+            formatted_body: None,
+            relates_to: None,
+        });
+        let txn_id = Uuid::new_v4();
+        let response = client
+            .room_send(&room_id, content, Some(txn_id))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            EventId::try_from("$h29iv0s8:example.com").ok(),
+            response.event_id
+        )
+    }
+
+    #[tokio::test]
+    async fn delete_request() {
+        use crate::api::r0::device::delete_device;
+
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: UserId::try_from("@example:localhost").unwrap(),
+            device_id: "DEVICEID".to_owned(),
+        };
+
+        let _m = mock("DELETE", "/_matrix/client/r0/devices/OTHERDEVICE")
+            .match_header("authorization", "Bearer 1234")
+            .match_header("content-type", "application/json")
+            .with_status(200)
+            // this is an empty JSON object
+            .with_body_from_file("../test_data/logout_response.json")
+            .create();
+
+        let client = Client::new(homeserver, Some(session)).unwrap();
+
+        let request = delete_device::Request {
+            device_id: "OTHERDEVICE".to_owned(),
+            auth: None,
+        };
+
+        let _response: delete_device::Response = client.send(request).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_raw_json() {
+        use crate::HttpMethod;
+        use serde_json::json;
+
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: UserId::try_from("@example:localhost").unwrap(),
+            device_id: "DEVICEID".to_owned(),
+        };
+
+        let _m = mock("PUT", "/_matrix/client/unstable/org.example.msc/foo")
+            .match_header("authorization", "Bearer 1234")
+            .match_query(Matcher::UrlEncoded("bar".to_owned(), "baz".to_owned()))
+            .match_body(Matcher::Json(json!({ "ping": "pong" })))
+            .with_status(200)
+            .with_body(r#"{ "pong": "ping" }"#)
+            .create();
+
+        let client = Client::new(homeserver, Some(session)).unwrap();
+
+        let response = client
+            .send_json(
+                HttpMethod::PUT,
+                "/_matrix/client/unstable/org.example.msc/foo",
+                &[("bar", "baz")],
+                Some(&json!({ "ping": "pong" })),
+                true,
             )
             .await
-            .unwrap()
-        {}
+            .unwrap();
+
+        assert_eq!(response, json!({ "pong": "ping" }));
     }
 
     #[tokio::test]
-    #[allow(irrefutable_let_patterns)]
-    async fn leave_room() {
+    async fn send_unauthenticated_raw_json() {
+        use crate::{Error, HttpMethod};
+        use serde_json::json;
+
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let client = Client::new(homeserver, None).unwrap();
+
+        let _m = mock("GET", "/_matrix/client/unstable/org.example.msc/public")
+            .match_header("authorization", Matcher::Missing)
+            .with_status(200)
+            .with_body(r#"{ "public": true }"#)
+            .create();
+
+        let response = client
+            .send_json(
+                HttpMethod::GET,
+                "/_matrix/client/unstable/org.example.msc/public",
+                &[],
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response, json!({ "public": true }));
+
+        match client
+            .send_json(
+                HttpMethod::GET,
+                "/_matrix/client/unstable/org.example.msc/public",
+                &[],
+                None,
+                true,
+            )
+            .await
+        {
+            Err(Error::AuthenticationRequired) => (),
+            r => panic!("Unauthenticated request was sent {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limited_request() {
+        use super::RetryPolicy;
+        use crate::api::r0::profile::get_display_name;
+        use crate::Error;
+
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let user_id = UserId::try_from("@ratelimited:localhost").unwrap();
+
+        let m = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/r0/profile/.*ratelimited.*/displayname".to_string()),
+        )
+        .with_status(429)
+        .with_body_from_file("../test_data/limit_exceeded_error.json")
+        .expect(4)
+        .create();
+
+        let client = Client::new(homeserver.clone(), None).unwrap();
+
+        let request = get_display_name::Request {
+            user_id: user_id.clone(),
+        };
+
+        match client.send(request).await {
+            Err(Error::RateLimited(e)) => {
+                assert_eq!(e.errcode, "M_LIMIT_EXCEEDED");
+                assert_eq!(e.retry_after_ms, Some(500));
+            }
+            r => panic!("The request wasn't rate limited {:?}", r),
+        }
+
+        let policy = RetryPolicy::new()
+            .max_attempts(3)
+            .max_backoff(Duration::from_millis(10));
+        let config = ClientConfig::new().retry_policy(policy);
+        let client = Client::new_with_config(homeserver, None, config).unwrap();
+
+        let request = get_display_name::Request { user_id };
+
+        match client.send(request).await {
+            Err(Error::RateLimited(e)) => assert_eq!(e.retry_after_ms, Some(500)),
+            r => panic!("The request wasn't rate limited {:?}", r),
+        }
+
+        m.assert();
+    }
+
+    #[tokio::test]
+    async fn server_error_retries() {
+        use super::RetryPolicy;
+        use crate::api::r0::profile::get_display_name;
 
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
         let session = Session {
             access_token: "1234".to_owned(),
             user_id: UserId::try_from("@example:localhost").unwrap(),
             device_id: "DEVICEID".to_owned(),
         };
 
-        let _m = mock(
+        let policy = RetryPolicy::new()
+            .max_attempts(3)
+            .initial_backoff(Duration::from_millis(1));
+        let config = ClientConfig::new().retry_policy(policy);
+        let client = Client::new_with_config(homeserver, Some(session), config).unwrap();
+
+        let m = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/r0/profile/.*unavailable.*/displayname".to_string()),
+        )
+        .with_status(502)
+        .expect(3)
+        .create();
+
+        let request = get_display_name::Request {
+            user_id: UserId::try_from("@unavailable:localhost").unwrap(),
+        };
+
+        assert!(client.send(request).await.is_err());
+        m.assert();
+
+        // The server might have processed the request before failing, POST
+        // requests aren't retried.
+        let m = mock(
             "POST",
-            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/leave".to_string()),
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/join".to_string()),
         )
-        .with_status(200)
-        // this is an empty JSON object
-        .with_body_from_file("../test_data/logout_response.json")
+        .with_status(502)
+        .expect(1)
         .create();
 
-        let client = Client::new(homeserver, Some(session)).unwrap();
         let room_id = RoomId::try_from("!testroom:example.org").unwrap();
 
-        let response = client.leave_room(&room_id).await.unwrap();
-        if let leave_room::Response = response {
-        } else {
-            panic!(
-                "expected `ruma_client_api::leave_room::Response` found {:?}",
-                response
-            )
-        }
+        assert!(client.join_room_by_id(&room_id).await.is_err());
+        m.assert();
+    }
+
+    #[test]
+    fn retry_backoff() {
+        use super::RetryPolicy;
+
+        let policy = RetryPolicy::new()
+            .initial_backoff(Duration::from_millis(100))
+            .max_backoff(Duration::from_secs(1));
+
+        assert_eq!(policy.backoff(1, None), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2, None), Duration::from_millis(200));
+        assert_eq!(policy.backoff(4, None), Duration::from_millis(800));
+        assert_eq!(policy.backoff(5, None), Duration::from_secs(1));
+        assert_eq!(policy.backoff(100, None), Duration::from_secs(1));
+        assert_eq!(
+            policy.backoff(1, Some(Duration::from_millis(500))),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            policy.backoff(1, Some(Duration::from_secs(5))),
+            Duration::from_secs(5)
+        );
     }
 
-    #[tokio::test]
-    #[allow(irrefutable_let_patterns)]
-    async fn ban_user() {
-        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
-        let user = UserId::try_from("@example:localhost").unwrap();
-        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
+    #[tokio::test]
+    async fn custom_http_client() {
+        use crate::{HttpSend, Result};
+        use http::{Request, Response};
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Debug, Default)]
+        struct RecordingClient {
+            requests: Mutex<Vec<(String, String, Option<String>)>>,
+        }
+
+        #[async_trait::async_trait]
+        impl HttpSend for RecordingClient {
+            async fn send_request(&self, request: Request<Vec<u8>>) -> Result<Response<Vec<u8>>> {
+                let authorization = request
+                    .headers()
+                    .get("authorization")
+                    .map(|h| h.to_str().unwrap().to_owned());
+
+                self.requests.lock().unwrap().push((
+                    request.method().to_string(),
+                    request.uri().to_string(),
+                    authorization,
+                ));
+
+                Ok(Response::new(b"{}".to_vec()))
+            }
+        }
 
         let session = Session {
             access_token: "1234".to_owned(),
-            user_id: user.clone(),
+            user_id: UserId::try_from("@example:localhost").unwrap(),
             device_id: "DEVICEID".to_owned(),
         };
 
-        let _m = mock(
-            "POST",
-            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/ban".to_string()),
-        )
-        .with_status(200)
-        // this is an empty JSON object
-        .with_body_from_file("../test_data/logout_response.json")
-        .create();
+        let http_client = Arc::new(RecordingClient::default());
+        let config = ClientConfig::new().http_client(http_client.clone());
+        let homeserver = Url::parse("https://example.org").unwrap();
+        let client = Client::new_with_config(homeserver, Some(session), config).unwrap();
 
-        let client = Client::new(homeserver, Some(session)).unwrap();
+        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
+        client.leave_room(&room_id).await.unwrap();
 
-        let response = client.ban_user(&room_id, &user, None).await.unwrap();
-        if let ban_user::Response = response {
-        } else {
-            panic!(
-                "expected `ruma_client_api::ban_user::Response` found {:?}",
-                response
-            )
-        }
+        let requests = http_client.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+
+        let (method, uri, authorization) = &requests[0];
+        assert_eq!(method, "POST");
+        assert!(uri.starts_with("https://example.org/_matrix/client/r0/rooms/"));
+        assert!(uri.ends_with("/leave"));
+        assert_eq!(authorization.as_deref(), Some("Bearer 1234"));
     }
 
     #[tokio::test]
-    #[allow(irrefutable_let_patterns)]
-    async fn kick_user() {
+    async fn middleware() {
+        use crate::HttpMiddleware;
+        use http::{Request, Response};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        #[derive(Debug, Default)]
+        struct CountingMiddleware {
+            responses: AtomicUsize,
+        }
+
+        #[async_trait::async_trait]
+        impl HttpMiddleware for CountingMiddleware {
+            async fn on_request(&self, request: &mut Request<Vec<u8>>) {
+                request
+                    .headers_mut()
+                    .insert("x-example", "middleware".parse().unwrap());
+            }
+
+            async fn on_response(
+                &self,
+                request: &Request<Vec<u8>>,
+                response: &Response<Vec<u8>>,
+                _elapsed: Duration,
+            ) {
+                assert_eq!(request.headers()["x-example"], "middleware");
+                assert!(response.status().is_success());
+                self.responses.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
-        let user = UserId::try_from("@example:localhost").unwrap();
-        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
 
         let session = Session {
             access_token: "1234".to_owned(),
-            user_id: user.clone(),
+            user_id: UserId::try_from("@example:localhost").unwrap(),
             device_id: "DEVICEID".to_owned(),
         };
 
         let _m = mock(
             "POST",
-            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/kick".to_string()),
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*middleware.*/leave".to_string()),
         )
+        .match_header("x-example", "middleware")
         .with_status(200)
         // this is an empty JSON object
         .with_body_from_file("../test_data/logout_response.json")
         .create();
 
-        let client = Client::new(homeserver, Some(session)).unwrap();
+        let middleware = Arc::new(CountingMiddleware::default());
+        let config = ClientConfig::new()
+            .middleware(middleware.clone())
+            .middleware(middleware.clone());
+        let client = Client::new_with_config(homeserver, Some(session), config).unwrap();
 
-        let response = client.kick_user(&room_id, &user, None).await.unwrap();
-        if let kick_user::Response = response {
-        } else {
-            panic!(
-                "expected `ruma_client_api::kick_user::Response` found {:?}",
-                response
-            )
-        }
+        let room_id = RoomId::try_from("!middleware:localhost").unwrap();
+        client.leave_room(&room_id).await.unwrap();
+
+        assert_eq!(middleware.responses.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn redacted_requests() {
+        use crate::{redact_request, redact_response};
+        use http::{Request, Response};
+        use serde_json::json;
+
+        let body = json!({
+            "type": "m.login.password",
+            "user": "example",
+            "password": "wordpass",
+            "auth": { "password": "wordpass" }
+        });
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("https://example.org/_matrix/client/r0/login")
+            .header("authorization", "Bearer 1234")
+            .body(serde_json::to_vec(&body).unwrap())
+            .unwrap();
+
+        let redacted = redact_request(&request);
+        assert_eq!(redacted.method(), request.method());
+        assert_eq!(redacted.uri(), request.uri());
+        assert_eq!(redacted.headers()["authorization"], "<redacted>");
+
+        let redacted_body: serde_json::Value = serde_json::from_slice(redacted.body()).unwrap();
+        assert_eq!(redacted_body["user"], "example");
+        assert_eq!(redacted_body["password"], "<redacted>");
+        assert_eq!(redacted_body["auth"]["password"], "<redacted>");
+        assert_eq!(request.headers()["authorization"], "Bearer 1234");
+
+        let response = Response::new(
+            serde_json::to_vec(&json!({
+                "user_id": "@example:localhost",
+                "access_token": "1234",
+                "device_id": "DEVICEID"
+            }))
+            .unwrap(),
+        );
+
+        let redacted = redact_response(&response);
+        let redacted_body: serde_json::Value = serde_json::from_slice(redacted.body()).unwrap();
+        assert_eq!(redacted_body["access_token"], "<redacted>");
+        assert_eq!(redacted_body["device_id"], "DEVICEID");
+
+        let response = Response::new(b"Hello world".to_vec());
+        assert_eq!(redact_response(&response).body(), b"Hello world");
     }
 
     #[tokio::test]
-    #[allow(irrefutable_let_patterns)]
-    async fn forget_room() {
+    async fn media_upload_and_download() {
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
-        let user = UserId::try_from("@example:localhost").unwrap();
-        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
 
         let session = Session {
             access_token: "1234".to_owned(),
-            user_id: user.clone(),
+            user_id: UserId::try_from("@example:localhost").unwrap(),
             device_id: "DEVICEID".to_owned(),
         };
 
+        let _m = mock("POST", "/_matrix/media/r0/upload")
+            .match_header("content-type", "image/png")
+            .match_header("authorization", "Bearer 1234")
+            .with_status(200)
+            .with_body_from_file("../test_data/upload_response.json")
+            .create();
+
         let _m = mock(
-            "POST",
-            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/forget".to_string()),
+            "GET",
+            "/_matrix/media/r0/download/example.com/AQwafuaFswefuhsfAFAgsw",
         )
         .with_status(200)
-        // this is an empty JSON object
-        .with_body_from_file("../test_data/logout_response.json")
+        .with_body("Hello world")
         .create();
 
         let client = Client::new(homeserver, Some(session)).unwrap();
 
-        let response = client.forget_room_by_id(&room_id).await.unwrap();
-        if let forget_room::Response = response {
-        } else {
-            panic!(
-                "expected `ruma_client_api::forget_room::Response` found {:?}",
-                response
-            )
-        }
+        let content_uri = client
+            .upload("image/png", b"Hello world".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(content_uri, "mxc://example.com/AQwafuaFswefuhsfAFAgsw");
+
+        let content = client.download(&content_uri).await.unwrap();
+        assert_eq!(content, b"Hello world");
+
+        assert!(client.download("https://example.com/media").await.is_err());
     }
 
     #[tokio::test]
-    #[allow(irrefutable_let_patterns)]
-    async fn read_receipt() {
+    async fn media_download_error() {
+        use crate::Error;
+
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
-        let user_id = UserId::try_from("@example:localhost").unwrap();
-        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
-        let event_id = EventId::new("example.org").unwrap();
+        let client = Client::new(homeserver, None).unwrap();
 
-        let session = Session {
-            access_token: "1234".to_owned(),
-            user_id,
-            device_id: "DEVICEID".to_owned(),
-        };
+        let _m = mock("GET", "/_matrix/media/r0/download/example.com/missing")
+            .with_status(404)
+            .with_body(r#"{"errcode": "M_NOT_FOUND", "error": "Not found"}"#)
+            .create();
 
-        let _m = mock(
-            "POST",
-            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/receipt".to_string()),
-        )
-        .with_status(200)
-        // this is an empty JSON object
-        .with_body_from_file("../test_data/logout_response.json")
-        .create();
+        match client.download("mxc://example.com/missing").await {
+            Err(Error::Matrix(status, e)) => {
+                assert_eq!(status, http::StatusCode::NOT_FOUND);
+                assert_eq!(e.errcode, "M_NOT_FOUND");
+                assert_eq!(e.error, "Not found");
+            }
+            r => panic!("The download didn't fail with a Matrix error {:?}", r),
+        }
 
-        let client = Client::new(homeserver, Some(session)).unwrap();
+        let _m = mock("GET", "/_matrix/media/r0/download/example.com/broken")
+            .with_status(502)
+            .with_body("Bad gateway")
+            .create();
 
-        let response = client.read_receipt(&room_id, &event_id).await.unwrap();
-        if let create_receipt::Response = response {
-        } else {
-            panic!(
-                "expected `ruma_client_api::create_receipt::Response` found {:?}",
-                response
-            )
+        match client.download("mxc://example.com/broken").await {
+            Err(Error::HttpStatus(http::StatusCode::BAD_GATEWAY)) => (),
+            r => panic!("The download didn't fail with a HTTP error {:?}", r),
         }
     }
 
     #[tokio::test]
-    #[allow(irrefutable_let_patterns)]
-    async fn typing_notice() {
+    #[cfg(feature = "encryption")]
+    async fn encrypted_attachments() {
+        use crate::encrypt_attachment;
+        use std::io::Cursor;
+
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
-        let user = UserId::try_from("@example:localhost").unwrap();
-        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
 
         let session = Session {
             access_token: "1234".to_owned(),
-            user_id: user.clone(),
+            user_id: UserId::try_from("@example:localhost").unwrap(),
             device_id: "DEVICEID".to_owned(),
         };
 
-        let _m = mock(
-            "PUT",
-            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/typing".to_string()),
-        )
-        .with_status(200)
-        // this is an empty JSON object
-        .with_body_from_file("../test_data/logout_response.json")
-        .create();
+        let _m = mock("POST", "/_matrix/media/r0/upload")
+            .match_header("content-type", "application/octet-stream")
+            .with_status(200)
+            .with_body_from_file("../test_data/upload_response.json")
+            .create();
 
         let client = Client::new(homeserver, Some(session)).unwrap();
 
-        let response = client
-            .typing_notice(
-                &room_id,
-                &user,
-                true,
-                Some(std::time::Duration::from_secs(1)),
+        let content = client
+            .upload_encrypted_attachment(
+                "cat.png",
+                "image/png",
+                &mut Cursor::new(b"Not really a cat".to_vec()),
             )
             .await
             .unwrap();
-        if let create_typing_event::Response = response {
-        } else {
-            panic!(
-                "expected `ruma_client_api::create_typing_event::Response` found {:?}",
-                response
-            )
-        }
+
+        let content = serde_json::to_value(&content).unwrap();
+        assert_eq!(content["msgtype"], "m.image");
+        assert_eq!(content["body"], "cat.png");
+        assert_eq!(
+            content["file"]["url"],
+            "mxc://example.com/AQwafuaFswefuhsfAFAgsw"
+        );
+        assert_eq!(content["file"]["v"], "v2");
+
+        let (ciphertext, info) =
+            encrypt_attachment(&mut Cursor::new(b"Not really a cat".to_vec())).unwrap();
+
+        let _m = mock("GET", "/_matrix/media/r0/download/example.com/encrypted")
+            .with_status(200)
+            .with_body(ciphertext)
+            .create();
+
+        let plaintext = client
+            .download_encrypted_attachment("mxc://example.com/encrypted", &info)
+            .await
+            .unwrap();
+        assert_eq!(plaintext, b"Not really a cat");
     }
 
     #[tokio::test]
-    async fn room_message_send() {
-        use matrix_sdk_common::uuid::Uuid;
+    #[cfg(feature = "encryption")]
+    async fn concurrent_encrypted_room_send() {
+        use serde_json::json;
 
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
-        let user = UserId::try_from("@example:localhost").unwrap();
-        let room_id = RoomId::try_from("!testroom:example.org").unwrap();
+        let room_id = RoomId::try_from("!SVkFJHzfwvuaIEawgC:localhost").unwrap();
 
         let session = Session {
             access_token: "1234".to_owned(),
-            user_id: user.clone(),
+            user_id: UserId::try_from("@example:localhost").unwrap(),
             device_id: "DEVICEID".to_owned(),
         };
 
+        // Turn the room of our sync response into an encrypted room which
+        // alice is a member of.
+        let mut sync: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string("../test_data/sync.json").unwrap())
+                .unwrap();
+        let state = sync["rooms"]["join"][room_id.as_str()]["state"]["events"]
+            .as_array_mut()
+            .unwrap();
+        state.push(json!({
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "rotation_period_ms": 604800000,
+                "rotation_period_msgs": 100
+            },
+            "event_id": "$143273582443PhrSn:localhost",
+            "origin_server_ts": 1432735824653u64,
+            "sender": "@example:localhost",
+            "state_key": "",
+            "type": "m.room.encryption",
+            "unsigned": { "age": 1234 }
+        }));
+        state.push(json!({
+            "content": { "membership": "join" },
+            "event_id": "$143273582443PhrSm:localhost",
+            "origin_server_ts": 1432735824653u64,
+            "sender": "@alice:example.org",
+            "state_key": "@alice:example.org",
+            "type": "m.room.member",
+            "unsigned": { "age": 1234 }
+        }));
+
         let _m = mock(
-            "PUT",
-            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/".to_string()),
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()),
         )
         .with_status(200)
-        .with_body_from_file("../test_data/event_id.json")
+        .with_body(sync.to_string())
         .create();
 
+        let _m_upload = mock("POST", "/_matrix/client/r0/keys/upload")
+            .with_status(200)
+            .with_body_from_file("../test_data/keys_upload.json")
+            .create();
+
+        let _m_query = mock("POST", "/_matrix/client/r0/keys/query")
+            .with_status(200)
+            .with_body_from_file("../test_data/keys_query.json")
+            .create();
+
         let client = Client::new(homeserver, Some(session)).unwrap();
+        client.sync(SyncSettings::new()).await.unwrap();
+        client.send_outgoing_requests().await;
 
-        let content = MessageEventContent::Text(TextMessageEventContent {
-            body: "Hello world".to_owned(),
-            format: None,
-            formatted_body: None,
-            relates_to: None,
-        });
-        let txn_id = Uuid::new_v4();
-        let response = client
-            .room_send(&room_id, content, Some(txn_id))
-            .await
-            .unwrap();
+        // We don't get any one-time keys for alice's device, the group session
+        // is shared anyway but alice's device gets a withheld notice.
+        let claim = mock("POST", "/_matrix/client/r0/keys/claim")
+            .with_status(200)
+            .with_body(r#"{"failures": {}, "one_time_keys": {}}"#)
+            .expect(1)
+            .create();
 
-        assert_eq!(
-            EventId::try_from("$h29iv0s8:example.com").ok(),
-            response.event_id
+        let _m_to_device = mock(
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/sendToDevice/.*".to_string()),
+        )
+        .with_status(200)
+        .with_body("{}")
+        .create();
+
+        let send = mock(
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/m.room.encrypted/".to_string()),
         )
+        .with_status(200)
+        .with_body_from_file("../test_data/event_id.json")
+        .expect(2)
+        .create();
+
+        let content = || {
+            MessageEventContent::Text(TextMessageEventContent {
+                body: "Hello world".to_owned(),
+                format: None,
+                formatted_body: None,
+                relates_to: None,
+            })
+        };
+
+        let (first, second) = futures::join!(
+            client.room_send(&room_id, content(), None),
+            client.room_send(&room_id, content(), None)
+        );
+
+        first.unwrap();
+        second.unwrap();
+
+        // Only one of the sends shared the group session, the other one waited
+        // for it and reused the session.
+        claim.assert();
+        send.assert();
+        assert!(
+            !client
+                .base_client
+                .should_share_group_session(&room_id)
+                .await
+        );
+
+        // The lock of the room is gone once nobody waits for it anymore.
+        assert!(client.group_session_locks.lock().await.is_empty());
     }
 
     #[tokio::test]
@@ -2006,4 +3808,70 @@ mod test {
 
         assert_eq!("tutorial".to_string(), room.read().await.display_name());
     }
+
+    #[tokio::test]
+    #[cfg(feature = "encryption")]
+    async fn room_key_backup() {
+        use crate::RecoveryKey;
+        use serde_json::json;
+
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: UserId::try_from("@example:localhost").unwrap(),
+            device_id: "DEVICEID".to_owned(),
+        };
+
+        let client = Client::new(homeserver, Some(session)).unwrap();
+
+        let m = mock("GET", "/_matrix/client/r0/room_keys/version")
+            .with_status(404)
+            .with_body(r#"{"errcode": "M_NOT_FOUND", "error": "No current backup version"}"#)
+            .create();
+
+        assert!(!client.enable_backup().await.unwrap());
+        assert_eq!(client.restore_backup(&RecoveryKey::new()).await.unwrap(), 0);
+        drop(m);
+
+        let _m = mock("POST", "/_matrix/client/r0/room_keys/version")
+            .with_status(200)
+            .with_body(r#"{"version": "1"}"#)
+            .create();
+
+        let recovery_key = client.create_backup().await.unwrap();
+        assert_eq!(
+            client.base_client.backup_version().await.as_deref(),
+            Some("1")
+        );
+
+        let mut backup = client
+            .base_client
+            .backup_auth_data(&recovery_key.public_key())
+            .await
+            .unwrap();
+        backup["version"] = json!("1");
+
+        let _m = mock("GET", "/_matrix/client/r0/room_keys/version")
+            .with_status(200)
+            .with_body(backup.to_string())
+            .create();
+
+        client.base_client.disable_backup().await;
+        assert!(client.enable_backup().await.unwrap());
+
+        let _m = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/r0/room_keys/keys\?version=1$".to_string()),
+        )
+        .with_status(200)
+        .with_body(r#"{"rooms": {}}"#)
+        .create();
+
+        assert_eq!(client.restore_backup(&recovery_key).await.unwrap(), 0);
+        assert!(client.restore_backup(&RecoveryKey::new()).await.is_err());
+
+        // None of our room keys need to be backed up.
+        client.backup_room_keys().await.unwrap();
+    }
 }
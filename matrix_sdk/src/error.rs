@@ -14,13 +14,20 @@
 
 //! Error conditions.
 
+use http::{Error as HttpError, Method as HttpMethod, Response as HttpResponse, StatusCode};
+use matrix_sdk_common::instant::Duration;
 use reqwest::Error as ReqwestError;
+use serde::{Deserialize, Serialize};
 use serde_json::Error as JsonError;
+use std::io::Error as IoError;
 use thiserror::Error;
 
-use matrix_sdk_base::Error as MatrixError;
+use matrix_sdk_base::Error as BaseError;
+#[cfg(feature = "encryption")]
+use matrix_sdk_base::{BackupError, DecryptorError};
 
 use crate::api::Error as RumaClientError;
+use crate::identifiers::RoomId;
 use crate::FromHttpResponseError as RumaResponseError;
 use crate::IntoHttpError as RumaIntoHttpError;
 
@@ -42,6 +49,18 @@ pub enum Error {
     #[error(transparent)]
     SerdeJson(#[from] JsonError),
 
+    /// The homeserver rate limited the request and no retries were left.
+    #[error("the request was rate limited, retry after {:?} ms", .0.retry_after_ms)]
+    RateLimited(MatrixError),
+
+    /// An IO error happened while reading an attachment.
+    #[error(transparent)]
+    Io(#[from] IoError),
+
+    /// The given content URI isn't a valid `mxc://` URI.
+    #[error("the content URI {0} isn't a valid mxc URI")]
+    InvalidContentUri(String),
+
     /// An error converting between ruma_client_api types and Hyper types.
     #[error("can't parse the JSON response as a Matrix response")]
     RumaResponse(RumaResponseError<RumaClientError>),
@@ -50,9 +69,54 @@ pub enum Error {
     #[error("can't convert between ruma_client_api and hyper types.")]
     IntoHttp(RumaIntoHttpError),
 
+    /// A request couldn't be built.
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    /// The homeserver responded with an unsuccessful status code and a body
+    /// that isn't in the standard Matrix error format.
+    #[error("the server responded with the status code {0}")]
+    HttpStatus(StatusCode),
+
+    /// The homeserver responded with an unsuccessful status code and a
+    /// standard Matrix error.
+    #[error("the server responded with the status code {0}, {}: {}", .1.errcode, .1.error)]
+    Matrix(StatusCode, MatrixError),
+
+    /// The room isn't known to the client or the client isn't joined to it.
+    #[error("the room {0} isn't a joined room")]
+    UnknownRoom(RoomId),
+
+    /// The endpoint uses a HTTP method that the client can't send.
+    #[error("the HTTP method {0} isn't supported")]
+    UnsupportedMethod(HttpMethod),
+
     /// An error occured in the Matrix client library.
     #[error(transparent)]
-    MatrixError(#[from] MatrixError),
+    MatrixError(#[from] BaseError),
+
+    /// A room key backup operation failed.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    #[error(transparent)]
+    Backup(#[from] BackupError),
+
+    /// An encrypted attachment couldn't be decrypted.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    #[error(transparent)]
+    AttachmentDecryption(#[from] DecryptorError),
+}
+
+impl Error {
+    /// Convert an unsuccessful response into an error, parsing its body if
+    /// it's in the standard Matrix error format.
+    pub(crate) fn from_response(response: &HttpResponse<Vec<u8>>) -> Self {
+        match MatrixError::from_response(response) {
+            Some(e) => Error::Matrix(response.status(), e),
+            None => Error::HttpStatus(response.status()),
+        }
+    }
 }
 
 impl From<RumaResponseError<RumaClientError>> for Error {
@@ -66,3 +130,42 @@ impl From<RumaIntoHttpError> for Error {
         Self::IntoHttp(error)
     }
 }
+
+/// An error response of the homeserver in the standard Matrix error format.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MatrixError {
+    /// The Matrix error code, e.g. `M_LIMIT_EXCEEDED`.
+    pub errcode: String,
+    /// The human readable error message.
+    #[serde(default)]
+    pub error: String,
+    /// The time in milliseconds the client should wait before retrying the
+    /// request, only present for rate limited requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+impl MatrixError {
+    /// The time the client should wait before retrying the request, if the
+    /// homeserver told us.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after_ms.map(Duration::from_millis)
+    }
+
+    /// Was the request rate limited by the homeserver.
+    pub fn is_limit_exceeded(&self) -> bool {
+        self.errcode == "M_LIMIT_EXCEEDED"
+    }
+
+    /// Parse the body of an unsuccessful response.
+    ///
+    /// Returns `None` if the response was successful or if its body isn't in
+    /// the standard Matrix error format.
+    pub(crate) fn from_response(response: &HttpResponse<Vec<u8>>) -> Option<Self> {
+        if response.status().is_success() {
+            return None;
+        }
+
+        serde_json::from_slice(response.body()).ok()
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The HTTP transport of the client and the middleware hooks around it.
+
+use std::fmt::Debug;
+
+use http::header::AUTHORIZATION;
+use http::{HeaderValue, Request as HttpRequest, Response as HttpResponse};
+use matrix_sdk_common::instant::Duration;
+use reqwest::Client as ReqwestClient;
+use serde_json::Value;
+use tracing::trace;
+
+use crate::{Error, Result};
+
+/// Abstraction around the HTTP transport the `Client` uses to talk to the
+/// homeserver.
+///
+/// The `Client` builds every request itself: the URI already points to the
+/// homeserver and the access token is already attached. An implementation
+/// only needs to send the request and hand back the response. Responses with
+/// an unsuccessful status code must be returned as a response, not as an
+/// error, the `Client` decides how to handle them.
+///
+/// # Example
+///
+/// ```
+/// use matrix_sdk::{HttpSend, Result};
+/// use http::{Request, Response};
+///
+/// #[derive(Debug)]
+/// struct EmptyResponses;
+///
+/// #[async_trait::async_trait]
+/// impl HttpSend for EmptyResponses {
+///     async fn send_request(&self, request: Request<Vec<u8>>) -> Result<Response<Vec<u8>>> {
+///         println!("Sending a request to {}", request.uri());
+///         Ok(Response::new(b"{}".to_vec()))
+///     }
+/// }
+/// ```
+#[async_trait::async_trait]
+pub trait HttpSend: Send + Sync + Debug {
+    /// Send the given request to the homeserver.
+    ///
+    /// Returns the response of the homeserver, whatever its status code is.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The fully built request that should be sent.
+    async fn send_request(&self, request: HttpRequest<Vec<u8>>) -> Result<HttpResponse<Vec<u8>>>;
+}
+
+/// The default `HttpSend` implementation, backed by a `reqwest::Client`.
+#[derive(Clone, Debug, Default)]
+pub struct DefaultHttpClient {
+    inner: ReqwestClient,
+}
+
+impl DefaultHttpClient {
+    /// Create a new transport using the given `reqwest::Client`.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The reqwest client that will send the requests.
+    pub fn with_client(client: ReqwestClient) -> Self {
+        Self { inner: client }
+    }
+}
+
+#[async_trait::async_trait]
+impl HttpSend for DefaultHttpClient {
+    async fn send_request(&self, request: HttpRequest<Vec<u8>>) -> Result<HttpResponse<Vec<u8>>> {
+        let (parts, body) = request.into_parts();
+
+        let mut request_builder = self
+            .inner
+            .request(parts.method, &parts.uri.to_string())
+            .headers(parts.headers);
+
+        if !body.is_empty() {
+            request_builder = request_builder.body(body);
+        }
+
+        let mut response = request_builder.send().await?;
+
+        trace!("Got response: {:?}", response);
+
+        let status = response.status();
+        let mut http_builder = HttpResponse::builder().status(status);
+        let headers = http_builder.headers_mut().unwrap();
+
+        for (k, v) in response.headers_mut().drain() {
+            if let Some(key) = k {
+                headers.insert(key, v);
+            }
+        }
+        let body = response.bytes().await?.as_ref().to_owned();
+
+        Ok(http_builder.body(body).unwrap())
+    }
+}
+
+/// Hooks that are called for every request the `Client` sends to the
+/// homeserver.
+///
+/// Middleware can add custom headers to the requests, log the requests and
+/// responses or collect metrics about them. A request passes through
+/// `on_request()` once, `on_response()` and `on_error()` are called once per
+/// attempt if the request is retried.
+///
+/// Requests and responses contain secrets like the access token of the user
+/// or the password of a login request, use `redact_request()` and
+/// `redact_response()` before logging them.
+///
+/// All the methods have a default implementation that does nothing, so only
+/// the needed ones have to be implemented.
+///
+/// # Example
+///
+/// ```
+/// use matrix_sdk::{redact_request, redact_response, ClientConfig, HttpMiddleware};
+/// use http::{Request, Response};
+/// use std::{sync::Arc, time::Duration};
+///
+/// #[derive(Debug)]
+/// struct RequestLogger;
+///
+/// #[async_trait::async_trait]
+/// impl HttpMiddleware for RequestLogger {
+///     async fn on_response(
+///         &self,
+///         request: &Request<Vec<u8>>,
+///         response: &Response<Vec<u8>>,
+///         elapsed: Duration,
+///     ) {
+///         println!(
+///             "{:?} returned {:?} after {:?}",
+///             redact_request(request),
+///             redact_response(response),
+///             elapsed
+///         );
+///     }
+/// }
+///
+/// let client_config = ClientConfig::new().middleware(Arc::new(RequestLogger));
+/// ```
+#[async_trait::async_trait]
+pub trait HttpMiddleware: Send + Sync + Debug {
+    /// Called before a request is sent, the request may be modified.
+    ///
+    /// The `Authorization` header of the request contains the access token of
+    /// the user, use `redact_request()` before the request is logged.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request that is about to be sent.
+    async fn on_request(&self, _request: &mut HttpRequest<Vec<u8>>) {}
+
+    /// Called after a response for a request was received.
+    ///
+    /// This is called for every response, whatever its status code is.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request that was sent.
+    ///
+    /// * `response` - The response of the homeserver.
+    ///
+    /// * `elapsed` - The time it took to receive the response.
+    async fn on_response(
+        &self,
+        _request: &HttpRequest<Vec<u8>>,
+        _response: &HttpResponse<Vec<u8>>,
+        _elapsed: Duration,
+    ) {
+    }
+
+    /// Called if the HTTP transport failed to send a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request that couldn't be sent.
+    ///
+    /// * `error` - The error of the HTTP transport.
+    ///
+    /// * `elapsed` - The time it took until the request failed.
+    async fn on_error(&self, _request: &HttpRequest<Vec<u8>>, _error: &Error, _elapsed: Duration) {}
+}
+
+/// The value that replaces secrets in redacted requests and responses.
+const REDACTED: &str = "<redacted>";
+
+/// Keys of JSON bodies whose values are secrets, e.g. the password of a login
+/// request or the access token of a login response.
+const SECRET_KEYS: &[&str] = &["access_token", "new_password", "password", "token"];
+
+/// Get a copy of the request that can be logged.
+///
+/// The `Authorization` header and secrets in the JSON body of the request,
+/// like passwords and access tokens, are replaced with a placeholder. Bodies
+/// that aren't JSON are kept as they are.
+///
+/// # Arguments
+///
+/// * `request` - The request that should be redacted.
+pub fn redact_request(request: &HttpRequest<Vec<u8>>) -> HttpRequest<Vec<u8>> {
+    let mut redacted = HttpRequest::new(redact_body(request.body()));
+    *redacted.method_mut() = request.method().clone();
+    *redacted.uri_mut() = request.uri().clone();
+    *redacted.version_mut() = request.version();
+    *redacted.headers_mut() = request.headers().clone();
+
+    if redacted.headers().contains_key(AUTHORIZATION) {
+        redacted
+            .headers_mut()
+            .insert(AUTHORIZATION, HeaderValue::from_static(REDACTED));
+    }
+
+    redacted
+}
+
+/// Get a copy of the response that can be logged.
+///
+/// Secrets in the JSON body of the response, like the access token of a
+/// login response, are replaced with a placeholder. Bodies that aren't JSON
+/// are kept as they are.
+///
+/// # Arguments
+///
+/// * `response` - The response that should be redacted.
+pub fn redact_response(response: &HttpResponse<Vec<u8>>) -> HttpResponse<Vec<u8>> {
+    let mut redacted = HttpResponse::new(redact_body(response.body()));
+    *redacted.status_mut() = response.status();
+    *redacted.version_mut() = response.version();
+    *redacted.headers_mut() = response.headers().clone();
+
+    redacted
+}
+
+fn redact_body(body: &[u8]) -> Vec<u8> {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            serde_json::to_vec(&value).unwrap_or_else(|_| body.to_vec())
+        }
+        Err(_) => body.to_vec(),
+    }
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) {
+                    *value = Value::String(REDACTED.to_owned());
+                } else {
+                    redact_value(value);
+                }
+            }
+        }
+        Value::Array(array) => array.iter_mut().for_each(redact_value),
+        _ => (),
+    }
+}
@@ -36,6 +36,7 @@
     unused_qualifications
 )]
 
+pub use http::Method as HttpMethod;
 #[cfg(not(target_arch = "wasm32"))]
 pub use matrix_sdk_base::JsonStore;
 pub use matrix_sdk_base::{EventEmitter, Room, Session, SyncRoom};
@@ -44,13 +45,25 @@ pub use matrix_sdk_common::*;
 pub use reqwest::header::InvalidHeaderValue;
 
 #[cfg(feature = "encryption")]
-pub use matrix_sdk_base::{Device, TrustState};
+pub use matrix_sdk_base::{
+    decrypt_attachment, encrypt_attachment, AttachmentDecryptor, AttachmentEncryptor, BackupError,
+    BackupVersion, CrossSigningError, CrossSigningKey, CrossSigningUploadRequest, DecryptorError,
+    Device, EncryptionSettings, GroupSessionSharePolicy, InboundGroupSession, IncomingResponse,
+    JsonWebKey, KeyExportError, KeysBackupRequest, KeysQueryCrossSigningKeys, KeysUploadRequest,
+    MediaEncryptionInfo, MegolmError, MegolmV1BackupKey, OutgoingRequest, OutgoingRequests,
+    RecoveryKey, RoomKeyBackup, Sas, SignatureUploadRequest, TrustState, UnusedFallbackKeyTypes,
+    UserIdentity, DEFAULT_EXPORT_ROUNDS,
+};
 
 mod client;
 mod error;
+mod http_client;
 mod request_builder;
-pub use client::{Client, ClientConfig, SyncSettings};
-pub use error::{Error, Result};
+pub use client::{Client, ClientConfig, RetryPolicy, SyncSettings};
+pub use error::{Error, MatrixError, Result};
+pub use http_client::{
+    redact_request, redact_response, DefaultHttpClient, HttpMiddleware, HttpSend,
+};
 pub use request_builder::{MessagesRequestBuilder, RoomBuilder};
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -20,9 +20,6 @@ use std::fmt;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-#[cfg(feature = "encryption")]
-use std::result::Result as StdResult;
-
 use crate::api::r0 as api;
 use crate::error::Result;
 use crate::events::collections::all::{RoomEvent, StateEvent};
@@ -32,12 +29,13 @@ use crate::events::collections::only::Event as NonRoomEvent;
 use crate::events::ignored_user_list::IgnoredUserListEvent;
 use crate::events::push_rules::{PushRulesEvent, Ruleset};
 use crate::events::stripped::AnyStrippedStateEvent;
+use crate::events::to_device::AnyToDeviceEvent;
 use crate::events::EventJson;
 use crate::identifiers::{RoomId, UserId};
 use crate::models::Room;
 use crate::session::Session;
 use crate::state::{AllRooms, ClientState, StateStore};
-use crate::EventEmitter;
+use crate::{DecryptedEvent, EncryptionInfo, EventEmitter};
 
 #[cfg(feature = "encryption")]
 use matrix_sdk_common::locks::Mutex;
@@ -47,19 +45,34 @@ use std::ops::Deref;
 #[cfg(feature = "encryption")]
 use crate::api::r0::keys::{
     claim_keys::Response as KeysClaimResponse, get_keys::Response as KeysQueryResponse,
-    upload_keys::Response as KeysUploadResponse, DeviceKeys, KeyAlgorithm,
+    upload_keys::Response as KeysUploadResponse,
 };
 #[cfg(feature = "encryption")]
-use crate::api::r0::to_device::send_event_to_device;
+use crate::api::r0::to_device::{send_event_to_device, DeviceIdOrAllDevices};
+#[cfg(feature = "encryption")]
+use crate::events::room::{
+    encrypted::{EncryptedEvent, EncryptedEventContent},
+    message::MessageEventContent,
+};
 #[cfg(feature = "encryption")]
-use crate::events::room::{encrypted::EncryptedEventContent, message::MessageEventContent};
+use crate::events::EventType;
 #[cfg(feature = "encryption")]
 use crate::identifiers::DeviceId;
 #[cfg(feature = "encryption")]
-use matrix_sdk_crypto::{OlmMachine, OneTimeKeys};
+use matrix_sdk_crypto::{
+    BackupVersion, CrossSigningUploadRequest, Device, EncryptionSettings, GroupSessionSharePolicy,
+    InboundGroupSession, IncomingResponse, KeysBackupRequest, KeysQueryCrossSigningKeys,
+    MegolmError, MegolmV1BackupKey, OlmError, OlmMachine, OutgoingRequest, RecoveryKey,
+    RoomKeyBackup, Sas, SignatureUploadRequest, TrustState, UnusedFallbackKeyTypes, UserIdentity,
+};
 
 pub type Token = String;
 
+/// The maximum number of undecryptable room events that are kept around to
+/// retry their decryption once their group session arrives.
+#[cfg(feature = "encryption")]
+const MAX_UNDECRYPTED_EVENTS: usize = 1000;
+
 /// Signals to the `BaseClient` which `RoomState` to send to `EventEmitter`.
 #[derive(Debug)]
 pub enum RoomStateType {
@@ -76,7 +89,7 @@ pub enum RoomStateType {
 /// If the event came from the `join`, `invite` or `leave` rooms map from the server
 /// the variant that holds the corresponding room is used. `RoomState` is generic
 /// so it can be used to represent a `Room` or an `Arc<RwLock<Room>>`
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum RoomState<R> {
     /// A room from the `join` section of a sync response.
     Joined(R),
@@ -120,6 +133,10 @@ pub struct BaseClient {
 
     #[cfg(feature = "encryption")]
     olm: Arc<Mutex<Option<OlmMachine>>>,
+    /// Room events that couldn't be decrypted because their group session was
+    /// missing, keyed by the session id.
+    #[cfg(feature = "encryption")]
+    undecrypted_events: Arc<Mutex<HashMap<String, Vec<EncryptedEvent>>>>,
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -183,6 +200,8 @@ impl BaseClient {
             needs_state_store_sync: Arc::new(AtomicBool::from(true)),
             #[cfg(feature = "encryption")]
             olm: Arc::new(Mutex::new(olm)),
+            #[cfg(feature = "encryption")]
+            undecrypted_events: Arc::new(Mutex::new(HashMap::new())),
         })
     }
 
@@ -251,6 +270,24 @@ impl BaseClient {
                     .map(|(k, room)| (k, Arc::new(RwLock::new(room))))
                     .collect();
 
+                #[cfg(feature = "encryption")]
+                {
+                    let mut olm = self.olm.lock().await;
+
+                    if let Some(o) = &mut *olm {
+                        // Let the crypto layer know with whom we share our
+                        // encrypted rooms.
+                        for (room_id, room) in self.joined_rooms.read().await.iter() {
+                            let room = room.read().await;
+
+                            if room.is_encrypted() {
+                                o.update_room_members(room_id, room.encryption_recipients())
+                                    .await;
+                            }
+                        }
+                    }
+                }
+
                 self.needs_state_store_sync.store(false, Ordering::Relaxed);
             }
         }
@@ -459,8 +496,9 @@ impl BaseClient {
 
     /// Receive a timeline event for a joined room and update the client state.
     ///
-    /// Returns a tuple of the successfully decrypted event, or None on failure and
-    /// a bool, true when the `Room` state has been updated.
+    /// Returns a tuple of the successfully decrypted event together with its
+    /// encryption info, or None on failure and a bool, true when the `Room`
+    /// state has been updated.
     ///
     /// # Arguments
     ///
@@ -471,7 +509,7 @@ impl BaseClient {
         &self,
         room_id: &RoomId,
         event: &mut EventJson<RoomEvent>,
-    ) -> (Option<EventJson<RoomEvent>>, bool) {
+    ) -> (Option<DecryptedEvent>, bool) {
         match event.deserialize() {
             #[allow(unused_mut)]
             Ok(mut e) => {
@@ -487,7 +525,29 @@ impl BaseClient {
                         let mut olm = self.olm.lock().await;
 
                         if let Some(o) = &mut *olm {
-                            decrypted_event = o.decrypt_room_event(&e).await.ok();
+                            match o.decrypt_room_event(&e).await {
+                                Ok(d) => decrypted_event = Some(d),
+                                Err(MegolmError::MissingSession)
+                                | Err(MegolmError::Withheld { .. }) => {
+                                    // The key might still arrive, e.g. as an
+                                    // answer to a room key request.
+                                    if let EncryptedEventContent::MegolmV1AesSha2(c) = &e.content {
+                                        let mut undecrypted = self.undecrypted_events.lock().await;
+                                        let count: usize =
+                                            undecrypted.values().map(|v| v.len()).sum();
+
+                                        // Don't let events whose keys never
+                                        // arrive pile up forever.
+                                        if count < MAX_UNDECRYPTED_EVENTS {
+                                            undecrypted
+                                                .entry(c.session_id.clone())
+                                                .or_insert_with(Vec::new)
+                                                .push(e.clone());
+                                        }
+                                    }
+                                }
+                                Err(_) => (),
+                            }
                         }
                     }
                 }
@@ -691,13 +751,23 @@ impl BaseClient {
         {
             let mut olm = self.olm.lock().await;
 
-            if let Some(o) = &mut *olm {
+            let received_sessions = if let Some(o) = &mut *olm {
                 // Let the crypto machine handle the sync response, this
                 // decryptes to-device events, but leaves room events alone.
                 // This makes sure that we have the deryption keys for the room
                 // events at hand.
                 o.receive_sync_response(response).await;
-            }
+                o.take_received_group_sessions()
+            } else {
+                HashSet::new()
+            };
+
+            drop(olm);
+            self.retry_decryption(received_sessions).await;
+        }
+
+        for event in &response.to_device.events {
+            self.emit_to_device_event(event).await;
         }
 
         // TODO do we want to move the rooms to the appropriate HashMaps when the corresponding
@@ -721,6 +791,65 @@ impl BaseClient {
         Ok(())
     }
 
+    /// Get the state of the room with the given id, None if we don't know
+    /// about the room.
+    #[cfg(feature = "encryption")]
+    async fn room_state_type(&self, room_id: &RoomId) -> Option<RoomStateType> {
+        if self.joined_rooms.read().await.contains_key(room_id) {
+            Some(RoomStateType::Joined)
+        } else if self.invited_rooms.read().await.contains_key(room_id) {
+            Some(RoomStateType::Invited)
+        } else if self.left_rooms.read().await.contains_key(room_id) {
+            Some(RoomStateType::Left)
+        } else {
+            None
+        }
+    }
+
+    /// Try to decrypt room events again that previously failed to be
+    /// decrypted because their group session was missing.
+    ///
+    /// Successfully decrypted events are passed to the event emitter.
+    ///
+    /// # Arguments
+    ///
+    /// * `sessions` - The ids of the group sessions that we received.
+    #[cfg(feature = "encryption")]
+    async fn retry_decryption(&self, sessions: HashSet<String>) {
+        for session_id in sessions {
+            let events = self.undecrypted_events.lock().await.remove(&session_id);
+
+            for event in events.unwrap_or_default() {
+                let decrypted = {
+                    let mut olm = self.olm.lock().await;
+
+                    match &mut *olm {
+                        Some(o) => o.decrypt_room_event(&event).await,
+                        None => return,
+                    }
+                };
+
+                if let (Some(room_id), Ok(decrypted)) = (&event.room_id, decrypted) {
+                    let room_state = if let Some(s) = self.room_state_type(room_id).await {
+                        s
+                    } else {
+                        continue;
+                    };
+
+                    if let Ok(e) = decrypted.event.deserialize() {
+                        self.emit_timeline_event(
+                            room_id,
+                            &e,
+                            room_state,
+                            Some(&decrypted.encryption_info),
+                        )
+                        .await;
+                    }
+                }
+            }
+        }
+    }
+
     async fn iter_joined_rooms(
         &self,
         response: &mut api::sync::sync_events::Response,
@@ -741,20 +870,6 @@ impl BaseClient {
                 self.get_or_create_joined_room(&room_id).await.clone()
             };
 
-            #[cfg(feature = "encryption")]
-            {
-                let mut olm = self.olm.lock().await;
-
-                if let Some(o) = &mut *olm {
-                    let room = matrix_room.read().await;
-
-                    // If the room is encrypted, update the tracked users.
-                    if room.is_encrypted() {
-                        o.update_tracked_users(room.members.keys()).await;
-                    }
-                }
-            }
-
             // RoomSummary contains information for calculating room name
             matrix_room
                 .write()
@@ -778,13 +893,38 @@ impl BaseClient {
                     decrypt_ev
                 };
 
-                if let Some(e) = decrypted_event {
-                    *event = e;
-                }
+                let encryption_info = if let Some(d) = decrypted_event {
+                    *event = d.event;
+                    Some(d.encryption_info)
+                } else {
+                    None
+                };
 
                 if let Ok(e) = event.deserialize() {
-                    self.emit_timeline_event(&room_id, &e, RoomStateType::Joined)
-                        .await;
+                    self.emit_timeline_event(
+                        &room_id,
+                        &e,
+                        RoomStateType::Joined,
+                        encryption_info.as_ref(),
+                    )
+                    .await;
+                }
+            }
+
+            #[cfg(feature = "encryption")]
+            {
+                let mut olm = self.olm.lock().await;
+
+                if let Some(o) = &mut *olm {
+                    let room = matrix_room.read().await;
+
+                    // If the room is encrypted, update the tracked users. This
+                    // needs to happen after the timeline was processed, it
+                    // can contain membership changes as well.
+                    if room.is_encrypted() {
+                        o.update_room_members(room_id, room.encryption_recipients())
+                            .await;
+                    }
                 }
             }
 
@@ -861,6 +1001,16 @@ impl BaseClient {
                 self.get_or_create_left_room(&room_id).await.clone()
             };
 
+            #[cfg(feature = "encryption")]
+            {
+                let mut olm = self.olm.lock().await;
+
+                if let Some(o) = &mut *olm {
+                    // We don't share this room with its members anymore.
+                    o.forget_room(room_id).await;
+                }
+            }
+
             for event in &mut left_room.state.events {
                 if let Ok(e) = event.deserialize() {
                     self.emit_state_event(&room_id, &e, RoomStateType::Left)
@@ -874,7 +1024,7 @@ impl BaseClient {
                 };
 
                 if let Ok(e) = event.deserialize() {
-                    self.emit_timeline_event(&room_id, &e, RoomStateType::Left)
+                    self.emit_timeline_event(&room_id, &e, RoomStateType::Left, None)
                         .await;
                 }
             }
@@ -926,18 +1076,6 @@ impl BaseClient {
         Ok(updated)
     }
 
-    /// Should account or one-time keys be uploaded to the server.
-    #[cfg(feature = "encryption")]
-    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
-    pub async fn should_upload_keys(&self) -> bool {
-        let olm = self.olm.lock().await;
-
-        match &*olm {
-            Some(o) => o.should_upload_keys().await,
-            None => false,
-        }
-    }
-
     /// Should the client share a group session for the given room.
     ///
     /// Returns true if a session needs to be shared before room messages can be
@@ -969,20 +1107,27 @@ impl BaseClient {
         }
     }
 
-    /// Get a tuple of device and one-time keys that need to be uploaded.
+    /// Queue up a one-time key claim for the devices of the given users that
+    /// we don't share an Olm session with yet.
     ///
-    /// Returns an empty error if no keys need to be uploaded.
+    /// Returns the queued request, None if no key needs to be claimed. The
+    /// request needs to be sent out and marked as sent using
+    /// `mark_request_as_sent()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - The users whose devices should have an Olm session with us.
     #[cfg(feature = "encryption")]
     #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
-    pub async fn get_missing_sessions(
+    pub async fn claim_missing_sessions(
         &self,
         users: impl Iterator<Item = &UserId>,
-    ) -> Result<BTreeMap<UserId, BTreeMap<DeviceId, KeyAlgorithm>>> {
+    ) -> Result<Option<OutgoingRequest>> {
         let mut olm = self.olm.lock().await;
 
         match &mut *olm {
-            Some(o) => Ok(o.get_missing_sessions(users).await?),
-            None => Ok(BTreeMap::new()),
+            Some(o) => Ok(o.claim_missing_sessions(users).await?),
+            None => Ok(None),
         }
     }
 
@@ -999,8 +1144,12 @@ impl BaseClient {
         match &mut *olm {
             Some(o) => {
                 let room = room.write().await;
-                let members = room.members.keys();
-                Ok(o.share_group_session(room_id, members).await?)
+                let members = room.encryption_recipients();
+                let settings = room
+                    .encryption_info()
+                    .map(EncryptionSettings::from)
+                    .unwrap_or_default();
+                Ok(o.share_group_session(room_id, members, settings).await?)
             }
             None => panic!("Olm machine wasn't started"),
         }
@@ -1022,36 +1171,6 @@ impl BaseClient {
         }
     }
 
-    /// Get a tuple of device and one-time keys that need to be uploaded.
-    ///
-    /// Returns an empty error if no keys need to be uploaded.
-    #[cfg(feature = "encryption")]
-    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
-    pub async fn keys_for_upload(
-        &self,
-    ) -> StdResult<(Option<DeviceKeys>, Option<OneTimeKeys>), ()> {
-        let olm = self.olm.lock().await;
-
-        match &*olm {
-            Some(o) => o.keys_for_upload().await,
-            None => Err(()),
-        }
-    }
-
-    /// Get the users that we need to query keys for.
-    ///
-    /// Returns an empty error if no keys need to be queried.
-    #[cfg(feature = "encryption")]
-    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
-    pub async fn users_for_key_query(&self) -> StdResult<HashSet<UserId>, ()> {
-        let olm = self.olm.lock().await;
-
-        match &*olm {
-            Some(o) => Ok(o.users_for_key_query()),
-            None => Err(()),
-        }
-    }
-
     /// Receive a successful keys upload response.
     ///
     /// # Arguments
@@ -1071,6 +1190,31 @@ impl BaseClient {
         Ok(())
     }
 
+    /// Receive the unused fallback key types of a sync response.
+    ///
+    /// A new fallback key will be part of the next keys upload request if our
+    /// current one was used up.
+    ///
+    /// # Arguments
+    ///
+    /// * `unused_fallback_keys` - The unused fallback key types that were part
+    /// of the sync response.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn receive_unused_fallback_key_types(
+        &self,
+        unused_fallback_keys: &UnusedFallbackKeyTypes,
+    ) -> Result<()> {
+        let mut olm = self.olm.lock().await;
+
+        if let Some(o) = &mut *olm {
+            o.receive_unused_fallback_key_types(unused_fallback_keys)
+                .await?;
+        }
+
+        Ok(())
+    }
+
     /// Receive a successful keys claim response.
     ///
     /// # Arguments
@@ -1097,15 +1241,23 @@ impl BaseClient {
     /// * `response` - The keys query response of the request that the client
     /// performed.
     ///
+    /// * `cross_signing_keys` - The cross signing keys that are part of the
+    /// same keys query response.
+    ///
     /// # Panics
     /// Panics if the client hasn't been logged in.
     #[cfg(feature = "encryption")]
     #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
-    pub async fn receive_keys_query_response(&self, response: &KeysQueryResponse) -> Result<()> {
+    pub async fn receive_keys_query_response(
+        &self,
+        response: &KeysQueryResponse,
+        cross_signing_keys: &KeysQueryCrossSigningKeys,
+    ) -> Result<()> {
         let mut olm = self.olm.lock().await;
 
         let o = olm.as_mut().expect("Client isn't logged in.");
-        o.receive_keys_query_response(response).await?;
+        o.receive_keys_query_response(response, cross_signing_keys)
+            .await?;
         // TODO notify our callers of new devices via some callback.
         Ok(())
     }
@@ -1121,16 +1273,559 @@ impl BaseClient {
         let mut olm = self.olm.lock().await;
 
         match &mut *olm {
-            Some(o) => o.invalidate_group_session(room_id),
+            Some(o) => o.invalidate_group_session(room_id).await,
             None => false,
         }
     }
 
+    /// Get a specific device of an user.
+    ///
+    /// Returns None if the client isn't logged in or if the device is unknown.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the device belongs to.
+    ///
+    /// * `device_id` - The unique id of the device.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    #[allow(clippy::ptr_arg)]
+    pub async fn get_device(&self, user_id: &UserId, device_id: &DeviceId) -> Option<Device> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => o.get_device(user_id, device_id).await.ok().flatten(),
+            None => None,
+        }
+    }
+
+    /// Set the trust state of a device.
+    ///
+    /// The new trust state is persisted in the crypto store. Blacklisted
+    /// devices won't receive any of our future room keys.
+    ///
+    /// Returns false if the device is unknown, true otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the device belongs to.
+    ///
+    /// * `device_id` - The unique id of the device.
+    ///
+    /// * `trust_state` - The new trust state of the device.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    #[allow(clippy::ptr_arg)]
+    pub async fn set_device_trust_state(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        trust_state: TrustState,
+    ) -> Result<bool> {
+        let mut olm = self.olm.lock().await;
+
+        match &mut *olm {
+            Some(o) => Ok(o
+                .set_device_trust_state(user_id, device_id, trust_state)
+                .await
+                .map_err(OlmError::from)?),
+            None => Err(crate::Error::AuthenticationRequired),
+        }
+    }
+
+    /// Get the trust state of a device, taking cross signing into account.
+    ///
+    /// Devices with an unset trust state are considered to be verified if
+    /// they are signed by the verified cross signing identity of their owner.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device whose trust state should be checked.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn device_trust_state(&self, device: &Device) -> Result<TrustState> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => Ok(o.device_trust_state(device).await.map_err(OlmError::from)?),
+            None => Err(crate::Error::AuthenticationRequired),
+        }
+    }
+
+    /// Get the cross signing identity of an user.
+    ///
+    /// Returns None if the client isn't logged in or if the identity is
+    /// unknown.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the identity belongs to.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn get_user_identity(&self, user_id: &UserId) -> Option<UserIdentity> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => o.get_user_identity(user_id).await.ok().flatten(),
+            None => None,
+        }
+    }
+
+    /// Check if the given cross signing identity is verified.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - The identity that should be checked.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn is_identity_verified(&self, identity: &UserIdentity) -> Result<bool> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => Ok(o
+                .is_identity_verified(identity)
+                .await
+                .map_err(OlmError::from)?),
+            None => Err(crate::Error::AuthenticationRequired),
+        }
+    }
+
+    /// Create our cross signing identity, if we don't have one yet, and
+    /// prepare it to be uploaded.
+    ///
+    /// Returns a request to upload the public cross signing keys and the
+    /// signatures that need to be uploaded after the keys.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn bootstrap_cross_signing(
+        &self,
+    ) -> Result<(CrossSigningUploadRequest, SignatureUploadRequest)> {
+        let mut olm = self.olm.lock().await;
+
+        match &mut *olm {
+            Some(o) => Ok(o.bootstrap_cross_signing().await?),
+            None => Err(crate::Error::AuthenticationRequired),
+        }
+    }
+
+    /// Verify the cross signing identity of another user by signing their
+    /// master key with our user-signing key.
+    ///
+    /// Returns the signature that needs to be uploaded to the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user whose identity should be verified.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn sign_user_identity(&self, user_id: &UserId) -> Result<SignatureUploadRequest> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => Ok(o.sign_user_identity(user_id).await?),
+            None => Err(crate::Error::AuthenticationRequired),
+        }
+    }
+
+    /// Set the policy that decides which devices get our room keys when a
+    /// group session is shared.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The policy that should be used when sharing group
+    /// sessions.
+    ///
+    /// # Panics
+    /// Panics if the client hasn't been logged in.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn set_group_session_share_policy(&self, policy: GroupSessionSharePolicy) {
+        let mut olm = self.olm.lock().await;
+
+        olm.as_mut()
+            .expect("Client isn't logged in.")
+            .set_group_session_share_policy(policy)
+    }
+
+    /// Start an interactive SAS verification with the given device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device that should be verified.
+    ///
+    /// # Panics
+    /// Panics if the client hasn't been logged in.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn start_verification(&self, device: Device) -> Sas {
+        let olm = self.olm.lock().await;
+
+        let o = olm.as_ref().expect("Client isn't logged in.");
+        o.start_verification(device)
+    }
+
+    /// Accept a verification request that another device sent us.
+    ///
+    /// Returns the newly started SAS verification flow, None if no request
+    /// with the given flow id is known.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The transaction id of the verification request.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn accept_verification_request(&self, flow_id: &str) -> Option<Sas> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => o.accept_verification_request(flow_id),
+            None => None,
+        }
+    }
+
+    /// Get a SAS verification flow with the given flow id.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The unique id of the verification flow.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn get_verification(&self, flow_id: &str) -> Option<Sas> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => o.get_verification(flow_id),
+            None => None,
+        }
+    }
+
+    /// Accept a SAS verification flow that the other side started.
+    ///
+    /// Returns true if the flow was accepted.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The unique id of the verification flow.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn accept_verification(&self, flow_id: &str) -> bool {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => o.accept_verification(flow_id),
+            None => false,
+        }
+    }
+
+    /// Confirm that the short authentication strings of a SAS verification
+    /// flow match.
+    ///
+    /// Returns true if the flow was confirmed.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The unique id of the verification flow.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn confirm_verification(&self, flow_id: &str) -> Result<bool> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => Ok(o.confirm_verification(flow_id).await?),
+            None => Ok(false),
+        }
+    }
+
+    /// Cancel a SAS verification flow.
+    ///
+    /// Returns true if the flow was canceled.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The unique id of the verification flow.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn cancel_verification(&self, flow_id: &str) -> bool {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => o.cancel_verification(flow_id),
+            None => false,
+        }
+    }
+
+    /// Get all the requests that the crypto machine wants to be sent out.
+    ///
+    /// This includes keys upload, keys query, keys claim and to-device
+    /// requests. Each request needs to be marked as sent using
+    /// `mark_request_as_sent()` once a response for it was received.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn outgoing_requests(&self) -> Vec<OutgoingRequest> {
+        let mut olm = self.olm.lock().await;
+
+        match &mut *olm {
+            Some(o) => o.outgoing_requests().await,
+            None => Vec::new(),
+        }
+    }
+
+    /// Mark the request with the given request id as sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `request_id` - The unique id of the request that was sent out.
+    ///
+    /// * `response` - The response the server returned for the request.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn mark_request_as_sent(
+        &self,
+        request_id: &str,
+        response: IncomingResponse<'_>,
+    ) -> Result<()> {
+        let mut olm = self.olm.lock().await;
+
+        match &mut *olm {
+            Some(o) => Ok(o.mark_request_as_sent(request_id, response).await?),
+            None => Err(crate::Error::AuthenticationRequired),
+        }
+    }
+
+    /// Olm encrypt to-device event contents for the given devices.
+    ///
+    /// Devices that we don't share an Olm session with are skipped.
+    ///
+    /// Returns the to-device requests carrying the encrypted events.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_type` - The type of the events that should be encrypted.
+    ///
+    /// * `messages` - The event content for each user and device.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn encrypt_to_device(
+        &self,
+        event_type: EventType,
+        messages: &BTreeMap<UserId, BTreeMap<DeviceIdOrAllDevices, serde_json::Value>>,
+    ) -> Result<Vec<send_event_to_device::Request>> {
+        let mut olm = self.olm.lock().await;
+
+        match &mut *olm {
+            Some(o) => Ok(o.encrypt_to_device(event_type, messages).await?),
+            None => Err(crate::Error::AuthenticationRequired),
+        }
+    }
+
+    /// Enable the backup of our room keys to the given backup version.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The public key of the backup.
+    ///
+    /// * `version` - The version of the backup on the server.
+    ///
+    /// # Panics
+    /// Panics if the client hasn't been logged in.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn enable_backup(&self, key: MegolmV1BackupKey, version: String) {
+        let mut olm = self.olm.lock().await;
+
+        olm.as_mut()
+            .expect("Client isn't logged in.")
+            .enable_backup(key, version)
+    }
+
+    /// Disable the backup of our room keys.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn disable_backup(&self) {
+        let mut olm = self.olm.lock().await;
+
+        if let Some(o) = &mut *olm {
+            o.disable_backup()
+        }
+    }
+
+    /// Get the version of the currently enabled room key backup.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn backup_version(&self) -> Option<String> {
+        let olm = self.olm.lock().await;
+
+        olm.as_ref()
+            .and_then(|o| o.backup_version().map(|v| v.to_owned()))
+    }
+
+    /// Get the signed algorithm and auth data for a new backup version.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The public key of the new backup.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn backup_auth_data(&self, key: &MegolmV1BackupKey) -> Result<serde_json::Value> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => Ok(o.backup_auth_data(key).await),
+            None => Err(crate::Error::AuthenticationRequired),
+        }
+    }
+
+    /// Check if the given backup version can be trusted.
+    ///
+    /// Returns false if the client isn't logged in.
+    ///
+    /// # Arguments
+    ///
+    /// * `backup` - The backup version that should be checked.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn verify_backup(&self, backup: &BackupVersion) -> bool {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => o.verify_backup(backup).await,
+            None => false,
+        }
+    }
+
+    /// Get a batch of room keys that need to be uploaded to the currently
+    /// enabled backup.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn backup_request(&self) -> Result<Option<KeysBackupRequest>> {
+        let mut olm = self.olm.lock().await;
+
+        match &mut *olm {
+            Some(o) => Ok(o.backup_request().await?),
+            None => Ok(None),
+        }
+    }
+
+    /// Mark the room keys of the given backup request as uploaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The backup request that was successfully sent out.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn mark_backup_as_sent(&self, request: &KeysBackupRequest) -> Result<()> {
+        let mut olm = self.olm.lock().await;
+
+        match &mut *olm {
+            Some(o) => Ok(o.mark_backup_as_sent(request).await?),
+            None => Err(crate::Error::AuthenticationRequired),
+        }
+    }
+
+    /// Import room keys that were downloaded from a backup.
+    ///
+    /// Events that couldn't be decrypted before because one of the imported
+    /// room keys was missing are decrypted again.
+    ///
+    /// Returns the number of room keys that were imported.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The recovery key of the backup.
+    ///
+    /// * `version` - The version of the backup the room keys were downloaded
+    /// from.
+    ///
+    /// * `rooms` - The backed up room keys, grouped by room.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn import_backed_up_room_keys(
+        &self,
+        recovery_key: &RecoveryKey,
+        version: &str,
+        rooms: BTreeMap<RoomId, RoomKeyBackup>,
+    ) -> Result<usize> {
+        let mut olm = self.olm.lock().await;
+
+        let (imported, received_sessions) = match &mut *olm {
+            Some(o) => {
+                let imported = o
+                    .import_backed_up_room_keys(recovery_key, version, rooms)
+                    .await?;
+                (imported, o.take_received_group_sessions())
+            }
+            None => return Err(crate::Error::AuthenticationRequired),
+        };
+
+        drop(olm);
+        self.retry_decryption(received_sessions).await;
+
+        Ok(imported)
+    }
+
+    /// Export room keys into a passphrase protected key export.
+    ///
+    /// # Arguments
+    ///
+    /// * `predicate` - Closure deciding which room keys should be exported.
+    ///
+    /// * `passphrase` - The passphrase that protects the export.
+    ///
+    /// * `rounds` - The number of PBKDF2 rounds used to derive the encryption
+    /// key from the passphrase.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn export_keys(
+        &self,
+        predicate: impl FnMut(&InboundGroupSession) -> bool,
+        passphrase: &str,
+        rounds: u32,
+    ) -> Result<String> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => Ok(o.export_keys(predicate, passphrase, rounds).await?),
+            None => Err(crate::Error::AuthenticationRequired),
+        }
+    }
+
+    /// Import room keys from a passphrase protected key export.
+    ///
+    /// Events that couldn't be decrypted before because one of the imported
+    /// room keys was missing are decrypted again.
+    ///
+    /// Returns the number of room keys that were imported.
+    ///
+    /// # Arguments
+    ///
+    /// * `export` - The key export, including the header and footer lines.
+    ///
+    /// * `passphrase` - The passphrase that protects the export.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub async fn import_keys(&self, export: &str, passphrase: &str) -> Result<usize> {
+        let mut olm = self.olm.lock().await;
+
+        let (imported, received_sessions) = match &mut *olm {
+            Some(o) => {
+                let imported = o.import_keys(export, passphrase).await?;
+                (imported, o.take_received_group_sessions())
+            }
+            None => return Err(crate::Error::AuthenticationRequired),
+        };
+
+        drop(olm);
+        self.retry_decryption(received_sessions).await;
+
+        Ok(imported)
+    }
+
     pub(crate) async fn emit_timeline_event(
         &self,
         room_id: &RoomId,
         event: &RoomEvent,
         room_state: RoomStateType,
+        encryption_info: Option<&EncryptionInfo>,
     ) {
         let lock = self.event_emitter.read().await;
         let event_emitter = if let Some(ee) = lock.as_ref() {
@@ -1163,6 +1858,12 @@ impl BaseClient {
             }
         };
 
+        if let Some(info) = encryption_info {
+            event_emitter
+                .on_room_decrypted_event(room.clone(), event, info)
+                .await;
+        }
+
         match event {
             RoomEvent::RoomMember(mem) => event_emitter.on_room_member(room, &mem).await,
             RoomEvent::RoomName(name) => event_emitter.on_room_name(room, &name).await,
@@ -1466,6 +2167,45 @@ impl BaseClient {
             ee.on_presence_event(room, &event).await;
         }
     }
+
+    pub(crate) async fn emit_to_device_event(&self, event: &EventJson<AnyToDeviceEvent>) {
+        if let Some(ee) = &self.event_emitter.read().await.as_ref() {
+            // Events that can't be sanitized aren't emitted at all, they might
+            // contain a private key.
+            match sanitize_to_device_event(event) {
+                Ok(Some(sanitized)) => ee.on_to_device_event(&sanitized).await,
+                Ok(None) => ee.on_to_device_event(event).await,
+                Err(_) => (),
+            }
+        }
+    }
+}
+
+/// Clear the private key of a room key event.
+///
+/// Room key events can reach us unencrypted, the private key of those needs
+/// to be removed as well before the event is handed out.
+///
+/// Returns `Ok(None)` if the event isn't a room key event and can be passed on
+/// as it is.
+fn sanitize_to_device_event(
+    event: &EventJson<AnyToDeviceEvent>,
+) -> serde_json::Result<Option<EventJson<AnyToDeviceEvent>>> {
+    let mut json: serde_json::Value = serde_json::from_str(event.json().get())?;
+
+    match json.get("type").and_then(|t| t.as_str()) {
+        Some("m.room_key") | Some("m.forwarded_room_key") => (),
+        _ => return Ok(None),
+    }
+
+    if let Some(session_key) = json
+        .get_mut("content")
+        .and_then(|c| c.get_mut("session_key"))
+    {
+        *session_key = serde_json::Value::String(String::new());
+    }
+
+    Ok(Some(serde_json::from_value(json)?))
 }
 
 #[cfg(test)]
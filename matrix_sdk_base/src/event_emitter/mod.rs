@@ -17,6 +17,7 @@ use std::sync::Arc;
 use matrix_sdk_common::locks::RwLock;
 
 use crate::events::{
+    collections::all::RoomEvent,
     fully_read::FullyReadEvent,
     ignored_user_list::IgnoredUserListEvent,
     presence::PresenceEvent,
@@ -38,9 +39,11 @@ use crate::events::{
         StrippedRoomAliases, StrippedRoomAvatar, StrippedRoomCanonicalAlias, StrippedRoomJoinRules,
         StrippedRoomMember, StrippedRoomName, StrippedRoomPowerLevels,
     },
+    to_device::AnyToDeviceEvent,
     typing::TypingEvent,
+    EventJson,
 };
-use crate::{Room, RoomState};
+use crate::{EncryptionInfo, Room, RoomState};
 
 /// Type alias for `RoomState` enum when passed to `EventEmitter` methods.
 pub type SyncRoom = RoomState<Arc<RwLock<Room>>>;
@@ -112,6 +115,12 @@ pub trait EventEmitter: Send + Sync {
     async fn on_room_power_levels(&self, _: SyncRoom, _: &PowerLevelsEvent) {}
     /// Fires when `Client` receives a `RoomEvent::Tombstone` event.
     async fn on_room_tombstone(&self, _: SyncRoom, _: &TombstoneEvent) {}
+    /// Fires when `Client` successfully decrypts a `RoomEvent::RoomEncrypted`
+    /// event.
+    ///
+    /// This fires right before the callback for the type of the decrypted
+    /// event, the `EncryptionInfo` tells if the sender's device is verified.
+    async fn on_room_decrypted_event(&self, _: SyncRoom, _: &RoomEvent, _: &EncryptionInfo) {}
 
     // `RoomEvent`s from `IncomingState`
     /// Fires when `Client` receives a `StateEvent::RoomMember` event.
@@ -165,6 +174,17 @@ pub trait EventEmitter: Send + Sync {
     // `PresenceEvent` is a struct so there is only the one method
     /// Fires when `Client` receives a `NonRoomEvent::RoomAliases` event.
     async fn on_presence_event(&self, _: SyncRoom, _: &PresenceEvent) {}
+
+    // To-device events
+    /// Fires when `Client` receives a to-device event.
+    ///
+    /// Encrypted to-device events are passed to this callback in their
+    /// decrypted form if decryption succeeded. The raw JSON of the event is
+    /// available for event types that aren't known to the library.
+    ///
+    /// The `session_key` of `m.room_key` and `m.forwarded_room_key` events is
+    /// always cleared, the room key itself is stored by the client.
+    async fn on_to_device_event(&self, _: &EventJson<AnyToDeviceEvent>) {}
 }
 
 #[cfg(test)]
@@ -288,6 +308,19 @@ mod test {
         async fn on_presence_event(&self, _: SyncRoom, _: &PresenceEvent) {
             self.0.lock().await.push("presence event".to_string())
         }
+        async fn on_to_device_event(&self, _: &EventJson<AnyToDeviceEvent>) {
+            self.0.lock().await.push("to-device".to_string())
+        }
+    }
+
+    pub struct ToDeviceRecorder(Arc<Mutex<Vec<serde_json::Value>>>);
+
+    #[async_trait::async_trait]
+    impl EventEmitter for ToDeviceRecorder {
+        async fn on_to_device_event(&self, event: &EventJson<AnyToDeviceEvent>) {
+            let json = serde_json::from_str(event.json().get()).unwrap();
+            self.0.lock().await.push(json)
+        }
     }
 
     use crate::identifiers::UserId;
@@ -335,6 +368,60 @@ mod test {
         )
     }
 
+    #[async_test]
+    async fn event_emitter_to_device() {
+        let vec = Arc::new(Mutex::new(Vec::new()));
+        let test_vec = Arc::clone(&vec);
+        let emitter = Box::new(EvEmitterTest(vec));
+
+        let client = get_client();
+        client.add_event_emitter(emitter).await;
+
+        let mut response = sync_response(SyncResponseFile::Default);
+        response.to_device.events = vec![serde_json::from_value(serde_json::json!({
+            "content": { "ping": "pong" },
+            "sender": "@alice:example.com",
+            "type": "org.example.ping"
+        }))
+        .unwrap()];
+        client.receive_sync_response(&mut response).await.unwrap();
+
+        let v = test_vec.lock().await;
+        assert_eq!(v.first().map(String::as_str), Some("to-device"));
+    }
+
+    #[async_test]
+    async fn event_emitter_forwarded_room_key() {
+        let vec = Arc::new(Mutex::new(Vec::new()));
+        let test_vec = Arc::clone(&vec);
+        let emitter = Box::new(ToDeviceRecorder(vec));
+
+        let client = get_client();
+        client.add_event_emitter(emitter).await;
+
+        let mut response = sync_response(SyncResponseFile::Default);
+        response.to_device.events = vec![serde_json::from_value(serde_json::json!({
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "forwarding_curve25519_key_chain": [],
+                "room_id": "!test:example.org",
+                "sender_claimed_ed25519_key": "aj40p+aw64yPIdsxoog8Jhlu9i0GRn3N6QkOm3hOqsY",
+                "sender_key": "RF3s+E7RkTQTGF2d8Deol0FkQvgII2aJDf3/Jp5mxVU",
+                "session_id": "X3lUlvLELLYxeTx4yOVu6UDpasGEVO0Jbu+QFnm0cKQ",
+                "session_key": "AgAAAADxKHa9uFxcXzwYoNueL5Xqi69IkD4sni8Llf"
+            },
+            "sender": "@alice:example.com",
+            "type": "m.forwarded_room_key"
+        }))
+        .unwrap()];
+        client.receive_sync_response(&mut response).await.unwrap();
+
+        let v = test_vec.lock().await;
+        assert_eq!(v.len(), 1);
+        assert_eq!(v[0]["type"], "m.forwarded_room_key");
+        assert_eq!(v[0]["content"]["session_key"], "");
+    }
+
     #[async_test]
     async fn event_emitter_invite() {
         let vec = Arc::new(Mutex::new(Vec::new()));
@@ -48,7 +48,15 @@ mod state;
 pub use client::{BaseClient, RoomState, RoomStateType};
 pub use event_emitter::{EventEmitter, SyncRoom};
 #[cfg(feature = "encryption")]
-pub use matrix_sdk_crypto::{Device, TrustState};
+pub use matrix_sdk_crypto::{
+    decrypt_attachment, encrypt_attachment, AttachmentDecryptor, AttachmentEncryptor, BackupError,
+    BackupVersion, CrossSigningError, CrossSigningKey, CrossSigningUploadRequest, DecryptorError,
+    Device, EncryptionSettings, GroupSessionSharePolicy, InboundGroupSession, IncomingResponse,
+    JsonWebKey, KeyExportError, KeysBackupRequest, KeysQueryCrossSigningKeys, KeysUploadRequest,
+    MediaEncryptionInfo, MegolmError, MegolmV1BackupKey, OutgoingRequest, OutgoingRequests,
+    RecoveryKey, RoomKeyBackup, Sas, SignatureUploadRequest, TrustState, UnusedFallbackKeyTypes,
+    UserIdentity, DEFAULT_EXPORT_ROUNDS,
+};
 pub use models::Room;
 #[cfg(not(target_arch = "wasm32"))]
 pub use state::JsonStore;
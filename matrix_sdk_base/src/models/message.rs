@@ -255,7 +255,8 @@ mod test {
     "encrypted": false,
     "unread_highlight": null,
     "unread_notifications": null,
-    "tombstone": null
+    "tombstone": null,
+    "history_visibility": null
   }
 }"#,
             serde_json::to_string_pretty(&joined_rooms).unwrap()
@@ -319,7 +320,8 @@ mod test {
     "encrypted": false,
     "unread_highlight": null,
     "unread_notifications": null,
-    "tombstone": null
+    "tombstone": null,
+    "history_visibility": null
   }
 }"#;
         assert_eq!(
@@ -15,6 +15,8 @@
 
 use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
+#[cfg(feature = "encryption")]
+use std::time::Duration;
 
 #[cfg(feature = "messages")]
 use super::message::MessageQueue;
@@ -27,7 +29,8 @@ use crate::events::room::{
     aliases::AliasesEvent,
     canonical_alias::CanonicalAliasEvent,
     encryption::EncryptionEvent,
-    member::{MemberEvent, MembershipChange},
+    history_visibility::{HistoryVisibility, HistoryVisibilityEvent},
+    member::{MemberEvent, MembershipChange, MembershipState},
     name::NameEvent,
     power_levels::{NotificationPowerLevels, PowerLevelsEvent, PowerLevelsEventContent},
     tombstone::TombstoneEvent,
@@ -42,6 +45,10 @@ use crate::identifiers::{RoomAliasId, RoomId, UserId};
 
 use crate::js_int::{Int, UInt};
 use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "encryption")]
+use matrix_sdk_crypto::EncryptionSettings;
+
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Clone))]
 /// `RoomName` allows the calculation of a text room name.
@@ -124,6 +131,17 @@ impl EncryptionInfo {
     }
 }
 
+#[cfg(feature = "encryption")]
+impl From<&EncryptionInfo> for EncryptionSettings {
+    fn from(info: &EncryptionInfo) -> Self {
+        EncryptionSettings {
+            algorithm: info.algorithm.clone(),
+            rotation_period: Duration::from_millis(info.rotation_period_ms),
+            rotation_period_msgs: info.rotation_period_messages,
+        }
+    }
+}
+
 impl From<&EncryptionEvent> for EncryptionInfo {
     fn from(event: &EncryptionEvent) -> Self {
         EncryptionInfo {
@@ -180,6 +198,8 @@ pub struct Room {
     pub unread_notifications: Option<UInt>,
     /// The tombstone state of this room.
     pub tombstone: Option<Tombstone>,
+    /// Who can read the message history of this room.
+    pub history_visibility: Option<HistoryVisibility>,
 }
 
 impl RoomName {
@@ -278,6 +298,7 @@ impl Room {
             unread_highlight: None,
             unread_notifications: None,
             tombstone: None,
+            history_visibility: None,
         }
     }
 
@@ -298,12 +319,31 @@ impl Room {
         self.encrypted.as_ref()
     }
 
+    /// Get the users that should receive the room keys of this room.
+    ///
+    /// Joined members always receive the room keys, invited members only if
+    /// the history visibility of the room lets them read messages that were
+    /// sent before they joined.
+    pub fn encryption_recipients(&self) -> impl Iterator<Item = &UserId> {
+        let include_invited = self.history_visibility != Some(HistoryVisibility::Joined);
+
+        self.members
+            .values()
+            .filter(move |m| match m.membership {
+                MembershipState::Join => true,
+                MembershipState::Invite => include_invited,
+                _ => false,
+            })
+            .map(|m| &m.user_id)
+    }
+
     fn add_member(&mut self, event: &MemberEvent) -> bool {
-        if self
+        // An invited member that joins only needs its membership updated.
+        if let Some(member) = self
             .members
-            .contains_key(&UserId::try_from(event.state_key.as_str()).unwrap())
+            .get_mut(&UserId::try_from(event.state_key.as_str()).unwrap())
         {
-            return false;
+            return member.membership != event.content.membership && member.update_member(event);
         }
 
         let member = RoomMember::new(event);
@@ -482,6 +522,11 @@ impl Room {
         true
     }
 
+    fn handle_history_visibility(&mut self, event: &HistoryVisibilityEvent) -> bool {
+        self.history_visibility = Some(event.content.history_visibility);
+        true
+    }
+
     /// Receive a timeline event for this room and update the room state.
     ///
     /// Returns true if the joined member list changed, false otherwise.
@@ -501,6 +546,9 @@ impl Room {
             RoomEvent::RoomPowerLevels(power) => self.handle_power_level(power),
             RoomEvent::RoomTombstone(tomb) => self.handle_tombstone(tomb),
             RoomEvent::RoomEncryption(encrypt) => self.handle_encryption_event(encrypt),
+            RoomEvent::RoomHistoryVisibility(visibility) => {
+                self.handle_history_visibility(visibility)
+            }
             #[cfg(feature = "messages")]
             RoomEvent::RoomMessage(msg) => self.handle_message(msg),
             _ => false,
@@ -526,6 +574,9 @@ impl Room {
             StateEvent::RoomPowerLevels(power) => self.handle_power_level(power),
             StateEvent::RoomTombstone(tomb) => self.handle_tombstone(tomb),
             StateEvent::RoomEncryption(encrypt) => self.handle_encryption_event(encrypt),
+            StateEvent::RoomHistoryVisibility(visibility) => {
+                self.handle_history_visibility(visibility)
+            }
             _ => false,
         }
     }
@@ -575,7 +626,7 @@ mod test {
     use super::*;
     use crate::events::{
         room::{encryption::EncryptionEventContent, member::MembershipState},
-        UnsignedData,
+        EventJson, UnsignedData,
     };
     use crate::identifiers::{EventId, UserId};
     use crate::{BaseClient, Session};
@@ -729,6 +780,45 @@ mod test {
         assert_eq!(vec!["example, example2"], room_names);
     }
 
+    #[async_test]
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn encryption_recipients() {
+        let client = get_client();
+        let room_id = get_room_id();
+        let invited_id = UserId::try_from("@invited:localhost").unwrap();
+
+        let mut response = sync_response(SyncResponseFile::Default);
+        client.receive_sync_response(&mut response).await.unwrap();
+
+        let json = std::fs::read_to_string("../test_data/events/member.json").unwrap();
+        let mut member: serde_json::Value = serde_json::from_str(&json).unwrap();
+        member["state_key"] = invited_id.to_string().into();
+        member["content"]["membership"] = "invite".into();
+        let member = serde_json::from_value::<EventJson<MemberEvent>>(member)
+            .unwrap()
+            .deserialize()
+            .unwrap();
+
+        let json = std::fs::read_to_string("../test_data/events/history_visibility.json").unwrap();
+        let mut visibility: serde_json::Value = serde_json::from_str(&json).unwrap();
+        visibility["content"]["history_visibility"] = "joined".into();
+        let visibility = serde_json::from_value::<EventJson<HistoryVisibilityEvent>>(visibility)
+            .unwrap()
+            .deserialize()
+            .unwrap();
+
+        let room = client.get_joined_room(&room_id).await.unwrap();
+        let mut room = room.write().await;
+
+        assert!(room.handle_membership(&member));
+        assert_eq!(room.encryption_recipients().count(), 3);
+        assert!(room.encryption_recipients().any(|u| u == &invited_id));
+
+        room.handle_history_visibility(&visibility);
+        assert_eq!(room.encryption_recipients().count(), 2);
+        assert!(!room.encryption_recipients().any(|u| u == &invited_id));
+    }
+
     #[async_test]
     #[cfg(not(target_arch = "wasm32"))]
     async fn encryption_info_test() {
@@ -771,5 +861,15 @@ mod test {
         assert_eq!(encryption_info.algorithm(), &Algorithm::MegolmV1AesSha2);
         assert_eq!(encryption_info.rotation_period(), 100_000);
         assert_eq!(encryption_info.rotation_period_messages(), 100);
+
+        #[cfg(feature = "encryption")]
+        {
+            let settings = matrix_sdk_crypto::EncryptionSettings::from(encryption_info);
+            assert_eq!(
+                settings.rotation_period,
+                std::time::Duration::from_millis(100_000)
+            );
+            assert_eq!(settings.rotation_period_msgs, 100);
+        }
     }
 }
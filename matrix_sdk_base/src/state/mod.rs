@@ -146,7 +146,8 @@ mod test {
     "encrypted": null,
     "unread_highlight": null,
     "unread_notifications": null,
-    "tombstone": null
+    "tombstone": null,
+    "history_visibility": null
   }
 }"#,
             serde_json::to_string_pretty(&joined_rooms).unwrap()
@@ -174,7 +175,8 @@ mod test {
     "encrypted": null,
     "unread_highlight": null,
     "unread_notifications": null,
-    "tombstone": null
+    "tombstone": null,
+    "history_visibility": null
   }
 }"#,
             serde_json::to_string_pretty(&joined_rooms).unwrap()
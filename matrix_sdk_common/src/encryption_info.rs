@@ -0,0 +1,60 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::events::{collections::all::RoomEvent, EventJson};
+use crate::identifiers::DeviceId;
+
+/// The verification state of the device that sent an encrypted event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerificationState {
+    /// The event was sent by a device that we have verified.
+    Trusted,
+    /// The event was sent by a device that we know about but haven't verified,
+    /// or its keys don't match the keys that were used to encrypt the event.
+    Untrusted,
+    /// The event was sent by a device that we don't know about.
+    UnknownDevice,
+}
+
+/// Information about the encryption of a successfully decrypted event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncryptionInfo {
+    /// The curve25519 key of the device that sent the event.
+    pub sender_key: String,
+    /// The ed25519 key that the sender of the group session claims to own.
+    pub claimed_ed25519_key: String,
+    /// The id of the device that sent the event.
+    pub sender_device: DeviceId,
+    /// The id of the group session that was used to decrypt the event.
+    pub session_id: String,
+    /// The verification state of the device that sent the event.
+    pub verification_state: VerificationState,
+}
+
+impl EncryptionInfo {
+    /// Was the event sent by a device that we have verified.
+    pub fn is_verified(&self) -> bool {
+        self.verification_state == VerificationState::Trusted
+    }
+}
+
+/// A successfully decrypted room event together with the info about its
+/// encryption.
+#[derive(Clone, Debug)]
+pub struct DecryptedEvent {
+    /// The decrypted event.
+    pub event: EventJson<RoomEvent>,
+    /// Information about the encryption of the event.
+    pub encryption_info: EncryptionInfo,
+}
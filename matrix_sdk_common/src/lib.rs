@@ -10,4 +10,7 @@ pub use ruma_identifiers as identifiers;
 
 pub use uuid;
 
+mod encryption_info;
 pub mod locks;
+
+pub use encryption_info::{DecryptedEvent, EncryptionInfo, VerificationState};
@@ -0,0 +1,395 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind, Read};
+
+use aes_ctr::stream_cipher::{NewStreamCipher, SyncStreamCipher};
+use aes_ctr::Aes256Ctr;
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+
+use super::error::DecryptorError;
+
+const VERSION: &str = "v2";
+const KEY_SIZE: usize = 32;
+const IV_SIZE: usize = 16;
+const HASH_ALGORITHM: &str = "sha256";
+
+/// The key of an encrypted attachment in the JSON Web Key format.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct JsonWebKey {
+    /// The key type, always `oct`.
+    pub kty: String,
+    /// The operations the key may be used for, `encrypt` and `decrypt`.
+    pub key_ops: Vec<String>,
+    /// The algorithm of the key, always `A256CTR`.
+    pub alg: String,
+    /// The URL safe unpadded base64 encoded key.
+    pub k: String,
+    /// Is the key extractable, always true.
+    pub ext: bool,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Debug for JsonWebKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonWebKey")
+            .field("kty", &self.kty)
+            .field("key_ops", &self.key_ops)
+            .field("alg", &self.alg)
+            .field("ext", &self.ext)
+            .finish()
+    }
+}
+
+/// The information that is needed to decrypt an encrypted attachment.
+///
+/// This is the `file` object of a media message in an encrypted room, without
+/// the URL of the attachment.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MediaEncryptionInfo {
+    /// The version of the encrypted attachment format.
+    #[serde(rename = "v")]
+    pub version: String,
+    /// The key that was used to encrypt the attachment.
+    #[serde(rename = "key")]
+    pub web_key: JsonWebKey,
+    /// The unpadded base64 encoded initialization vector.
+    pub iv: String,
+    /// The unpadded base64 encoded hashes of the ciphertext, keyed by the
+    /// hash algorithm.
+    pub hashes: BTreeMap<String, String>,
+}
+
+/// Decode base64 that may or may not be padded.
+fn decode(input: &str, config: base64::Config) -> Result<Vec<u8>, base64::DecodeError> {
+    base64::decode_config(input.trim_end_matches('='), config)
+}
+
+/// A wrapper around a reader that encrypts the data it reads.
+///
+/// The encryption info can be fetched using `finish()` once all the data was
+/// read.
+pub struct AttachmentEncryptor<'a, R: Read> {
+    inner: &'a mut R,
+    web_key: JsonWebKey,
+    iv: String,
+    cipher: Aes256Ctr,
+    sha: Sha256,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl<'a, R: Read> fmt::Debug for AttachmentEncryptor<'a, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AttachmentEncryptor")
+            .field("iv", &self.iv)
+            .finish()
+    }
+}
+
+impl<'a, R: Read> AttachmentEncryptor<'a, R> {
+    /// Wrap the given reader encrypting all the data that is read from it.
+    ///
+    /// A new random key and initialization vector are generated for every
+    /// attachment.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The reader of the plaintext attachment.
+    pub fn new(reader: &'a mut R) -> Self {
+        let mut key = [0u8; KEY_SIZE];
+        let mut iv = [0u8; IV_SIZE];
+
+        thread_rng().fill_bytes(&mut key);
+        // Only the first half of the IV is random, the second half is the
+        // block counter which starts at zero.
+        thread_rng().fill_bytes(&mut iv[..IV_SIZE / 2]);
+
+        let web_key = JsonWebKey {
+            kty: "oct".to_owned(),
+            key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
+            alg: "A256CTR".to_owned(),
+            k: base64::encode_config(&key, base64::URL_SAFE_NO_PAD),
+            ext: true,
+        };
+
+        let cipher = Aes256Ctr::new_var(&key, &iv).expect("Invalid AES key or IV length");
+        key.zeroize();
+
+        AttachmentEncryptor {
+            inner: reader,
+            web_key,
+            iv: base64::encode_config(&iv, base64::STANDARD_NO_PAD),
+            cipher,
+            sha: Sha256::new(),
+        }
+    }
+
+    /// Get the information that is needed to decrypt the attachment.
+    ///
+    /// This needs to be called after all the data was read, the hash only
+    /// covers the data that was read until then.
+    pub fn finish(self) -> MediaEncryptionInfo {
+        let mut hashes = BTreeMap::new();
+        hashes.insert(
+            HASH_ALGORITHM.to_owned(),
+            base64::encode_config(&self.sha.result(), base64::STANDARD_NO_PAD),
+        );
+
+        MediaEncryptionInfo {
+            version: VERSION.to_owned(),
+            web_key: self.web_key,
+            iv: self.iv,
+            hashes,
+        }
+    }
+}
+
+impl<'a, R: Read> Read for AttachmentEncryptor<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let read_bytes = self.inner.read(buf)?;
+
+        self.cipher.apply_keystream(&mut buf[..read_bytes]);
+        self.sha.input(&buf[..read_bytes]);
+
+        Ok(read_bytes)
+    }
+}
+
+/// A wrapper around a reader that decrypts the data it reads.
+///
+/// The hash of the ciphertext is checked once the end of the data is reached,
+/// reading returns an `InvalidData` error wrapping a
+/// `DecryptorError::HashMismatch` if it doesn't match.
+pub struct AttachmentDecryptor<'a, R: Read> {
+    inner: &'a mut R,
+    expected_hash: Vec<u8>,
+    cipher: Aes256Ctr,
+    sha: Sha256,
+    finished: bool,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl<'a, R: Read> fmt::Debug for AttachmentDecryptor<'a, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AttachmentDecryptor")
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+impl<'a, R: Read> AttachmentDecryptor<'a, R> {
+    /// Wrap the given reader decrypting all the data that is read from it.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The reader of the encrypted attachment.
+    ///
+    /// * `info` - The encryption info of the attachment.
+    pub fn new(reader: &'a mut R, info: &MediaEncryptionInfo) -> Result<Self, DecryptorError> {
+        if info.version != VERSION {
+            return Err(DecryptorError::UnknownVersion(info.version.clone()));
+        }
+
+        let expected_hash = decode(
+            info.hashes
+                .get(HASH_ALGORITHM)
+                .ok_or(DecryptorError::MissingHash)?,
+            base64::STANDARD_NO_PAD,
+        )?;
+        let mut key = decode(&info.web_key.k, base64::URL_SAFE_NO_PAD)?;
+        let iv = decode(&info.iv, base64::STANDARD_NO_PAD)?;
+
+        if key.len() != KEY_SIZE || iv.len() != IV_SIZE {
+            key.zeroize();
+            return Err(DecryptorError::KeyNonceLength);
+        }
+
+        let cipher = Aes256Ctr::new_var(&key, &iv).expect("Invalid AES key or IV length");
+        key.zeroize();
+
+        Ok(AttachmentDecryptor {
+            inner: reader,
+            expected_hash,
+            cipher,
+            sha: Sha256::new(),
+            finished: false,
+        })
+    }
+}
+
+impl<'a, R: Read> Read for AttachmentDecryptor<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if self.finished {
+            return Ok(0);
+        }
+
+        let read_bytes = self.inner.read(buf)?;
+
+        if read_bytes == 0 && !buf.is_empty() {
+            self.finished = true;
+
+            if self.sha.result_reset().as_slice() != self.expected_hash.as_slice() {
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    DecryptorError::HashMismatch,
+                ));
+            }
+
+            return Ok(0);
+        }
+
+        self.sha.input(&buf[..read_bytes]);
+        self.cipher.apply_keystream(&mut buf[..read_bytes]);
+
+        Ok(read_bytes)
+    }
+}
+
+/// Encrypt the given attachment.
+///
+/// Returns the ciphertext and the information that is needed to decrypt it.
+///
+/// # Arguments
+///
+/// * `reader` - The reader of the plaintext attachment.
+pub fn encrypt_attachment<R: Read>(
+    reader: &mut R,
+) -> Result<(Vec<u8>, MediaEncryptionInfo), IoError> {
+    let mut encryptor = AttachmentEncryptor::new(reader);
+    let mut ciphertext = Vec::new();
+    encryptor.read_to_end(&mut ciphertext)?;
+
+    Ok((ciphertext, encryptor.finish()))
+}
+
+/// Decrypt the given attachment, checking the hash of the ciphertext.
+///
+/// # Arguments
+///
+/// * `reader` - The reader of the encrypted attachment.
+///
+/// * `info` - The encryption info of the attachment.
+pub fn decrypt_attachment<R: Read>(
+    reader: &mut R,
+    info: &MediaEncryptionInfo,
+) -> Result<Vec<u8>, DecryptorError> {
+    let mut decryptor = AttachmentDecryptor::new(reader, info)?;
+    let mut plaintext = Vec::new();
+    decryptor.read_to_end(&mut plaintext)?;
+
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        decrypt_attachment, encrypt_attachment, AttachmentDecryptor, AttachmentEncryptor,
+        MediaEncryptionInfo,
+    };
+    use crate::error::DecryptorError;
+    use serde_json::json;
+    use std::io::{Cursor, Read};
+
+    fn example_info() -> MediaEncryptionInfo {
+        serde_json::from_value(json!({
+            "v": "v2",
+            "key": {
+                "kty": "oct",
+                "key_ops": ["encrypt", "decrypt"],
+                "alg": "A256CTR",
+                "k": "Voq2nkPme_x8no5-Tjq_laDAdxE6iDbxnlQXxwFPgE4",
+                "ext": true
+            },
+            "iv": "i0DovxYdJEcAAAAAAAAAAA",
+            "hashes": {
+                "sha256": "ANdt819a8bZl4jKy3Z+jcqtiNICa2y0AW4BBJ/iQRAU"
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn encrypt_decrypt_cycle() {
+        let data = "Hello world".to_owned();
+        let mut cursor = Cursor::new(data.clone());
+
+        let (ciphertext, info) = encrypt_attachment(&mut cursor).unwrap();
+        assert_ne!(ciphertext.as_slice(), data.as_bytes());
+        assert_eq!(info.version, "v2");
+        assert!(info.hashes.contains_key("sha256"));
+
+        let plaintext = decrypt_attachment(&mut Cursor::new(ciphertext), &info).unwrap();
+        assert_eq!(plaintext, data.as_bytes());
+    }
+
+    #[test]
+    fn streaming_encrypt_decrypt() {
+        let data = vec![42u8; 100_000];
+        let mut cursor = Cursor::new(data.clone());
+        let mut encryptor = AttachmentEncryptor::new(&mut cursor);
+
+        let mut ciphertext = Vec::new();
+        let mut buffer = [0u8; 1000];
+
+        loop {
+            let read = encryptor.read(&mut buffer).unwrap();
+            if read == 0 {
+                break;
+            }
+            ciphertext.extend_from_slice(&buffer[..read]);
+        }
+
+        let info = encryptor.finish();
+        assert_eq!(ciphertext.len(), data.len());
+
+        let mut cursor = Cursor::new(ciphertext);
+        let mut decryptor = AttachmentDecryptor::new(&mut cursor, &info).unwrap();
+        let mut plaintext = Vec::new();
+        decryptor.read_to_end(&mut plaintext).unwrap();
+
+        assert_eq!(plaintext, data);
+    }
+
+    #[test]
+    fn tampered_attachment() {
+        let mut cursor = Cursor::new("Hello world".to_owned());
+        let (mut ciphertext, info) = encrypt_attachment(&mut cursor).unwrap();
+        ciphertext[0] ^= 1;
+
+        match decrypt_attachment(&mut Cursor::new(ciphertext), &info) {
+            Err(DecryptorError::HashMismatch) => (),
+            r => panic!("Tampered attachment was decrypted {:?}", r),
+        }
+    }
+
+    #[test]
+    fn invalid_encryption_info() {
+        let mut info = example_info();
+        info.version = "v1".to_owned();
+        assert!(AttachmentDecryptor::new(&mut Cursor::new(Vec::new()), &info).is_err());
+
+        let mut info = example_info();
+        info.hashes.clear();
+        assert!(AttachmentDecryptor::new(&mut Cursor::new(Vec::new()), &info).is_err());
+
+        let mut info = example_info();
+        info.iv = "i0DovxYdJEc".to_owned();
+        assert!(AttachmentDecryptor::new(&mut Cursor::new(Vec::new()), &info).is_err());
+    }
+}
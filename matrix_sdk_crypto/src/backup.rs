@@ -0,0 +1,567 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use aes::Aes256;
+use base64::DecodeError;
+use block_modes::block_padding::Pkcs7;
+use block_modes::{BlockMode, Cbc};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+use matrix_sdk_common::events::Algorithm;
+use matrix_sdk_common::identifiers::{RoomId, UserId};
+
+use super::error::BackupError;
+use super::olm::{GroupSessionKey, InboundGroupSession};
+
+/// The algorithm of the room key backups we support.
+pub const BACKUP_ALGORITHM: &str = "m.megolm_backup.v1.curve25519-aes-sha2";
+
+const RECOVERY_KEY_PREFIX: [u8; 2] = [0x8B, 0x01];
+const RECOVERY_KEY_LENGTH: usize = 35;
+
+type Aes256Cbc = Cbc<Aes256, Pkcs7>;
+type HmacSha256 = Hmac<Sha256>;
+
+fn encode(input: impl AsRef<[u8]>) -> String {
+    base64::encode_config(input, base64::STANDARD_NO_PAD)
+}
+
+fn decode(input: &str) -> Result<Vec<u8>, DecodeError> {
+    base64::decode_config(input.trim_end_matches('='), base64::STANDARD_NO_PAD)
+}
+
+fn decode_key(input: &str) -> Result<[u8; 32], BackupError> {
+    let bytes = decode(input)?;
+
+    if bytes.len() != 32 {
+        return Err(BackupError::InvalidPublicKey);
+    }
+
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+
+    Ok(key)
+}
+
+/// The keys that are derived from the shared secret of the ephemeral key and
+/// the backup key.
+struct BackupKeys {
+    aes_key: [u8; 32],
+    mac_key: [u8; 32],
+    iv: [u8; 16],
+}
+
+impl BackupKeys {
+    fn derive(shared_secret: &[u8]) -> Self {
+        let hkdf: Hkdf<Sha256> = Hkdf::new(None, shared_secret);
+        let mut okm = [0u8; 80];
+        hkdf.expand(b"", &mut okm)
+            .expect("Can't expand the shared secret into backup keys");
+
+        let mut keys = BackupKeys {
+            aes_key: [0u8; 32],
+            mac_key: [0u8; 32],
+            iv: [0u8; 16],
+        };
+
+        keys.aes_key.copy_from_slice(&okm[0..32]);
+        keys.mac_key.copy_from_slice(&okm[32..64]);
+        keys.iv.copy_from_slice(&okm[64..80]);
+        okm.zeroize();
+
+        keys
+    }
+
+    fn cipher(&self) -> Aes256Cbc {
+        Aes256Cbc::new_var(&self.aes_key, &self.iv).expect("Invalid AES key or IV length")
+    }
+
+    /// Calculate the MAC of a backed up room key.
+    ///
+    /// libolm calculates the MAC over an empty buffer instead of the
+    /// ciphertext, backups created by other clients can only be read if we do
+    /// the same.
+    fn mac(&self) -> Vec<u8> {
+        let mac = HmacSha256::new_varkey(&self.mac_key).expect("HMAC can take keys of any size");
+        mac.result().code()[0..8].to_vec()
+    }
+}
+
+impl Drop for BackupKeys {
+    fn drop(&mut self) {
+        self.aes_key.zeroize();
+        self.mac_key.zeroize();
+        self.iv.zeroize();
+    }
+}
+
+/// The encrypted form of a backed up room key.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct EncryptedSessionData {
+    /// The public part of the ephemeral key that was used to encrypt the room
+    /// key.
+    pub ephemeral: String,
+    /// The encrypted room key.
+    pub ciphertext: String,
+    /// The MAC of the encrypted room key.
+    pub mac: String,
+}
+
+/// A single backed up room key as it is stored on the server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KeyBackupData {
+    /// The first message index the backed up room key can decrypt.
+    pub first_message_index: u32,
+    /// How many times the room key was forwarded before it reached us.
+    pub forwarded_count: u32,
+    /// Was the device that sent us the room key verified.
+    pub is_verified: bool,
+    /// The encrypted room key.
+    pub session_data: EncryptedSessionData,
+}
+
+/// The backed up room keys of a single room.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RoomKeyBackup {
+    /// The backed up room keys, keyed by their session id.
+    pub sessions: BTreeMap<String, KeyBackupData>,
+}
+
+/// A batch of encrypted room keys that should be uploaded to the currently
+/// enabled backup.
+///
+/// The room keys need to be uploaded using a `PUT /room_keys/keys` request,
+/// once the upload succeeds the `OlmMachine` needs to be notified using
+/// `mark_backup_as_sent()`.
+#[derive(Clone, Debug)]
+pub struct KeysBackupRequest {
+    /// The version of the backup the room keys should be uploaded to.
+    pub version: String,
+    /// The encrypted room keys, grouped by room.
+    pub rooms: BTreeMap<RoomId, RoomKeyBackup>,
+    /// The room keys that are part of this request.
+    pub(crate) sessions: Vec<InboundGroupSession>,
+}
+
+/// The plaintext of a backed up room key.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct BackedUpSessionData {
+    pub(crate) algorithm: Algorithm,
+    #[serde(default)]
+    pub(crate) forwarding_curve25519_key_chain: Vec<String>,
+    pub(crate) sender_key: String,
+    pub(crate) sender_claimed_keys: BTreeMap<String, String>,
+    pub(crate) session_key: String,
+}
+
+impl BackedUpSessionData {
+    /// Turn the backed up room key into an inbound group session.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room that the room key belongs to.
+    pub(crate) fn into_session(self, room_id: &RoomId) -> Result<InboundGroupSession, BackupError> {
+        if self.algorithm != Algorithm::MegolmV1AesSha2 {
+            return Err(BackupError::UnsupportedAlgorithm(
+                self.algorithm.to_string(),
+            ));
+        }
+
+        let signing_key = self
+            .sender_claimed_keys
+            .get("ed25519")
+            .cloned()
+            .unwrap_or_default();
+
+        InboundGroupSession::from_export(
+            &self.sender_key,
+            &signing_key,
+            room_id,
+            GroupSessionKey(self.session_key.clone()),
+            self.forwarding_curve25519_key_chain.clone(),
+        )
+        .map_err(|_| BackupError::Decryption)
+    }
+}
+
+impl Drop for BackedUpSessionData {
+    fn drop(&mut self) {
+        self.session_key.zeroize();
+    }
+}
+
+/// The signed public information of a backup version.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BackupAuthData {
+    /// The public curve25519 key of the backup.
+    pub public_key: String,
+    /// The signatures of the auth data, keyed by the signing user.
+    #[serde(default)]
+    pub signatures: BTreeMap<UserId, BTreeMap<String, String>>,
+}
+
+/// Information about a backup version that exists on the server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BackupVersion {
+    /// The algorithm that is used to encrypt the room keys of the backup.
+    pub algorithm: String,
+    /// The public information of the backup.
+    pub auth_data: BackupAuthData,
+    /// The unique version of the backup.
+    pub version: String,
+}
+
+/// The public part of a recovery key.
+///
+/// This key is used to encrypt room keys before they are uploaded to a
+/// backup.
+#[derive(Clone)]
+pub struct MegolmV1BackupKey {
+    key: PublicKey,
+}
+
+impl MegolmV1BackupKey {
+    /// Create a backup key from an unpadded base64 encoded curve25519 public
+    /// key.
+    ///
+    /// # Arguments
+    ///
+    /// * `public_key` - The base64 encoded public key of the backup.
+    pub fn from_base64(public_key: &str) -> Result<Self, BackupError> {
+        Ok(MegolmV1BackupKey {
+            key: PublicKey::from(decode_key(public_key)?),
+        })
+    }
+
+    /// Get the unpadded base64 encoded public key.
+    pub fn to_base64(&self) -> String {
+        encode(self.key.as_bytes())
+    }
+
+    /// Encrypt the given room key so it can be uploaded to a backup.
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - The room key that should be backed up.
+    pub(crate) async fn encrypt(&self, session: &InboundGroupSession) -> KeyBackupData {
+        let forwarding_chain = session.forwarding_chain().await;
+        let mut sender_claimed_keys = BTreeMap::new();
+        sender_claimed_keys.insert("ed25519".to_owned(), session.signing_key.to_string());
+
+        let session_data = BackedUpSessionData {
+            algorithm: Algorithm::MegolmV1AesSha2,
+            forwarding_curve25519_key_chain: forwarding_chain.clone(),
+            sender_key: session.sender_key.to_string(),
+            sender_claimed_keys,
+            session_key: session.export().await.0.clone(),
+        };
+
+        let mut plaintext =
+            serde_json::to_vec(&session_data).expect("Can't serialize a backed up room key");
+
+        let ephemeral = EphemeralSecret::new(&mut OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared_secret = ephemeral.diffie_hellman(&self.key);
+
+        let keys = BackupKeys::derive(shared_secret.as_bytes());
+        let ciphertext = keys.cipher().encrypt_vec(&plaintext);
+        plaintext.zeroize();
+
+        KeyBackupData {
+            first_message_index: session.first_known_index().await,
+            forwarded_count: forwarding_chain.len() as u32,
+            // TODO set this once we can tell which device sent us the room
+            // key.
+            is_verified: false,
+            session_data: EncryptedSessionData {
+                ephemeral: encode(ephemeral_public.as_bytes()),
+                ciphertext: encode(ciphertext),
+                mac: encode(keys.mac()),
+            },
+        }
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Debug for MegolmV1BackupKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MegolmV1BackupKey")
+            .field("public_key", &self.to_base64())
+            .finish()
+    }
+}
+
+impl PartialEq for MegolmV1BackupKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.key.as_bytes() == other.key.as_bytes()
+    }
+}
+
+/// The private key of a room key backup.
+///
+/// The recovery key is needed to decrypt room keys that were uploaded to a
+/// backup, it should be shown to the user in its base58 encoded form.
+#[derive(Clone)]
+pub struct RecoveryKey {
+    secret: StaticSecret,
+}
+
+impl RecoveryKey {
+    /// Create a new random recovery key.
+    pub fn new() -> Self {
+        RecoveryKey {
+            secret: StaticSecret::new(&mut OsRng),
+        }
+    }
+
+    /// Restore a recovery key from its base58 encoded form.
+    ///
+    /// Whitespace in the encoded key is ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The base58 encoded recovery key.
+    pub fn from_base58(recovery_key: &str) -> Result<Self, BackupError> {
+        let recovery_key: String = recovery_key
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+
+        let mut bytes = bs58::decode(recovery_key)
+            .into_vec()
+            .map_err(|_| BackupError::InvalidRecoveryKey)?;
+
+        let parity = bytes.iter().fold(0u8, |parity, b| parity ^ b);
+
+        if bytes.len() != RECOVERY_KEY_LENGTH || bytes[0..2] != RECOVERY_KEY_PREFIX || parity != 0 {
+            bytes.zeroize();
+            return Err(BackupError::InvalidRecoveryKey);
+        }
+
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&bytes[2..34]);
+        bytes.zeroize();
+
+        let key = RecoveryKey {
+            secret: StaticSecret::from(secret),
+        };
+        secret.zeroize();
+
+        Ok(key)
+    }
+
+    /// Get the base58 encoded form of the recovery key.
+    ///
+    /// The key is split up into groups of four characters to make it easier
+    /// to read.
+    pub fn to_base58(&self) -> String {
+        let mut bytes = Vec::with_capacity(RECOVERY_KEY_LENGTH);
+        bytes.extend_from_slice(&RECOVERY_KEY_PREFIX);
+        bytes.extend_from_slice(&self.secret.to_bytes());
+
+        let parity = bytes.iter().fold(0u8, |parity, b| parity ^ b);
+        bytes.push(parity);
+
+        let encoded = bs58::encode(&bytes).into_string();
+        bytes.zeroize();
+
+        let chars: Vec<char> = encoded.chars().collect();
+
+        chars
+            .chunks(4)
+            .map(|c| c.iter().collect::<String>())
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// Get the public key of the recovery key.
+    pub fn public_key(&self) -> MegolmV1BackupKey {
+        MegolmV1BackupKey {
+            key: PublicKey::from(&self.secret),
+        }
+    }
+
+    /// Check that the given backup version was created with this recovery
+    /// key.
+    ///
+    /// # Arguments
+    ///
+    /// * `backup` - The backup version that should be checked.
+    pub fn check_backup(&self, backup: &BackupVersion) -> Result<(), BackupError> {
+        if backup.algorithm != BACKUP_ALGORITHM {
+            return Err(BackupError::UnsupportedAlgorithm(backup.algorithm.clone()));
+        }
+
+        if MegolmV1BackupKey::from_base64(&backup.auth_data.public_key)? != self.public_key() {
+            return Err(BackupError::MismatchedRecoveryKey);
+        }
+
+        Ok(())
+    }
+
+    /// Decrypt a backed up room key.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_data` - The encrypted room key.
+    pub(crate) fn decrypt(
+        &self,
+        session_data: &EncryptedSessionData,
+    ) -> Result<BackedUpSessionData, BackupError> {
+        let ephemeral = PublicKey::from(decode_key(&session_data.ephemeral)?);
+        let shared_secret = self.secret.diffie_hellman(&ephemeral);
+        let keys = BackupKeys::derive(shared_secret.as_bytes());
+
+        if decode(&session_data.mac)? != keys.mac() {
+            return Err(BackupError::InvalidMac);
+        }
+
+        let ciphertext = decode(&session_data.ciphertext)?;
+        let mut plaintext = keys
+            .cipher()
+            .decrypt_vec(&ciphertext)
+            .map_err(|_| BackupError::Decryption)?;
+
+        let session_data = serde_json::from_slice(&plaintext);
+        plaintext.zeroize();
+
+        Ok(session_data?)
+    }
+}
+
+impl Default for RecoveryKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Debug for RecoveryKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecoveryKey")
+            .field("public_key", &self.public_key())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+
+    use matrix_sdk_common::identifiers::RoomId;
+
+    use super::{BackupAuthData, BackupVersion, RecoveryKey, BACKUP_ALGORITHM};
+    use crate::error::BackupError;
+    use crate::olm::{EncryptionSettings, InboundGroupSession, OutboundGroupSession};
+
+    #[test]
+    fn recovery_key_encoding() {
+        let key = RecoveryKey::new();
+        let encoded = key.to_base58();
+
+        let restored = RecoveryKey::from_base58(&encoded).unwrap();
+        assert_eq!(key.public_key(), restored.public_key());
+
+        let without_spaces: String = encoded.split_whitespace().collect();
+        assert!(RecoveryKey::from_base58(&without_spaces).is_ok());
+
+        let mut invalid = without_spaces.clone();
+        invalid.pop();
+        invalid.push(if without_spaces.ends_with('A') {
+            'B'
+        } else {
+            'A'
+        });
+
+        assert!(matches!(
+            RecoveryKey::from_base58(&invalid),
+            Err(BackupError::InvalidRecoveryKey)
+        ));
+        assert!(RecoveryKey::from_base58("not a key").is_err());
+    }
+
+    #[tokio::test]
+    async fn room_key_backup_roundtrip() {
+        let room_id = RoomId::try_from("!test:localhost").unwrap();
+        let outbound = OutboundGroupSession::new(&room_id, EncryptionSettings::default());
+        let session = InboundGroupSession::new(
+            "sender_key",
+            "signing_key",
+            &room_id,
+            outbound.session_key().await,
+        )
+        .unwrap();
+
+        let recovery_key = RecoveryKey::new();
+        let backed_up = recovery_key.public_key().encrypt(&session).await;
+
+        assert_eq!(backed_up.first_message_index, 0);
+        assert_eq!(backed_up.forwarded_count, 0);
+
+        let restored = recovery_key
+            .decrypt(&backed_up.session_data)
+            .unwrap()
+            .into_session(&room_id)
+            .unwrap();
+
+        assert_eq!(restored.session_id(), session.session_id());
+        assert_eq!(&*restored.sender_key, "sender_key");
+        assert_eq!(&*restored.signing_key, "signing_key");
+
+        let plaintext = "It's a secret to everybody".to_owned();
+        let ciphertext = outbound.encrypt(plaintext.clone()).await;
+        assert_eq!(restored.decrypt(ciphertext).await.unwrap().0, plaintext);
+
+        let other_key = RecoveryKey::new();
+        assert!(matches!(
+            other_key.decrypt(&backed_up.session_data),
+            Err(BackupError::InvalidMac)
+        ));
+    }
+
+    #[test]
+    fn backup_checking() {
+        let recovery_key = RecoveryKey::new();
+
+        let mut backup = BackupVersion {
+            algorithm: BACKUP_ALGORITHM.to_owned(),
+            auth_data: BackupAuthData {
+                public_key: recovery_key.public_key().to_base64(),
+                signatures: Default::default(),
+            },
+            version: "1".to_owned(),
+        };
+
+        assert!(recovery_key.check_backup(&backup).is_ok());
+        assert!(matches!(
+            RecoveryKey::new().check_backup(&backup),
+            Err(BackupError::MismatchedRecoveryKey)
+        ));
+
+        backup.algorithm = "m.megolm_backup.v2".to_owned();
+        assert!(matches!(
+            recovery_key.check_backup(&backup),
+            Err(BackupError::UnsupportedAlgorithm(_))
+        ));
+    }
+}
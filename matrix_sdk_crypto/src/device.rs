@@ -20,6 +20,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use atomic::Atomic;
+use serde_json::{json, Value};
 
 #[cfg(test)]
 use super::OlmMachine;
@@ -35,6 +36,7 @@ pub struct Device {
     algorithms: Arc<Vec<Algorithm>>,
     keys: Arc<BTreeMap<KeyAlgorithm, String>>,
     display_name: Arc<Option<String>>,
+    signatures: Arc<BTreeMap<UserId, BTreeMap<String, String>>>,
     deleted: Arc<AtomicBool>,
     trust_state: Arc<Atomic<TrustState>>,
 }
@@ -64,6 +66,25 @@ impl From<i64> for TrustState {
     }
 }
 
+/// Convert the signatures of the device keys into a map from the user id to a
+/// map from the key id to the signature.
+fn signatures(device_keys: &DeviceKeys) -> BTreeMap<UserId, BTreeMap<String, String>> {
+    device_keys
+        .signatures
+        .iter()
+        .map(|(user_id, signatures)| {
+            let signatures = signatures
+                .iter()
+                .map(|(key_id, signature)| {
+                    (format!("{}:{}", key_id.0, key_id.1), signature.to_owned())
+                })
+                .collect();
+
+            (user_id.clone(), signatures)
+        })
+        .collect()
+}
+
 impl Device {
     /// Create a new Device.
     pub fn new(
@@ -73,6 +94,7 @@ impl Device {
         trust_state: TrustState,
         algorithms: Vec<Algorithm>,
         keys: BTreeMap<KeyAlgorithm, String>,
+        signatures: BTreeMap<UserId, BTreeMap<String, String>>,
     ) -> Self {
         Device {
             user_id: Arc::new(user_id),
@@ -81,6 +103,7 @@ impl Device {
             trust_state: Arc::new(Atomic::new(trust_state)),
             algorithms: Arc::new(algorithms),
             keys: Arc::new(keys),
+            signatures: Arc::new(signatures),
             deleted: Arc::new(AtomicBool::new(false)),
         }
     }
@@ -110,11 +133,43 @@ impl Device {
         &self.keys
     }
 
+    /// Get the signatures of the device keys, a map from the user id to a map
+    /// from the key id to the signature.
+    pub fn signatures(&self) -> &BTreeMap<UserId, BTreeMap<String, String>> {
+        &self.signatures
+    }
+
+    /// Get the signed device keys as a JSON object, as they were uploaded by
+    /// the device.
+    pub(crate) fn as_signed_json(&self) -> Value {
+        let keys: BTreeMap<String, &String> = self
+            .keys
+            .iter()
+            .map(|(algorithm, key)| (format!("{}:{}", algorithm, self.device_id), key))
+            .collect();
+
+        json!({
+            "user_id": &*self.user_id,
+            "device_id": &*self.device_id,
+            "algorithms": &*self.algorithms,
+            "keys": keys,
+            "signatures": &*self.signatures,
+        })
+    }
+
     /// Get the trust state of the device.
     pub fn trust_state(&self) -> TrustState {
         self.trust_state.load(Ordering::Relaxed)
     }
 
+    /// Set the trust state of the device.
+    ///
+    /// The change is shared between all clones of the device, the device
+    /// needs to be saved in the store for the change to be persisted.
+    pub(crate) fn set_trust_state(&self, state: TrustState) {
+        self.trust_state.store(state, Ordering::Relaxed)
+    }
+
     /// Get the list of algorithms this device supports.
     pub fn algorithms(&self) -> &[Algorithm] {
         &self.algorithms
@@ -147,6 +202,7 @@ impl Device {
             Arc::new(device_keys.algorithms.clone()),
         );
         let _ = mem::replace(&mut self.keys, Arc::new(keys));
+        let _ = mem::replace(&mut self.signatures, Arc::new(signatures(device_keys)));
         let _ = mem::replace(&mut self.display_name, display_name);
     }
 
@@ -179,6 +235,7 @@ impl From<&OlmMachine> for Device {
                     .collect(),
             ),
             display_name: Arc::new(None),
+            signatures: Arc::new(BTreeMap::new()),
             deleted: Arc::new(AtomicBool::new(false)),
             trust_state: Arc::new(Atomic::new(TrustState::Unset)),
         }
@@ -206,6 +263,7 @@ impl From<&DeviceKeys> for Device {
                     .map(|d| d.device_display_name.clone())
                     .flatten(),
             ),
+            signatures: Arc::new(signatures(device_keys)),
             deleted: Arc::new(AtomicBool::new(false)),
             trust_state: Arc::new(Atomic::new(TrustState::Unset)),
         }
@@ -283,6 +341,19 @@ pub(crate) mod test {
             device.get_key(KeyAlgorithm::Ed25519).unwrap(),
             "nE6W2fCblxDcOFmeEtCHNl8/l8bXcu7GKyAswA4r3mM"
         );
+        assert_eq!(
+            device.signatures()[&user_id]["ed25519:DEVICEID"],
+            "m53Wkbh2HXkc3vFApZvCrfXcX3AI51GsDHustMhKwlv3TuOJMj4wistcOTM8q2+e/Ro7rWFUb9ZfnNbwptSUBA"
+        );
+    }
+
+    #[test]
+    fn signed_json_of_a_device() {
+        let device = get_device();
+        let mut device_keys = serde_json::to_value(device_keys()).unwrap();
+        device_keys.as_object_mut().unwrap().remove("unsigned");
+
+        assert_eq!(device.as_signed_json(), device_keys);
     }
 
     #[test]
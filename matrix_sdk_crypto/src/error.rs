@@ -12,12 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use base64::DecodeError;
 use cjson::Error as CjsonError;
 use olm_rs::errors::{OlmGroupSessionError, OlmSessionError};
 use serde_json::Error as SerdeError;
+use std::io::Error as IoError;
 use thiserror::Error;
 
+use matrix_sdk_common::identifiers::{DeviceId, UserId};
+
 use super::store::CryptoStoreError;
+use super::withheld::WithheldCode;
 
 pub type OlmResult<T> = Result<T, OlmError>;
 pub type MegolmResult<T> = Result<T, MegolmError>;
@@ -49,6 +54,28 @@ pub enum OlmError {
     /// The session with a device has become corrupted.
     #[error("decryption failed likely because a Olm session was wedged")]
     SessionWedged,
+
+    /// The room key can't be shared because some of the devices that should
+    /// receive it have an unset trust state.
+    #[error("refusing to share the room key, {} devices are unverified", .0.len())]
+    UnverifiedDevices(Vec<(UserId, DeviceId)>),
+
+    /// A room key backup operation failed.
+    #[error(transparent)]
+    Backup(#[from] BackupError),
+
+    /// Room keys couldn't be exported or imported.
+    #[error(transparent)]
+    KeyExport(#[from] KeyExportError),
+
+    /// A cross signing operation failed.
+    #[error(transparent)]
+    CrossSigning(#[from] CrossSigningError),
+
+    /// The response that was passed to `mark_request_as_sent()` doesn't
+    /// belong to the kind of request with the given id.
+    #[error("the response doesn't match the type of the request {0}")]
+    MismatchedResponse(String),
 }
 
 /// Error representing a failure during a group encryption operation.
@@ -67,6 +94,40 @@ pub enum MegolmError {
     #[error("decryption failed because the session to decrypt the message is missing")]
     MissingSession,
 
+    /// Encryption failed because no outbound group session was shared for the
+    /// room.
+    #[error("encryption failed because no group session was shared for the room")]
+    MissingOutboundSession,
+
+    /// Encryption failed because the outbound group session of the room
+    /// expired, a new one needs to be shared.
+    #[error("encryption failed because the group session of the room expired")]
+    ExpiredOutboundSession,
+
+    /// Decryption failed because the sender refused to share the session
+    /// needed to decrypt the event with us.
+    #[error("decryption failed because the room key was withheld by the sender: {code}")]
+    Withheld {
+        /// The code explaining why the room key was withheld.
+        code: WithheldCode,
+        /// The human readable reason the sender gave us.
+        reason: Option<String>,
+    },
+
+    /// The message index of the decrypted message was already used to decrypt
+    /// a different event, the message is likely a replay attack.
+    #[error(
+        "the message index {message_index} of the group session {session_id} was already used to decrypt the event {event_id}"
+    )]
+    ReplayedMessageIndex {
+        /// The unique id of the group session.
+        session_id: String,
+        /// The reused message index.
+        message_index: u32,
+        /// The event that was originally decrypted using the message index.
+        event_id: String,
+    },
+
     /// The underlying group session operation returned an error.
     #[error("can't finish Olm group session operation {0}")]
     OlmGroupSession(#[from] OlmGroupSessionError),
@@ -76,6 +137,156 @@ pub enum MegolmError {
     Store(#[from] CryptoStoreError),
 }
 
+/// Error representing a failure during a room key backup operation.
+#[derive(Error, Debug)]
+pub enum BackupError {
+    /// The recovery key isn't a valid base58 encoded recovery key.
+    #[error("the recovery key is malformed")]
+    InvalidRecoveryKey,
+
+    /// The public key of the backup isn't a valid curve25519 key.
+    #[error("the public key of the backup is malformed")]
+    InvalidPublicKey,
+
+    /// The backup uses an algorithm we don't support.
+    #[error("the backup uses an unsupported algorithm {0}")]
+    UnsupportedAlgorithm(String),
+
+    /// The recovery key doesn't belong to the backup.
+    #[error("the recovery key doesn't match the public key of the backup")]
+    MismatchedRecoveryKey,
+
+    /// The MAC of a backed up room key didn't match.
+    #[error("the MAC of the backed up room key doesn't match")]
+    InvalidMac,
+
+    /// A backed up room key couldn't be decrypted.
+    #[error("the backed up room key couldn't be decrypted")]
+    Decryption,
+
+    /// A backed up room key contained invalid base64.
+    #[error(transparent)]
+    Base64(#[from] DecodeError),
+
+    /// The decrypted room key couldn't be deserialized.
+    #[error(transparent)]
+    JsonError(#[from] SerdeError),
+}
+
+/// Error representing a failure while exporting or importing room keys.
+#[derive(Error, Debug)]
+pub enum KeyExportError {
+    /// The key export is missing its header or footer.
+    #[error("the key export is missing the header or footer line")]
+    InvalidHeader,
+
+    /// The key export is too short to be valid.
+    #[error("the key export is too short")]
+    InvalidLength,
+
+    /// The key export uses a version of the format we don't support.
+    #[error("the key export uses an unsupported version {0}")]
+    UnsupportedVersion(u8),
+
+    /// The MAC of the key export didn't match, the passphrase is most
+    /// likely wrong.
+    #[error("the MAC of the key export doesn't match, the passphrase might be wrong")]
+    InvalidMac,
+
+    /// The key export uses an invalid or unreasonably large number of PBKDF2
+    /// rounds.
+    #[error("the key export uses an invalid number of PBKDF2 rounds {0}")]
+    InvalidRounds(u32),
+
+    /// An exported room key is missing the ed25519 key of its creator.
+    #[error("the exported room key is missing the signing key of its sender")]
+    MissingSigningKey,
+
+    /// An exported room key couldn't be turned into a group session.
+    #[error(transparent)]
+    OlmGroupSession(#[from] OlmGroupSessionError),
+
+    /// The key export contained invalid base64.
+    #[error(transparent)]
+    Base64(#[from] DecodeError),
+
+    /// The exported room keys couldn't be (de)serialized.
+    #[error(transparent)]
+    JsonError(#[from] SerdeError),
+}
+
+/// Error representing a failure during a cross signing operation.
+#[derive(Error, Debug)]
+pub enum CrossSigningError {
+    /// We don't have the private keys of our cross signing identity.
+    #[error("the private cross signing keys are missing")]
+    MissingPrivateIdentity,
+
+    /// The cross signing identity of the user isn't known.
+    #[error("the cross signing identity of {0} isn't known")]
+    MissingUserIdentity(UserId),
+
+    /// A persisted cross signing key doesn't contain a valid ed25519 seed.
+    #[error("the cross signing key seed is malformed")]
+    InvalidSeed,
+
+    /// The pickled cross signing keys couldn't be decrypted or are malformed.
+    #[error("the pickled cross signing keys are invalid, the passphrase might be wrong")]
+    InvalidPickle,
+
+    /// A persisted cross signing key contained invalid base64.
+    #[error(transparent)]
+    Base64(#[from] DecodeError),
+}
+
+/// Error representing a failure while decrypting an encrypted attachment.
+#[derive(Error, Debug)]
+pub enum DecryptorError {
+    /// The encryption info contained invalid base64.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+
+    /// The encryption info doesn't contain a sha256 hash of the ciphertext.
+    #[error("the encryption info is missing the sha256 hash of the attachment")]
+    MissingHash,
+
+    /// The key or the initialization vector has an invalid length.
+    #[error("the attachment key or initialization vector has an invalid length")]
+    KeyNonceLength,
+
+    /// The attachment was encrypted using an unknown version of the format.
+    #[error("the attachment was encrypted using an unknown version {0}")]
+    UnknownVersion(String),
+
+    /// The sha256 hash of the ciphertext doesn't match the one in the
+    /// encryption info.
+    #[error("the hash of the encrypted attachment doesn't match")]
+    HashMismatch,
+
+    /// Reading the attachment failed.
+    #[error(transparent)]
+    Io(IoError),
+}
+
+impl From<IoError> for DecryptorError {
+    fn from(error: IoError) -> Self {
+        // The decryptor reports a hash mismatch through its `Read`
+        // implementation, unwrap it again.
+        let hash_mismatch = matches!(
+            error
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<DecryptorError>()),
+            Some(DecryptorError::HashMismatch)
+        );
+
+        if hash_mismatch {
+            DecryptorError::HashMismatch
+        } else {
+            DecryptorError::Io(error)
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum EventError {
     #[error("the Olm message has a unsupported type")]
@@ -101,6 +312,9 @@ pub enum EventError {
 
     #[error("the keys of the message don't match the keys in our database.")]
     MissmatchedKeys,
+
+    #[error("the decrypted event couldn't be parsed")]
+    InvalidDecryptedEvent,
 }
 
 #[derive(Error, Debug)]
@@ -116,6 +330,9 @@ pub(crate) enum SignatureError {
 
     #[error("the signature didn't match the provided key")]
     VerificationError,
+
+    #[error("the cross signing key has the wrong usage or is missing its public key")]
+    InvalidCrossSigningKey,
 }
 
 impl From<CjsonError> for SignatureError {
@@ -0,0 +1,569 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+use olm_rs::PicklingMode;
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use zeroize::Zeroize;
+
+use matrix_sdk_common::identifiers::UserId;
+
+use super::error::CrossSigningError;
+use super::key_export::{decrypt_helper, encrypt_helper};
+
+/// The number of PBKDF2 rounds used to derive the key that encrypts the
+/// pickled private cross signing keys.
+const PICKLE_ROUNDS: u32 = 10_000;
+
+fn encode(input: impl AsRef<[u8]>) -> String {
+    base64::encode_config(input, base64::STANDARD_NO_PAD)
+}
+
+/// Add a signature to the `signatures` object of the given JSON object.
+fn add_signature(json: &mut Value, user_id: &UserId, key_id: String, signature: String) {
+    let signatures = json
+        .as_object_mut()
+        .expect("Signed values need to be JSON objects")
+        .entry("signatures")
+        .or_insert_with(|| json!({}));
+
+    signatures
+        .as_object_mut()
+        .expect("The signatures of a JSON object need to be an object")
+        .entry(user_id.to_string())
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+        .expect("The signatures of an user need to be an object")
+        .insert(key_id, Value::String(signature));
+}
+
+/// The purpose of a cross signing key.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyUsage {
+    /// The master key of an user, signs the other cross signing keys.
+    Master,
+    /// The self-signing key of an user, signs the devices of the user.
+    SelfSigning,
+    /// The user-signing key of an user, signs the master keys of other users.
+    UserSigning,
+}
+
+/// A public cross signing key, in the form the server hands it out.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CrossSigningKey {
+    /// The user the key belongs to.
+    pub user_id: UserId,
+    /// What the key is used for.
+    pub usage: Vec<KeyUsage>,
+    /// The public key, keyed by its key id. Cross signing keys only contain
+    /// a single key.
+    pub keys: BTreeMap<String, String>,
+    /// Signatures of the key, a map from the user id to a map from the key id
+    /// to the signature.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub signatures: BTreeMap<UserId, BTreeMap<String, String>>,
+}
+
+impl CrossSigningKey {
+    /// Get the public ed25519 key of the cross signing key.
+    pub fn public_key(&self) -> Option<&str> {
+        self.keys
+            .iter()
+            .find(|(key_id, _)| key_id.starts_with("ed25519:"))
+            .map(|(_, key)| key.as_str())
+    }
+
+    /// Is the key meant to be used for the given purpose.
+    pub(crate) fn has_usage(&self, usage: KeyUsage) -> bool {
+        self.usage.contains(&usage)
+    }
+
+    /// Add the signatures of another copy of the same key that this copy is
+    /// missing.
+    ///
+    /// Nothing is merged if the other copy contains a different public key.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The other copy of the key.
+    pub(crate) fn merge_signatures(&mut self, other: &CrossSigningKey) {
+        if self.public_key() != other.public_key() {
+            return;
+        }
+
+        for (signer, signatures) in &other.signatures {
+            let own_signatures = self
+                .signatures
+                .entry(signer.clone())
+                .or_insert_with(BTreeMap::new);
+
+            for (key_id, signature) in signatures {
+                own_signatures
+                    .entry(key_id.clone())
+                    .or_insert_with(|| signature.clone());
+            }
+        }
+    }
+}
+
+/// The cross signing keys that are part of a keys query response.
+///
+/// The keys query response of `ruma-client-api` doesn't contain the cross
+/// signing keys yet, they need to be deserialized from the response body
+/// separately.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct KeysQueryCrossSigningKeys {
+    /// The master keys of the queried users.
+    #[serde(default)]
+    pub master_keys: BTreeMap<UserId, CrossSigningKey>,
+    /// The self-signing keys of the queried users.
+    #[serde(default)]
+    pub self_signing_keys: BTreeMap<UserId, CrossSigningKey>,
+    /// The user-signing key, the server only returns our own.
+    #[serde(default)]
+    pub user_signing_keys: BTreeMap<UserId, CrossSigningKey>,
+}
+
+/// The public cross signing identity of an user.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct UserIdentity {
+    user_id: UserId,
+    master_key: CrossSigningKey,
+    self_signing_key: CrossSigningKey,
+    user_signing_key: Option<CrossSigningKey>,
+}
+
+impl UserIdentity {
+    /// Create a new user identity, the identity belongs to the owner of the
+    /// master key.
+    pub(crate) fn new(
+        master_key: CrossSigningKey,
+        self_signing_key: CrossSigningKey,
+        user_signing_key: Option<CrossSigningKey>,
+    ) -> Self {
+        UserIdentity {
+            user_id: master_key.user_id.clone(),
+            master_key,
+            self_signing_key,
+            user_signing_key,
+        }
+    }
+
+    /// The user id of the identity owner.
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
+
+    /// The master key of the identity.
+    pub fn master_key(&self) -> &CrossSigningKey {
+        &self.master_key
+    }
+
+    /// The self-signing key of the identity.
+    pub fn self_signing_key(&self) -> &CrossSigningKey {
+        &self.self_signing_key
+    }
+
+    /// The user-signing key of the identity, only known for our own identity.
+    pub fn user_signing_key(&self) -> Option<&CrossSigningKey> {
+        self.user_signing_key.as_ref()
+    }
+}
+
+/// Request to upload our public cross signing keys.
+///
+/// This is the body of a `POST /keys/device_signing/upload` request.
+#[derive(Clone, Debug, Serialize)]
+pub struct CrossSigningUploadRequest {
+    /// Our new master key.
+    pub master_key: CrossSigningKey,
+    /// Our new self-signing key, signed by the master key.
+    pub self_signing_key: CrossSigningKey,
+    /// Our new user-signing key, signed by the master key.
+    pub user_signing_key: CrossSigningKey,
+}
+
+/// Signatures that need to be uploaded to the server.
+///
+/// This is the body of a `POST /keys/signatures/upload` request, it maps the
+/// user id and the device id or public key of the signed object to the signed
+/// object.
+pub type SignatureUploadRequest = BTreeMap<UserId, BTreeMap<String, Value>>;
+
+/// An ed25519 key pair belonging to one of our cross signing keys.
+struct Signing {
+    keypair: Keypair,
+}
+
+impl Signing {
+    fn new() -> Self {
+        Signing {
+            keypair: Keypair::generate(&mut thread_rng()),
+        }
+    }
+
+    fn from_seed(seed: &str) -> Result<Self, CrossSigningError> {
+        let mut seed = base64::decode_config(seed, base64::STANDARD_NO_PAD)?;
+        let secret = SecretKey::from_bytes(&seed).map_err(|_| CrossSigningError::InvalidSeed);
+        seed.zeroize();
+
+        let secret = secret?;
+        let public = PublicKey::from(&secret);
+
+        Ok(Signing {
+            keypair: Keypair { secret, public },
+        })
+    }
+
+    fn seed(&self) -> String {
+        encode(self.keypair.secret.as_bytes())
+    }
+
+    fn public_key(&self) -> String {
+        encode(self.keypair.public.as_bytes())
+    }
+
+    fn key_id(&self) -> String {
+        format!("ed25519:{}", self.public_key())
+    }
+
+    /// Sign the canonical form of a JSON object, the `signatures` and
+    /// `unsigned` fields of the object aren't signed.
+    fn sign_json(&self, json: &Value) -> String {
+        let mut json = json.clone();
+
+        if let Some(object) = json.as_object_mut() {
+            object.remove("signatures");
+            object.remove("unsigned");
+        }
+
+        let canonical_json = cjson::to_string(&json)
+            .unwrap_or_else(|_| panic!(format!("Can't serialize {} to canonical JSON", json)));
+
+        encode(&self.keypair.sign(canonical_json.as_bytes()).to_bytes()[..])
+    }
+
+    fn cross_signing_key(&self, user_id: &UserId, usage: KeyUsage) -> CrossSigningKey {
+        let mut keys = BTreeMap::new();
+        keys.insert(self.key_id(), self.public_key());
+
+        CrossSigningKey {
+            user_id: user_id.clone(),
+            usage: vec![usage],
+            keys,
+            signatures: BTreeMap::new(),
+        }
+    }
+}
+
+/// The private cross signing keys in a form that can be persisted.
+#[derive(Deserialize, Serialize)]
+pub struct PickledCrossSigningIdentity {
+    /// The user the identity belongs to.
+    pub user_id: UserId,
+    /// The seeds of the private keys, encrypted and base64 encoded if the
+    /// identity was pickled using an encrypted pickling mode.
+    pub pickle: String,
+}
+
+impl Drop for PickledCrossSigningIdentity {
+    fn drop(&mut self) {
+        self.pickle.zeroize();
+    }
+}
+
+/// The seeds of the private cross signing keys.
+#[derive(Deserialize, Serialize)]
+struct PickledSeeds {
+    master_key: String,
+    self_signing_key: String,
+    user_signing_key: String,
+}
+
+impl Drop for PickledSeeds {
+    fn drop(&mut self) {
+        self.master_key.zeroize();
+        self.self_signing_key.zeroize();
+        self.user_signing_key.zeroize();
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Debug for PickledCrossSigningIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PickledCrossSigningIdentity")
+            .field("user_id", &self.user_id)
+            .finish()
+    }
+}
+
+/// Our own cross signing identity, holding the private parts of our cross
+/// signing keys.
+pub struct PrivateCrossSigningIdentity {
+    user_id: UserId,
+    master_key: Signing,
+    self_signing_key: Signing,
+    user_signing_key: Signing,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Debug for PrivateCrossSigningIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrivateCrossSigningIdentity")
+            .field("user_id", &self.user_id)
+            .field("master_key", &self.master_public_key())
+            .finish()
+    }
+}
+
+impl PrivateCrossSigningIdentity {
+    /// Generate a new set of cross signing keys for the given user.
+    pub(crate) fn new(user_id: UserId) -> Self {
+        PrivateCrossSigningIdentity {
+            user_id,
+            master_key: Signing::new(),
+            self_signing_key: Signing::new(),
+            user_signing_key: Signing::new(),
+        }
+    }
+
+    /// The user id of the identity owner.
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
+
+    /// The public part of our master key.
+    pub fn master_public_key(&self) -> String {
+        self.master_key.public_key()
+    }
+
+    /// Create a subkey of the master key, signed by the master key.
+    fn signed_subkey(&self, key: &Signing, usage: KeyUsage) -> CrossSigningKey {
+        let mut subkey = key.cross_signing_key(&self.user_id, usage);
+        let signature = self.master_key.sign_json(&json!(&subkey));
+
+        subkey
+            .signatures
+            .entry(self.user_id.clone())
+            .or_insert_with(BTreeMap::new)
+            .insert(self.master_key.key_id(), signature);
+
+        subkey
+    }
+
+    /// Get the public part of our cross signing identity.
+    pub fn public_identity(&self) -> UserIdentity {
+        UserIdentity::new(
+            self.master_key
+                .cross_signing_key(&self.user_id, KeyUsage::Master),
+            self.signed_subkey(&self.self_signing_key, KeyUsage::SelfSigning),
+            Some(self.signed_subkey(&self.user_signing_key, KeyUsage::UserSigning)),
+        )
+    }
+
+    /// Get a request to upload the public parts of our cross signing keys.
+    pub(crate) fn upload_request(&self) -> CrossSigningUploadRequest {
+        let identity = self.public_identity();
+
+        CrossSigningUploadRequest {
+            master_key: identity.master_key,
+            self_signing_key: identity.self_signing_key,
+            user_signing_key: identity
+                .user_signing_key
+                .expect("Our own identity always has an user-signing key"),
+        }
+    }
+
+    /// Sign the device keys of one of our devices with our self-signing key.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_keys` - The JSON object containing the device keys.
+    pub(crate) fn sign_device(&self, device_keys: &mut Value) {
+        let signature = self.self_signing_key.sign_json(device_keys);
+        add_signature(
+            device_keys,
+            &self.user_id,
+            self.self_signing_key.key_id(),
+            signature,
+        );
+    }
+
+    /// Sign the master key of another user with our user-signing key.
+    ///
+    /// Returns a copy of the master key with our signature added.
+    ///
+    /// # Arguments
+    ///
+    /// * `master_key` - The master key of the other user.
+    pub(crate) fn sign_user(&self, master_key: &CrossSigningKey) -> CrossSigningKey {
+        let mut master_key = master_key.clone();
+        let signature = self.user_signing_key.sign_json(&json!(&master_key));
+
+        master_key
+            .signatures
+            .entry(self.user_id.clone())
+            .or_insert_with(BTreeMap::new)
+            .insert(self.user_signing_key.key_id(), signature);
+
+        master_key
+    }
+
+    /// Store the private cross signing keys in a form that can be persisted.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle_mode` - The mode that should be used to pickle the keys, the
+    /// seeds of the keys are encrypted using the key of an encrypted mode.
+    pub fn pickle(&self, pickle_mode: PicklingMode) -> PickledCrossSigningIdentity {
+        let seeds = PickledSeeds {
+            master_key: self.master_key.seed(),
+            self_signing_key: self.self_signing_key.seed(),
+            user_signing_key: self.user_signing_key.seed(),
+        };
+
+        let mut seeds =
+            serde_json::to_vec(&seeds).expect("Can't serialize the cross signing seeds");
+
+        let pickle = match pickle_mode {
+            PicklingMode::Encrypted { key } => {
+                base64::encode(&encrypt_helper(seeds, &key, PICKLE_ROUNDS))
+            }
+            PicklingMode::Unencrypted => {
+                let pickle = String::from_utf8_lossy(&seeds).into_owned();
+                seeds.zeroize();
+                pickle
+            }
+        };
+
+        PickledCrossSigningIdentity {
+            user_id: self.user_id.clone(),
+            pickle,
+        }
+    }
+
+    /// Restore the private cross signing keys from a pickle.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle` - The pickled version of the cross signing keys.
+    ///
+    /// * `pickle_mode` - The mode that was used to pickle the keys.
+    pub fn from_pickle(
+        pickle: &PickledCrossSigningIdentity,
+        pickle_mode: PicklingMode,
+    ) -> Result<Self, CrossSigningError> {
+        let mut seeds = match pickle_mode {
+            PicklingMode::Encrypted { key } => {
+                let mut payload = base64::decode(&pickle.pickle)?;
+                let seeds = decrypt_helper(&payload, &key);
+                payload.zeroize();
+                seeds.map_err(|_| CrossSigningError::InvalidPickle)?
+            }
+            PicklingMode::Unencrypted => pickle.pickle.as_bytes().to_vec(),
+        };
+
+        let parsed: Result<PickledSeeds, _> = serde_json::from_slice(&seeds);
+        seeds.zeroize();
+        let seeds = parsed.map_err(|_| CrossSigningError::InvalidPickle)?;
+
+        Ok(PrivateCrossSigningIdentity {
+            user_id: pickle.user_id.clone(),
+            master_key: Signing::from_seed(&seeds.master_key)?,
+            self_signing_key: Signing::from_seed(&seeds.self_signing_key)?,
+            user_signing_key: Signing::from_seed(&seeds.user_signing_key)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+
+    use matrix_sdk_common::identifiers::UserId;
+    use olm_rs::{utility::OlmUtility, PicklingMode};
+    use serde_json::json;
+
+    use super::{KeyUsage, PrivateCrossSigningIdentity};
+
+    fn user_id() -> UserId {
+        UserId::try_from("@alice:example.org").unwrap()
+    }
+
+    #[test]
+    fn public_identity_is_signed_by_the_master_key() {
+        let identity = PrivateCrossSigningIdentity::new(user_id());
+        let public = identity.public_identity();
+
+        assert_eq!(public.user_id(), &user_id());
+        assert!(public.master_key().has_usage(KeyUsage::Master));
+        assert!(public.self_signing_key().has_usage(KeyUsage::SelfSigning));
+        assert_eq!(
+            public.master_key().public_key().unwrap(),
+            identity.master_public_key()
+        );
+
+        let user_signing_key = public.user_signing_key().unwrap();
+        let signature = &user_signing_key.signatures[&user_id()]
+            [&format!("ed25519:{}", identity.master_public_key())];
+
+        let mut unsigned = json!(user_signing_key);
+        unsigned.as_object_mut().unwrap().remove("signatures");
+        let canonical_json = cjson::to_string(&unsigned).unwrap();
+
+        assert!(OlmUtility::new()
+            .ed25519_verify(&identity.master_public_key(), &canonical_json, signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn identity_pickling() {
+        let identity = PrivateCrossSigningIdentity::new(user_id());
+        let pickle = identity.pickle(PicklingMode::Unencrypted);
+
+        let unpickled =
+            PrivateCrossSigningIdentity::from_pickle(&pickle, PicklingMode::Unencrypted).unwrap();
+
+        assert_eq!(identity.master_public_key(), unpickled.master_public_key());
+        assert_eq!(identity.public_identity(), unpickled.public_identity());
+    }
+
+    #[test]
+    fn encrypted_identity_pickling() {
+        let identity = PrivateCrossSigningIdentity::new(user_id());
+        let key = b"secret_passphrase".to_vec();
+        let pickle = identity.pickle(PicklingMode::Encrypted { key: key.clone() });
+
+        assert!(!pickle.pickle.contains(&identity.master_key.seed()));
+
+        let unpickled =
+            PrivateCrossSigningIdentity::from_pickle(&pickle, PicklingMode::Encrypted { key })
+                .unwrap();
+        assert_eq!(identity.public_identity(), unpickled.public_identity());
+
+        assert!(PrivateCrossSigningIdentity::from_pickle(
+            &pickle,
+            PicklingMode::Encrypted {
+                key: b"wrong_passphrase".to_vec()
+            }
+        )
+        .is_err());
+    }
+}
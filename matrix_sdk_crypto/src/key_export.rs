@@ -0,0 +1,397 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+
+use aes_ctr::stream_cipher::{NewStreamCipher, SyncStreamCipher};
+use aes_ctr::Aes256Ctr;
+use hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2;
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Sha256, Sha512};
+use zeroize::Zeroize;
+
+use matrix_sdk_common::events::Algorithm;
+use matrix_sdk_common::identifiers::RoomId;
+
+use super::error::KeyExportError;
+use super::olm::{GroupSessionKey, InboundGroupSession};
+
+const HEADER: &str = "-----BEGIN MEGOLM SESSION DATA-----";
+const FOOTER: &str = "-----END MEGOLM SESSION DATA-----";
+
+const VERSION: u8 = 1;
+const SALT_SIZE: usize = 16;
+const IV_SIZE: usize = 16;
+const MAC_SIZE: usize = 32;
+const KEY_SIZE: usize = 32;
+const LINE_LENGTH: usize = 96;
+/// The size of the version, salt, IV and rounds fields.
+const PREFIX_SIZE: usize = 1 + SALT_SIZE + IV_SIZE + 4;
+
+/// The default number of PBKDF2 rounds that are used to derive the keys of a
+/// key export.
+pub const DEFAULT_EXPORT_ROUNDS: u32 = 500_000;
+/// The maximal number of PBKDF2 rounds we accept, a malicious export could
+/// otherwise keep us busy deriving keys for hours.
+const MAX_EXPORT_ROUNDS: u32 = 5_000_000;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A single room key in the format that is used by key exports.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ExportedRoomKey {
+    /// The encryption algorithm the room key is used with.
+    pub algorithm: Algorithm,
+    /// The room the room key belongs to.
+    pub room_id: RoomId,
+    /// The curve25519 key of the device that created the room key.
+    pub sender_key: String,
+    /// The unique id of the room key.
+    pub session_id: String,
+    /// The exported session key.
+    pub session_key: String,
+    /// The keys that the creator of the room key claims to own.
+    #[serde(default)]
+    pub sender_claimed_keys: BTreeMap<String, String>,
+    /// The curve25519 keys of the devices that forwarded the room key to us.
+    #[serde(default)]
+    pub forwarding_curve25519_key_chain: Vec<String>,
+}
+
+impl ExportedRoomKey {
+    /// Export the given inbound group session at its first known message
+    /// index.
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - The session that should be exported.
+    pub(crate) async fn from_session(session: &InboundGroupSession) -> Self {
+        let mut sender_claimed_keys = BTreeMap::new();
+        sender_claimed_keys.insert("ed25519".to_owned(), session.signing_key.to_string());
+
+        ExportedRoomKey {
+            algorithm: Algorithm::MegolmV1AesSha2,
+            room_id: (&*session.room_id).clone(),
+            sender_key: session.sender_key.to_string(),
+            session_id: session.session_id().to_owned(),
+            session_key: session.export().await.0.clone(),
+            sender_claimed_keys,
+            forwarding_curve25519_key_chain: session.forwarding_chain().await,
+        }
+    }
+
+    /// Turn the exported room key into an inbound group session.
+    pub(crate) fn to_session(&self) -> Result<InboundGroupSession, KeyExportError> {
+        let signing_key = self
+            .sender_claimed_keys
+            .get("ed25519")
+            .ok_or(KeyExportError::MissingSigningKey)?;
+
+        Ok(InboundGroupSession::from_export(
+            &self.sender_key,
+            signing_key,
+            &self.room_id,
+            GroupSessionKey(self.session_key.clone()),
+            self.forwarding_curve25519_key_chain.clone(),
+        )?)
+    }
+}
+
+impl Drop for ExportedRoomKey {
+    fn drop(&mut self) {
+        self.session_key.zeroize();
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Debug for ExportedRoomKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExportedRoomKey")
+            .field("room_id", &self.room_id)
+            .field("sender_key", &self.sender_key)
+            .field("session_id", &self.session_id)
+            .finish()
+    }
+}
+
+/// Derive the AES and HMAC keys of a key export from the passphrase.
+fn derive_keys(passphrase: &[u8], salt: &[u8], rounds: u32) -> [u8; KEY_SIZE * 2] {
+    let mut keys = [0u8; KEY_SIZE * 2];
+    pbkdf2::<Hmac<Sha512>>(passphrase, salt, rounds as usize, &mut keys);
+    keys
+}
+
+/// Encrypt the given plaintext using keys derived from the passphrase.
+///
+/// Returns the binary payload of the key export format, the version, salt, IV
+/// and rounds followed by the ciphertext and the MAC.
+pub(crate) fn encrypt_helper(mut plaintext: Vec<u8>, passphrase: &[u8], rounds: u32) -> Vec<u8> {
+    let mut salt = [0u8; SALT_SIZE];
+    let mut iv = [0u8; IV_SIZE];
+    thread_rng().fill_bytes(&mut salt);
+    thread_rng().fill_bytes(&mut iv);
+    // Clear bit 63 of the counter so other implementations don't run into
+    // counter overflows.
+    iv[8] &= 0x7f;
+
+    let mut derived_keys = derive_keys(passphrase, &salt, rounds);
+    let (aes_key, mac_key) = derived_keys.split_at(KEY_SIZE);
+
+    let mut cipher = Aes256Ctr::new_var(aes_key, &iv).expect("Invalid AES key or IV length");
+    cipher.apply_keystream(&mut plaintext);
+    let ciphertext = plaintext;
+
+    let mut payload = Vec::with_capacity(PREFIX_SIZE + ciphertext.len() + MAC_SIZE);
+    payload.push(VERSION);
+    payload.extend_from_slice(&salt);
+    payload.extend_from_slice(&iv);
+    payload.extend_from_slice(&rounds.to_be_bytes());
+    payload.extend_from_slice(&ciphertext);
+
+    let mut mac = HmacSha256::new_varkey(mac_key).expect("HMAC can take keys of any size");
+    mac.input(&payload);
+    payload.extend_from_slice(&mac.result().code());
+
+    derived_keys.zeroize();
+
+    payload
+}
+
+/// Decrypt a payload that was created by `encrypt_helper()`.
+///
+/// The returned plaintext should be zeroized once it isn't needed anymore.
+pub(crate) fn decrypt_helper(payload: &[u8], passphrase: &[u8]) -> Result<Vec<u8>, KeyExportError> {
+    if payload.len() < PREFIX_SIZE + MAC_SIZE {
+        return Err(KeyExportError::InvalidLength);
+    }
+
+    if payload[0] != VERSION {
+        return Err(KeyExportError::UnsupportedVersion(payload[0]));
+    }
+
+    let (data, expected_mac) = payload.split_at(payload.len() - MAC_SIZE);
+    let salt = &data[1..1 + SALT_SIZE];
+    let iv = &data[1 + SALT_SIZE..1 + SALT_SIZE + IV_SIZE];
+    let rounds = u32::from_be_bytes(
+        data[1 + SALT_SIZE + IV_SIZE..PREFIX_SIZE]
+            .try_into()
+            .expect("The rounds slice has the wrong length"),
+    );
+    let ciphertext = &data[PREFIX_SIZE..];
+
+    if rounds == 0 || rounds > MAX_EXPORT_ROUNDS {
+        return Err(KeyExportError::InvalidRounds(rounds));
+    }
+
+    let mut derived_keys = derive_keys(passphrase, salt, rounds);
+    let (aes_key, mac_key) = derived_keys.split_at(KEY_SIZE);
+
+    let mut mac = HmacSha256::new_varkey(mac_key).expect("HMAC can take keys of any size");
+    mac.input(data);
+
+    if mac.verify(expected_mac).is_err() {
+        derived_keys.zeroize();
+        return Err(KeyExportError::InvalidMac);
+    }
+
+    let mut plaintext = ciphertext.to_vec();
+    let mut cipher = Aes256Ctr::new_var(aes_key, iv).expect("Invalid AES key or IV length");
+    cipher.apply_keystream(&mut plaintext);
+    derived_keys.zeroize();
+
+    Ok(plaintext)
+}
+
+/// Encrypt the given room keys into a passphrase protected key export.
+///
+/// The output uses the `-----BEGIN MEGOLM SESSION DATA-----` format that
+/// other Matrix clients use to export and import room keys.
+///
+/// # Arguments
+///
+/// * `keys` - The room keys that should be exported.
+///
+/// * `passphrase` - The passphrase that protects the export.
+///
+/// * `rounds` - The number of PBKDF2 rounds used to derive the encryption key
+/// from the passphrase, at most 5 000 000.
+pub fn encrypt_key_export(
+    keys: &[ExportedRoomKey],
+    passphrase: &str,
+    rounds: u32,
+) -> Result<String, KeyExportError> {
+    if rounds == 0 || rounds > MAX_EXPORT_ROUNDS {
+        return Err(KeyExportError::InvalidRounds(rounds));
+    }
+
+    let plaintext = serde_json::to_vec(keys)?;
+    let payload = encrypt_helper(plaintext, passphrase.as_bytes(), rounds);
+
+    let encoded = base64::encode(&payload);
+    let mut output = String::from(HEADER);
+    output.push('\n');
+
+    for line in encoded.as_bytes().chunks(LINE_LENGTH) {
+        // Base64 is pure ASCII, the chunks are always valid UTF-8.
+        output.push_str(std::str::from_utf8(line).expect("Base64 isn't valid UTF-8"));
+        output.push('\n');
+    }
+
+    output.push_str(FOOTER);
+    output.push('\n');
+
+    Ok(output)
+}
+
+/// Decrypt a passphrase protected key export.
+///
+/// # Arguments
+///
+/// * `export` - The key export, including the header and footer lines.
+///
+/// * `passphrase` - The passphrase that protects the export.
+pub fn decrypt_key_export(
+    export: &str,
+    passphrase: &str,
+) -> Result<Vec<ExportedRoomKey>, KeyExportError> {
+    let export = export.trim();
+
+    if !export.starts_with(HEADER) || !export.ends_with(FOOTER) {
+        return Err(KeyExportError::InvalidHeader);
+    }
+
+    let encoded: String = export[HEADER.len()..export.len() - FOOTER.len()]
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let payload = base64::decode(&encoded)?;
+    let mut plaintext = decrypt_helper(&payload, passphrase.as_bytes())?;
+
+    let keys = serde_json::from_slice(&plaintext);
+    plaintext.zeroize();
+
+    Ok(keys?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+
+    use matrix_sdk_common::identifiers::RoomId;
+
+    use super::{
+        decrypt_key_export, encrypt_key_export, ExportedRoomKey, FOOTER, HEADER, MAX_EXPORT_ROUNDS,
+    };
+    use crate::error::KeyExportError;
+    use crate::olm::{EncryptionSettings, InboundGroupSession, OutboundGroupSession};
+
+    async fn exported_key() -> ExportedRoomKey {
+        let room_id = RoomId::try_from("!test:localhost").unwrap();
+        let outbound = OutboundGroupSession::new(&room_id, EncryptionSettings::default());
+        let session = InboundGroupSession::new(
+            "sender_key",
+            "signing_key",
+            &room_id,
+            outbound.session_key().await,
+        )
+        .unwrap();
+
+        ExportedRoomKey::from_session(&session).await
+    }
+
+    #[tokio::test]
+    async fn key_export_roundtrip() {
+        let key = exported_key().await;
+
+        let export = encrypt_key_export(&[key.clone()], "1234", 10).unwrap();
+        assert!(export.starts_with(HEADER));
+        assert!(export.trim_end().ends_with(FOOTER));
+
+        let decrypted = decrypt_key_export(&export, "1234").unwrap();
+        assert_eq!(decrypted.len(), 1);
+        assert_eq!(decrypted[0].session_id, key.session_id);
+        assert_eq!(decrypted[0].session_key, key.session_key);
+        assert_eq!(decrypted[0].room_id, key.room_id);
+
+        let session = decrypted[0].to_session().unwrap();
+        assert_eq!(session.session_id(), key.session_id);
+        assert_eq!(&*session.signing_key, "signing_key");
+    }
+
+    #[tokio::test]
+    async fn key_export_with_wrong_passphrase() {
+        let key = exported_key().await;
+        let export = encrypt_key_export(&[key], "1234", 10).unwrap();
+
+        assert!(matches!(
+            decrypt_key_export(&export, "4321"),
+            Err(KeyExportError::InvalidMac)
+        ));
+    }
+
+    #[tokio::test]
+    async fn key_export_with_too_many_rounds() {
+        let key = exported_key().await;
+
+        assert!(matches!(
+            encrypt_key_export(&[key.clone()], "1234", MAX_EXPORT_ROUNDS + 1),
+            Err(KeyExportError::InvalidRounds(_))
+        ));
+
+        // Patch the rounds of a valid export, the export needs to be rejected
+        // before the keys are derived.
+        let export = encrypt_key_export(&[key], "1234", 10).unwrap();
+        let encoded: String = export[HEADER.len()..export.len() - FOOTER.len() - 1]
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        let mut payload = base64::decode(&encoded).unwrap();
+        payload[33..37].copy_from_slice(&u32::MAX.to_be_bytes());
+        let export = format!("{}\n{}\n{}", HEADER, base64::encode(&payload), FOOTER);
+
+        assert!(matches!(
+            decrypt_key_export(&export, "1234"),
+            Err(KeyExportError::InvalidRounds(u32::MAX))
+        ));
+    }
+
+    #[tokio::test]
+    async fn exported_key_without_signing_key() {
+        let mut key = exported_key().await;
+        key.sender_claimed_keys.clear();
+
+        assert!(matches!(
+            key.to_session(),
+            Err(KeyExportError::MissingSigningKey)
+        ));
+    }
+
+    #[test]
+    fn malformed_key_export() {
+        assert!(matches!(
+            decrypt_key_export("not an export", "1234"),
+            Err(KeyExportError::InvalidHeader)
+        ));
+
+        let export = format!("{}\nAQID\n{}", HEADER, FOOTER);
+        assert!(matches!(
+            decrypt_key_export(&export, "1234"),
+            Err(KeyExportError::InvalidLength)
+        ));
+    }
+}
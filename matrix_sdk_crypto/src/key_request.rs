@@ -0,0 +1,156 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::fmt::Debug;
+
+use serde_json::{json, Value};
+
+use matrix_sdk_common::events::Algorithm;
+use matrix_sdk_common::identifiers::{DeviceId, RoomId, UserId};
+
+use super::device::{Device, TrustState};
+use super::olm::InboundGroupSession;
+
+/// The info that uniquely identifies a group session we requested from other
+/// devices.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct RequestedSession {
+    pub(crate) room_id: RoomId,
+    pub(crate) sender_key: String,
+    pub(crate) session_id: String,
+}
+
+/// A room key request that we sent out and that is waiting to be answered
+/// with a forwarded room key.
+#[derive(Clone, Debug)]
+pub(crate) struct OutgoingKeyRequest {
+    /// The unique id of the request.
+    pub(crate) request_id: String,
+    /// The user that sent us the undecryptable event.
+    pub(crate) sender: UserId,
+    /// The device that sent us the undecryptable event.
+    pub(crate) sender_device: DeviceId,
+}
+
+impl OutgoingKeyRequest {
+    /// Get the content of the `m.room_key_request` event requesting the
+    /// given session.
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - The session that is requested.
+    ///
+    /// * `requesting_device_id` - The device id of our own device.
+    pub(crate) fn request_content(
+        &self,
+        session: &RequestedSession,
+        requesting_device_id: &DeviceId,
+    ) -> Value {
+        json!({
+            "action": "request",
+            "body": {
+                "algorithm": Algorithm::MegolmV1AesSha2,
+                "room_id": session.room_id,
+                "sender_key": session.sender_key,
+                "session_id": session.session_id,
+            },
+            "request_id": self.request_id,
+            "requesting_device_id": requesting_device_id,
+        })
+    }
+
+    /// Get the content of the `m.room_key_request` event cancelling this
+    /// request.
+    ///
+    /// # Arguments
+    ///
+    /// * `requesting_device_id` - The device id of our own device.
+    pub(crate) fn cancellation_content(&self, requesting_device_id: &DeviceId) -> Value {
+        json!({
+            "action": "request_cancellation",
+            "request_id": self.request_id,
+            "requesting_device_id": requesting_device_id,
+        })
+    }
+}
+
+/// Trait deciding if a room key should be forwarded to a device that requested
+/// it.
+pub trait KeySharePolicy: Debug + Send + Sync {
+    /// Should the given group session be shared with the given device.
+    ///
+    /// # Arguments
+    ///
+    /// * `own_user_id` - The user id of the user that owns the `OlmMachine`.
+    ///
+    /// * `device` - The device that requested the group session.
+    ///
+    /// * `session` - The group session that was requested.
+    fn should_share(
+        &self,
+        own_user_id: &UserId,
+        device: &Device,
+        session: &InboundGroupSession,
+    ) -> bool;
+}
+
+/// The default key share policy.
+///
+/// Room keys are only forwarded to our own devices, and only if those have
+/// been verified.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OwnVerifiedDevices;
+
+impl KeySharePolicy for OwnVerifiedDevices {
+    fn should_share(&self, own_user_id: &UserId, device: &Device, _: &InboundGroupSession) -> bool {
+        device.user_id() == own_user_id && device.trust_state() == TrustState::Verified
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+
+    use matrix_sdk_common::identifiers::{RoomId, UserId};
+
+    use super::{KeySharePolicy, OwnVerifiedDevices};
+    use crate::device::test::get_device;
+    use crate::device::TrustState;
+    use crate::olm::{EncryptionSettings, InboundGroupSession, OutboundGroupSession};
+
+    #[tokio::test]
+    async fn own_verified_devices_policy() {
+        let own_user_id = UserId::try_from("@alice:example.org").unwrap();
+        let other_user_id = UserId::try_from("@bob:example.org").unwrap();
+        let room_id = RoomId::try_from("!test:localhost").unwrap();
+
+        let outbound = OutboundGroupSession::new(&room_id, EncryptionSettings::default());
+        let session = InboundGroupSession::new(
+            "test_key",
+            "test_key",
+            &room_id,
+            outbound.session_key().await,
+        )
+        .unwrap();
+
+        let device = get_device();
+        let policy = OwnVerifiedDevices;
+
+        assert!(!policy.should_share(&own_user_id, &device, &session));
+
+        device.set_trust_state(TrustState::Verified);
+        assert!(policy.should_share(&own_user_id, &device, &session));
+        assert!(!policy.should_share(&other_user_id, &device, &session));
+    }
+}
@@ -26,18 +26,49 @@
     unused_qualifications
 )]
 
+mod attachments;
+mod backup;
 mod device;
 mod error;
+mod identities;
+mod key_export;
+mod key_request;
 mod machine;
 mod memory_stores;
 mod olm;
+mod requests;
 mod store;
+mod verification;
+mod withheld;
 
+pub use attachments::{
+    decrypt_attachment, encrypt_attachment, AttachmentDecryptor, AttachmentEncryptor, JsonWebKey,
+    MediaEncryptionInfo,
+};
+pub use backup::{
+    BackupAuthData, BackupVersion, EncryptedSessionData, KeyBackupData, KeysBackupRequest,
+    MegolmV1BackupKey, RecoveryKey, RoomKeyBackup, BACKUP_ALGORITHM,
+};
 pub use device::{Device, TrustState};
-pub use error::{MegolmError, OlmError};
-pub use machine::{OlmMachine, OneTimeKeys};
+pub use error::{
+    BackupError, CrossSigningError, DecryptorError, KeyExportError, MegolmError, OlmError,
+};
+pub use identities::{
+    CrossSigningKey, CrossSigningUploadRequest, KeyUsage, KeysQueryCrossSigningKeys,
+    PickledCrossSigningIdentity, PrivateCrossSigningIdentity, SignatureUploadRequest, UserIdentity,
+};
+pub use key_export::{
+    decrypt_key_export, encrypt_key_export, ExportedRoomKey, DEFAULT_EXPORT_ROUNDS,
+};
+pub use key_request::{KeySharePolicy, OwnVerifiedDevices};
+pub use machine::{
+    FallbackKeys, GroupSessionSharePolicy, OlmMachine, OneTimeKeys, UnusedFallbackKeyTypes,
+};
 pub use memory_stores::{DeviceStore, GroupSessionStore, SessionStore, UserDevices};
-pub use olm::{Account, InboundGroupSession, OutboundGroupSession, Session};
+pub use olm::{Account, EncryptionSettings, InboundGroupSession, OutboundGroupSession, Session};
+pub use requests::{IncomingResponse, KeysUploadRequest, OutgoingRequest, OutgoingRequests};
 #[cfg(feature = "sqlite-cryptostore")]
 pub use store::sqlite::SqliteStore;
 pub use store::{CryptoStore, CryptoStoreError};
+pub use verification::Sas;
+pub use withheld::WithheldCode;
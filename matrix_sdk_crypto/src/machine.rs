@@ -19,16 +19,41 @@ use std::mem;
 use std::path::Path;
 use std::result::Result as StdResult;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 
-use super::error::{EventError, MegolmError, MegolmResult, OlmError, OlmResult, SignatureError};
+use super::backup::{
+    BackupVersion, KeysBackupRequest, MegolmV1BackupKey, RecoveryKey, RoomKeyBackup,
+    BACKUP_ALGORITHM,
+};
+use super::error::{
+    CrossSigningError, EventError, MegolmError, MegolmResult, OlmError, OlmResult, SignatureError,
+};
+use super::identities::{
+    CrossSigningUploadRequest, KeyUsage, KeysQueryCrossSigningKeys, PrivateCrossSigningIdentity,
+    SignatureUploadRequest, UserIdentity,
+};
+use super::key_export::{decrypt_key_export, encrypt_key_export, ExportedRoomKey};
+use super::key_request::{KeySharePolicy, OutgoingKeyRequest, OwnVerifiedDevices, RequestedSession};
 use super::olm::{
-    Account, GroupSessionKey, IdentityKeys, InboundGroupSession, OlmMessage, OlmUtility,
-    OutboundGroupSession, Session,
+    Account, EncryptionSettings, GroupSessionKey, IdentityKeys, InboundGroupSession, OlmMessage,
+    OlmUtility, OutboundGroupSession, Session,
 };
+use super::requests::{IncomingResponse, KeysUploadRequest, OutgoingRequest, OutgoingRequests};
 use super::store::memorystore::MemoryStore;
 #[cfg(feature = "sqlite-cryptostore")]
 use super::store::sqlite::SqliteStore;
-use super::{device::Device, store::Result as StoreError, CryptoStore};
+use super::verification::{Sas, VerificationMachine};
+use super::withheld::{
+    withheld_event_type, RoomKeyWithheldContent, ToDeviceRoomKeyWithheld, WithheldCode,
+    WITHHELD_EVENT_TYPE,
+};
+use super::{
+    device::{Device, TrustState},
+    store::Result as StoreError,
+    CryptoStore,
+};
+
+use dashmap::DashMap;
 
 use matrix_sdk_common::api;
 use matrix_sdk_common::events::{
@@ -38,6 +63,7 @@ use matrix_sdk_common::events::{
         OlmV1Curve25519AesSha2Content,
     },
     room::message::MessageEventContent,
+    room_key_request::Action,
     to_device::{
         AnyToDeviceEvent as ToDeviceEvent, ToDeviceEncrypted, ToDeviceForwardedRoomKey,
         ToDeviceRoomKey, ToDeviceRoomKeyRequest,
@@ -45,7 +71,9 @@ use matrix_sdk_common::events::{
     Algorithm, EventJson, EventType,
 };
 use matrix_sdk_common::identifiers::{DeviceId, RoomId, UserId};
+use matrix_sdk_common::instant::{Duration, Instant};
 use matrix_sdk_common::uuid::Uuid;
+use matrix_sdk_common::{DecryptedEvent, EncryptionInfo, VerificationState};
 
 use api::r0::keys;
 use api::r0::{
@@ -54,6 +82,7 @@ use api::r0::{
     to_device::{send_event_to_device::Request as ToDeviceRequest, DeviceIdOrAllDevices},
 };
 
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use tracing::{debug, error, info, instrument, trace, warn};
 
@@ -62,6 +91,47 @@ use tracing::{debug, error, info, instrument, trace, warn};
 /// These keys need to be periodically uploaded to the server.
 pub type OneTimeKeys = BTreeMap<AlgorithmAndDeviceId, OneTimeKey>;
 
+/// A map from the algorithm and key id to a signed fallback key.
+///
+/// Signed fallback keys carry a `fallback` flag which ruma's `OneTimeKey`
+/// can't represent, so the keys are kept as JSON objects.
+pub type FallbackKeys = BTreeMap<AlgorithmAndDeviceId, Value>;
+
+/// The key types of our fallback keys that the server didn't hand out yet.
+///
+/// This is part of the sync response but isn't yet supported by ruma, servers
+/// that don't support fallback keys don't send it.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct UnusedFallbackKeyTypes {
+    /// The key types of the unused fallback keys, e.g. `signed_curve25519`.
+    #[serde(
+        rename = "org.matrix.msc2732.device_unused_fallback_key_types",
+        alias = "device_unused_fallback_key_types"
+    )]
+    pub key_types: Option<Vec<String>>,
+}
+
+/// Policy deciding which devices receive our room keys when a group session
+/// gets shared.
+///
+/// Blacklisted devices never receive our room keys, regardless of the policy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GroupSessionSharePolicy {
+    /// Share the room key with every device that isn't blacklisted.
+    AllDevices,
+    /// Share the room key only with devices that have been verified.
+    VerifiedOnly,
+    /// Refuse to share the room key if any of the devices has an unset trust
+    /// state, devices need to be verified, ignored or blacklisted first.
+    FailOnUnverified,
+}
+
+impl Default for GroupSessionSharePolicy {
+    fn default() -> Self {
+        GroupSessionSharePolicy::AllDevices
+    }
+}
+
 /// State machine implementation of the Olm/Megolm encryption protocol used for
 /// Matrix end to end encryption.
 pub struct OlmMachine {
@@ -82,6 +152,53 @@ pub struct OlmMachine {
     store: Box<dyn CryptoStore>,
     /// The currently active outbound group sessions.
     outbound_group_sessions: HashMap<RoomId, OutboundGroupSession>,
+    /// State machine handling all the interactive verification flows.
+    verification_machine: VerificationMachine,
+    /// Policy deciding which devices get our room keys when they request them.
+    key_share_policy: Box<dyn KeySharePolicy>,
+    /// Policy deciding which devices get our room keys when we share a group
+    /// session.
+    group_session_share_policy: GroupSessionSharePolicy,
+    /// To-device requests that are waiting to be sent out, keyed by their
+    /// transaction id.
+    outgoing_to_device_messages: DashMap<String, ToDeviceRequest>,
+    /// Room key requests of devices we don't share an Olm session with yet,
+    /// they are answered once a session was created.
+    pending_key_requests: HashMap<(UserId, DeviceId), Vec<ToDeviceRoomKeyRequest>>,
+    /// Room key requests we sent out for sessions we're missing.
+    outgoing_key_requests: HashMap<RequestedSession, OutgoingKeyRequest>,
+    /// Ids of the group sessions we received since the last call to
+    /// `take_received_group_sessions()`.
+    received_group_sessions: HashSet<String>,
+    /// Devices with which our Olm session got wedged, a new session needs to
+    /// be created with them.
+    wedged_devices: HashMap<UserId, HashSet<DeviceId>>,
+    /// The time we last tried to unwedge a session with a device, keyed by the
+    /// curve25519 key of the device.
+    last_unwedging: HashMap<String, Instant>,
+    /// The time we last claimed a one-time key to unwedge a session with a
+    /// device.
+    last_unwedging_claim: HashMap<(UserId, DeviceId), Instant>,
+    /// The time a one-time key claim for a device last failed because the
+    /// server didn't return a key for it.
+    failed_key_claims: HashMap<(UserId, DeviceId), Instant>,
+    /// Group sessions that other devices refused to share with us, keyed by
+    /// the sender key and session id.
+    withheld_sessions: HashMap<(String, String), RoomKeyWithheldContent>,
+    /// Devices that we already notified that we couldn't establish an Olm
+    /// session with them.
+    no_olm_sent: HashSet<(UserId, DeviceId)>,
+    /// The public key and version of the room key backup that is currently
+    /// enabled.
+    backup: Option<(MegolmV1BackupKey, String)>,
+    /// The private keys of our cross signing identity, if we created one.
+    cross_signing_identity: Option<PrivateCrossSigningIdentity>,
+    /// The users that should receive room keys in each encrypted room, used
+    /// to find out which users we don't share an encrypted room with anymore.
+    room_members: HashMap<RoomId, HashSet<UserId>>,
+    /// Keys upload, query and claim requests that were handed out to the
+    /// client but weren't yet marked as sent, keyed by their request id.
+    outgoing_requests: HashMap<String, OutgoingRequest>,
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -102,6 +219,18 @@ impl OlmMachine {
 
     const MAX_TO_DEVICE_MESSAGES: usize = 20;
 
+    /// The minimal time between two attempts to unwedge a session with the
+    /// same device.
+    const UNWEDGING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+    /// The minimal time before a one-time key is claimed again for a device
+    /// that had no one-time keys left the last time.
+    const KEY_CLAIM_RETRY_INTERVAL: Duration = Duration::from_secs(60 * 15);
+
+    /// The maximal number of room keys that are uploaded to a backup in a
+    /// single request.
+    const BACKUP_BATCH_SIZE: usize = 100;
+
     /// Create a new memory based OlmMachine.
     ///
     /// The created machine will keep the encryption keys only in memory and
@@ -113,13 +242,37 @@ impl OlmMachine {
     ///
     /// * `device_id` - The unique id of the device that owns this machine.
     pub fn new(user_id: &UserId, device_id: &str) -> Self {
+        let account = Account::new();
+        let verification_machine = VerificationMachine::new(
+            user_id.clone(),
+            device_id.to_owned(),
+            account.identity_keys().ed25519(),
+        );
+
         OlmMachine {
             user_id: user_id.clone(),
             device_id: device_id.to_owned(),
-            account: Account::new(),
+            account,
             uploaded_signed_key_count: None,
             store: Box::new(MemoryStore::new()),
             outbound_group_sessions: HashMap::new(),
+            verification_machine,
+            key_share_policy: Box::new(OwnVerifiedDevices),
+            group_session_share_policy: GroupSessionSharePolicy::default(),
+            outgoing_to_device_messages: DashMap::new(),
+            pending_key_requests: HashMap::new(),
+            outgoing_key_requests: HashMap::new(),
+            received_group_sessions: HashSet::new(),
+            wedged_devices: HashMap::new(),
+            last_unwedging: HashMap::new(),
+            last_unwedging_claim: HashMap::new(),
+            failed_key_claims: HashMap::new(),
+            withheld_sessions: HashMap::new(),
+            no_olm_sent: HashSet::new(),
+            backup: None,
+            cross_signing_identity: None,
+            room_members: HashMap::new(),
+            outgoing_requests: HashMap::new(),
         }
     }
 
@@ -145,9 +298,19 @@ impl OlmMachine {
         device_id: &str,
         mut store: impl CryptoStore + 'static,
     ) -> StoreError<Self> {
+        let mut outbound_group_sessions = HashMap::new();
+        let mut cross_signing_identity = None;
+
         let account = match store.load_account().await? {
             Some(a) => {
                 debug!("Restored account");
+
+                for session in store.load_outbound_group_sessions().await? {
+                    outbound_group_sessions.insert(session.room_id().to_owned(), session);
+                }
+
+                cross_signing_identity = store.load_private_identity().await?;
+
                 a
             }
             None => {
@@ -156,13 +319,36 @@ impl OlmMachine {
             }
         };
 
+        let verification_machine = VerificationMachine::new(
+            user_id.clone(),
+            device_id.to_owned(),
+            account.identity_keys().ed25519(),
+        );
+
         Ok(OlmMachine {
             user_id: user_id.clone(),
             device_id: device_id.to_owned(),
             account,
             uploaded_signed_key_count: None,
             store: Box::new(store),
-            outbound_group_sessions: HashMap::new(),
+            outbound_group_sessions,
+            verification_machine,
+            key_share_policy: Box::new(OwnVerifiedDevices),
+            group_session_share_policy: GroupSessionSharePolicy::default(),
+            outgoing_to_device_messages: DashMap::new(),
+            pending_key_requests: HashMap::new(),
+            outgoing_key_requests: HashMap::new(),
+            received_group_sessions: HashSet::new(),
+            wedged_devices: HashMap::new(),
+            last_unwedging: HashMap::new(),
+            last_unwedging_claim: HashMap::new(),
+            failed_key_claims: HashMap::new(),
+            withheld_sessions: HashMap::new(),
+            no_olm_sent: HashSet::new(),
+            backup: None,
+            cross_signing_identity,
+            room_members: HashMap::new(),
+            outgoing_requests: HashMap::new(),
         })
     }
 
@@ -189,6 +375,33 @@ impl OlmMachine {
         OlmMachine::new_with_store(user_id, device_id, store).await
     }
 
+    /// Set the policy that decides which devices get our room keys when they
+    /// request them.
+    ///
+    /// By default keys are only forwarded to our own verified devices.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The policy that should be used to answer room key
+    /// requests.
+    pub fn set_key_share_policy(&mut self, policy: impl KeySharePolicy + 'static) {
+        self.key_share_policy = Box::new(policy);
+    }
+
+    /// Set the policy that decides which devices get our room keys when a
+    /// group session is shared.
+    ///
+    /// By default room keys are shared with all devices that aren't
+    /// blacklisted.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The policy that should be used when sharing group
+    /// sessions.
+    pub fn set_group_session_share_policy(&mut self, policy: GroupSessionSharePolicy) {
+        self.group_session_share_policy = policy;
+    }
+
     /// The unique user id that owns this identity.
     pub fn user_id(&self) -> &UserId {
         &self.user_id
@@ -205,7 +418,7 @@ impl OlmMachine {
     }
 
     /// Should account or one-time keys be uploaded to the server.
-    pub async fn should_upload_keys(&self) -> bool {
+    async fn should_upload_keys(&self) -> bool {
         if !self.account.shared() {
             return true;
         }
@@ -326,6 +539,68 @@ impl OlmMachine {
         Ok(missing)
     }
 
+    /// Queue up a one-time key claim for the devices of the given users that
+    /// we don't share an Olm session with yet.
+    ///
+    /// Devices for which the server recently didn't return a one-time key are
+    /// skipped until `KEY_CLAIM_RETRY_INTERVAL` passed.
+    ///
+    /// Returns the queued request, None if no key needs to be claimed. The
+    /// request needs to be sent out and marked as sent using
+    /// `mark_request_as_sent()` before the sessions can be used.
+    ///
+    /// # Arguments
+    ///
+    /// `users` - The list of users that we should check if we lack a session
+    /// with one of their devices.
+    pub async fn claim_missing_sessions(
+        &mut self,
+        users: impl Iterator<Item = &UserId>,
+    ) -> OlmResult<Option<OutgoingRequest>> {
+        let missing = self.get_missing_sessions(users).await?;
+        Ok(self.queue_key_claim(missing))
+    }
+
+    /// Queue up a one-time key claim for the given devices.
+    ///
+    /// Devices for which the server recently didn't return a one-time key are
+    /// skipped, returns None if no device is left.
+    fn queue_key_claim(
+        &mut self,
+        devices: BTreeMap<UserId, BTreeMap<DeviceId, KeyAlgorithm>>,
+    ) -> Option<OutgoingRequest> {
+        let mut missing: BTreeMap<UserId, BTreeMap<DeviceId, KeyAlgorithm>> = BTreeMap::new();
+
+        for (user_id, devices) in devices {
+            for (device_id, algorithm) in devices {
+                let recently_failed = self
+                    .failed_key_claims
+                    .get(&(user_id.clone(), device_id.clone()))
+                    .map_or(false, |t| {
+                        t.elapsed() < OlmMachine::KEY_CLAIM_RETRY_INTERVAL
+                    });
+
+                if !recently_failed {
+                    missing
+                        .entry(user_id.clone())
+                        .or_insert_with(BTreeMap::new)
+                        .insert(device_id, algorithm);
+                }
+            }
+        }
+
+        if missing.is_empty() {
+            return None;
+        }
+
+        let request = keys::claim_keys::Request {
+            timeout: None,
+            one_time_keys: missing,
+        };
+
+        Some(self.queue_request(OutgoingRequests::KeysClaim(request)))
+    }
+
     /// Receive a successful key claim response and create new Olm sessions with
     /// the claimed keys.
     ///
@@ -426,15 +701,58 @@ impl OlmMachine {
                     }
                 };
 
-                if let Err(e) = self.store.save_sessions(&[session]).await {
+                if let Err(e) = self.store.save_sessions(&[session.clone()]).await {
                     error!("Failed to store newly created Olm session {}", e);
                     continue;
                 }
 
-                // TODO if this session was created because a previous one was
-                // wedged queue up a dummy event to be sent out.
-                // TODO if this session was created because of a key request,
-                // mark the forwarding keys to be sent out
+                self.no_olm_sent
+                    .remove(&(user_id.clone(), device_id.clone()));
+
+                let was_wedged = self
+                    .wedged_devices
+                    .get_mut(user_id)
+                    .map_or(false, |d| d.remove(device_id));
+
+                if was_wedged {
+                    // Let the other side know about our new session so it
+                    // can start using it as well.
+                    info!(
+                        "Sending a dummy event to {} {} to unwedge our Olm session",
+                        user_id, device_id
+                    );
+                    let request = match self
+                        .olm_encrypt(session, &device, EventType::Dummy, json!({}))
+                        .await
+                        .and_then(|c| self.olm_content_to_request(&device, c))
+                    {
+                        Ok(r) => r,
+                        Err(e) => {
+                            error!(
+                                "Failed to encrypt a dummy event for {} {}: {}",
+                                user_id, device_id, e
+                            );
+                            continue;
+                        }
+                    };
+
+                    self.outgoing_to_device_messages
+                        .insert(request.txn_id.clone(), request);
+                }
+
+                let pending_requests = self
+                    .pending_key_requests
+                    .remove(&(user_id.clone(), device_id.clone()))
+                    .unwrap_or_default();
+
+                for request in &pending_requests {
+                    if let Err(e) = self.handle_room_key_request(request).await {
+                        error!(
+                            "Failed to answer a room key request of {} {}: {:?}",
+                            user_id, device_id, e
+                        );
+                    }
+                }
             }
         }
         Ok(())
@@ -449,9 +767,13 @@ impl OlmMachine {
     ///
     /// * `response` - The keys query response of the request that the client
     /// performed.
+    ///
+    /// * `cross_signing_keys` - The cross signing keys that are part of the
+    /// same keys query response.
     pub async fn receive_keys_query_response(
         &mut self,
         response: &keys::get_keys::Response,
+        cross_signing_keys: &KeysQueryCrossSigningKeys,
     ) -> OlmResult<Vec<Device>> {
         let mut changed_devices = Vec::new();
 
@@ -535,9 +857,95 @@ impl OlmMachine {
 
         self.store.save_devices(&changed_devices).await?;
 
+        let mut changed_identities = Vec::new();
+
+        for (user_id, master_key) in &cross_signing_keys.master_keys {
+            let self_signing_key =
+                if let Some(k) = cross_signing_keys.self_signing_keys.get(user_id) {
+                    k.clone()
+                } else {
+                    warn!("The self-signing key of {} is missing", user_id);
+                    continue;
+                };
+
+            // The server only hands out our own user-signing key.
+            let user_signing_key = if user_id == &self.user_id {
+                cross_signing_keys.user_signing_keys.get(user_id).cloned()
+            } else {
+                None
+            };
+
+            let mut master_key = master_key.clone();
+
+            // Keep the signatures we created locally, the server doesn't
+            // necessarily know about them yet.
+            if let Some(stored_identity) = self.store.get_user_identity(user_id).await? {
+                master_key.merge_signatures(stored_identity.master_key());
+            }
+
+            let identity = UserIdentity::new(master_key, self_signing_key, user_signing_key);
+
+            if identity.user_id() != user_id {
+                warn!(
+                    "Mismatch in the cross signing keys payload of user {}",
+                    user_id
+                );
+                continue;
+            }
+
+            if let Err(e) = self.verify_identity(&identity) {
+                warn!(
+                    "Failed to verify the cross signing keys of {}: {}",
+                    user_id, e
+                );
+                continue;
+            }
+
+            changed_identities.push(identity);
+        }
+
+        self.store.save_user_identities(&changed_identities).await?;
+
         Ok(changed_devices)
     }
 
+    /// Check that the cross signing keys of an identity have the right usage
+    /// and that the subkeys are signed by the master key.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - The identity that should be checked.
+    fn verify_identity(&self, identity: &UserIdentity) -> Result<(), SignatureError> {
+        let user_id = identity.user_id();
+        let master_key = identity.master_key();
+
+        let master_public_key = match master_key.public_key() {
+            Some(k) if master_key.has_usage(KeyUsage::Master) => k,
+            _ => return Err(SignatureError::InvalidCrossSigningKey),
+        };
+
+        let mut subkeys = vec![(identity.self_signing_key(), KeyUsage::SelfSigning)];
+
+        if let Some(k) = identity.user_signing_key() {
+            subkeys.push((k, KeyUsage::UserSigning));
+        }
+
+        for (subkey, usage) in subkeys {
+            if &subkey.user_id != user_id || !subkey.has_usage(usage) {
+                return Err(SignatureError::InvalidCrossSigningKey);
+            }
+
+            self.verify_json(
+                user_id,
+                master_public_key,
+                master_public_key,
+                &mut json!(subkey),
+            )?;
+        }
+
+        Ok(())
+    }
+
     /// Generate new one-time keys.
     ///
     /// Returns the number of newly generated one-time keys. If no keys can be
@@ -649,6 +1057,65 @@ impl OlmMachine {
         Ok(one_time_key_map)
     }
 
+    /// Sign our current fallback key so it can be uploaded to the server.
+    async fn signed_fallback_keys(&self) -> OlmResult<FallbackKeys> {
+        let mut fallback_keys = BTreeMap::new();
+
+        for (key_id, key) in self.account.fallback_key().await? {
+            let mut key_json = json!({
+                "key": key,
+                "fallback": true,
+            });
+
+            let signature = self.sign_json(&key_json).await;
+
+            let mut signature_map = BTreeMap::new();
+            signature_map.insert(format!("ed25519:{}", self.device_id), signature);
+
+            let mut signatures = BTreeMap::new();
+            signatures.insert(self.user_id.clone(), signature_map);
+
+            key_json["signatures"] = json!(signatures);
+
+            fallback_keys.insert(
+                AlgorithmAndDeviceId(KeyAlgorithm::SignedCurve25519, key_id),
+                key_json,
+            );
+        }
+
+        Ok(fallback_keys)
+    }
+
+    /// Receive the unused fallback key types of a sync response.
+    ///
+    /// If the server handed out our fallback key a new one is generated, it
+    /// will be part of the next keys upload request.
+    ///
+    /// # Arguments
+    ///
+    /// * `unused_fallback_keys` - The unused fallback key types that were part
+    /// of the sync response.
+    pub async fn receive_unused_fallback_key_types(
+        &mut self,
+        unused_fallback_keys: &UnusedFallbackKeyTypes,
+    ) -> OlmResult<()> {
+        // Servers that don't support fallback keys don't tell us about them,
+        // we don't need to rotate anything if a new key is already waiting to
+        // be uploaded.
+        let key_types = match &unused_fallback_keys.key_types {
+            Some(k) if !self.account.should_upload_fallback_key() && self.account.shared() => k,
+            _ => return Ok(()),
+        };
+
+        if !key_types.iter().any(|k| k == "signed_curve25519") {
+            debug!("Our fallback key was used up, generating a new one");
+            self.account.generate_fallback_key().await;
+            self.store.save_account(self.account.clone()).await?;
+        }
+
+        Ok(())
+    }
+
     /// Convert a JSON value to the canonical representation and sign the JSON
     /// string.
     ///
@@ -731,9 +1198,7 @@ impl OlmMachine {
     /// Get a tuple of device and one-time keys that need to be uploaded.
     ///
     /// Returns an empty error if no keys need to be uploaded.
-    pub async fn keys_for_upload(
-        &self,
-    ) -> StdResult<(Option<DeviceKeys>, Option<OneTimeKeys>), ()> {
+    async fn keys_for_upload(&self) -> StdResult<(Option<DeviceKeys>, Option<OneTimeKeys>), ()> {
         if !self.should_upload_keys().await {
             return Err(());
         }
@@ -983,7 +1448,7 @@ impl OlmMachine {
                 .decrypt_olm_message(&event.sender, &content.sender_key, message)
                 .await?;
 
-            debug!("Decrypted a to-device event {:?}", decrypted_event);
+            debug!("Decrypted a to-device event from {}", event.sender);
 
             // Handle the decrypted event, e.g. fetch out Megolm sessions out of
             // the event.
@@ -1017,45 +1482,71 @@ impl OlmMachine {
         signing_key: &str,
         event: &mut ToDeviceRoomKey,
     ) -> OlmResult<Option<EventJson<ToDeviceEvent>>> {
+        // The private key is removed from the event whatever happens with it,
+        // the event gets handed out to the user.
+        let session_key = GroupSessionKey(mem::take(&mut event.content.session_key));
+
         match event.content.algorithm {
             Algorithm::MegolmV1AesSha2 => {
-                let session_key = GroupSessionKey(mem::take(&mut event.content.session_key));
-
                 let session = InboundGroupSession::new(
                     sender_key,
                     signing_key,
                     &event.content.room_id,
                     session_key,
                 )?;
-                let _ = self.store.save_inbound_group_session(session).await?;
-                // TODO ideally we would rewrap the event again just like so
-                // let event = EventJson::from(ToDeviceEvent::RoomKey(event.clone()));
-                // This saidly lacks a type once it's serialized again, fix
-                // this in Ruma.
-                let mut json = serde_json::to_value(event.clone())?;
-                json.as_object_mut()
-                    .unwrap()
-                    .insert("type".to_owned(), Value::String("m.room_key".to_owned()));
-                let event = serde_json::from_value::<EventJson<ToDeviceEvent>>(json)?;
-
-                Ok(Some(event))
+                let session_id = session.session_id().to_owned();
+
+                if self.store.save_inbound_group_session(session).await? {
+                    self.received_group_sessions.insert(session_id);
+                }
             }
             _ => {
                 warn!(
                     "Received room key with unsupported key algorithm {}",
                     event.content.algorithm
                 );
-                Ok(None)
             }
         }
+
+        Ok(Some(OlmMachine::rewrap_to_device_event(
+            event,
+            EventType::RoomKey,
+        )?))
+    }
+
+    /// Turn a to-device event back into its raw JSON form.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event that should be rewrapped.
+    ///
+    /// * `event_type` - The type of the event.
+    fn rewrap_to_device_event<T: Serialize>(
+        event: &T,
+        event_type: EventType,
+    ) -> OlmResult<EventJson<ToDeviceEvent>> {
+        // TODO ideally we would rewrap the event again just like so
+        // let event = EventJson::from(ToDeviceEvent::RoomKey(event.clone()));
+        // This saidly lacks a type once it's serialized again, fix
+        // this in Ruma.
+        let mut json = serde_json::to_value(event)?;
+        json.as_object_mut()
+            .ok_or(EventError::NotAnObject)?
+            .insert("type".to_owned(), Value::String(event_type.to_string()));
+
+        Ok(serde_json::from_value::<EventJson<ToDeviceEvent>>(json)?)
     }
 
     /// Create a new outbound group session.
     ///
     /// This also creates a matching inbound group session and saves that one in
     /// the store.
-    async fn create_outbound_group_session(&mut self, room_id: &RoomId) -> OlmResult<()> {
-        let session = OutboundGroupSession::new(room_id);
+    async fn create_outbound_group_session(
+        &mut self,
+        room_id: &RoomId,
+        settings: EncryptionSettings,
+    ) -> OlmResult<()> {
+        let session = OutboundGroupSession::new(room_id, settings);
         let identity_keys = self.account.identity_keys();
 
         let sender_key = identity_keys.curve25519();
@@ -1096,24 +1587,23 @@ impl OlmMachine {
     /// * `content` - The plaintext content of the message that should be
     /// encrypted.
     ///
-    /// # Panics
-    ///
-    /// Panics if a group session for the given room wasn't shared beforehand.
+    /// Returns a `MegolmError::MissingOutboundSession` if a group session for
+    /// the given room wasn't shared beforehand and a
+    /// `MegolmError::ExpiredOutboundSession` if the group session expired.
     pub async fn encrypt(
-        &self,
+        &mut self,
         room_id: &RoomId,
         content: MessageEventContent,
     ) -> MegolmResult<EncryptedEventContent> {
         let session = self.outbound_group_sessions.get(room_id);
 
-        let session = if let Some(s) = session {
-            s
-        } else {
-            panic!("Session wasn't created nor shared");
+        let session = match session {
+            Some(s) if s.shared() => s.clone(),
+            _ => return Err(MegolmError::MissingOutboundSession),
         };
 
         if session.expired() {
-            panic!("Session is expired");
+            return Err(MegolmError::ExpiredOutboundSession);
         }
 
         let json_content = json!({
@@ -1130,6 +1620,9 @@ impl OlmMachine {
         });
 
         let ciphertext = session.encrypt(plaintext).await;
+        self.store
+            .save_outbound_group_session(session.clone())
+            .await?;
 
         Ok(EncryptedEventContent::MegolmV1AesSha2(
             MegolmV1AesSha2Content {
@@ -1197,72 +1690,318 @@ impl OlmMachine {
         ))
     }
 
-    /// Should the client share a group session for the given room.
+    /// Get the most recently created Olm session we share with the given
+    /// curve25519 key.
     ///
-    /// Returns true if a session needs to be shared before room messages can be
-    /// encrypted, false if one is already shared and ready to encrypt room
-    /// messages.
+    /// # Arguments
     ///
-    /// This should be called every time a new room message wants to be sent out
-    /// since group sessions can expire at any time.
-    pub fn should_share_group_session(&self, room_id: &RoomId) -> bool {
-        let session = self.outbound_group_sessions.get(room_id);
+    /// * `sender_key` - The curve25519 key of the device.
+    async fn get_newest_session(&mut self, sender_key: &str) -> OlmResult<Option<Session>> {
+        let sessions = if let Some(s) = self.store.get_sessions(sender_key).await? {
+            s
+        } else {
+            return Ok(None);
+        };
 
-        match session {
-            Some(s) => !s.shared() || s.expired(),
-            None => true,
-        }
+        let sessions = sessions.lock().await;
+
+        Ok(sessions.iter().max_by_key(|s| *s.creation_time).cloned())
     }
 
-    /// Invalidate the currently active outbound group session for the given
-    /// room.
+    /// Create a to-device request sending the given Olm encrypted content to a
+    /// single device.
     ///
-    /// Returns true if a session was invalidated, false if there was no session
-    /// to invalidate.
-    pub fn invalidate_group_session(&mut self, room_id: &RoomId) -> bool {
-        self.outbound_group_sessions.remove(room_id).is_some()
+    /// # Arguments
+    ///
+    /// * `device` - The device that should receive the content.
+    ///
+    /// * `content` - The Olm encrypted content.
+    fn olm_content_to_request(
+        &self,
+        device: &Device,
+        content: EncryptedEventContent,
+    ) -> OlmResult<ToDeviceRequest> {
+        let mut messages = BTreeMap::new();
+        let mut user_messages = BTreeMap::new();
+
+        user_messages.insert(
+            DeviceIdOrAllDevices::DeviceId(device.device_id().clone()),
+            serde_json::value::to_raw_value(&content)?,
+        );
+        messages.insert(device.user_id().clone(), user_messages);
+
+        Ok(ToDeviceRequest {
+            event_type: EventType::RoomEncrypted,
+            txn_id: Uuid::new_v4().to_string(),
+            messages,
+        })
     }
 
-    // TODO accept an algorithm here
-    /// Get to-device requests to share a group session with users in a room.
+    /// Olm encrypt to-device event contents for the given devices.
+    ///
+    /// Devices that we don't share an Olm session with are skipped, use
+    /// `claim_missing_sessions()` to claim one-time keys for them first.
+    ///
+    /// Returns the to-device requests carrying the encrypted events.
     ///
     /// # Arguments
     ///
-    /// `room_id` - The room id of the room where the group session will be
-    /// used.
+    /// * `event_type` - The type of the events that should be encrypted.
     ///
-    /// `users` - The list of users that should receive the group session.
-    pub async fn share_group_session<'a, I>(
+    /// * `messages` - The event content for each user and device. Content that
+    /// is addressed to all devices of an user is encrypted for each known
+    /// device of the user.
+    pub async fn encrypt_to_device(
         &mut self,
-        room_id: &RoomId,
-        users: I,
-    ) -> OlmResult<Vec<ToDeviceRequest>>
-    where
-        I: IntoIterator<Item = &'a UserId>,
-    {
-        self.create_outbound_group_session(room_id).await?;
-        let megolm_session = self.outbound_group_sessions.get(room_id).unwrap();
+        event_type: EventType,
+        messages: &BTreeMap<UserId, BTreeMap<DeviceIdOrAllDevices, Value>>,
+    ) -> OlmResult<Vec<ToDeviceRequest>> {
+        let mut recipients = Vec::new();
 
-        if megolm_session.shared() {
-            panic!("Session is already shared");
+        for (user_id, user_messages) in messages {
+            let user_devices = self.store.get_user_devices(user_id).await?;
+
+            for (device_id, content) in user_messages {
+                let devices: Vec<Device> = match device_id {
+                    DeviceIdOrAllDevices::DeviceId(d) => user_devices.get(d).into_iter().collect(),
+                    DeviceIdOrAllDevices::AllDevices => user_devices.devices().cloned().collect(),
+                };
+
+                for device in devices {
+                    let session = match device.get_key(KeyAlgorithm::Curve25519) {
+                        Some(k) => self.get_newest_session(k).await?,
+                        None => None,
+                    };
+
+                    if let Some(session) = session {
+                        recipients.push((session, device, content));
+                    } else {
+                        warn!(
+                            "Not sending the to-device event to {} {}, no Olm session found",
+                            user_id,
+                            device.device_id()
+                        );
+                    }
+                }
+            }
         }
 
-        let session_id = megolm_session.session_id().to_owned();
-        megolm_session.mark_as_shared();
+        let mut requests = Vec::new();
 
-        let key_content = json!({
-            "algorithm": Algorithm::MegolmV1AesSha2,
-            "room_id": room_id,
-            "session_id": session_id.clone(),
-            "session_key": megolm_session.session_key().await,
-            "chain_index": megolm_session.message_index().await,
-        });
+        for chunk in recipients.chunks(OlmMachine::MAX_TO_DEVICE_MESSAGES) {
+            let mut messages = BTreeMap::new();
 
-        let mut user_map = Vec::new();
+            for (session, device, content) in chunk {
+                let encrypted_content = self
+                    .olm_encrypt(
+                        session.clone(),
+                        device,
+                        event_type.clone(),
+                        (*content).clone(),
+                    )
+                    .await?;
 
-        for user_id in users {
-            for device in self.store.get_user_devices(user_id).await?.devices() {
-                let sender_key = if let Some(k) = device.get_key(KeyAlgorithm::Curve25519) {
+                messages
+                    .entry(device.user_id().clone())
+                    .or_insert_with(BTreeMap::new)
+                    .insert(
+                        DeviceIdOrAllDevices::DeviceId(device.device_id().clone()),
+                        serde_json::value::to_raw_value(&encrypted_content)?,
+                    );
+            }
+
+            requests.push(ToDeviceRequest {
+                event_type: EventType::RoomEncrypted,
+                txn_id: Uuid::new_v4().to_string(),
+                messages,
+            });
+        }
+
+        Ok(requests)
+    }
+
+    /// Mark the Olm session we share with the given device as wedged.
+    ///
+    /// The device will be queued up for a one-time key claim, once a new
+    /// session is created a `m.dummy` event will be sent to the device.
+    /// Devices are only unwedged once per `UNWEDGING_INTERVAL`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The user that sent us the undecryptable Olm message.
+    ///
+    /// * `sender_key` - The curve25519 key of the device that sent the
+    /// message.
+    async fn mark_session_as_wedged(&mut self, sender: &UserId, sender_key: &str) -> OlmResult<()> {
+        if let Some(time) = self.last_unwedging.get(sender_key) {
+            if time.elapsed() < OlmMachine::UNWEDGING_INTERVAL {
+                debug!(
+                    "Not unwedging the Olm session with {} {}, tried recently",
+                    sender, sender_key
+                );
+                return Ok(());
+            }
+        }
+
+        let devices = self.store.get_user_devices(sender).await?;
+        let device = devices.devices().find(|d| {
+            d.get_key(KeyAlgorithm::Curve25519)
+                .map_or(false, |k| k == sender_key)
+        });
+
+        if let Some(device) = device {
+            info!(
+                "Marking the Olm session with {} {} as wedged",
+                sender,
+                device.device_id()
+            );
+
+            self.last_unwedging
+                .insert(sender_key.to_owned(), Instant::now());
+            self.wedged_devices
+                .entry(sender.clone())
+                .or_insert_with(HashSet::new)
+                .insert(device.device_id().clone());
+        } else {
+            warn!(
+                "Can't unwedge the Olm session with {} {}, the device is unknown",
+                sender, sender_key
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Get the devices with which our Olm session got wedged.
+    ///
+    /// The returned map can be used for a one-time key claim request, the
+    /// response to it needs to be passed to `receive_keys_claim_response()`.
+    ///
+    /// A device is only returned once per `UNWEDGING_INTERVAL`, otherwise a
+    /// device that has no one-time keys left would be claimed for over and
+    /// over again.
+    pub fn get_devices_for_unwedging(
+        &mut self,
+    ) -> BTreeMap<UserId, BTreeMap<DeviceId, KeyAlgorithm>> {
+        let mut devices_for_unwedging: BTreeMap<UserId, BTreeMap<DeviceId, KeyAlgorithm>> =
+            BTreeMap::new();
+
+        for (user_id, devices) in &self.wedged_devices {
+            for device_id in devices {
+                let key = (user_id.clone(), device_id.clone());
+
+                if let Some(time) = self.last_unwedging_claim.get(&key) {
+                    if time.elapsed() < OlmMachine::UNWEDGING_INTERVAL {
+                        continue;
+                    }
+                }
+
+                self.last_unwedging_claim.insert(key, Instant::now());
+                devices_for_unwedging
+                    .entry(user_id.clone())
+                    .or_insert_with(BTreeMap::new)
+                    .insert(device_id.clone(), KeyAlgorithm::SignedCurve25519);
+            }
+        }
+
+        devices_for_unwedging
+    }
+
+    /// Should the client share a group session for the given room.
+    ///
+    /// Returns true if a session needs to be shared before room messages can be
+    /// encrypted, false if one is already shared and ready to encrypt room
+    /// messages.
+    ///
+    /// This should be called every time a new room message wants to be sent out
+    /// since group sessions can expire at any time.
+    pub fn should_share_group_session(&self, room_id: &RoomId) -> bool {
+        let session = self.outbound_group_sessions.get(room_id);
+
+        match session {
+            Some(s) => !s.shared() || s.expired(),
+            None => true,
+        }
+    }
+
+    /// Invalidate the currently active outbound group session for the given
+    /// room.
+    ///
+    /// Returns true if a session was invalidated, false if there was no session
+    /// to invalidate.
+    pub async fn invalidate_group_session(&mut self, room_id: &RoomId) -> bool {
+        let invalidated = self.outbound_group_sessions.remove(room_id).is_some();
+
+        if invalidated {
+            if let Err(e) = self.store.delete_outbound_group_session(room_id).await {
+                error!(
+                    "Failed to remove the outbound group session of {} from the store {:?}",
+                    room_id, e
+                );
+            }
+        }
+
+        invalidated
+    }
+
+    // TODO accept an algorithm here
+    /// Get to-device requests to share a group session with users in a room.
+    ///
+    /// # Arguments
+    ///
+    /// `room_id` - The room id of the room where the group session will be
+    /// used.
+    ///
+    /// `users` - The list of users that should receive the group session.
+    ///
+    /// `settings` - The encryption settings of the room, these decide when
+    /// the group session will need to be rotated.
+    pub async fn share_group_session<'a, I>(
+        &mut self,
+        room_id: &RoomId,
+        users: I,
+        settings: EncryptionSettings,
+    ) -> OlmResult<Vec<ToDeviceRequest>>
+    where
+        I: IntoIterator<Item = &'a UserId>,
+    {
+        let mut user_map = Vec::new();
+        let mut unverified_devices = Vec::new();
+        let mut withheld_devices = Vec::new();
+        let mut no_olm_devices = Vec::new();
+
+        for user_id in users {
+            for device in self.store.get_user_devices(user_id).await?.devices() {
+                let trust_state = self.device_trust_state(device).await?;
+
+                match (trust_state, self.group_session_share_policy) {
+                    (TrustState::BlackListed, _) => {
+                        debug!(
+                            "Not sharing the room key with the blacklisted device {} {}",
+                            user_id,
+                            device.device_id()
+                        );
+                        withheld_devices.push((device.clone(), WithheldCode::Blacklisted));
+                        continue;
+                    }
+                    (TrustState::Verified, _) | (_, GroupSessionSharePolicy::AllDevices) => (),
+                    (_, GroupSessionSharePolicy::VerifiedOnly) => {
+                        debug!(
+                            "Not sharing the room key with the unverified device {} {}",
+                            user_id,
+                            device.device_id()
+                        );
+                        withheld_devices.push((device.clone(), WithheldCode::Unverified));
+                        continue;
+                    }
+                    (TrustState::Unset, GroupSessionSharePolicy::FailOnUnverified) => {
+                        unverified_devices.push((user_id.clone(), device.device_id().clone()));
+                        continue;
+                    }
+                    (TrustState::Ignored, GroupSessionSharePolicy::FailOnUnverified) => (),
+                }
+
+                let sender_key = if let Some(k) = device.get_key(KeyAlgorithm::Curve25519) {
                     k
                 } else {
                     warn!(
@@ -1274,12 +2013,10 @@ impl OlmMachine {
                     continue;
                 };
 
-                // TODO abort if the device isn't verified
-                let sessions = self.store.get_sessions(sender_key).await?;
+                let session = self.get_newest_session(sender_key).await?;
 
-                if let Some(s) = sessions {
-                    let session = &s.lock().await[0];
-                    user_map.push((session.clone(), device.clone()));
+                if let Some(session) = session {
+                    user_map.push((session, device.clone()));
                 } else {
                     warn!(
                         "Trying to encrypt a Megolm session for user
@@ -1287,10 +2024,42 @@ impl OlmMachine {
                         user_id,
                         device.device_id()
                     );
+
+                    let device_key = (user_id.clone(), device.device_id().clone());
+
+                    // Devices only need to be told once that we can't
+                    // establish an Olm session with them, they are only
+                    // remembered once the notice is part of the requests.
+                    if !self.no_olm_sent.contains(&device_key) {
+                        no_olm_devices.push(device_key);
+                        withheld_devices.push((device.clone(), WithheldCode::NoOlm));
+                    }
                 }
             }
         }
 
+        if !unverified_devices.is_empty() {
+            return Err(OlmError::UnverifiedDevices(unverified_devices));
+        }
+
+        self.create_outbound_group_session(room_id, settings).await?;
+        let megolm_session = self.outbound_group_sessions.get(room_id).unwrap().clone();
+
+        if megolm_session.shared() {
+            panic!("Session is already shared");
+        }
+
+        let session_id = megolm_session.session_id().to_owned();
+        megolm_session.mark_as_shared();
+
+        let key_content = json!({
+            "algorithm": Algorithm::MegolmV1AesSha2,
+            "room_id": room_id,
+            "session_id": session_id.clone(),
+            "session_key": megolm_session.session_key().await,
+            "chain_index": megolm_session.message_index().await,
+        });
+
         let mut message_vec = Vec::new();
 
         for user_map_chunk in user_map.chunks(OlmMachine::MAX_TO_DEVICE_MESSAGES) {
@@ -1318,6 +2087,10 @@ impl OlmMachine {
                     DeviceIdOrAllDevices::DeviceId(device.device_id().clone()),
                     serde_json::value::to_raw_value(&encrypted_content)?,
                 );
+
+                megolm_session
+                    .mark_shared_with(device.user_id(), device.device_id())
+                    .await;
             }
 
             message_vec.push(ToDeviceRequest {
@@ -1327,373 +2100,3458 @@ impl OlmMachine {
             });
         }
 
+        let sender_key = self.account.identity_keys().curve25519().to_owned();
+
+        for withheld_chunk in withheld_devices.chunks(OlmMachine::MAX_TO_DEVICE_MESSAGES) {
+            let mut messages = BTreeMap::new();
+
+            for (device, code) in withheld_chunk {
+                let content = RoomKeyWithheldContent::new(room_id, &session_id, &sender_key, *code);
+
+                messages
+                    .entry(device.user_id().clone())
+                    .or_insert_with(BTreeMap::new)
+                    .insert(
+                        DeviceIdOrAllDevices::DeviceId(device.device_id().clone()),
+                        serde_json::value::to_raw_value(&content)?,
+                    );
+            }
+
+            message_vec.push(ToDeviceRequest {
+                event_type: withheld_event_type(),
+                txn_id: Uuid::new_v4().to_string(),
+                messages,
+            });
+        }
+
+        self.store
+            .save_outbound_group_session(megolm_session)
+            .await?;
+
+        self.no_olm_sent.extend(no_olm_devices);
+
         Ok(message_vec)
     }
 
-    fn add_forwarded_room_key(
-        &self,
-        _sender_key: &str,
-        _signing_key: &str,
-        _event: &ToDeviceForwardedRoomKey,
-    ) -> OlmResult<()> {
+    /// Remember that a group session was withheld from us.
+    ///
+    /// The event is ignored if the sender key of the session doesn't belong to
+    /// one of the devices of the sender.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The `m.room_key.withheld` event.
+    async fn receive_room_key_withheld(
+        &mut self,
+        event: ToDeviceRoomKeyWithheld,
+    ) -> StoreError<()> {
+        let content = event.content;
+
+        let session_id = if let Some(s) = &content.session_id {
+            s.clone()
+        } else {
+            // Without a session id the event only tells us that the sender
+            // can't establish an Olm session with us, nothing to remember.
+            debug!(
+                "{} can't establish an Olm session with us: {}",
+                event.sender, content.code
+            );
+            return Ok(());
+        };
+
+        let devices = self.store.get_user_devices(&event.sender).await?;
+        let owns_sender_key = devices.devices().any(|d| {
+            d.get_key(KeyAlgorithm::Curve25519)
+                .map_or(false, |k| k == &content.sender_key)
+        });
+
+        if !owns_sender_key {
+            warn!(
+                "Received a withheld notice for the group session {} from {}, \
+                 but the sender key {} doesn't belong to any of their devices",
+                session_id, event.sender, content.sender_key
+            );
+            return Ok(());
+        }
+
+        info!(
+            "The group session {} was withheld by {} with the code {}",
+            session_id, event.sender, content.code
+        );
+
+        self.withheld_sessions
+            .insert((content.sender_key.clone(), session_id), content);
+
         Ok(())
-        // TODO
     }
 
-    /// Receive and properly handle a decrypted to-device event.
+    /// Import a forwarded room key that another device sent us.
     ///
-    /// # Arguments
+    /// The key is only accepted if we requested it, the matching outgoing key
+    /// request will be cancelled.
     ///
-    /// * `sender_key` - The sender (curve25519) key of the event sender.
+    /// Returns the event with the private key removed, whether the key was
+    /// accepted or not.
     ///
-    /// * `signing_key` - The signing (ed25519) key of the event sender.
+    /// # Arguments
     ///
-    /// * `event` - The decrypted to-device event.
-    async fn handle_decrypted_to_device_event(
+    /// * `sender_key` - The sender (curve25519) key of the device that
+    /// forwarded the room key.
+    ///
+    /// * `event` - The decrypted `m.forwarded_room_key` event.
+    async fn add_forwarded_room_key(
         &mut self,
         sender_key: &str,
-        signing_key: &str,
-        event: &EventJson<ToDeviceEvent>,
+        event: &mut ToDeviceForwardedRoomKey,
     ) -> OlmResult<Option<EventJson<ToDeviceEvent>>> {
-        let event = if let Ok(e) = event.deserialize() {
-            e
+        let session_key = GroupSessionKey(mem::take(&mut event.content.session_key));
+        self.import_forwarded_room_key(sender_key, event, session_key)
+            .await?;
+
+        Ok(Some(OlmMachine::rewrap_to_device_event(
+            event,
+            EventType::ForwardedRoomKey,
+        )?))
+    }
+
+    async fn import_forwarded_room_key(
+        &mut self,
+        sender_key: &str,
+        event: &ToDeviceForwardedRoomKey,
+        session_key: GroupSessionKey,
+    ) -> OlmResult<()> {
+        let content = &event.content;
+
+        if content.algorithm != Algorithm::MegolmV1AesSha2 {
+            warn!(
+                "Received a forwarded room key with unsupported key algorithm {}",
+                content.algorithm
+            );
+            return Ok(());
+        }
+
+        if !self
+            .is_trusted_key_forwarder(&event.sender, sender_key, &content.sender_key)
+            .await?
+        {
+            warn!(
+                "Received a forwarded room key for the session {} from an untrusted device {} {}",
+                content.session_id, event.sender, sender_key
+            );
+            return Ok(());
+        }
+
+        let requested_session = RequestedSession {
+            room_id: content.room_id.clone(),
+            sender_key: content.sender_key.clone(),
+            session_id: content.session_id.clone(),
+        };
+
+        let request = if let Some(r) = self.outgoing_key_requests.remove(&requested_session) {
+            r
         } else {
-            warn!("Decrypted to-device event failed to be parsed correctly");
-            return Ok(None);
+            warn!(
+                "Received a forwarded room key from {} for the session {} that we didn't request",
+                event.sender, content.session_id
+            );
+            return Ok(());
         };
 
-        match event {
-            ToDeviceEvent::RoomKey(mut e) => {
-                Ok(self.add_room_key(sender_key, signing_key, &mut e).await?)
-            }
-            ToDeviceEvent::ForwardedRoomKey(e) => {
-                self.add_forwarded_room_key(sender_key, signing_key, &e)?;
-                Ok(None)
-            }
-            _ => {
-                warn!("Received a unexpected encrypted to-device event");
-                Ok(None)
-            }
+        let mut forwarding_chain = content.forwarding_curve25519_key_chain.clone();
+        forwarding_chain.push(sender_key.to_owned());
+
+        let session = InboundGroupSession::from_export(
+            &content.sender_key,
+            &content.sender_claimed_ed25519_key,
+            &content.room_id,
+            session_key,
+            forwarding_chain,
+        )?;
+
+        if session.session_id() != content.session_id {
+            warn!(
+                "Received a forwarded room key with a mismatched session id {} from {}",
+                content.session_id, event.sender
+            );
+            self.outgoing_key_requests.insert(requested_session, request);
+            return Ok(());
         }
-    }
 
-    fn handle_room_key_request(&self, _: &ToDeviceRoomKeyRequest) {
-        // TODO handle room key requests here.
-    }
+        info!(
+            "Received a forwarded room key for the session {} from {}",
+            content.session_id, event.sender
+        );
+
+        if self.store.save_inbound_group_session(session).await? {
+            self.received_group_sessions
+                .insert(content.session_id.clone());
+        }
+
+        let cancellation = self.key_request_to_device_request(
+            &request,
+            request.cancellation_content(&self.device_id),
+        )?;
+        self.outgoing_to_device_messages
+            .insert(cancellation.txn_id.clone(), cancellation);
 
-    fn handle_verification_event(&self, _: &ToDeviceEvent) {
-        // TODO handle to-device verification events here.
+        Ok(())
     }
 
-    /// Handle a sync response and update the internal state of the Olm machine.
-    ///
-    /// This will decrypt to-device events but will not touch events in the room
-    /// timeline.
+    /// Check if we accept forwarded room keys from the given device.
     ///
-    /// To decrypt an event from the room timeline call `decrypt_room_event()`.
+    /// Keys are only accepted from the device that created the session or
+    /// from our own verified devices, anybody else could forward us a key
+    /// claiming to belong to a different sender.
     ///
     /// # Arguments
     ///
-    /// * `response` - The sync latest sync response.
-    #[instrument(skip(response))]
-    pub async fn receive_sync_response(&mut self, response: &mut SyncResponse) {
-        let one_time_key_count = response
-            .device_one_time_keys_count
-            .get(&keys::KeyAlgorithm::SignedCurve25519);
+    /// * `sender` - The user that forwarded the key.
+    ///
+    /// * `sender_key` - The curve25519 key of the device that forwarded the
+    /// key.
+    ///
+    /// * `session_sender_key` - The curve25519 key of the device that created
+    /// the session.
+    async fn is_trusted_key_forwarder(
+        &self,
+        sender: &UserId,
+        sender_key: &str,
+        session_sender_key: &str,
+    ) -> StoreError<bool> {
+        if sender_key == session_sender_key {
+            return Ok(true);
+        }
 
-        let count: u64 = one_time_key_count.map_or(0, |c| (*c).into());
-        self.update_key_count(count);
+        if sender != &self.user_id {
+            return Ok(false);
+        }
 
-        for event_result in &mut response.to_device.events {
-            let event = if let Ok(e) = event_result.deserialize() {
-                e
-            } else {
-                // Skip invalid events.
-                warn!("Received an invalid to-device event {:?}", event_result);
-                continue;
-            };
+        let own_devices = self.store.get_user_devices(&self.user_id).await?;
 
-            info!("Received a to-device event {:?}", event);
+        for device in own_devices.devices() {
+            if device.get_key(KeyAlgorithm::Curve25519).map(|k| k.as_str()) == Some(sender_key) {
+                return Ok(self.device_trust_state(device).await? == TrustState::Verified);
+            }
+        }
 
-            match &event {
-                ToDeviceEvent::RoomEncrypted(e) => {
-                    let decrypted_event = match self.decrypt_to_device_event(e).await {
-                        Ok(e) => e,
-                        Err(err) => {
-                            warn!(
-                                "Failed to decrypt to-device event from {} {}",
-                                e.sender, err
-                            );
-                            // TODO if the session is wedged mark it for
-                            // unwedging.
-                            continue;
-                        }
-                    };
-
-                    // TODO make sure private keys are cleared from the event
-                    // before we replace the result.
-                    *event_result = decrypted_event;
-                }
-                ToDeviceEvent::RoomKeyRequest(e) => self.handle_room_key_request(e),
-                ToDeviceEvent::KeyVerificationAccept(..)
-                | ToDeviceEvent::KeyVerificationCancel(..)
-                | ToDeviceEvent::KeyVerificationKey(..)
-                | ToDeviceEvent::KeyVerificationMac(..)
-                | ToDeviceEvent::KeyVerificationRequest(..)
-                | ToDeviceEvent::KeyVerificationStart(..) => self.handle_verification_event(&event),
-                _ => continue,
-            }
-        }
+        Ok(false)
     }
 
-    /// Decrypt an event from a room timeline.
+    /// Request the group session of an event we couldn't decrypt.
+    ///
+    /// The request is sent to all of our own devices and to the device that
+    /// sent the event. Only one request per session is sent out.
     ///
     /// # Arguments
     ///
-    /// * `event` - The event that should be decrypted.
-    pub async fn decrypt_room_event(
+    /// * `event` - The event that we couldn't decrypt.
+    ///
+    /// * `content` - The Megolm content of the event.
+    fn request_room_key(
         &mut self,
         event: &EncryptedEvent,
-    ) -> MegolmResult<EventJson<RoomEvent>> {
-        let content = match &event.content {
-            EncryptedEventContent::MegolmV1AesSha2(c) => c,
-            _ => return Err(EventError::UnsupportedAlgorithm.into()),
+        room_id: &RoomId,
+        content: &MegolmV1AesSha2Content,
+    ) -> OlmResult<()> {
+        let requested_session = RequestedSession {
+            room_id: room_id.clone(),
+            sender_key: content.sender_key.clone(),
+            session_id: content.session_id.clone(),
         };
 
-        let room_id = event.room_id.as_ref().unwrap();
+        if self.outgoing_key_requests.contains_key(&requested_session) {
+            return Ok(());
+        }
 
-        let session = self
-            .store
-            .get_inbound_group_session(&room_id, &content.sender_key, &content.session_id)
-            .await?;
-        // TODO check if the Olm session is wedged and re-request the key.
-        let session = session.ok_or(MegolmError::MissingSession)?;
+        let request = OutgoingKeyRequest {
+            request_id: Uuid::new_v4().to_string(),
+            sender: event.sender.clone(),
+            sender_device: content.device_id.clone(),
+        };
 
-        let (plaintext, _) = session.decrypt(content.ciphertext.clone()).await?;
-        // TODO check the message index.
-        // TODO check if this is from a verified device.
+        let to_device_request = self.key_request_to_device_request(
+            &request,
+            request.request_content(&requested_session, &self.device_id),
+        )?;
 
-        let mut decrypted_value = serde_json::from_str::<Value>(&plaintext)?;
-        let decrypted_object = decrypted_value
-            .as_object_mut()
-            .ok_or(EventError::NotAnObject)?;
+        info!(
+            "Requesting the missing session {} from {}",
+            content.session_id, event.sender
+        );
 
-        // TODO better number conversion here.
-        let server_ts = event
-            .origin_server_ts
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_millis();
-        let server_ts: i64 = server_ts.try_into().unwrap_or_default();
+        self.outgoing_to_device_messages
+            .insert(to_device_request.txn_id.clone(), to_device_request);
+        self.outgoing_key_requests.insert(requested_session, request);
 
-        decrypted_object.insert("sender".to_owned(), event.sender.to_string().into());
-        decrypted_object.insert("event_id".to_owned(), event.event_id.to_string().into());
-        decrypted_object.insert("origin_server_ts".to_owned(), server_ts.into());
+        Ok(())
+    }
 
-        decrypted_object.insert(
-            "unsigned".to_owned(),
-            serde_json::to_value(&event.unsigned).unwrap_or_default(),
-        );
+    /// Create a to-device request carrying the given `m.room_key_request`
+    /// content.
+    ///
+    /// The request is addressed to all of our own devices and to the device
+    /// that sent us the undecryptable event.
+    fn key_request_to_device_request(
+        &self,
+        request: &OutgoingKeyRequest,
+        content: Value,
+    ) -> OlmResult<ToDeviceRequest> {
+        let content = serde_json::value::to_raw_value(&content)?;
+        let mut messages = BTreeMap::new();
+
+        let mut own_messages = BTreeMap::new();
+        own_messages.insert(DeviceIdOrAllDevices::AllDevices, content.clone());
+        messages.insert(self.user_id.clone(), own_messages);
+
+        if request.sender != self.user_id {
+            let mut sender_messages = BTreeMap::new();
+            sender_messages.insert(
+                DeviceIdOrAllDevices::DeviceId(request.sender_device.clone()),
+                content,
+            );
+            messages.insert(request.sender.clone(), sender_messages);
+        }
 
-        let decrypted_event = serde_json::from_value::<EventJson<RoomEvent>>(decrypted_value)?;
-        trace!("Successfully decrypted Megolm event {:?}", decrypted_event);
-        // TODO set the encryption info on the event (is it verified, was it
-        // decrypted, sender key...)
+        Ok(ToDeviceRequest {
+            event_type: EventType::RoomKeyRequest,
+            txn_id: Uuid::new_v4().to_string(),
+            messages,
+        })
+    }
 
-        Ok(decrypted_event)
+    /// Get the ids of the group sessions we received since the last call of
+    /// this method.
+    ///
+    /// Events that couldn't be decrypted because one of those sessions was
+    /// missing should be decrypted again.
+    pub fn take_received_group_sessions(&mut self) -> HashSet<String> {
+        mem::take(&mut self.received_group_sessions)
     }
 
-    /// Mark that the given user has changed his devices.
+    /// Enable the backup of our room keys to the given backup version.
     ///
-    /// This will queue up the given user for a key query.
+    /// The backup version should be checked using `verify_backup()` before it
+    /// gets enabled.
     ///
-    /// Note: The user already needs to be tracked for it to be queued up for a
-    /// key query.
+    /// # Arguments
     ///
-    /// Returns true if the user was queued up for a key query, false otherwise.
-    pub async fn mark_user_as_changed(&mut self, user_id: &UserId) -> StoreError<bool> {
-        if self.store.tracked_users().contains(user_id) {
-            self.store.update_tracked_user(user_id, true).await?;
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+    /// * `key` - The public key of the backup.
+    ///
+    /// * `version` - The version of the backup on the server.
+    pub fn enable_backup(&mut self, key: MegolmV1BackupKey, version: String) {
+        self.backup = Some((key, version));
     }
 
-    /// Update the tracked users.
+    /// Disable the backup of our room keys.
+    pub fn disable_backup(&mut self) {
+        self.backup = None;
+    }
+
+    /// Get the version of the currently enabled room key backup.
+    pub fn backup_version(&self) -> Option<&str> {
+        self.backup.as_ref().map(|(_, version)| version.as_str())
+    }
+
+    /// Get the signed algorithm and auth data for a new backup version.
+    ///
+    /// The returned value is the body of a `POST /room_keys/version` request.
     ///
     /// # Arguments
     ///
-    /// * `users` - An iterator over user ids that should be marked for
-    /// tracking.
+    /// * `key` - The public key of the new backup.
+    pub async fn backup_auth_data(&self, key: &MegolmV1BackupKey) -> Value {
+        let mut auth_data = json!({
+            "public_key": key.to_base64(),
+        });
+
+        let signature = self.sign_json(&auth_data).await;
+        let key_id = format!("{}:{}", KeyAlgorithm::Ed25519, self.device_id);
+
+        auth_data.as_object_mut().unwrap().insert(
+            "signatures".to_owned(),
+            json!({ self.user_id.to_string(): { key_id: signature } }),
+        );
+
+        json!({
+            "algorithm": BACKUP_ALGORITHM,
+            "auth_data": auth_data,
+        })
+    }
+
+    /// Check if the given backup version can be trusted.
     ///
-    /// This will mark users that weren't seen before for a key query and
-    /// tracking.
+    /// A backup is trusted if its auth data is signed by our own device or by
+    /// one of our other devices that we have verified.
     ///
-    /// If the user is already known to the Olm machine it will not be
-    /// considered for a key query.
+    /// # Arguments
     ///
-    /// Use the `mark_user_as_changed()` if the user really needs a key query.
-    pub async fn update_tracked_users<'a, I>(&mut self, users: I)
-    where
-        I: IntoIterator<Item = &'a UserId>,
-    {
-        for user in users {
-            if self.store.tracked_users().contains(user) {
-                continue;
-            }
+    /// * `backup` - The backup version that should be checked.
+    pub async fn verify_backup(&self, backup: &BackupVersion) -> bool {
+        if backup.algorithm != BACKUP_ALGORITHM {
+            return false;
+        }
 
-            if let Err(e) = self.store.update_tracked_user(user, true).await {
-                warn!("Error storing users for tracking {}", e);
+        let signatures = if let Some(s) = backup.auth_data.signatures.get(&self.user_id) {
+            s
+        } else {
+            return false;
+        };
+
+        let mut auth_data = if let Ok(a) = serde_json::to_value(&backup.auth_data) {
+            a
+        } else {
+            return false;
+        };
+
+        for key_id in signatures.keys() {
+            let mut split = key_id.splitn(2, ':');
+
+            let device_id = match (split.next(), split.next()) {
+                (Some(algorithm), Some(device_id))
+                    if algorithm == KeyAlgorithm::Ed25519.to_string() =>
+                {
+                    device_id.to_owned()
+                }
+                _ => continue,
+            };
+
+            let key = if device_id == self.device_id {
+                self.account.identity_keys().ed25519().to_owned()
+            } else {
+                let device = match self.store.get_device(&self.user_id, &device_id).await {
+                    Ok(Some(d)) => d,
+                    _ => continue,
+                };
+
+                match (
+                    self.device_trust_state(&device).await,
+                    device.get_key(KeyAlgorithm::Ed25519),
+                ) {
+                    (Ok(TrustState::Verified), Some(k)) => k.to_owned(),
+                    _ => continue,
+                }
+            };
+
+            if self
+                .verify_json(&self.user_id, &device_id, &key, &mut auth_data)
+                .is_ok()
+            {
+                return true;
             }
         }
+
+        false
     }
 
-    /// Should the client perform a key query request.
-    pub fn should_query_keys(&self) -> bool {
-        !self.store.users_for_key_query().is_empty()
+    /// Get a batch of room keys that need to be uploaded to the currently
+    /// enabled backup.
+    ///
+    /// Returns None if no backup is enabled or if all of our room keys are
+    /// already backed up.
+    pub async fn backup_request(&mut self) -> OlmResult<Option<KeysBackupRequest>> {
+        let (key, version) = if let Some(b) = &self.backup {
+            b
+        } else {
+            return Ok(None);
+        };
+
+        let sessions = self
+            .store
+            .inbound_group_sessions_for_backup(version, OlmMachine::BACKUP_BATCH_SIZE)
+            .await?;
+
+        if sessions.is_empty() {
+            return Ok(None);
+        }
+
+        let mut rooms: BTreeMap<RoomId, RoomKeyBackup> = BTreeMap::new();
+
+        for session in &sessions {
+            let backed_up = key.encrypt(session).await;
+
+            rooms
+                .entry((&*session.room_id).clone())
+                .or_default()
+                .sessions
+                .insert(session.session_id().to_owned(), backed_up);
+        }
+
+        Ok(Some(KeysBackupRequest {
+            version: version.clone(),
+            rooms,
+            sessions,
+        }))
     }
 
-    /// Get the set of users that we need to query keys for.
+    /// Mark the room keys of the given backup request as uploaded.
     ///
-    /// Returns a hash set of users that need to be queried for keys.
-    pub fn users_for_key_query(&self) -> HashSet<UserId> {
-        self.store.users_for_key_query().clone()
+    /// # Arguments
+    ///
+    /// * `request` - The backup request that was successfully sent out.
+    pub async fn mark_backup_as_sent(&mut self, request: &KeysBackupRequest) -> OlmResult<()> {
+        self.store
+            .mark_inbound_group_sessions_as_backed_up(&request.version, &request.sessions)
+            .await?;
+
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod test {
-    static USER_ID: &str = "@bob:example.org";
-    static DEVICE_ID: &str = "DEVICEID";
+    /// Import room keys that were downloaded from a backup.
+    ///
+    /// Room keys that we already know about are only replaced if the backed up
+    /// copy can decrypt older messages. Room keys that can't be decrypted are
+    /// skipped.
+    ///
+    /// Returns the number of room keys that were imported.
+    ///
+    /// # Arguments
+    ///
+    /// * `recovery_key` - The recovery key of the backup.
+    ///
+    /// * `version` - The version of the backup the room keys were downloaded
+    /// from.
+    ///
+    /// * `rooms` - The backed up room keys, grouped by room.
+    pub async fn import_backed_up_room_keys(
+        &mut self,
+        recovery_key: &RecoveryKey,
+        version: &str,
+        rooms: BTreeMap<RoomId, RoomKeyBackup>,
+    ) -> OlmResult<usize> {
+        let mut sessions = Vec::new();
+
+        for (room_id, room_backup) in rooms {
+            for (session_id, backed_up) in room_backup.sessions {
+                match recovery_key
+                    .decrypt(&backed_up.session_data)
+                    .and_then(|s| s.into_session(&room_id))
+                {
+                    Ok(s) => sessions.push(s),
+                    Err(e) => warn!(
+                        "Failed to decrypt the backed up room key {} of room {}: {}",
+                        session_id, room_id, e
+                    ),
+                }
+            }
+        }
 
-    use matrix_sdk_common::js_int::UInt;
-    use std::collections::BTreeMap;
-    use std::convert::TryFrom;
-    use std::fs::File;
-    use std::io::prelude::*;
-    use std::sync::atomic::AtomicU64;
-    use std::time::SystemTime;
+        let imported = self.import_group_sessions(sessions).await?;
 
-    use http::Response;
-    use serde_json::json;
+        // The imported room keys are already in the backup, there's no need
+        // to upload them again.
+        self.store
+            .mark_inbound_group_sessions_as_backed_up(version, &imported)
+            .await?;
 
-    use crate::machine::{OlmMachine, OneTimeKeys};
-    use crate::Device;
+        Ok(imported.len())
+    }
 
-    use matrix_sdk_common::api::r0::{
-        keys, to_device::send_event_to_device::Request as ToDeviceRequest,
-    };
-    use matrix_sdk_common::events::{
-        collections::all::RoomEvent,
-        room::{
-            encrypted::{EncryptedEvent, EncryptedEventContent},
-            message::{MessageEventContent, TextMessageEventContent},
-        },
-        to_device::{AnyToDeviceEvent, ToDeviceEncrypted},
-        EventJson, EventType, UnsignedData,
-    };
-    use matrix_sdk_common::identifiers::{DeviceId, EventId, RoomId, UserId};
+    /// Export room keys into a passphrase protected key export.
+    ///
+    /// The export uses the `-----BEGIN MEGOLM SESSION DATA-----` format that
+    /// other Matrix clients can import.
+    ///
+    /// # Arguments
+    ///
+    /// * `predicate` - Closure deciding which room keys should be exported.
+    ///
+    /// * `passphrase` - The passphrase that protects the export.
+    ///
+    /// * `rounds` - The number of PBKDF2 rounds used to derive the encryption
+    /// key from the passphrase, `DEFAULT_EXPORT_ROUNDS` is a sensible
+    /// default.
+    pub async fn export_keys(
+        &self,
+        mut predicate: impl FnMut(&InboundGroupSession) -> bool,
+        passphrase: &str,
+        rounds: u32,
+    ) -> OlmResult<String> {
+        let mut keys = Vec::new();
+
+        for session in self.store.get_inbound_group_sessions().await? {
+            if predicate(&session) {
+                keys.push(ExportedRoomKey::from_session(&session).await);
+            }
+        }
 
-    fn alice_id() -> UserId {
-        UserId::try_from("@alice:example.org").unwrap()
+        Ok(encrypt_key_export(&keys, passphrase, rounds)?)
     }
 
-    fn alice_device_id() -> DeviceId {
-        "JLAFKJWSCS".to_string()
+    /// Import room keys from a passphrase protected key export.
+    ///
+    /// Room keys that we already know about are only replaced if the imported
+    /// copy can decrypt older messages.
+    ///
+    /// Returns the number of room keys that were imported.
+    ///
+    /// # Arguments
+    ///
+    /// * `export` - The key export, including the header and footer lines.
+    ///
+    /// * `passphrase` - The passphrase that protects the export.
+    pub async fn import_keys(&mut self, export: &str, passphrase: &str) -> OlmResult<usize> {
+        let mut sessions = Vec::new();
+
+        for key in decrypt_key_export(export, passphrase)? {
+            if key.algorithm != Algorithm::MegolmV1AesSha2 {
+                warn!(
+                    "Not importing room key {} with unsupported algorithm {}",
+                    key.session_id, key.algorithm
+                );
+                continue;
+            }
+
+            match key.to_session() {
+                Ok(s) => sessions.push(s),
+                Err(e) => warn!("Failed to import room key {}: {}", key.session_id, e),
+            }
+        }
+
+        Ok(self.import_group_sessions(sessions).await?.len())
     }
 
-    fn user_id() -> UserId {
-        UserId::try_from(USER_ID).unwrap()
+    /// Store group sessions that were imported from a backup or a key export.
+    ///
+    /// Sessions that we already know about are only replaced if the imported
+    /// copy can decrypt older messages.
+    ///
+    /// Returns the sessions that were stored.
+    async fn import_group_sessions(
+        &mut self,
+        sessions: Vec<InboundGroupSession>,
+    ) -> OlmResult<Vec<InboundGroupSession>> {
+        let mut imported = Vec::new();
+
+        for session in sessions {
+            if let Some(existing) = self
+                .store
+                .get_inbound_group_session(
+                    &session.room_id,
+                    &session.sender_key,
+                    session.session_id(),
+                )
+                .await?
+            {
+                if existing.first_known_index().await <= session.first_known_index().await {
+                    continue;
+                }
+            }
+
+            let _ = self
+                .store
+                .save_inbound_group_session(session.clone())
+                .await?;
+            self.received_group_sessions
+                .insert(session.session_id().to_owned());
+            imported.push(session);
+        }
+
+        Ok(imported)
     }
 
-    fn response_from_file(path: &str) -> Response<Vec<u8>> {
-        let mut file = File::open(path)
-            .unwrap_or_else(|_| panic!(format!("No such data file found {}", path)));
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents)
-            .unwrap_or_else(|_| panic!(format!("Can't read data file {}", path)));
+    /// Receive and properly handle a decrypted to-device event.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender_key` - The sender (curve25519) key of the event sender.
+    ///
+    /// * `signing_key` - The signing (ed25519) key of the event sender.
+    ///
+    /// * `event` - The decrypted to-device event.
+    async fn handle_decrypted_to_device_event(
+        &mut self,
+        sender_key: &str,
+        signing_key: &str,
+        event: &EventJson<ToDeviceEvent>,
+    ) -> OlmResult<Option<EventJson<ToDeviceEvent>>> {
+        // Passing an unparsed event on could leak the private keys it
+        // contains, so refuse it.
+        let event = if let Ok(e) = event.deserialize() {
+            e
+        } else {
+            warn!("Decrypted to-device event failed to be parsed correctly");
+            return Err(EventError::InvalidDecryptedEvent.into());
+        };
+
+        match event {
+            ToDeviceEvent::RoomKey(mut e) => {
+                Ok(self.add_room_key(sender_key, signing_key, &mut e).await?)
+            }
+            ToDeviceEvent::ForwardedRoomKey(mut e) => {
+                Ok(self.add_forwarded_room_key(sender_key, &mut e).await?)
+            }
+            _ => {
+                warn!("Received a unexpected encrypted to-device event");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Answer a room key request of another device.
+    ///
+    /// If we have the requested session and the key share policy allows it the
+    /// session will be forwarded to the requesting device, the forwarded room
+    /// key will be queued up as a to-device request.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The `m.room_key_request` event that the other device sent.
+    async fn handle_room_key_request(&mut self, event: &ToDeviceRoomKeyRequest) -> OlmResult<()> {
+        let content = &event.content;
+
+        if content.action != Action::Request {
+            // We answer requests immediately, there's nothing to cancel.
+            return Ok(());
+        }
+
+        if event.sender == self.user_id && content.requesting_device_id == self.device_id {
+            trace!("Ignoring a room key request from our own device");
+            return Ok(());
+        }
+
+        let key_info = if let Some(b) = &content.body {
+            b
+        } else {
+            warn!("Received a room key request without a body {:?}", event);
+            return Ok(());
+        };
+
+        let session = if let Some(s) = self
+            .store
+            .get_inbound_group_session(&key_info.room_id, &key_info.sender_key, &key_info.session_id)
+            .await?
+        {
+            s
+        } else {
+            debug!(
+                "Received a room key request for an unknown session {} from {} {}",
+                key_info.session_id, event.sender, content.requesting_device_id
+            );
+            return Ok(());
+        };
+
+        let device = if let Some(d) = self
+            .store
+            .get_device(&event.sender, &content.requesting_device_id)
+            .await?
+        {
+            d
+        } else {
+            warn!(
+                "Received a room key request from an unknown device {} {}",
+                event.sender, content.requesting_device_id
+            );
+            return Ok(());
+        };
+
+        if !self
+            .key_share_policy
+            .should_share(&self.user_id, &device, &session)
+        {
+            info!(
+                "Refusing to share the session {} with {} {}",
+                session.session_id(),
+                device.user_id(),
+                device.device_id()
+            );
+            return Ok(());
+        }
+
+        let sender_key = device
+            .get_key(KeyAlgorithm::Curve25519)
+            .ok_or(EventError::MissingSigningKey)?;
+
+        let olm_session = if let Some(s) = self.get_newest_session(sender_key).await? {
+            s
+        } else {
+            info!(
+                "Trying to forward a room key to {} {}, but no Olm session is found, claiming a one-time key",
+                device.user_id(),
+                device.device_id()
+            );
+
+            let pending_requests = self
+                .pending_key_requests
+                .entry((device.user_id().clone(), device.device_id().clone()))
+                .or_insert_with(Vec::new);
+
+            if !pending_requests
+                .iter()
+                .any(|r| r.content.request_id == content.request_id)
+            {
+                pending_requests.push(event.clone());
+            }
+
+            let mut devices = BTreeMap::new();
+            devices.insert(device.device_id().clone(), KeyAlgorithm::SignedCurve25519);
+            let mut missing = BTreeMap::new();
+            missing.insert(device.user_id().clone(), devices);
+            self.queue_key_claim(missing);
+
+            return Ok(());
+        };
+
+        let key_content = json!({
+            "algorithm": Algorithm::MegolmV1AesSha2,
+            "room_id": &*session.room_id,
+            "sender_key": &*session.sender_key,
+            "session_id": session.session_id(),
+            "session_key": session.export().await,
+            "sender_claimed_ed25519_key": &*session.signing_key,
+            "forwarding_curve25519_key_chain": session.forwarding_chain().await,
+        });
+
+        let encrypted_content = self
+            .olm_encrypt(
+                olm_session,
+                &device,
+                EventType::ForwardedRoomKey,
+                key_content,
+            )
+            .await?;
+
+        let request = self.olm_content_to_request(&device, encrypted_content)?;
+
+        info!(
+            "Forwarding the session {} to {} {}",
+            session.session_id(),
+            device.user_id(),
+            device.device_id()
+        );
+
+        self.outgoing_to_device_messages
+            .insert(request.txn_id.clone(), request);
+
+        Ok(())
+    }
+
+    async fn handle_verification_event(&self, event: &ToDeviceEvent) {
+        if let Err(e) = self
+            .verification_machine
+            .receive_event(&*self.store, event)
+            .await
+        {
+            error!("Error handling a verification event {:?}", e);
+        }
+    }
+
+    /// Get a specific device of an user.
+    ///
+    /// Returns None if the device is unknown.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the device belongs to.
+    ///
+    /// * `device_id` - The unique id of the device.
+    #[allow(clippy::ptr_arg)]
+    pub async fn get_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> StoreError<Option<Device>> {
+        self.store.get_device(user_id, device_id).await
+    }
+
+    /// Set the trust state of a device and persist it in the store.
+    ///
+    /// Blacklisting a device invalidates all of our outbound group sessions,
+    /// so the device won't be able to decrypt any messages we send after
+    /// this.
+    ///
+    /// Returns false if the device is unknown, true otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the device belongs to.
+    ///
+    /// * `device_id` - The unique id of the device.
+    ///
+    /// * `trust_state` - The new trust state of the device.
+    #[allow(clippy::ptr_arg)]
+    pub async fn set_device_trust_state(
+        &mut self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        trust_state: TrustState,
+    ) -> StoreError<bool> {
+        let device = if let Some(d) = self.store.get_device(user_id, device_id).await? {
+            d
+        } else {
+            return Ok(false);
+        };
+
+        device.set_trust_state(trust_state);
+        self.store.save_devices(&[device]).await?;
+
+        if trust_state == TrustState::BlackListed {
+            for (room_id, _) in self.outbound_group_sessions.drain() {
+                self.store.delete_outbound_group_session(&room_id).await?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Get the trust state of a device, taking cross signing into account.
+    ///
+    /// Devices with an unset trust state are considered to be verified if
+    /// they are signed by the verified cross signing identity of their owner.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device whose trust state should be checked.
+    pub async fn device_trust_state(&self, device: &Device) -> StoreError<TrustState> {
+        let trust_state = device.trust_state();
+
+        if trust_state != TrustState::Unset {
+            return Ok(trust_state);
+        }
+
+        let identity = if let Some(i) = self.store.get_user_identity(device.user_id()).await? {
+            i
+        } else {
+            return Ok(trust_state);
+        };
+
+        if self.is_device_signed(&identity, device) && self.is_identity_verified(&identity).await? {
+            Ok(TrustState::Verified)
+        } else {
+            Ok(trust_state)
+        }
+    }
+
+    /// Is the device signed by the self-signing key of the given identity.
+    fn is_device_signed(&self, identity: &UserIdentity, device: &Device) -> bool {
+        let self_signing_key = if let Some(k) = identity.self_signing_key().public_key() {
+            k
+        } else {
+            return false;
+        };
+
+        identity.user_id() == device.user_id()
+            && self
+                .verify_json(
+                    device.user_id(),
+                    self_signing_key,
+                    self_signing_key,
+                    &mut device.as_signed_json(),
+                )
+                .is_ok()
+    }
+
+    /// Check if the given cross signing identity is verified.
+    ///
+    /// Our own identity is verified if we hold its private keys or if its
+    /// master key is signed by one of our verified devices. The identity of
+    /// another user is verified if their master key is signed by our
+    /// user-signing key.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - The identity that should be checked.
+    pub async fn is_identity_verified(&self, identity: &UserIdentity) -> StoreError<bool> {
+        if identity.user_id() == &self.user_id {
+            return self.is_own_identity_verified(identity).await;
+        }
+
+        let own_identity = if let Some(i) = self.store.get_user_identity(&self.user_id).await? {
+            i
+        } else {
+            return Ok(false);
+        };
+
+        if !self.is_own_identity_verified(&own_identity).await? {
+            return Ok(false);
+        }
+
+        let user_signing_key =
+            if let Some(k) = own_identity.user_signing_key().and_then(|k| k.public_key()) {
+                k
+            } else {
+                return Ok(false);
+            };
+
+        Ok(self
+            .verify_json(
+                &self.user_id,
+                user_signing_key,
+                user_signing_key,
+                &mut json!(identity.master_key()),
+            )
+            .is_ok())
+    }
+
+    /// Check if our own cross signing identity is verified.
+    async fn is_own_identity_verified(&self, identity: &UserIdentity) -> StoreError<bool> {
+        let master_key = if let Some(k) = identity.master_key().public_key() {
+            k
+        } else {
+            return Ok(false);
+        };
+
+        if let Some(private_identity) = &self.cross_signing_identity {
+            if private_identity.master_public_key() == master_key {
+                return Ok(true);
+            }
+        }
+
+        let signatures = if let Some(s) = identity.master_key().signatures.get(&self.user_id) {
+            s
+        } else {
+            return Ok(false);
+        };
+
+        for key_id in signatures.keys() {
+            let mut split = key_id.splitn(2, ':');
+
+            let device_id = match (split.next(), split.next()) {
+                (Some(algorithm), Some(device_id))
+                    if algorithm == KeyAlgorithm::Ed25519.to_string() =>
+                {
+                    device_id.to_owned()
+                }
+                _ => continue,
+            };
+
+            let key = if device_id == self.device_id {
+                self.account.identity_keys().ed25519().to_owned()
+            } else {
+                match self.store.get_device(&self.user_id, &device_id).await? {
+                    Some(d) if d.trust_state() == TrustState::Verified => {
+                        if let Some(k) = d.get_key(KeyAlgorithm::Ed25519) {
+                            k.to_owned()
+                        } else {
+                            continue;
+                        }
+                    }
+                    _ => continue,
+                }
+            };
+
+            if self
+                .verify_json(
+                    &self.user_id,
+                    &device_id,
+                    &key,
+                    &mut json!(identity.master_key()),
+                )
+                .is_ok()
+            {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Get the cross signing identity of an user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the identity belongs to.
+    pub async fn get_user_identity(&self, user_id: &UserId) -> StoreError<Option<UserIdentity>> {
+        self.store.get_user_identity(user_id).await
+    }
+
+    /// Create our cross signing identity, if we don't have one yet, and
+    /// prepare it to be uploaded.
+    ///
+    /// Returns a request to upload the public parts of our cross signing keys
+    /// and the signatures that connect the identity with our device, the
+    /// signatures need to be uploaded after the keys.
+    ///
+    /// If we already have an identity the same keys are returned again, this
+    /// way a failed upload can be retried.
+    pub async fn bootstrap_cross_signing(
+        &mut self,
+    ) -> OlmResult<(CrossSigningUploadRequest, SignatureUploadRequest)> {
+        if self.cross_signing_identity.is_none() {
+            let identity = PrivateCrossSigningIdentity::new(self.user_id.clone());
+
+            self.store.save_private_identity(&identity).await?;
+            self.store
+                .save_user_identities(&[identity.public_identity()])
+                .await?;
+
+            self.cross_signing_identity = Some(identity);
+        }
+
+        let identity = self
+            .cross_signing_identity
+            .as_ref()
+            .expect("The cross signing identity was just created");
+        let upload_request = identity.upload_request();
+
+        // Sign our device with the self-signing key.
+        let mut device_keys = json!(self.device_keys().await);
+
+        if let Some(object) = device_keys.as_object_mut() {
+            object.remove("unsigned");
+        }
+
+        identity.sign_device(&mut device_keys);
+
+        // Sign the master key with our device, so our other devices can trust
+        // the identity once they verified this device.
+        let mut master_key = json!(&upload_request.master_key);
+        let signature = self.sign_json(&master_key).await;
+
+        let mut device_signature = BTreeMap::new();
+        device_signature.insert(
+            format!("{}:{}", KeyAlgorithm::Ed25519, self.device_id),
+            signature,
+        );
+
+        let mut master_key_signatures = BTreeMap::new();
+        master_key_signatures.insert(self.user_id.to_string(), device_signature);
+        master_key["signatures"] = json!(master_key_signatures);
+
+        let mut signed_objects = BTreeMap::new();
+        signed_objects.insert(self.device_id.clone(), device_keys);
+        signed_objects.insert(identity.master_public_key(), master_key);
+
+        let mut signatures = BTreeMap::new();
+        signatures.insert(self.user_id.clone(), signed_objects);
+
+        Ok((upload_request, signatures))
+    }
+
+    /// Verify the cross signing identity of another user by signing their
+    /// master key with our user-signing key.
+    ///
+    /// The devices the user signed with their self-signing key will be
+    /// considered to be verified from now on.
+    ///
+    /// Returns the signature that needs to be uploaded to the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user whose identity should be verified.
+    pub async fn sign_user_identity(&self, user_id: &UserId) -> OlmResult<SignatureUploadRequest> {
+        let private_identity = self
+            .cross_signing_identity
+            .as_ref()
+            .ok_or(CrossSigningError::MissingPrivateIdentity)?;
+
+        let identity = self
+            .store
+            .get_user_identity(user_id)
+            .await?
+            .ok_or_else(|| CrossSigningError::MissingUserIdentity(user_id.clone()))?;
+
+        let master_key = private_identity.sign_user(identity.master_key());
+        let public_key = master_key
+            .public_key()
+            .expect("Stored identities always have a master key")
+            .to_owned();
+        let signed_master_key = json!(&master_key);
+
+        // Remember our signature, the server will hand it out with the next
+        // keys query as well.
+        let identity = UserIdentity::new(
+            master_key,
+            identity.self_signing_key().clone(),
+            identity.user_signing_key().cloned(),
+        );
+        self.store.save_user_identities(&[identity]).await?;
+
+        let mut signed_objects = BTreeMap::new();
+        signed_objects.insert(public_key, signed_master_key);
+
+        let mut signatures = BTreeMap::new();
+        signatures.insert(user_id.clone(), signed_objects);
+
+        Ok(signatures)
+    }
+
+    /// Start an interactive SAS verification with the given device.
+    ///
+    /// The `m.key.verification.start` event will be queued up and can be
+    /// fetched using `outgoing_to_device_requests()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device that should be verified.
+    pub fn start_verification(&self, device: Device) -> Sas {
+        self.verification_machine.start_sas(device)
+    }
+
+    /// Accept a verification request that another device sent us.
+    ///
+    /// Returns the newly started SAS verification flow, None if no request
+    /// with the given flow id is known.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The transaction id of the verification request.
+    pub fn accept_verification_request(&self, flow_id: &str) -> Option<Sas> {
+        self.verification_machine.accept_request(flow_id)
+    }
+
+    /// Get a SAS verification flow with the given flow id.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The unique id of the verification flow.
+    pub fn get_verification(&self, flow_id: &str) -> Option<Sas> {
+        self.verification_machine.get_sas(flow_id)
+    }
+
+    /// Accept a SAS verification flow that the other side started.
+    ///
+    /// Returns true if the flow was accepted, false if the flow is unknown or
+    /// can't be accepted in its current state.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The unique id of the verification flow.
+    pub fn accept_verification(&self, flow_id: &str) -> bool {
+        self.verification_machine.accept_sas(flow_id)
+    }
+
+    /// Confirm that the short authentication strings of a SAS verification
+    /// flow match.
+    ///
+    /// Once both sides confirmed the match the other device will be marked as
+    /// verified and saved in the store.
+    ///
+    /// Returns true if the flow was confirmed, false if the flow is unknown or
+    /// can't be confirmed in its current state.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The unique id of the verification flow.
+    pub async fn confirm_verification(&self, flow_id: &str) -> OlmResult<bool> {
+        Ok(self
+            .verification_machine
+            .confirm_sas(&*self.store, flow_id)
+            .await?)
+    }
+
+    /// Cancel a SAS verification flow.
+    ///
+    /// Returns true if the flow was canceled, false if the flow is unknown or
+    /// already finished.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The unique id of the verification flow.
+    pub fn cancel_verification(&self, flow_id: &str) -> bool {
+        self.verification_machine.cancel_sas(flow_id)
+    }
+
+    /// Is a request of the given kind already waiting to be sent out.
+    fn has_outgoing_request(&self, predicate: impl Fn(&OutgoingRequests) -> bool) -> bool {
+        self.outgoing_requests
+            .values()
+            .any(|r| predicate(r.request()))
+    }
+
+    /// Add a new keys upload, query or claim request to the queue of outgoing
+    /// requests.
+    ///
+    /// Returns the queued request.
+    fn queue_request(&mut self, request: OutgoingRequests) -> OutgoingRequest {
+        let request_id = Uuid::new_v4().to_string();
+        let request = OutgoingRequest::new(request_id.clone(), request);
+        self.outgoing_requests.insert(request_id, request.clone());
+
+        request
+    }
+
+    /// Get all the requests that need to be sent out.
+    ///
+    /// This collects keys upload, keys query and keys claim requests, if they
+    /// are needed, as well as all the queued up to-device requests.
+    ///
+    /// Requests are returned on every call until they are marked as sent
+    /// using `mark_request_as_sent()`, a request of a given kind is only
+    /// created once until then.
+    pub async fn outgoing_requests(&mut self) -> Vec<OutgoingRequest> {
+        // A new account uploads a fallback key together with its device keys.
+        if !self.account.shared() && !self.account.should_upload_fallback_key() {
+            self.account.generate_fallback_key().await;
+        }
+
+        if !self.has_outgoing_request(|r| matches!(r, OutgoingRequests::KeysUpload(_))) {
+            let keys = self.keys_for_upload().await.ok();
+
+            let fallback_keys = if self.account.should_upload_fallback_key() {
+                match self.signed_fallback_keys().await {
+                    Ok(k) => Some(k),
+                    Err(e) => {
+                        warn!("Can't sign our fallback key for the upload {:?}", e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            if keys.is_some() || fallback_keys.is_some() {
+                let (device_keys, one_time_keys) = keys.unwrap_or_default();
+
+                self.queue_request(OutgoingRequests::KeysUpload(KeysUploadRequest {
+                    device_keys,
+                    one_time_keys,
+                    fallback_keys,
+                }));
+            }
+        }
+
+        if self.should_query_keys()
+            && !self.has_outgoing_request(|r| matches!(r, OutgoingRequests::KeysQuery(_)))
+        {
+            let device_keys = self
+                .users_for_key_query()
+                .into_iter()
+                .map(|u| (u, Vec::new()))
+                .collect();
+
+            self.queue_request(OutgoingRequests::KeysQuery(keys::get_keys::Request {
+                timeout: None,
+                device_keys,
+                token: None,
+            }));
+        }
+
+        if !self.has_outgoing_request(|r| matches!(r, OutgoingRequests::KeysClaim(_))) {
+            let one_time_keys = self.get_devices_for_unwedging();
+
+            if !one_time_keys.is_empty() {
+                self.queue_request(OutgoingRequests::KeysClaim(keys::claim_keys::Request {
+                    timeout: None,
+                    one_time_keys,
+                }));
+            }
+        }
+
+        let mut requests: Vec<OutgoingRequest> = self.outgoing_requests.values().cloned().collect();
+
+        requests.extend(
+            self.outgoing_to_device_requests().into_iter().map(|r| {
+                OutgoingRequest::new(r.txn_id.clone(), OutgoingRequests::ToDeviceRequest(r))
+            }),
+        );
+
+        requests
+    }
+
+    /// Mark the request with the given request id as sent.
+    ///
+    /// The request is removed from the queue of outgoing requests and the
+    /// response is processed.
+    ///
+    /// # Arguments
+    ///
+    /// * `request_id` - The unique id of the request that was sent out.
+    ///
+    /// * `response` - The response the server returned for the request.
+    pub async fn mark_request_as_sent(
+        &mut self,
+        request_id: &str,
+        response: IncomingResponse<'_>,
+    ) -> OlmResult<()> {
+        let request = self.outgoing_requests.get(request_id).cloned();
+
+        if let Some(request) = &request {
+            let response_matches = match (request.request(), &response) {
+                (OutgoingRequests::KeysUpload(_), IncomingResponse::KeysUpload(_)) => true,
+                (OutgoingRequests::KeysQuery(_), IncomingResponse::KeysQuery(..)) => true,
+                (OutgoingRequests::KeysClaim(_), IncomingResponse::KeysClaim(_)) => true,
+                (OutgoingRequests::ToDeviceRequest(_), IncomingResponse::ToDevice(_)) => true,
+                _ => false,
+            };
+
+            if !response_matches {
+                return Err(OlmError::MismatchedResponse(request_id.to_owned()));
+            }
+        } else if !matches!(response, IncomingResponse::ToDevice(_)) {
+            // To-device requests aren't part of the queue, any other response
+            // belongs to a request that was already marked as sent.
+            warn!("Received a response for the unknown request {}", request_id);
+            return Ok(());
+        }
+
+        // Remove the request first, the server already accepted it so sending
+        // it out again wouldn't help if we fail to process the response.
+        self.outgoing_requests.remove(request_id);
+
+        if let Some(OutgoingRequests::KeysUpload(upload)) = request.as_ref().map(|r| r.request()) {
+            if upload.fallback_keys.is_some() {
+                self.account.mark_fallback_key_as_published();
+            }
+        }
+
+        match response {
+            IncomingResponse::KeysUpload(response) => {
+                self.receive_keys_upload_response(response).await?;
+            }
+            IncomingResponse::KeysQuery(response, cross_signing_keys) => {
+                self.receive_keys_query_response(response, cross_signing_keys)
+                    .await?;
+            }
+            IncomingResponse::KeysClaim(response) => {
+                if let Some(OutgoingRequests::KeysClaim(claim)) =
+                    request.as_ref().map(|r| r.request())
+                {
+                    self.remember_failed_key_claims(claim, response);
+                }
+
+                self.receive_keys_claim_response(response).await?;
+            }
+            IncomingResponse::ToDevice(_) => {
+                self.mark_to_device_request_as_sent(request_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remember the devices for which the server didn't return a one-time key,
+    /// so they aren't claimed for over and over again.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The keys claim request that was sent out.
+    ///
+    /// * `response` - The response of the server to the request.
+    fn remember_failed_key_claims(
+        &mut self,
+        request: &keys::claim_keys::Request,
+        response: &keys::claim_keys::Response,
+    ) {
+        for (user_id, devices) in &request.one_time_keys {
+            for device_id in devices.keys() {
+                let claimed = response
+                    .one_time_keys
+                    .get(user_id)
+                    .map_or(false, |d| d.contains_key(device_id));
+
+                if claimed {
+                    self.failed_key_claims
+                        .remove(&(user_id.clone(), device_id.clone()));
+                } else {
+                    debug!("No one-time key was returned for {} {}", user_id, device_id);
+                    self.failed_key_claims
+                        .insert((user_id.clone(), device_id.clone()), Instant::now());
+                }
+            }
+        }
+    }
+
+    /// Get the to-device requests that need to be sent out.
+    fn outgoing_to_device_requests(&self) -> Vec<ToDeviceRequest> {
+        let mut requests: Vec<ToDeviceRequest> = self
+            .outgoing_to_device_messages
+            .iter()
+            .map(|r| r.value().clone())
+            .collect();
+
+        requests.extend(self.verification_machine.outgoing_to_device_requests());
+
+        requests
+    }
+
+    /// Mark an outgoing to-device request as sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `txn_id` - The transaction id of the request that was sent out.
+    fn mark_to_device_request_as_sent(&self, txn_id: &str) {
+        self.outgoing_to_device_messages.remove(txn_id);
+        self.verification_machine
+            .mark_to_device_request_as_sent(txn_id)
+    }
+
+    /// Handle a sync response and update the internal state of the Olm machine.
+    ///
+    /// This will decrypt to-device events but will not touch events in the room
+    /// timeline.
+    ///
+    /// To decrypt an event from the room timeline call `decrypt_room_event()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `response` - The sync latest sync response.
+    #[instrument(skip(response))]
+    pub async fn receive_sync_response(&mut self, response: &mut SyncResponse) {
+        let one_time_key_count = response
+            .device_one_time_keys_count
+            .get(&keys::KeyAlgorithm::SignedCurve25519);
+
+        let count: u64 = one_time_key_count.map_or(0, |c| (*c).into());
+        self.update_key_count(count);
+
+        if let Some(device_lists) = &response.device_lists {
+            self.receive_device_list_changes(&device_lists.changed, &device_lists.left)
+                .await;
+        }
+
+        for event_result in &mut response.to_device.events {
+            // Withheld events aren't yet known to ruma, parse them manually.
+            if let Ok(e) =
+                serde_json::from_str::<ToDeviceRoomKeyWithheld>(event_result.json().get())
+            {
+                if e.event_type == WITHHELD_EVENT_TYPE {
+                    if let Err(e) = self.receive_room_key_withheld(e).await {
+                        error!("Failed to process a withheld notice {:?}", e);
+                    }
+                    continue;
+                }
+            }
+
+            let event = if let Ok(e) = event_result.deserialize() {
+                e
+            } else {
+                // Skip invalid events.
+                warn!("Received an invalid to-device event {:?}", event_result);
+                continue;
+            };
+
+            info!("Received a to-device event {:?}", event);
+
+            match &event {
+                ToDeviceEvent::RoomEncrypted(e) => {
+                    let decrypted_event = match self.decrypt_to_device_event(e).await {
+                        Ok(e) => e,
+                        Err(err) => {
+                            warn!(
+                                "Failed to decrypt to-device event from {} {}",
+                                e.sender, err
+                            );
+
+                            if let (
+                                OlmError::SessionWedged,
+                                EncryptedEventContent::OlmV1Curve25519AesSha2(c),
+                            ) = (&err, &e.content)
+                            {
+                                if let Err(err) =
+                                    self.mark_session_as_wedged(&e.sender, &c.sender_key).await
+                                {
+                                    error!(
+                                        "Failed to mark the Olm session with {} as wedged {}",
+                                        e.sender, err
+                                    );
+                                }
+                            }
+
+                            continue;
+                        }
+                    };
+
+                    // Room key events come back without their private keys.
+                    *event_result = decrypted_event;
+                }
+                ToDeviceEvent::RoomKeyRequest(e) => {
+                    if let Err(err) = self.handle_room_key_request(e).await {
+                        error!(
+                            "Failed to answer a room key request from {} {}",
+                            e.sender, err
+                        );
+                    }
+                }
+                ToDeviceEvent::KeyVerificationAccept(..)
+                | ToDeviceEvent::KeyVerificationCancel(..)
+                | ToDeviceEvent::KeyVerificationKey(..)
+                | ToDeviceEvent::KeyVerificationMac(..)
+                | ToDeviceEvent::KeyVerificationRequest(..)
+                | ToDeviceEvent::KeyVerificationStart(..) => {
+                    self.handle_verification_event(&event).await
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// Check that the message index of a decrypted room event wasn't already
+    /// used to decrypt a different event.
+    ///
+    /// The message index is remembered for the event if it wasn't used
+    /// before.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event that was decrypted.
+    ///
+    /// * `session_id` - The id of the group session that decrypted the event.
+    ///
+    /// * `message_index` - The message index that the group session returned.
+    async fn check_message_index(
+        &mut self,
+        event: &EncryptedEvent,
+        session_id: &str,
+        message_index: u32,
+    ) -> MegolmResult<()> {
+        if let Some((event_id, origin_server_ts)) = self
+            .store
+            .get_message_index(session_id, message_index)
+            .await?
+        {
+            // Stores might only persist the timestamp with millisecond
+            // precision, which is what servers give us anyways.
+            let as_millis = |ts: SystemTime| {
+                ts.duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis()
+            };
+
+            if event_id != event.event_id
+                || as_millis(origin_server_ts) != as_millis(event.origin_server_ts)
+            {
+                warn!(
+                    "Message index {} of the group session {} was reused by event {}, \
+                     it was previously used by event {}",
+                    message_index, session_id, event.event_id, event_id
+                );
+
+                return Err(MegolmError::ReplayedMessageIndex {
+                    session_id: session_id.to_owned(),
+                    message_index,
+                    event_id: event_id.to_string(),
+                });
+            }
+        } else {
+            self.store
+                .save_message_index(
+                    session_id,
+                    message_index,
+                    &event.event_id,
+                    event.origin_server_ts,
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the encryption info of an event that was decrypted using the given
+    /// group session.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The user that sent the event.
+    ///
+    /// * `content` - The encrypted content of the event.
+    ///
+    /// * `session` - The group session that decrypted the event.
+    async fn get_encryption_info(
+        &self,
+        sender: &UserId,
+        content: &MegolmV1AesSha2Content,
+        session: &InboundGroupSession,
+    ) -> MegolmResult<EncryptionInfo> {
+        let device = self.store.get_device(sender, &content.device_id).await?;
+
+        let verification_state = if let Some(device) = device {
+            let keys_match = device.get_key(KeyAlgorithm::Curve25519) == Some(&content.sender_key)
+                && device.get_key(KeyAlgorithm::Ed25519) == Some(&*session.signing_key);
+
+            if keys_match && self.device_trust_state(&device).await? == TrustState::Verified {
+                VerificationState::Trusted
+            } else {
+                VerificationState::Untrusted
+            }
+        } else {
+            VerificationState::UnknownDevice
+        };
+
+        Ok(EncryptionInfo {
+            sender_key: content.sender_key.clone(),
+            claimed_ed25519_key: session.signing_key.to_string(),
+            sender_device: content.device_id.clone(),
+            session_id: content.session_id.clone(),
+            verification_state,
+        })
+    }
+
+    /// Decrypt an event from a room timeline.
+    ///
+    /// Returns the decrypted event together with info about its encryption,
+    /// e.g. if the event was sent by a verified device.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event that should be decrypted.
+    pub async fn decrypt_room_event(
+        &mut self,
+        event: &EncryptedEvent,
+    ) -> MegolmResult<DecryptedEvent> {
+        let content = match &event.content {
+            EncryptedEventContent::MegolmV1AesSha2(c) => c,
+            _ => return Err(EventError::UnsupportedAlgorithm.into()),
+        };
+
+        let room_id = event.room_id.as_ref().unwrap();
+
+        let session = if let Some(s) = self
+            .store
+            .get_inbound_group_session(&room_id, &content.sender_key, &content.session_id)
+            .await?
+        {
+            s
+        } else {
+            if let Err(e) = self.request_room_key(event, room_id, content) {
+                warn!("Failed to request the missing room key {:?}", e);
+            }
+
+            if let Some(withheld) = self
+                .withheld_sessions
+                .get(&(content.sender_key.clone(), content.session_id.clone()))
+            {
+                return Err(MegolmError::Withheld {
+                    code: withheld.code,
+                    reason: withheld.reason.clone(),
+                });
+            }
+
+            return Err(MegolmError::MissingSession);
+        };
+
+        let (plaintext, message_index) = session.decrypt(content.ciphertext.clone()).await?;
+        self.check_message_index(event, &content.session_id, message_index)
+            .await?;
+
+        let mut decrypted_value = serde_json::from_str::<Value>(&plaintext)?;
+        let decrypted_object = decrypted_value
+            .as_object_mut()
+            .ok_or(EventError::NotAnObject)?;
+
+        // TODO better number conversion here.
+        let server_ts = event
+            .origin_server_ts
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let server_ts: i64 = server_ts.try_into().unwrap_or_default();
+
+        decrypted_object.insert("sender".to_owned(), event.sender.to_string().into());
+        decrypted_object.insert("event_id".to_owned(), event.event_id.to_string().into());
+        decrypted_object.insert("origin_server_ts".to_owned(), server_ts.into());
+
+        decrypted_object.insert(
+            "unsigned".to_owned(),
+            serde_json::to_value(&event.unsigned).unwrap_or_default(),
+        );
+
+        let decrypted_event = serde_json::from_value::<EventJson<RoomEvent>>(decrypted_value)?;
+        trace!("Successfully decrypted Megolm event {:?}", decrypted_event);
+
+        let encryption_info = self
+            .get_encryption_info(&event.sender, content, &session)
+            .await?;
+
+        Ok(DecryptedEvent {
+            event: decrypted_event,
+            encryption_info,
+        })
+    }
+
+    /// Mark that the given user has changed his devices.
+    ///
+    /// This will queue up the given user for a key query.
+    ///
+    /// Note: The user already needs to be tracked for it to be queued up for a
+    /// key query.
+    ///
+    /// Returns true if the user was queued up for a key query, false otherwise.
+    pub async fn mark_user_as_changed(&mut self, user_id: &UserId) -> StoreError<bool> {
+        if self.store.tracked_users().contains(user_id) {
+            self.store.update_tracked_user(user_id, true).await?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Stop tracking the devices of the given user.
+    ///
+    /// The user won't be considered for key queries anymore until it gets
+    /// tracked again.
+    ///
+    /// Returns true if the user was tracked, false otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that should not be tracked anymore.
+    pub async fn untrack_user(&mut self, user_id: &UserId) -> StoreError<bool> {
+        self.store.remove_tracked_user(user_id).await
+    }
+
+    /// Handle the device list changes of a sync response.
+    ///
+    /// Users that changed their devices are queued up for a key query, users
+    /// that we don't share an encrypted room with anymore stop being tracked.
+    ///
+    /// # Arguments
+    ///
+    /// * `changed` - The users that updated their device list.
+    ///
+    /// * `left` - The users that we don't share an encrypted room with
+    /// anymore.
+    async fn receive_device_list_changes(&mut self, changed: &[UserId], left: &[UserId]) {
+        for user_id in changed {
+            if let Err(e) = self.mark_user_as_changed(user_id).await {
+                error!("Error marking the user {} as changed {}", user_id, e);
+            }
+        }
+
+        for user_id in left {
+            if let Err(e) = self.untrack_user(user_id).await {
+                error!("Error removing the user {} from tracking {}", user_id, e);
+            }
+        }
+    }
+
+    /// Update the members of an encrypted room.
+    ///
+    /// The members will be tracked, members that aren't part of the room
+    /// anymore stop being tracked, unless we still share another encrypted
+    /// room with them. The
+    /// active outbound group session of the room is invalidated if a member
+    /// was removed or if the session was shared with an user that isn't a
+    /// member anymore.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the encrypted room.
+    ///
+    /// * `members` - The users that should receive the room keys of the room.
+    pub async fn update_room_members<'a, I>(&mut self, room_id: &RoomId, members: I)
+    where
+        I: IntoIterator<Item = &'a UserId>,
+    {
+        let members: HashSet<UserId> = members.into_iter().cloned().collect();
+        self.update_tracked_users(&members).await;
+
+        let removed: Vec<UserId> = self
+            .room_members
+            .get(room_id)
+            .map(|old| old.difference(&members).cloned().collect())
+            .unwrap_or_default();
+
+        // The members are only kept in memory, the users the session was
+        // shared with catch members that left while we weren't running.
+        let shared_with_non_member =
+            if let Some(session) = self.outbound_group_sessions.get(room_id) {
+                session
+                    .shared_with()
+                    .await
+                    .keys()
+                    .any(|u| u != &self.user_id && !members.contains(u))
+            } else {
+                false
+            };
+
+        self.room_members.insert(room_id.clone(), members);
+
+        if !removed.is_empty() || shared_with_non_member {
+            self.invalidate_group_session(room_id).await;
+        }
+
+        for user_id in &removed {
+            self.prune_user(user_id).await;
+        }
+    }
+
+    /// Forget the members of a room that we left.
+    ///
+    /// Members that we don't share another encrypted room with stop being
+    /// tracked.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room that we left.
+    pub async fn forget_room(&mut self, room_id: &RoomId) {
+        self.invalidate_group_session(room_id).await;
+
+        if let Some(members) = self.room_members.remove(room_id) {
+            for user_id in &members {
+                self.prune_user(user_id).await;
+            }
+        }
+    }
+
+    /// Stop tracking an user if we don't share any encrypted room with it
+    /// anymore.
+    ///
+    /// The devices of the user are kept in the store so their trust state
+    /// survives the user leaving and joining an encrypted room again.
+    async fn prune_user(&mut self, user_id: &UserId) {
+        if user_id == &self.user_id || self.room_members.values().any(|m| m.contains(user_id)) {
+            return;
+        }
+
+        if let Err(e) = self.untrack_user(user_id).await {
+            error!("Error removing the user {} from tracking {}", user_id, e);
+        }
+    }
+
+    /// Update the tracked users.
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - An iterator over user ids that should be marked for
+    /// tracking.
+    ///
+    /// This will mark users that weren't seen before for a key query and
+    /// tracking.
+    ///
+    /// If the user is already known to the Olm machine it will not be
+    /// considered for a key query.
+    ///
+    /// Use the `mark_user_as_changed()` if the user really needs a key query.
+    pub async fn update_tracked_users<'a, I>(&mut self, users: I)
+    where
+        I: IntoIterator<Item = &'a UserId>,
+    {
+        for user in users {
+            if self.store.tracked_users().contains(user) {
+                continue;
+            }
+
+            if let Err(e) = self.store.update_tracked_user(user, true).await {
+                warn!("Error storing users for tracking {}", e);
+            }
+        }
+    }
+
+    /// Should the client perform a key query request.
+    pub fn should_query_keys(&self) -> bool {
+        !self.store.users_for_key_query().is_empty()
+    }
+
+    /// Get the set of users that we need to query keys for.
+    ///
+    /// Returns a hash set of users that need to be queried for keys.
+    fn users_for_key_query(&self) -> HashSet<UserId> {
+        self.store.users_for_key_query().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    static USER_ID: &str = "@bob:example.org";
+    static DEVICE_ID: &str = "DEVICEID";
+
+    use matrix_sdk_common::js_int::UInt;
+    use std::collections::{BTreeMap, HashSet};
+    use std::convert::TryFrom;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::sync::atomic::AtomicU64;
+    use std::time::SystemTime;
+
+    use http::Response;
+    use serde_json::json;
+
+    use crate::backup::{BackupVersion, RecoveryKey};
+    use crate::identities::KeysQueryCrossSigningKeys;
+    use crate::machine::{
+        GroupSessionSharePolicy, OlmMachine, OneTimeKeys, UnusedFallbackKeyTypes,
+    };
+    use crate::olm::EncryptionSettings;
+    use crate::requests::{IncomingResponse, OutgoingRequest, OutgoingRequests};
+    use crate::withheld::withheld_event_type;
+    use crate::WithheldCode;
+    use crate::{Device, InboundGroupSession, KeySharePolicy, MegolmError, OlmError, TrustState};
+
+    use matrix_sdk_common::api::r0::{
+        keys::{self, AlgorithmAndDeviceId, KeyAlgorithm, OneTimeKey},
+        sync::sync_events::Response as SyncResponse,
+        to_device::{send_event_to_device::Request as ToDeviceRequest, DeviceIdOrAllDevices},
+    };
+    use matrix_sdk_common::events::{
+        collections::all::RoomEvent,
+        room::{
+            encrypted::{EncryptedEvent, EncryptedEventContent},
+            message::{MessageEventContent, TextMessageEventContent},
+        },
+        to_device::{AnyToDeviceEvent, ToDeviceEncrypted, ToDeviceRoomKeyRequest},
+        EventJson, EventType, UnsignedData,
+    };
+    use matrix_sdk_common::identifiers::{DeviceId, EventId, RoomId, UserId};
+    use matrix_sdk_common::VerificationState;
+
+    fn alice_id() -> UserId {
+        UserId::try_from("@alice:example.org").unwrap()
+    }
+
+    fn alice_device_id() -> DeviceId {
+        "JLAFKJWSCS".to_string()
+    }
+
+    fn user_id() -> UserId {
+        UserId::try_from(USER_ID).unwrap()
+    }
+
+    fn response_from_file(path: &str) -> Response<Vec<u8>> {
+        let mut file = File::open(path)
+            .unwrap_or_else(|_| panic!(format!("No such data file found {}", path)));
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)
+            .unwrap_or_else(|_| panic!(format!("Can't read data file {}", path)));
+
+        Response::builder().status(200).body(contents).unwrap()
+    }
+
+    fn keys_upload_response() -> keys::upload_keys::Response {
+        let data = response_from_file("../test_data/keys_upload.json");
+        keys::upload_keys::Response::try_from(data).expect("Can't parse the keys upload response")
+    }
+
+    fn keys_query_response() -> keys::get_keys::Response {
+        let data = response_from_file("../test_data/keys_query.json");
+        keys::get_keys::Response::try_from(data).expect("Can't parse the keys upload response")
+    }
+
+    fn keys_query_response_from_json(
+        json: serde_json::Value,
+    ) -> (keys::get_keys::Response, KeysQueryCrossSigningKeys) {
+        let body = serde_json::to_vec(&json).unwrap();
+        let data = Response::builder().status(200).body(body).unwrap();
+
+        (
+            keys::get_keys::Response::try_from(data).expect("Can't parse the keys query response"),
+            serde_json::from_value(json).expect("Can't parse the cross signing keys"),
+        )
+    }
+
+    fn sync_response_with_to_device(events: Vec<serde_json::Value>) -> SyncResponse {
+        let data = response_from_file("../test_data/sync.json");
+        let mut response = SyncResponse::try_from(data).expect("Can't parse the sync response");
+
+        response.to_device.events = events
+            .into_iter()
+            .map(|e| serde_json::from_value::<EventJson<AnyToDeviceEvent>>(e).unwrap())
+            .collect();
+
+        response
+    }
+
+    fn sync_response_with_device_lists(changed: &[&UserId], left: &[&UserId]) -> SyncResponse {
+        let data = response_from_file("../test_data/sync.json");
+        let mut json: serde_json::Value = serde_json::from_slice(data.body()).unwrap();
+
+        json["device_lists"] = json!({
+            "changed": changed,
+            "left": left,
+        });
+
+        let body = serde_json::to_vec(&json).unwrap();
+        let data = Response::builder().status(200).body(body).unwrap();
+
+        SyncResponse::try_from(data).expect("Can't parse the sync response")
+    }
+
+    fn to_device_requests_to_content(requests: Vec<ToDeviceRequest>) -> EncryptedEventContent {
+        let to_device_request = &requests[0];
+
+        let content: EventJson<EncryptedEventContent> = serde_json::from_str(
+            to_device_request
+                .messages
+                .values()
+                .next()
+                .unwrap()
+                .values()
+                .next()
+                .unwrap()
+                .get(),
+        )
+        .unwrap();
+
+        content.deserialize().unwrap()
+    }
+
+    async fn get_prepared_machine() -> (OlmMachine, OneTimeKeys) {
+        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
+        machine.uploaded_signed_key_count = Some(AtomicU64::new(0));
+        let (_, otk) = machine
+            .keys_for_upload()
+            .await
+            .expect("Can't prepare initial key upload");
+        let response = keys_upload_response();
+        machine
+            .receive_keys_upload_response(&response)
+            .await
+            .unwrap();
+
+        (machine, otk.unwrap())
+    }
+
+    async fn get_machine_after_query() -> (OlmMachine, OneTimeKeys) {
+        let (mut machine, otk) = get_prepared_machine().await;
+        let response = keys_query_response();
+
+        machine
+            .receive_keys_query_response(&response, &KeysQueryCrossSigningKeys::default())
+            .await
+            .unwrap();
+
+        (machine, otk)
+    }
+
+    async fn get_machine_pair() -> (OlmMachine, OlmMachine, OneTimeKeys) {
+        let (bob, otk) = get_prepared_machine().await;
+
+        let alice_id = alice_id();
+        let alice_device = alice_device_id();
+        let alice = OlmMachine::new(&alice_id, &alice_device);
+
+        let alice_deivce = Device::from(&alice);
+        let bob_device = Device::from(&bob);
+        alice.store.save_devices(&[bob_device]).await.unwrap();
+        bob.store.save_devices(&[alice_deivce]).await.unwrap();
+
+        (alice, bob, otk)
+    }
+
+    async fn get_machine_pair_with_session() -> (OlmMachine, OlmMachine) {
+        let (mut alice, bob, one_time_keys) = get_machine_pair().await;
+
+        let mut bob_keys = BTreeMap::new();
+
+        let one_time_key = one_time_keys.iter().next().unwrap();
+        let mut keys = BTreeMap::new();
+        keys.insert(one_time_key.0.clone(), one_time_key.1.clone());
+        bob_keys.insert(bob.device_id.clone(), keys);
+
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys.insert(bob.user_id.clone(), bob_keys);
+
+        let response = keys::claim_keys::Response {
+            failures: BTreeMap::new(),
+            one_time_keys,
+        };
+
+        alice.receive_keys_claim_response(&response).await.unwrap();
+
+        (alice, bob)
+    }
+
+    async fn get_machine_pair_with_setup_sessions() -> (OlmMachine, OlmMachine) {
+        let (mut alice, mut bob) = get_machine_pair_with_session().await;
+
+        let session = alice
+            .store
+            .get_sessions(bob.account.identity_keys().curve25519())
+            .await
+            .unwrap()
+            .unwrap()
+            .lock()
+            .await[0]
+            .clone();
+
+        let bob_device = alice
+            .store
+            .get_device(&bob.user_id, &bob.device_id)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let event = ToDeviceEncrypted {
+            sender: alice.user_id.clone(),
+            content: alice
+                .olm_encrypt(session, &bob_device, EventType::Dummy, json!({}))
+                .await
+                .unwrap(),
+        };
+
+        bob.decrypt_to_device_event(&event).await.unwrap();
+
+        (alice, bob)
+    }
+
+    #[tokio::test]
+    async fn create_olm_machine() {
+        let machine = OlmMachine::new(&user_id(), DEVICE_ID);
+        assert!(machine.should_upload_keys().await);
+    }
+
+    #[tokio::test]
+    async fn receive_keys_upload_response() {
+        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
+        let mut response = keys_upload_response();
+
+        response
+            .one_time_key_counts
+            .remove(&keys::KeyAlgorithm::SignedCurve25519)
+            .unwrap();
+
+        assert!(machine.should_upload_keys().await);
+        machine
+            .receive_keys_upload_response(&response)
+            .await
+            .unwrap();
+        assert!(machine.should_upload_keys().await);
+
+        response.one_time_key_counts.insert(
+            keys::KeyAlgorithm::SignedCurve25519,
+            UInt::try_from(10).unwrap(),
+        );
+        machine
+            .receive_keys_upload_response(&response)
+            .await
+            .unwrap();
+        assert!(machine.should_upload_keys().await);
+
+        response.one_time_key_counts.insert(
+            keys::KeyAlgorithm::SignedCurve25519,
+            UInt::try_from(50).unwrap(),
+        );
+        machine
+            .receive_keys_upload_response(&response)
+            .await
+            .unwrap();
+        assert!(!machine.should_upload_keys().await);
+    }
+
+    #[tokio::test]
+    async fn generate_one_time_keys() {
+        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
+
+        let mut response = keys_upload_response();
+
+        assert!(machine.should_upload_keys().await);
+        assert!(machine.generate_one_time_keys().await.is_err());
+
+        machine
+            .receive_keys_upload_response(&response)
+            .await
+            .unwrap();
+        assert!(machine.should_upload_keys().await);
+        assert!(machine.generate_one_time_keys().await.is_ok());
+
+        response.one_time_key_counts.insert(
+            keys::KeyAlgorithm::SignedCurve25519,
+            UInt::try_from(50).unwrap(),
+        );
+        machine
+            .receive_keys_upload_response(&response)
+            .await
+            .unwrap();
+        assert!(machine.generate_one_time_keys().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_device_key_signing() {
+        let machine = OlmMachine::new(&user_id(), DEVICE_ID);
+
+        let mut device_keys = machine.device_keys().await;
+        let identity_keys = machine.account.identity_keys();
+        let ed25519_key = identity_keys.ed25519();
+
+        let ret = machine.verify_json(
+            &machine.user_id,
+            &machine.device_id,
+            ed25519_key,
+            &mut json!(&mut device_keys),
+        );
+        assert!(ret.is_ok());
+    }
+
+    #[tokio::test]
+    async fn tests_session_invalidation() {
+        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
+
+        machine
+            .create_outbound_group_session(&room_id, EncryptionSettings::default())
+            .await
+            .unwrap();
+        assert!(machine.outbound_group_sessions.get(&room_id).is_some());
+
+        assert!(machine.invalidate_group_session(&room_id).await);
+
+        assert!(machine.outbound_group_sessions.get(&room_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_rotation() {
+        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
+        let users: Vec<UserId> = Vec::new();
+
+        let settings = EncryptionSettings {
+            rotation_period_msgs: 1,
+            ..Default::default()
+        };
+
+        assert!(machine.should_share_group_session(&room_id));
+        machine
+            .share_group_session(&room_id, &users, settings)
+            .await
+            .unwrap();
+        assert!(!machine.should_share_group_session(&room_id));
+
+        let content = MessageEventContent::Text(TextMessageEventContent::new_plain("Hello"));
+        machine.encrypt(&room_id, content).await.unwrap();
+        assert!(machine.should_share_group_session(&room_id));
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_with_expired_session() {
+        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
+        let users: Vec<UserId> = Vec::new();
+        let content = MessageEventContent::Text(TextMessageEventContent::new_plain("Hello"));
+
+        match machine.encrypt(&room_id, content.clone()).await {
+            Err(MegolmError::MissingOutboundSession) => (),
+            _ => panic!("Encrypted without a shared group session"),
+        }
+
+        let settings = EncryptionSettings {
+            rotation_period_msgs: 2,
+            ..Default::default()
+        };
+
+        machine
+            .share_group_session(&room_id, &users, settings)
+            .await
+            .unwrap();
+
+        machine.encrypt(&room_id, content.clone()).await.unwrap();
+        machine.encrypt(&room_id, content.clone()).await.unwrap();
+
+        match machine.encrypt(&room_id, content.clone()).await {
+            Err(MegolmError::ExpiredOutboundSession) => (),
+            _ => panic!("Encrypted with an expired group session"),
+        }
+
+        // Sharing a new session makes encryption work again.
+        machine
+            .share_group_session(&room_id, &users, EncryptionSettings::default())
+            .await
+            .unwrap();
+        machine.encrypt(&room_id, content).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_signature() {
+        let machine = OlmMachine::new(&user_id(), DEVICE_ID);
+
+        let mut device_keys = machine.device_keys().await;
+
+        let ret = machine.verify_json(
+            &machine.user_id,
+            &machine.device_id,
+            "fake_key",
+            &mut json!(&mut device_keys),
+        );
+        assert!(ret.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_one_time_key_signing() {
+        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
+        machine.uploaded_signed_key_count = Some(AtomicU64::new(49));
+
+        let mut one_time_keys = machine.signed_one_time_keys().await.unwrap();
+        let identity_keys = machine.account.identity_keys();
+        let ed25519_key = identity_keys.ed25519();
+
+        let mut one_time_key = one_time_keys.values_mut().next().unwrap();
+
+        let ret = machine.verify_json(
+            &machine.user_id,
+            &machine.device_id,
+            ed25519_key,
+            &mut json!(&mut one_time_key),
+        );
+        assert!(ret.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_keys_for_upload() {
+        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
+        machine.uploaded_signed_key_count = Some(AtomicU64::default());
+
+        let identity_keys = machine.account.identity_keys();
+        let ed25519_key = identity_keys.ed25519();
+
+        let (device_keys, mut one_time_keys) = machine
+            .keys_for_upload()
+            .await
+            .expect("Can't prepare initial key upload");
+
+        let ret = machine.verify_json(
+            &machine.user_id,
+            &machine.device_id,
+            ed25519_key,
+            &mut json!(&mut one_time_keys.as_mut().unwrap().values_mut().next()),
+        );
+        assert!(ret.is_ok());
+
+        let ret = machine.verify_json(
+            &machine.user_id,
+            &machine.device_id,
+            ed25519_key,
+            &mut json!(&mut device_keys.unwrap()),
+        );
+        assert!(ret.is_ok());
+
+        let mut response = keys_upload_response();
+        response.one_time_key_counts.insert(
+            keys::KeyAlgorithm::SignedCurve25519,
+            UInt::new_wrapping(one_time_keys.unwrap().len() as u64),
+        );
+
+        machine
+            .receive_keys_upload_response(&response)
+            .await
+            .unwrap();
+
+        let ret = machine.keys_for_upload().await;
+        assert!(ret.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_keys_query() {
+        let (mut machine, _) = get_prepared_machine().await;
+        let response = keys_query_response();
+        let alice_id = UserId::try_from("@alice:example.org").unwrap();
+        let alice_device_id = "JLAFKJWSCS".to_owned();
+
+        let alice_devices = machine.store.get_user_devices(&alice_id).await.unwrap();
+        assert!(alice_devices.devices().peekable().peek().is_none());
+
+        machine
+            .receive_keys_query_response(&response, &KeysQueryCrossSigningKeys::default())
+            .await
+            .unwrap();
+
+        let device = machine
+            .store
+            .get_device(&alice_id, &alice_device_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(device.user_id(), &alice_id);
+        assert_eq!(device.device_id(), &alice_device_id);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_key_rotation() {
+        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
+        machine.uploaded_signed_key_count = Some(AtomicU64::default());
+        let ed25519_key = machine.account.identity_keys().ed25519().to_owned();
+
+        let upload_request = |requests: Vec<OutgoingRequest>| {
+            assert_eq!(requests.len(), 1);
+
+            match requests[0].request() {
+                OutgoingRequests::KeysUpload(upload) => {
+                    (requests[0].request_id().to_owned(), upload.clone())
+                }
+                request => panic!("Unexpected outgoing request {:?}", request),
+            }
+        };
+
+        let (request_id, upload) = upload_request(machine.outgoing_requests().await);
+        let mut fallback_keys = upload.fallback_keys.unwrap();
+        assert_eq!(fallback_keys.len(), 1);
+
+        let fallback_key = fallback_keys.values_mut().next().unwrap();
+        assert_eq!(fallback_key["fallback"], true);
+        let first_key = fallback_key["key"].clone();
+
+        let ret = machine.verify_json(
+            &machine.user_id,
+            &machine.device_id,
+            &ed25519_key,
+            fallback_key,
+        );
+        assert!(ret.is_ok());
+
+        let mut response = keys_upload_response();
+        response.one_time_key_counts.insert(
+            keys::KeyAlgorithm::SignedCurve25519,
+            UInt::new_wrapping(upload.one_time_keys.unwrap().len() as u64),
+        );
+        machine
+            .mark_request_as_sent(&request_id, IncomingResponse::KeysUpload(&response))
+            .await
+            .unwrap();
+        assert!(machine.outgoing_requests().await.is_empty());
+
+        // The server still has our fallback key or doesn't support fallback
+        // keys at all, nothing needs to be uploaded.
+        let unused: UnusedFallbackKeyTypes = serde_json::from_value(json!({
+            "org.matrix.msc2732.device_unused_fallback_key_types": ["signed_curve25519"]
+        }))
+        .unwrap();
+        machine
+            .receive_unused_fallback_key_types(&unused)
+            .await
+            .unwrap();
+        assert!(machine.outgoing_requests().await.is_empty());
+
+        machine
+            .receive_unused_fallback_key_types(&UnusedFallbackKeyTypes::default())
+            .await
+            .unwrap();
+        assert!(machine.outgoing_requests().await.is_empty());
+
+        // Our fallback key was used up, a new one gets uploaded.
+        let unused: UnusedFallbackKeyTypes = serde_json::from_value(json!({
+            "org.matrix.msc2732.device_unused_fallback_key_types": []
+        }))
+        .unwrap();
+        machine
+            .receive_unused_fallback_key_types(&unused)
+            .await
+            .unwrap();
+
+        let (_, upload) = upload_request(machine.outgoing_requests().await);
+        assert!(upload.device_keys.is_none());
+        assert!(upload.one_time_keys.is_none());
+
+        let fallback_keys = upload.fallback_keys.unwrap();
+        assert_eq!(fallback_keys.len(), 1);
+        assert_ne!(fallback_keys.values().next().unwrap()["key"], first_key);
+    }
+
+    #[tokio::test]
+    async fn test_device_list_changes() {
+        let (mut machine, _) = get_machine_after_query().await;
+        let alice_id = UserId::try_from("@alice:example.org").unwrap();
+        let bob_id = UserId::try_from("@bob:example.org").unwrap();
+        let carol_id = UserId::try_from("@carol:example.org").unwrap();
+
+        machine.update_tracked_users(&[bob_id.clone()]).await;
+        machine
+            .store
+            .update_tracked_user(&bob_id, false)
+            .await
+            .unwrap();
+
+        assert!(machine.store.tracked_users().contains(&alice_id));
+        assert!(!machine.should_query_keys());
+
+        let mut response = sync_response_with_device_lists(&[&bob_id, &carol_id], &[&alice_id]);
+        machine.receive_sync_response(&mut response).await;
+
+        assert!(machine.users_for_key_query().contains(&bob_id));
+        assert!(!machine.store.tracked_users().contains(&alice_id));
+        assert!(!machine.store.tracked_users().contains(&carol_id));
+        assert!(!machine.users_for_key_query().contains(&carol_id));
+    }
+
+    #[tokio::test]
+    async fn test_outgoing_requests() {
+        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
+        machine.uploaded_signed_key_count = Some(AtomicU64::default());
+        machine.update_tracked_users(&[alice_id()]).await;
+
+        let requests = machine.outgoing_requests().await;
+        assert_eq!(requests.len(), 2);
+
+        let request_ids: HashSet<String> =
+            requests.iter().map(|r| r.request_id().to_owned()).collect();
+        assert_eq!(request_ids.len(), 2);
+
+        // Pending requests are handed out again instead of creating new ones.
+        let requests = machine.outgoing_requests().await;
+        let new_request_ids: HashSet<String> =
+            requests.iter().map(|r| r.request_id().to_owned()).collect();
+        assert_eq!(request_ids, new_request_ids);
+
+        for request in requests {
+            match request.request() {
+                OutgoingRequests::KeysUpload(upload) => {
+                    let mut response = keys_upload_response();
+                    response.one_time_key_counts.insert(
+                        keys::KeyAlgorithm::SignedCurve25519,
+                        UInt::new_wrapping(upload.one_time_keys.as_ref().unwrap().len() as u64),
+                    );
+
+                    machine
+                        .mark_request_as_sent(
+                            request.request_id(),
+                            IncomingResponse::KeysUpload(&response),
+                        )
+                        .await
+                        .unwrap();
+                }
+                OutgoingRequests::KeysQuery(query) => {
+                    assert!(query.device_keys.contains_key(&alice_id()));
+
+                    machine
+                        .mark_request_as_sent(
+                            request.request_id(),
+                            IncomingResponse::KeysQuery(
+                                &keys_query_response(),
+                                &KeysQueryCrossSigningKeys::default(),
+                            ),
+                        )
+                        .await
+                        .unwrap();
+                }
+                _ => panic!("Unexpected outgoing request {:?}", request),
+            }
+        }
+
+        assert!(machine.outgoing_requests().await.is_empty());
+        assert!(machine
+            .get_device(&alice_id(), &alice_device_id())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_room_member_tracking() {
+        let (mut machine, _) = get_machine_after_query().await;
+        let alice_id = UserId::try_from("@alice:example.org").unwrap();
+        let bob_id = UserId::try_from("@bob:example.org").unwrap();
+        let first_room = RoomId::try_from("!first:example.org").unwrap();
+        let second_room = RoomId::try_from("!second:example.org").unwrap();
+
+        machine
+            .update_room_members(&first_room, &[alice_id.clone(), user_id()])
+            .await;
+        machine
+            .update_room_members(&second_room, &[alice_id.clone(), bob_id.clone()])
+            .await;
+
+        assert!(machine.store.tracked_users().contains(&bob_id));
+        assert!(machine.users_for_key_query().contains(&bob_id));
+
+        machine.update_room_members(&first_room, &[user_id()]).await;
+
+        assert!(machine.store.tracked_users().contains(&alice_id));
+        assert!(machine.store.tracked_users().contains(&user_id()));
+
+        machine
+            .update_room_members(&second_room, &[bob_id.clone()])
+            .await;
+
+        assert!(!machine.store.tracked_users().contains(&alice_id));
+        assert!(machine
+            .store
+            .get_user_devices(&alice_id)
+            .await
+            .unwrap()
+            .devices()
+            .next()
+            .is_some());
+
+        machine.forget_room(&second_room).await;
+
+        assert!(!machine.store.tracked_users().contains(&bob_id));
+        assert!(!machine.users_for_key_query().contains(&bob_id));
+        assert!(machine.store.tracked_users().contains(&user_id()));
+    }
+
+    #[tokio::test]
+    async fn test_trust_state_survives_rejoin() {
+        let (mut machine, _) = get_machine_after_query().await;
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
+
+        machine
+            .update_room_members(&room_id, &[alice_id(), user_id()])
+            .await;
+        machine
+            .set_device_trust_state(&alice_id(), &alice_device_id(), TrustState::Verified)
+            .await
+            .unwrap();
+
+        machine.update_room_members(&room_id, &[user_id()]).await;
+        assert!(!machine.store.tracked_users().contains(&alice_id()));
+
+        machine
+            .update_room_members(&room_id, &[alice_id(), user_id()])
+            .await;
+        assert!(machine.users_for_key_query().contains(&alice_id()));
+
+        machine
+            .receive_keys_query_response(
+                &keys_query_response(),
+                &KeysQueryCrossSigningKeys::default(),
+            )
+            .await
+            .unwrap();
+
+        let device = machine
+            .get_device(&alice_id(), &alice_device_id())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(device.trust_state(), TrustState::Verified);
+    }
+
+    #[tokio::test]
+    async fn test_room_member_left_while_offline() {
+        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
+        let bob_id = UserId::try_from("@bob:example.org").unwrap();
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
+
+        machine
+            .create_outbound_group_session(&room_id, EncryptionSettings::default())
+            .await
+            .unwrap();
+        machine.outbound_group_sessions[&room_id]
+            .mark_shared_with(&bob_id, &"BOBDEVICE".into())
+            .await;
+
+        // We don't know the previous members of the room, e.g. after a
+        // restart, but the session was shared with an user that left.
+        machine.update_room_members(&room_id, &[user_id()]).await;
+        assert!(machine.outbound_group_sessions.get(&room_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_missing_sessions_calculation() {
+        let (mut machine, _) = get_machine_after_query().await;
+
+        let alice = alice_id();
+        let alice_device = alice_device_id();
+
+        let missing_sessions = machine
+            .get_missing_sessions([alice.clone()].iter())
+            .await
+            .unwrap();
+
+        assert!(missing_sessions.contains_key(&alice));
+        let user_sessions = missing_sessions.get(&alice).unwrap();
+        assert!(user_sessions.contains_key(&alice_device));
+    }
+
+    #[tokio::test]
+    async fn test_missing_session_claims() {
+        let (mut machine, _) = get_machine_after_query().await;
+        let alice = alice_id();
+
+        let request = machine
+            .claim_missing_sessions([alice.clone()].iter())
+            .await
+            .unwrap()
+            .unwrap();
+
+        if let OutgoingRequests::KeysClaim(claim) = request.request() {
+            assert!(claim.one_time_keys[&alice].contains_key(&alice_device_id()));
+        } else {
+            panic!("Unexpected outgoing request {:?}", request);
+        }
+
+        // The claim is part of the queue and a response of another kind is
+        // refused.
+        assert!(machine
+            .outgoing_requests()
+            .await
+            .iter()
+            .any(|r| r.request_id() == request.request_id()));
+        assert!(matches!(
+            machine
+                .mark_request_as_sent(
+                    request.request_id(),
+                    IncomingResponse::KeysUpload(&keys_upload_response()),
+                )
+                .await,
+            Err(OlmError::MismatchedResponse(_))
+        ));
+
+        // The server had no one-time keys left for the device.
+        let response = keys::claim_keys::Response {
+            failures: BTreeMap::new(),
+            one_time_keys: BTreeMap::new(),
+        };
+        machine
+            .mark_request_as_sent(request.request_id(), IncomingResponse::KeysClaim(&response))
+            .await
+            .unwrap();
+
+        // The device isn't claimed for again right away.
+        assert!(machine
+            .claim_missing_sessions([alice].iter())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_creation() {
+        let (mut alice_machine, bob_machine, one_time_keys) = get_machine_pair().await;
+
+        let mut bob_keys = BTreeMap::new();
+
+        let one_time_key = one_time_keys.iter().next().unwrap();
+        let mut keys = BTreeMap::new();
+        keys.insert(one_time_key.0.clone(), one_time_key.1.clone());
+        bob_keys.insert(bob_machine.device_id.clone(), keys);
+
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys.insert(bob_machine.user_id.clone(), bob_keys);
+
+        let response = keys::claim_keys::Response {
+            failures: BTreeMap::new(),
+            one_time_keys,
+        };
+
+        alice_machine
+            .receive_keys_claim_response(&response)
+            .await
+            .unwrap();
+
+        let session = alice_machine
+            .store
+            .get_sessions(bob_machine.account.identity_keys().curve25519())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(!session.lock().await.is_empty())
+    }
+
+    #[tokio::test]
+    async fn test_olm_encryption() {
+        let (mut alice, mut bob) = get_machine_pair_with_session().await;
+
+        let session = alice
+            .store
+            .get_sessions(bob.account.identity_keys().curve25519())
+            .await
+            .unwrap()
+            .unwrap()
+            .lock()
+            .await[0]
+            .clone();
+
+        let bob_device = alice
+            .store
+            .get_device(&bob.user_id, &bob.device_id)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let event = ToDeviceEncrypted {
+            sender: alice.user_id.clone(),
+            content: alice
+                .olm_encrypt(session, &bob_device, EventType::Dummy, json!({}))
+                .await
+                .unwrap(),
+        };
+
+        let event = bob.decrypt_to_device_event(&event).await.unwrap();
+
+        if let AnyToDeviceEvent::Dummy(e) = event.deserialize().unwrap() {
+            assert_eq!(e.sender, alice.user_id);
+        } else {
+            panic!("Event had the wrong type");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_to_device_encryption() {
+        let (mut alice, mut bob) = get_machine_pair_with_session().await;
+
+        let mut bob_messages = BTreeMap::new();
+        bob_messages.insert(DeviceIdOrAllDevices::AllDevices, json!({ "ping": "pong" }));
+        let mut messages = BTreeMap::new();
+        messages.insert(bob.user_id.clone(), bob_messages);
+
+        let requests = alice
+            .encrypt_to_device(EventType::Custom("org.example.ping".to_owned()), &messages)
+            .await
+            .unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].event_type, EventType::RoomEncrypted);
+
+        let event = ToDeviceEncrypted {
+            sender: alice.user_id.clone(),
+            content: to_device_requests_to_content(requests),
+        };
+
+        let event = bob.decrypt_to_device_event(&event).await.unwrap();
+        let event: serde_json::Value = serde_json::from_str(event.json().get()).unwrap();
+
+        assert_eq!(event["type"], "org.example.ping");
+        assert_eq!(event["content"]["ping"], "pong");
+    }
+
+    #[tokio::test]
+    async fn test_session_unwedging() {
+        let (mut alice, mut bob, one_time_keys) = get_machine_pair().await;
+        let mut one_time_keys = one_time_keys.into_iter();
+
+        let claim_response = |key: (AlgorithmAndDeviceId, OneTimeKey)| {
+            let mut keys = BTreeMap::new();
+            keys.insert(key.0, key.1);
+            let mut bob_keys = BTreeMap::new();
+            bob_keys.insert(bob.device_id.clone(), keys);
+            let mut one_time_keys = BTreeMap::new();
+            one_time_keys.insert(bob.user_id.clone(), bob_keys);
+
+            keys::claim_keys::Response {
+                failures: BTreeMap::new(),
+                one_time_keys,
+            }
+        };
+
+        let response = claim_response(one_time_keys.next().unwrap());
+        alice.receive_keys_claim_response(&response).await.unwrap();
+        assert!(alice.get_devices_for_unwedging().is_empty());
+        assert!(alice.outgoing_to_device_requests().is_empty());
+
+        let bob_key = bob.account.identity_keys().curve25519().to_owned();
+
+        alice
+            .mark_session_as_wedged(&bob.user_id, &bob_key)
+            .await
+            .unwrap();
+
+        let devices = alice.get_devices_for_unwedging();
+        assert_eq!(
+            devices.get(&bob.user_id).unwrap().get(&bob.device_id),
+            Some(&KeyAlgorithm::SignedCurve25519)
+        );
+        // The device was just handed out for a key claim, it isn't claimed
+        // again until the unwedging interval passed.
+        assert!(alice.get_devices_for_unwedging().is_empty());
+
+        let response = claim_response(one_time_keys.next().unwrap());
+        alice.receive_keys_claim_response(&response).await.unwrap();
+        assert!(alice.get_devices_for_unwedging().is_empty());
+
+        let requests = alice.outgoing_to_device_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].event_type, EventType::RoomEncrypted);
+
+        let event = ToDeviceEncrypted {
+            sender: alice.user_id.clone(),
+            content: to_device_requests_to_content(requests),
+        };
+
+        let event = bob.decrypt_to_device_event(&event).await.unwrap();
+
+        if let AnyToDeviceEvent::Dummy(e) = event.deserialize().unwrap() {
+            assert_eq!(e.sender, alice.user_id);
+        } else {
+            panic!("Event had the wrong type");
+        }
+
+        // We just tried to unwedge the session, another attempt is rate
+        // limited.
+        alice
+            .mark_session_as_wedged(&bob.user_id, &bob_key)
+            .await
+            .unwrap();
+        assert!(alice.get_devices_for_unwedging().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_room_key_sharing() {
+        let (mut alice, mut bob) = get_machine_pair_with_session().await;
+
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
+
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id.clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let event = ToDeviceEncrypted {
+            sender: alice.user_id.clone(),
+            content: to_device_requests_to_content(to_device_requests),
+        };
+
+        let alice_session = alice.outbound_group_sessions.get(&room_id).unwrap();
+
+        let event = bob.decrypt_to_device_event(&event).await.unwrap();
+
+        if let AnyToDeviceEvent::RoomKey(e) = event.deserialize().unwrap() {
+            assert_eq!(e.sender, alice.user_id);
+            assert!(e.content.session_key.is_empty())
+        } else {
+            panic!("Event had the wrong type");
+        }
+
+        let session = bob
+            .store
+            .get_inbound_group_session(
+                &room_id,
+                alice.account.identity_keys().curve25519(),
+                alice_session.session_id(),
+            )
+            .await;
+
+        assert!(session.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_group_session_share_policy() {
+        let (mut alice, _) = get_machine_pair_with_session().await;
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
+        let bob_id = user_id();
+        let bob_device_id: DeviceId = DEVICE_ID.to_owned();
+
+        let requests = alice
+            .share_group_session(&room_id, [bob_id.clone()].iter(), EncryptionSettings::default())
+            .await
+            .unwrap();
+        assert_eq!(requests.len(), 1);
+
+        alice.set_group_session_share_policy(GroupSessionSharePolicy::VerifiedOnly);
+        let requests = alice
+            .share_group_session(&room_id, [bob_id.clone()].iter(), EncryptionSettings::default())
+            .await
+            .unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].event_type, withheld_event_type());
+
+        alice.set_group_session_share_policy(GroupSessionSharePolicy::FailOnUnverified);
+        match alice
+            .share_group_session(&room_id, [bob_id.clone()].iter(), EncryptionSettings::default())
+            .await
+        {
+            Err(OlmError::UnverifiedDevices(devices)) => {
+                assert_eq!(devices, vec![(bob_id.clone(), bob_device_id.clone())])
+            }
+            _ => panic!("Sharing the session should fail for unverified devices"),
+        }
+
+        assert!(alice
+            .set_device_trust_state(&bob_id, &bob_device_id, TrustState::Verified)
+            .await
+            .unwrap());
+        let requests = alice
+            .share_group_session(&room_id, [bob_id.clone()].iter(), EncryptionSettings::default())
+            .await
+            .unwrap();
+        assert_eq!(requests.len(), 1);
+
+        alice.set_group_session_share_policy(GroupSessionSharePolicy::AllDevices);
+        alice
+            .set_device_trust_state(&bob_id, &bob_device_id, TrustState::BlackListed)
+            .await
+            .unwrap();
+        assert!(alice.should_share_group_session(&room_id));
+
+        let requests = alice
+            .share_group_session(&room_id, [bob_id.clone()].iter(), EncryptionSettings::default())
+            .await
+            .unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].event_type, withheld_event_type());
+
+        let device = alice
+            .get_device(&bob_id, &bob_device_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(device.trust_state(), TrustState::BlackListed);
+
+        assert!(!alice
+            .set_device_trust_state(&bob_id, &"UNKNOWN".to_owned(), TrustState::Verified)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_no_olm_notice_after_failed_share() {
+        let (mut alice, bob, _) = get_machine_pair().await;
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
+        let other_device_id: DeviceId = "OTHERDEVICE".to_owned();
+
+        // Alice doesn't share an Olm session with any of Bob's devices.
+        let other_device = Device::from(&OlmMachine::new(bob.user_id(), &other_device_id));
+        alice.store.save_devices(&[other_device]).await.unwrap();
+
+        alice
+            .set_device_trust_state(bob.user_id(), bob.device_id(), TrustState::Verified)
+            .await
+            .unwrap();
+        alice.set_group_session_share_policy(GroupSessionSharePolicy::FailOnUnverified);
+
+        assert!(alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .is_err());
+
+        // The failed share didn't notify anybody, the notice is sent once the
+        // share goes through.
+        alice
+            .set_device_trust_state(bob.user_id(), &other_device_id, TrustState::Ignored)
+            .await
+            .unwrap();
+
+        let requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].event_type, withheld_event_type());
+        assert!(requests[0].messages[bob.user_id()]
+            .contains_key(&DeviceIdOrAllDevices::DeviceId(bob.device_id().clone())));
+        assert!(alice
+            .no_olm_sent
+            .contains(&(bob.user_id().clone(), bob.device_id().clone())));
+    }
+
+    #[tokio::test]
+    async fn test_room_key_withheld() {
+        let (mut alice, mut bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
+
+        alice
+            .set_device_trust_state(bob.user_id(), bob.device_id(), TrustState::BlackListed)
+            .await
+            .unwrap();
+
+        let requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(requests.len(), 1);
+
+        let content: serde_json::Value = serde_json::from_str(
+            requests[0].messages[bob.user_id()]
+                [&DeviceIdOrAllDevices::DeviceId(bob.device_id().clone())]
+                .get(),
+        )
+        .unwrap();
+        assert_eq!(content["code"], "m.blacklisted");
+
+        // A notice that claims to come from a device the sender doesn't own
+        // is ignored.
+        let mut forged_content = content.clone();
+        forged_content["sender_key"] = json!(bob.account.identity_keys().curve25519());
+        let forged_session = (
+            bob.account.identity_keys().curve25519().to_owned(),
+            content["session_id"].as_str().unwrap().to_owned(),
+        );
+
+        let event = json!({
+            "sender": alice.user_id(),
+            "type": "m.room_key.withheld",
+            "content": forged_content,
+        });
+
+        let mut response = sync_response_with_to_device(vec![event]);
+        bob.receive_sync_response(&mut response).await;
+        assert!(!bob.withheld_sessions.contains_key(&forged_session));
+
+        let event = json!({
+            "sender": alice.user_id(),
+            "type": "m.room_key.withheld",
+            "content": content,
+        });
+
+        let mut response = sync_response_with_to_device(vec![event]);
+        bob.receive_sync_response(&mut response).await;
+
+        let content = MessageEventContent::Text(TextMessageEventContent::new_plain("Hello"));
+        let encrypted_content = alice.encrypt(&room_id, content).await.unwrap();
+
+        let event = EncryptedEvent {
+            event_id: EventId::new("example.org").unwrap(),
+            origin_server_ts: SystemTime::now(),
+            room_id: Some(room_id.clone()),
+            sender: alice.user_id().clone(),
+            content: encrypted_content,
+            unsigned: UnsignedData::default(),
+        };
+
+        match bob.decrypt_room_event(&event).await {
+            Err(MegolmError::Withheld { code, .. }) => assert_eq!(code, WithheldCode::Blacklisted),
+            _ => panic!("Decrypting the event should fail with a withheld error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_megolm_encryption() {
+        let (mut alice, mut bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
+
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let event = ToDeviceEncrypted {
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(to_device_requests),
+        };
+
+        bob.decrypt_to_device_event(&event).await.unwrap();
+
+        let plaintext = "It is a secret to everybody";
+
+        let content = MessageEventContent::Text(TextMessageEventContent::new_plain(plaintext));
+
+        let encrypted_content = alice.encrypt(&room_id, content.clone()).await.unwrap();
+
+        let event = EncryptedEvent {
+            event_id: EventId::new("example.org").unwrap(),
+            origin_server_ts: SystemTime::now(),
+            room_id: Some(room_id.clone()),
+            sender: alice.user_id().clone(),
+            content: encrypted_content,
+            unsigned: UnsignedData::default(),
+        };
+
+        let decrypted = bob.decrypt_room_event(&event).await.unwrap();
+
+        assert_eq!(
+            decrypted.encryption_info.sender_key,
+            alice.account.identity_keys().curve25519()
+        );
+        assert_eq!(&decrypted.encryption_info.sender_device, alice.device_id());
+        assert_eq!(
+            decrypted.encryption_info.verification_state,
+            VerificationState::Untrusted
+        );
+
+        let alice_device = bob
+            .get_device(alice.user_id(), alice.device_id())
+            .await
+            .unwrap()
+            .unwrap();
+        alice_device.set_trust_state(TrustState::Verified);
+
+        let decrypted = bob.decrypt_room_event(&event).await.unwrap();
+        assert!(decrypted.encryption_info.is_verified());
+
+        let decrypted_event = decrypted.event.deserialize().unwrap();
+
+        let decrypted_event = match decrypted_event {
+            RoomEvent::RoomMessage(e) => e,
+            _ => panic!("Decrypted room event has the wrong type"),
+        };
+
+        assert_eq!(&decrypted_event.sender, alice.user_id());
+        assert_eq!(&decrypted_event.room_id, &Some(room_id));
+        if let MessageEventContent::Text(c) = &decrypted_event.content {
+            assert_eq!(&c.body, plaintext);
+        } else {
+            panic!("Decrypted event has a missmatched content");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_megolm_replay_protection() {
+        let (mut alice, mut bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
+
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let event = ToDeviceEncrypted {
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(to_device_requests),
+        };
+
+        bob.decrypt_to_device_event(&event).await.unwrap();
+
+        let content = MessageEventContent::Text(TextMessageEventContent::new_plain("Hello"));
+        let encrypted_content = alice.encrypt(&room_id, content).await.unwrap();
 
-        Response::builder().status(200).body(contents).unwrap()
-    }
+        let event = EncryptedEvent {
+            event_id: EventId::new("example.org").unwrap(),
+            origin_server_ts: SystemTime::now(),
+            room_id: Some(room_id.clone()),
+            sender: alice.user_id().clone(),
+            content: encrypted_content,
+            unsigned: UnsignedData::default(),
+        };
 
-    fn keys_upload_response() -> keys::upload_keys::Response {
-        let data = response_from_file("../test_data/keys_upload.json");
-        keys::upload_keys::Response::try_from(data).expect("Can't parse the keys upload response")
+        bob.decrypt_room_event(&event).await.unwrap();
+        // Decrypting the same event again is fine.
+        bob.decrypt_room_event(&event).await.unwrap();
+
+        let mut replayed_event = event.clone();
+        replayed_event.event_id = EventId::new("example.org").unwrap();
+
+        match bob.decrypt_room_event(&replayed_event).await {
+            Err(MegolmError::ReplayedMessageIndex {
+                message_index,
+                event_id,
+                ..
+            }) => {
+                assert_eq!(message_index, 0);
+                assert_eq!(event_id, event.event_id.to_string());
+            }
+            _ => panic!("Decrypting a replayed event should fail"),
+        }
     }
 
-    fn keys_query_response() -> keys::get_keys::Response {
-        let data = response_from_file("../test_data/keys_query.json");
-        keys::get_keys::Response::try_from(data).expect("Can't parse the keys upload response")
+    #[derive(Debug)]
+    struct ShareWithEveryone;
+
+    impl KeySharePolicy for ShareWithEveryone {
+        fn should_share(&self, _: &UserId, _: &Device, _: &InboundGroupSession) -> bool {
+            true
+        }
     }
 
-    fn to_device_requests_to_content(requests: Vec<ToDeviceRequest>) -> EncryptedEventContent {
-        let to_device_request = &requests[0];
+    fn room_key_request(
+        sender: &UserId,
+        device_id: &DeviceId,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> ToDeviceRoomKeyRequest {
+        let event = json!({
+            "sender": sender,
+            "type": "m.room_key_request",
+            "content": {
+                "action": "request",
+                "body": {
+                    "algorithm": "m.megolm.v1.aes-sha2",
+                    "room_id": room_id,
+                    "sender_key": sender_key,
+                    "session_id": session_id,
+                },
+                "requesting_device_id": device_id,
+                "request_id": "1495474790150.19",
+            }
+        });
 
-        let content: EventJson<EncryptedEventContent> = serde_json::from_str(
-            to_device_request
-                .messages
-                .values()
-                .next()
+        if let AnyToDeviceEvent::RoomKeyRequest(e) =
+            serde_json::from_value::<EventJson<AnyToDeviceEvent>>(event)
                 .unwrap()
-                .values()
-                .next()
+                .deserialize()
                 .unwrap()
-                .get(),
-        )
-        .unwrap();
-
-        content.deserialize().unwrap()
+        {
+            e
+        } else {
+            panic!("Invalid room key request event");
+        }
     }
 
-    async fn get_prepared_machine() -> (OlmMachine, OneTimeKeys) {
-        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
-        machine.uploaded_signed_key_count = Some(AtomicU64::new(0));
-        let (_, otk) = machine
-            .keys_for_upload()
-            .await
-            .expect("Can't prepare initial key upload");
-        let response = keys_upload_response();
-        machine
-            .receive_keys_upload_response(&response)
+    #[tokio::test]
+    async fn test_room_key_request_answering() {
+        let (mut alice, mut bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
+
+        alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
             .await
             .unwrap();
 
-        (machine, otk.unwrap())
+        let session_id = alice
+            .outbound_group_sessions
+            .get(&room_id)
+            .unwrap()
+            .session_id()
+            .to_owned();
+
+        let request = room_key_request(
+            bob.user_id(),
+            bob.device_id(),
+            &room_id,
+            alice.account.identity_keys().curve25519(),
+            &session_id,
+        );
+
+        // Bob isn't one of Alice's devices, the default policy refuses to
+        // share the session.
+        alice.handle_room_key_request(&request).await.unwrap();
+        assert!(alice.outgoing_to_device_requests().is_empty());
+
+        alice.set_key_share_policy(ShareWithEveryone);
+        alice.handle_room_key_request(&request).await.unwrap();
+
+        let requests = alice.outgoing_to_device_requests();
+        assert_eq!(requests.len(), 1);
+        let txn_id = requests[0].txn_id.clone();
+
+        let event = ToDeviceEncrypted {
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(requests),
+        };
+
+        let event = bob.decrypt_to_device_event(&event).await.unwrap();
+
+        // Bob didn't request the key, it's removed from the event anyways.
+        if let AnyToDeviceEvent::ForwardedRoomKey(e) = event.deserialize().unwrap() {
+            assert_eq!(e.content.session_id, session_id);
+            assert_eq!(e.content.room_id, room_id);
+            assert!(e.content.session_key.is_empty());
+        } else {
+            panic!("Event had the wrong type");
+        }
+
+        alice.mark_to_device_request_as_sent(&txn_id);
+        assert!(alice.outgoing_to_device_requests().is_empty());
     }
 
-    async fn get_machine_after_query() -> (OlmMachine, OneTimeKeys) {
-        let (mut machine, otk) = get_prepared_machine().await;
-        let response = keys_query_response();
+    #[tokio::test]
+    async fn test_room_key_request_without_session() {
+        let (mut alice, mut bob, one_time_keys) = get_machine_pair().await;
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
 
-        machine
-            .receive_keys_query_response(&response)
+        alice
+            .share_group_session(&room_id, [].iter(), EncryptionSettings::default())
             .await
             .unwrap();
+        alice.set_key_share_policy(ShareWithEveryone);
 
-        (machine, otk)
-    }
-
-    async fn get_machine_pair() -> (OlmMachine, OlmMachine, OneTimeKeys) {
-        let (bob, otk) = get_prepared_machine().await;
+        let session_id = alice
+            .outbound_group_sessions
+            .get(&room_id)
+            .unwrap()
+            .session_id()
+            .to_owned();
 
-        let alice_id = alice_id();
-        let alice_device = alice_device_id();
-        let alice = OlmMachine::new(&alice_id, &alice_device);
+        let request = room_key_request(
+            bob.user_id(),
+            bob.device_id(),
+            &room_id,
+            alice.account.identity_keys().curve25519(),
+            &session_id,
+        );
 
-        let alice_deivce = Device::from(&alice);
-        let bob_device = Device::from(&bob);
-        alice.store.save_devices(&[bob_device]).await.unwrap();
-        bob.store.save_devices(&[alice_deivce]).await.unwrap();
+        // There's no Olm session with Bob yet, a one-time key gets claimed
+        // instead of answering the request.
+        alice.handle_room_key_request(&request).await.unwrap();
+        assert!(alice.outgoing_to_device_requests().is_empty());
 
-        (alice, bob, otk)
-    }
+        let claim = alice
+            .outgoing_requests()
+            .await
+            .into_iter()
+            .find(|r| matches!(r.request(), OutgoingRequests::KeysClaim(_)))
+            .expect("No key claim was queued up");
 
-    async fn get_machine_pair_with_session() -> (OlmMachine, OlmMachine) {
-        let (mut alice, bob, one_time_keys) = get_machine_pair().await;
+        if let OutgoingRequests::KeysClaim(c) = claim.request() {
+            assert!(c.one_time_keys[bob.user_id()].contains_key(bob.device_id()));
+        }
 
         let mut bob_keys = BTreeMap::new();
-
         let one_time_key = one_time_keys.iter().next().unwrap();
         let mut keys = BTreeMap::new();
         keys.insert(one_time_key.0.clone(), one_time_key.1.clone());
@@ -1707,430 +5565,422 @@ mod test {
             one_time_keys,
         };
 
-        alice.receive_keys_claim_response(&response).await.unwrap();
-
-        (alice, bob)
-    }
-
-    async fn get_machine_pair_with_setup_sessions() -> (OlmMachine, OlmMachine) {
-        let (mut alice, mut bob) = get_machine_pair_with_session().await;
-
-        let session = alice
-            .store
-            .get_sessions(bob.account.identity_keys().curve25519())
-            .await
-            .unwrap()
-            .unwrap()
-            .lock()
-            .await[0]
-            .clone();
-
-        let bob_device = alice
-            .store
-            .get_device(&bob.user_id, &bob.device_id)
+        alice
+            .mark_request_as_sent(claim.request_id(), IncomingResponse::KeysClaim(&response))
             .await
-            .unwrap()
             .unwrap();
 
+        // The request gets answered once the session exists.
+        let requests = alice.outgoing_to_device_requests();
+        assert_eq!(requests.len(), 1);
+        assert!(alice.pending_key_requests.is_empty());
+
         let event = ToDeviceEncrypted {
-            sender: alice.user_id.clone(),
-            content: alice
-                .olm_encrypt(session, &bob_device, EventType::Dummy, json!({}))
-                .await
-                .unwrap(),
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(requests),
         };
 
-        bob.decrypt_to_device_event(&event).await.unwrap();
-
-        (alice, bob)
-    }
+        let event = bob.decrypt_to_device_event(&event).await.unwrap();
 
-    #[tokio::test]
-    async fn create_olm_machine() {
-        let machine = OlmMachine::new(&user_id(), DEVICE_ID);
-        assert!(machine.should_upload_keys().await);
+        if let AnyToDeviceEvent::ForwardedRoomKey(e) = event.deserialize().unwrap() {
+            assert_eq!(e.content.session_id, session_id);
+            assert_eq!(e.content.room_id, room_id);
+        } else {
+            panic!("Event had the wrong type");
+        }
     }
 
     #[tokio::test]
-    async fn receive_keys_upload_response() {
-        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
-        let mut response = keys_upload_response();
-
-        response
-            .one_time_key_counts
-            .remove(&keys::KeyAlgorithm::SignedCurve25519)
-            .unwrap();
-
-        assert!(machine.should_upload_keys().await);
-        machine
-            .receive_keys_upload_response(&response)
-            .await
-            .unwrap();
-        assert!(machine.should_upload_keys().await);
-
-        response.one_time_key_counts.insert(
-            keys::KeyAlgorithm::SignedCurve25519,
-            UInt::try_from(10).unwrap(),
-        );
-        machine
-            .receive_keys_upload_response(&response)
-            .await
-            .unwrap();
-        assert!(machine.should_upload_keys().await);
+    async fn test_key_forwarder_trust() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let alice_key = alice.account.identity_keys().curve25519().to_owned();
 
-        response.one_time_key_counts.insert(
-            keys::KeyAlgorithm::SignedCurve25519,
-            UInt::try_from(50).unwrap(),
-        );
-        machine
-            .receive_keys_upload_response(&response)
+        // The creator of the session may forward it.
+        assert!(bob
+            .is_trusted_key_forwarder(alice.user_id(), &alice_key, &alice_key)
             .await
-            .unwrap();
-        assert!(!machine.should_upload_keys().await);
-    }
-
-    #[tokio::test]
-    async fn generate_one_time_keys() {
-        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
-
-        let mut response = keys_upload_response();
-
-        assert!(machine.should_upload_keys().await);
-        assert!(machine.generate_one_time_keys().await.is_err());
+            .unwrap());
 
-        machine
-            .receive_keys_upload_response(&response)
+        // Other users may not forward sessions of somebody else.
+        assert!(!bob
+            .is_trusted_key_forwarder(alice.user_id(), &alice_key, "some_other_key")
             .await
-            .unwrap();
-        assert!(machine.should_upload_keys().await);
-        assert!(machine.generate_one_time_keys().await.is_ok());
+            .unwrap());
 
-        response.one_time_key_counts.insert(
-            keys::KeyAlgorithm::SignedCurve25519,
-            UInt::try_from(50).unwrap(),
-        );
-        machine
-            .receive_keys_upload_response(&response)
+        // Unknown devices of our own may not forward sessions either.
+        assert!(!bob
+            .is_trusted_key_forwarder(bob.user_id(), "unknown_key", "some_other_key")
             .await
-            .unwrap();
-        assert!(machine.generate_one_time_keys().await.is_err());
-    }
-
-    #[tokio::test]
-    async fn test_device_key_signing() {
-        let machine = OlmMachine::new(&user_id(), DEVICE_ID);
-
-        let mut device_keys = machine.device_keys().await;
-        let identity_keys = machine.account.identity_keys();
-        let ed25519_key = identity_keys.ed25519();
-
-        let ret = machine.verify_json(
-            &machine.user_id,
-            &machine.device_id,
-            ed25519_key,
-            &mut json!(&mut device_keys),
-        );
-        assert!(ret.is_ok());
+            .unwrap());
     }
 
     #[tokio::test]
-    async fn tests_session_invalidation() {
-        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
+    async fn test_missing_room_key_request() {
+        let (mut alice, mut bob) = get_machine_pair_with_setup_sessions().await;
         let room_id = RoomId::try_from("!test:example.org").unwrap();
 
-        machine
-            .create_outbound_group_session(&room_id)
+        // Share the session with nobody, Bob will need to request it.
+        alice
+            .share_group_session(&room_id, [].iter(), EncryptionSettings::default())
             .await
             .unwrap();
-        assert!(machine.outbound_group_sessions.get(&room_id).is_some());
-
-        machine.invalidate_group_session(&room_id);
-
-        assert!(machine.outbound_group_sessions.get(&room_id).is_none());
-    }
-
-    #[tokio::test]
-    async fn test_invalid_signature() {
-        let machine = OlmMachine::new(&user_id(), DEVICE_ID);
-
-        let mut device_keys = machine.device_keys().await;
+        alice.set_key_share_policy(ShareWithEveryone);
 
-        let ret = machine.verify_json(
-            &machine.user_id,
-            &machine.device_id,
-            "fake_key",
-            &mut json!(&mut device_keys),
-        );
-        assert!(ret.is_err());
-    }
+        let content = MessageEventContent::Text(TextMessageEventContent::new_plain("Hello"));
+        let encrypted_content = alice.encrypt(&room_id, content).await.unwrap();
 
-    #[tokio::test]
-    async fn test_one_time_key_signing() {
-        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
-        machine.uploaded_signed_key_count = Some(AtomicU64::new(49));
+        let event = EncryptedEvent {
+            event_id: EventId::new("example.org").unwrap(),
+            origin_server_ts: SystemTime::now(),
+            room_id: Some(room_id.clone()),
+            sender: alice.user_id().clone(),
+            content: encrypted_content,
+            unsigned: UnsignedData::default(),
+        };
 
-        let mut one_time_keys = machine.signed_one_time_keys().await.unwrap();
-        let identity_keys = machine.account.identity_keys();
-        let ed25519_key = identity_keys.ed25519();
+        match bob.decrypt_room_event(&event).await {
+            Err(MegolmError::MissingSession) => (),
+            _ => panic!("Decrypting the event should fail with a missing session"),
+        }
+        // A second failure doesn't create a new request.
+        assert!(bob.decrypt_room_event(&event).await.is_err());
 
-        let mut one_time_key = one_time_keys.values_mut().next().unwrap();
+        let requests = bob.outgoing_to_device_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].event_type, EventType::RoomKeyRequest);
 
-        let ret = machine.verify_json(
-            &machine.user_id,
-            &machine.device_id,
-            ed25519_key,
-            &mut json!(&mut one_time_key),
-        );
-        assert!(ret.is_ok());
-    }
+        let request_content: serde_json::Value = serde_json::from_str(
+            requests[0].messages[alice.user_id()]
+                [&DeviceIdOrAllDevices::DeviceId(alice.device_id().clone())]
+                .get(),
+        )
+        .unwrap();
+        bob.mark_to_device_request_as_sent(&requests[0].txn_id);
 
-    #[tokio::test]
-    async fn test_keys_for_upload() {
-        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
-        machine.uploaded_signed_key_count = Some(AtomicU64::default());
+        let request = serde_json::json!({
+            "sender": bob.user_id(),
+            "type": "m.room_key_request",
+            "content": request_content,
+        });
 
-        let identity_keys = machine.account.identity_keys();
-        let ed25519_key = identity_keys.ed25519();
+        let request = if let AnyToDeviceEvent::RoomKeyRequest(e) =
+            serde_json::from_value::<EventJson<AnyToDeviceEvent>>(request)
+                .unwrap()
+                .deserialize()
+                .unwrap()
+        {
+            e
+        } else {
+            panic!("Invalid room key request event");
+        };
 
-        let (device_keys, mut one_time_keys) = machine
-            .keys_for_upload()
-            .await
-            .expect("Can't prepare initial key upload");
+        alice.handle_room_key_request(&request).await.unwrap();
 
-        let ret = machine.verify_json(
-            &machine.user_id,
-            &machine.device_id,
-            ed25519_key,
-            &mut json!(&mut one_time_keys.as_mut().unwrap().values_mut().next()),
-        );
-        assert!(ret.is_ok());
+        let event_to_bob = ToDeviceEncrypted {
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(alice.outgoing_to_device_requests()),
+        };
 
-        let ret = machine.verify_json(
-            &machine.user_id,
-            &machine.device_id,
-            ed25519_key,
-            &mut json!(&mut device_keys.unwrap()),
-        );
-        assert!(ret.is_ok());
+        let event = bob.decrypt_to_device_event(&event_to_bob).await.unwrap();
 
-        let mut response = keys_upload_response();
-        response.one_time_key_counts.insert(
-            keys::KeyAlgorithm::SignedCurve25519,
-            UInt::new_wrapping(one_time_keys.unwrap().len() as u64),
-        );
+        // The private key of the accepted session isn't part of the event.
+        if let AnyToDeviceEvent::ForwardedRoomKey(e) = event.deserialize().unwrap() {
+            assert!(e.content.session_key.is_empty());
+        } else {
+            panic!("Event had the wrong type");
+        }
 
-        machine
-            .receive_keys_upload_response(&response)
-            .await
-            .unwrap();
+        let received = bob.take_received_group_sessions();
+        assert_eq!(received.len(), 1);
+        assert!(bob.take_received_group_sessions().is_empty());
 
-        let ret = machine.keys_for_upload().await;
-        assert!(ret.is_err());
+        // The request got cancelled.
+        let requests = bob.outgoing_to_device_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].event_type, EventType::RoomKeyRequest);
+
+        assert!(bob.decrypt_room_event(&event).await.is_ok());
     }
 
     #[tokio::test]
-    async fn test_keys_query() {
-        let (mut machine, _) = get_prepared_machine().await;
-        let response = keys_query_response();
-        let alice_id = UserId::try_from("@alice:example.org").unwrap();
-        let alice_device_id = "JLAFKJWSCS".to_owned();
+    #[cfg(feature = "sqlite-cryptostore")]
+    async fn test_outbound_group_session_persistence() {
+        use crate::store::CryptoStore;
 
-        let alice_devices = machine.store.get_user_devices(&alice_id).await.unwrap();
-        assert!(alice_devices.devices().peekable().peek().is_none());
+        let tmpdir = tempfile::tempdir().unwrap();
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
 
+        let mut machine = OlmMachine::new_with_default_store(
+            &user_id(),
+            DEVICE_ID,
+            tmpdir.path(),
+            "secret".to_owned(),
+        )
+        .await
+        .unwrap();
         machine
-            .receive_keys_query_response(&response)
+            .store
+            .save_account(machine.account.clone())
             .await
             .unwrap();
 
-        let device = machine
-            .store
-            .get_device(&alice_id, &alice_device_id)
+        let users: Vec<UserId> = Vec::new();
+        machine
+            .share_group_session(&room_id, &users, EncryptionSettings::default())
             .await
-            .unwrap()
             .unwrap();
-        assert_eq!(device.user_id(), &alice_id);
-        assert_eq!(device.device_id(), &alice_device_id);
-    }
 
-    #[tokio::test]
-    async fn test_missing_sessions_calculation() {
-        let (mut machine, _) = get_machine_after_query().await;
+        let content = MessageEventContent::Text(TextMessageEventContent::new_plain("Hello"));
+        machine.encrypt(&room_id, content).await.unwrap();
 
-        let alice = alice_id();
-        let alice_device = alice_device_id();
+        let session_id = machine.outbound_group_sessions[&room_id]
+            .session_id()
+            .to_owned();
+        drop(machine);
 
-        let missing_sessions = machine
-            .get_missing_sessions([alice.clone()].iter())
-            .await
-            .unwrap();
+        let mut machine = OlmMachine::new_with_default_store(
+            &user_id(),
+            DEVICE_ID,
+            tmpdir.path(),
+            "secret".to_owned(),
+        )
+        .await
+        .unwrap();
 
-        assert!(missing_sessions.contains_key(&alice));
-        let user_sessions = missing_sessions.get(&alice).unwrap();
-        assert!(user_sessions.contains_key(&alice_device));
+        assert!(!machine.should_share_group_session(&room_id));
+        let session = &machine.outbound_group_sessions[&room_id];
+        assert_eq!(session.session_id(), session_id);
+        assert_eq!(session.message_count(), 1);
+
+        assert!(machine.invalidate_group_session(&room_id).await);
+        drop(machine);
+
+        let machine = OlmMachine::new_with_default_store(
+            &user_id(),
+            DEVICE_ID,
+            tmpdir.path(),
+            "secret".to_owned(),
+        )
+        .await
+        .unwrap();
+
+        assert!(machine.should_share_group_session(&room_id));
     }
 
     #[tokio::test]
-    async fn test_session_creation() {
-        let (mut alice_machine, bob_machine, one_time_keys) = get_machine_pair().await;
+    async fn test_room_key_backup() {
+        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
+        let room_id = RoomId::try_from("!test:example.org").unwrap();
+        let recovery_key = RecoveryKey::new();
 
-        let mut bob_keys = BTreeMap::new();
+        machine
+            .create_outbound_group_session(&room_id, EncryptionSettings::default())
+            .await
+            .unwrap();
+        let session_id = machine
+            .outbound_group_sessions
+            .get(&room_id)
+            .unwrap()
+            .session_id()
+            .to_owned();
 
-        let one_time_key = one_time_keys.iter().next().unwrap();
-        let mut keys = BTreeMap::new();
-        keys.insert(one_time_key.0.clone(), one_time_key.1.clone());
-        bob_keys.insert(bob_machine.device_id.clone(), keys);
+        assert!(machine.backup_request().await.unwrap().is_none());
 
-        let mut one_time_keys = BTreeMap::new();
-        one_time_keys.insert(bob_machine.user_id.clone(), bob_keys);
+        machine.enable_backup(recovery_key.public_key(), "1".to_owned());
+        assert_eq!(machine.backup_version(), Some("1"));
 
-        let response = keys::claim_keys::Response {
-            failures: BTreeMap::new(),
-            one_time_keys,
-        };
+        let request = machine.backup_request().await.unwrap().unwrap();
+        assert_eq!(request.version, "1");
+        assert!(request.rooms[&room_id].sessions.contains_key(&session_id));
 
-        alice_machine
-            .receive_keys_claim_response(&response)
+        machine.mark_backup_as_sent(&request).await.unwrap();
+        assert!(machine.backup_request().await.unwrap().is_none());
+
+        let mut other = OlmMachine::new(&user_id(), "OTHERDEVICE");
+        let imported = other
+            .import_backed_up_room_keys(&recovery_key, "1", request.rooms.clone())
             .await
             .unwrap();
+        assert_eq!(imported, 1);
+        assert!(other.take_received_group_sessions().contains(&session_id));
 
-        let session = alice_machine
-            .store
-            .get_sessions(bob_machine.account.identity_keys().curve25519())
+        let imported = other
+            .import_backed_up_room_keys(&recovery_key, "1", request.rooms.clone())
             .await
-            .unwrap()
             .unwrap();
+        assert_eq!(imported, 0);
 
-        assert!(!session.lock().await.is_empty())
+        other.enable_backup(recovery_key.public_key(), "1".to_owned());
+        assert!(other.backup_request().await.unwrap().is_none());
+
+        let imported = OlmMachine::new(&user_id(), "THIRDDEVICE")
+            .import_backed_up_room_keys(&RecoveryKey::new(), "1", request.rooms)
+            .await
+            .unwrap();
+        assert_eq!(imported, 0);
     }
 
     #[tokio::test]
-    async fn test_olm_encryption() {
-        let (mut alice, mut bob) = get_machine_pair_with_session().await;
+    async fn test_backup_verification() {
+        let machine = OlmMachine::new(&user_id(), DEVICE_ID);
+        let other = OlmMachine::new(&user_id(), "OTHERDEVICE");
+        let recovery_key = RecoveryKey::new();
 
-        let session = alice
-            .store
-            .get_sessions(bob.account.identity_keys().curve25519())
-            .await
-            .unwrap()
+        let mut auth_data = other.backup_auth_data(&recovery_key.public_key()).await;
+        auth_data
+            .as_object_mut()
             .unwrap()
-            .lock()
-            .await[0]
-            .clone();
+            .insert("version".to_owned(), json!("1"));
+        let mut backup: BackupVersion = serde_json::from_value(auth_data).unwrap();
 
-        let bob_device = alice
-            .store
-            .get_device(&bob.user_id, &bob.device_id)
-            .await
-            .unwrap()
-            .unwrap();
+        assert!(other.verify_backup(&backup).await);
+        assert!(!machine.verify_backup(&backup).await);
 
-        let event = ToDeviceEncrypted {
-            sender: alice.user_id.clone(),
-            content: alice
-                .olm_encrypt(session, &bob_device, EventType::Dummy, json!({}))
-                .await
-                .unwrap(),
-        };
+        let device = Device::from(&other);
+        machine.store.save_devices(&[device.clone()]).await.unwrap();
+        assert!(!machine.verify_backup(&backup).await);
 
-        let event = bob.decrypt_to_device_event(&event).await.unwrap();
+        device.set_trust_state(TrustState::Verified);
+        machine.store.save_devices(&[device]).await.unwrap();
+        assert!(machine.verify_backup(&backup).await);
 
-        if let AnyToDeviceEvent::Dummy(e) = event.deserialize().unwrap() {
-            assert_eq!(e.sender, alice.user_id);
-        } else {
-            panic!("Event had the wrong type");
-        }
+        backup.auth_data.public_key = RecoveryKey::new().public_key().to_base64();
+        assert!(!machine.verify_backup(&backup).await);
     }
 
     #[tokio::test]
-    async fn test_room_key_sharing() {
-        let (mut alice, mut bob) = get_machine_pair_with_session().await;
-
+    async fn test_key_export() {
+        let mut machine = OlmMachine::new(&user_id(), DEVICE_ID);
         let room_id = RoomId::try_from("!test:example.org").unwrap();
+        let other_room_id = RoomId::try_from("!other:example.org").unwrap();
 
-        let to_device_requests = alice
-            .share_group_session(&room_id, [bob.user_id.clone()].iter())
+        machine
+            .create_outbound_group_session(&room_id, EncryptionSettings::default())
+            .await
+            .unwrap();
+        machine
+            .create_outbound_group_session(&other_room_id, EncryptionSettings::default())
             .await
             .unwrap();
 
-        let event = ToDeviceEncrypted {
-            sender: alice.user_id.clone(),
-            content: to_device_requests_to_content(to_device_requests),
-        };
-
-        let alice_session = alice.outbound_group_sessions.get(&room_id).unwrap();
+        let export = machine
+            .export_keys(|s| *s.room_id == room_id, "1234", 10)
+            .await
+            .unwrap();
 
-        let event = bob.decrypt_to_device_event(&event).await.unwrap();
+        let mut other = OlmMachine::new(&user_id(), "OTHERDEVICE");
+        assert_eq!(other.import_keys(&export, "1234").await.unwrap(), 1);
+        assert!(other.import_keys(&export, "4321").await.is_err());
 
-        if let AnyToDeviceEvent::RoomKey(e) = event.deserialize().unwrap() {
-            assert_eq!(e.sender, alice.user_id);
-            assert!(e.content.session_key.is_empty())
-        } else {
-            panic!("Event had the wrong type");
-        }
+        let session_id = machine
+            .outbound_group_sessions
+            .get(&room_id)
+            .unwrap()
+            .session_id()
+            .to_owned();
+        assert!(other.take_received_group_sessions().contains(&session_id));
 
-        let session = bob
-            .store
-            .get_inbound_group_session(
-                &room_id,
-                alice.account.identity_keys().curve25519(),
-                alice_session.session_id(),
-            )
-            .await;
+        // Importing the same room keys again doesn't replace our copies.
+        assert_eq!(other.import_keys(&export, "1234").await.unwrap(), 0);
 
-        assert!(session.unwrap().is_some());
+        let export = machine.export_keys(|_| true, "1234", 10).await.unwrap();
+        assert_eq!(other.import_keys(&export, "1234").await.unwrap(), 1);
     }
 
     #[tokio::test]
-    async fn test_megolm_encryption() {
-        let (mut alice, mut bob) = get_machine_pair_with_setup_sessions().await;
-        let room_id = RoomId::try_from("!test:example.org").unwrap();
+    async fn test_cross_signing() {
+        let mut alice = OlmMachine::new(&alice_id(), &alice_device_id());
+        let mut bob = OlmMachine::new(&user_id(), DEVICE_ID);
 
-        let to_device_requests = alice
-            .share_group_session(&room_id, [bob.user_id().clone()].iter())
+        let (alice_keys, alice_signatures) = alice.bootstrap_cross_signing().await.unwrap();
+        bob.bootstrap_cross_signing().await.unwrap();
+
+        let alice_identity = alice.get_user_identity(&alice_id()).await.unwrap().unwrap();
+        assert!(alice.is_identity_verified(&alice_identity).await.unwrap());
+
+        // Alice's device keys, signed by her self-signing key.
+        let alice_device_keys = alice_signatures[&alice_id()][&alice_device_id()].clone();
+
+        let (response, cross_signing_keys) = keys_query_response_from_json(json!({
+            "device_keys": {
+                alice_id().to_string(): {
+                    alice_device_id(): alice_device_keys
+                }
+            },
+            "master_keys": {
+                alice_id().to_string(): alice_keys.master_key
+            },
+            "self_signing_keys": {
+                alice_id().to_string(): alice_keys.self_signing_key
+            },
+        }));
+
+        bob.receive_keys_query_response(&response, &cross_signing_keys)
             .await
             .unwrap();
 
-        let event = ToDeviceEncrypted {
-            sender: alice.user_id().clone(),
-            content: to_device_requests_to_content(to_device_requests),
-        };
+        let device = bob
+            .get_device(&alice_id(), &alice_device_id())
+            .await
+            .unwrap()
+            .unwrap();
+        let identity = bob.get_user_identity(&alice_id()).await.unwrap().unwrap();
+        assert_eq!(identity.master_key(), alice_identity.master_key());
+
+        // The device is signed by Alice, but Bob didn't verify her identity yet.
+        assert!(!bob.is_identity_verified(&identity).await.unwrap());
+        assert_eq!(
+            bob.device_trust_state(&device).await.unwrap(),
+            TrustState::Unset
+        );
 
-        bob.decrypt_to_device_event(&event).await.unwrap();
+        let signatures = bob.sign_user_identity(&alice_id()).await.unwrap();
+        let master_key = alice_identity.master_key().public_key().unwrap();
+        assert!(signatures[&alice_id()].contains_key(master_key));
 
-        let plaintext = "It is a secret to everybody";
+        let identity = bob.get_user_identity(&alice_id()).await.unwrap().unwrap();
+        assert!(bob.is_identity_verified(&identity).await.unwrap());
+        assert_eq!(
+            bob.device_trust_state(&device).await.unwrap(),
+            TrustState::Verified
+        );
 
-        let content = MessageEventContent::Text(TextMessageEventContent::new_plain(plaintext));
+        // A keys query that doesn't contain our signature yet doesn't undo the
+        // verification.
+        bob.receive_keys_query_response(&response, &cross_signing_keys)
+            .await
+            .unwrap();
 
-        let encrypted_content = alice.encrypt(&room_id, content.clone()).await.unwrap();
+        let identity = bob.get_user_identity(&alice_id()).await.unwrap().unwrap();
+        assert!(bob.is_identity_verified(&identity).await.unwrap());
+    }
 
-        let event = EncryptedEvent {
-            event_id: EventId::new("example.org").unwrap(),
-            origin_server_ts: SystemTime::now(),
-            room_id: Some(room_id.clone()),
-            sender: alice.user_id().clone(),
-            content: encrypted_content,
-            unsigned: UnsignedData::default(),
-        };
+    #[tokio::test]
+    async fn test_invalid_cross_signing_keys() {
+        let mut alice = OlmMachine::new(&alice_id(), &alice_device_id());
+        let mut bob = OlmMachine::new(&user_id(), DEVICE_ID);
+
+        let (alice_keys, _) = alice.bootstrap_cross_signing().await.unwrap();
+        let (bob_keys, _) = bob.bootstrap_cross_signing().await.unwrap();
+
+        // Alice's master key with a self-signing key that wasn't signed by it.
+        let mut self_signing_key = bob_keys.self_signing_key;
+        self_signing_key.user_id = alice_id();
 
-        let decrypted_event = bob
-            .decrypt_room_event(&event)
+        let (response, cross_signing_keys) = keys_query_response_from_json(json!({
+            "device_keys": {},
+            "master_keys": {
+                alice_id().to_string(): alice_keys.master_key
+            },
+            "self_signing_keys": {
+                alice_id().to_string(): self_signing_key
+            },
+        }));
+
+        bob.receive_keys_query_response(&response, &cross_signing_keys)
             .await
-            .unwrap()
-            .deserialize()
             .unwrap();
 
-        let decrypted_event = match decrypted_event {
-            RoomEvent::RoomMessage(e) => e,
-            _ => panic!("Decrypted room event has the wrong type"),
-        };
-
-        assert_eq!(&decrypted_event.sender, alice.user_id());
-        assert_eq!(&decrypted_event.room_id, &Some(room_id));
-        if let MessageEventContent::Text(c) = &decrypted_event.content {
-            assert_eq!(&c.body, plaintext);
-        } else {
-            panic!("Decrypted event has a missmatched content");
-        }
+        assert!(bob.get_user_identity(&alice_id()).await.unwrap().is_none());
     }
 }
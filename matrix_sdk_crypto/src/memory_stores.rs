@@ -124,6 +124,16 @@ impl GroupSessionStore {
             .get(room_id)
             .and_then(|m| m.get(sender_key).and_then(|m| m.get(session_id).cloned()))
     }
+
+    /// Get all the inbound group sessions that are in the store.
+    pub fn get_all(&self) -> Vec<InboundGroupSession> {
+        self.entries
+            .values()
+            .flat_map(|m| m.values())
+            .flat_map(|m| m.values())
+            .cloned()
+            .collect()
+    }
 }
 
 /// In-memory store holding the devices of users.
@@ -214,7 +224,7 @@ mod test {
     use crate::device::test::get_device;
     use crate::memory_stores::{DeviceStore, GroupSessionStore, SessionStore};
     use crate::olm::test::get_account_and_session;
-    use crate::olm::{InboundGroupSession, OutboundGroupSession};
+    use crate::olm::{EncryptionSettings, InboundGroupSession, OutboundGroupSession};
     use matrix_sdk_common::identifiers::RoomId;
 
     #[tokio::test]
@@ -253,7 +263,7 @@ mod test {
     async fn test_group_session_store() {
         let room_id = RoomId::try_from("!test:localhost").unwrap();
 
-        let outbound = OutboundGroupSession::new(&room_id);
+        let outbound = OutboundGroupSession::new(&room_id, EncryptionSettings::default());
 
         assert_eq!(0, outbound.message_index().await);
         assert!(!outbound.shared());
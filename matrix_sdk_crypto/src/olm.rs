@@ -12,14 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use matrix_sdk_common::instant::Instant;
+use matrix_sdk_common::instant::{Duration, Instant};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::mem;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use matrix_sdk_common::locks::Mutex;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use zeroize::Zeroize;
 
 pub use olm_rs::account::IdentityKeys;
@@ -36,7 +37,8 @@ pub use olm_rs::{
 };
 
 use matrix_sdk_common::api::r0::keys::SignedKey;
-use matrix_sdk_common::identifiers::RoomId;
+use matrix_sdk_common::events::Algorithm;
+use matrix_sdk_common::identifiers::{DeviceId, RoomId, UserId};
 
 /// Account holding identity keys for which sessions can be created.
 ///
@@ -47,6 +49,7 @@ pub struct Account {
     inner: Arc<Mutex<OlmAccount>>,
     identity_keys: Arc<IdentityKeys>,
     shared: Arc<AtomicBool>,
+    upload_fallback_key: Arc<AtomicBool>,
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -55,6 +58,7 @@ impl fmt::Debug for Account {
         f.debug_struct("Account")
             .field("identity_keys", self.identity_keys())
             .field("shared", &self.shared())
+            .field("upload_fallback_key", &self.should_upload_fallback_key())
             .finish()
     }
 }
@@ -76,6 +80,7 @@ impl Account {
             inner: Arc::new(Mutex::new(account)),
             identity_keys: Arc::new(identity_keys),
             shared: Arc::new(AtomicBool::new(false)),
+            upload_fallback_key: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -119,6 +124,40 @@ impl Account {
         self.inner.lock().await.mark_keys_as_published();
     }
 
+    /// Generate a new fallback key.
+    ///
+    /// The previous fallback key is kept until the next one is generated, so
+    /// sessions that were created using it can still be accepted. The new key
+    /// needs to be uploaded, see `should_upload_fallback_key()`.
+    pub async fn generate_fallback_key(&self) {
+        self.inner.lock().await.generate_fallback_key();
+        self.upload_fallback_key.store(true, Ordering::Relaxed);
+    }
+
+    /// Does our current fallback key need to be uploaded to the server.
+    pub fn should_upload_fallback_key(&self) -> bool {
+        self.upload_fallback_key.load(Ordering::Relaxed)
+    }
+
+    /// Mark the current fallback key as being published.
+    pub fn mark_fallback_key_as_published(&self) {
+        self.upload_fallback_key.store(false, Ordering::Relaxed);
+    }
+
+    /// Get the current fallback key of the account.
+    ///
+    /// Returns a map from the key id to the curve25519 key, this is empty if no
+    /// fallback key was generated yet.
+    ///
+    /// Returns an error if the key returned by libolm couldn't be parsed.
+    pub async fn fallback_key(&self) -> Result<BTreeMap<String, String>, serde_json::Error> {
+        let fallback_key = self.inner.lock().await.fallback_key();
+        let mut keys: BTreeMap<String, BTreeMap<String, String>> =
+            serde_json::from_str(&fallback_key)?;
+
+        Ok(keys.remove("curve25519").unwrap_or_default())
+    }
+
     /// Sign the given string using the accounts signing key.
     ///
     /// Returns the signature as a base64 encoded string.
@@ -147,10 +186,14 @@ impl Account {
     ///
     /// * `shared` - Boolean determining if the account was uploaded to the
     /// server.
+    ///
+    /// * `upload_fallback_key` - Boolean determining if the current fallback
+    /// key still needs to be uploaded to the server.
     pub fn from_pickle(
         pickle: String,
         pickle_mode: PicklingMode,
         shared: bool,
+        upload_fallback_key: bool,
     ) -> Result<Self, OlmAccountError> {
         let account = OlmAccount::unpickle(pickle, pickle_mode)?;
         let identity_keys = account.parsed_identity_keys();
@@ -159,6 +202,7 @@ impl Account {
             inner: Arc::new(Mutex::new(account)),
             identity_keys: Arc::new(identity_keys),
             shared: Arc::new(AtomicBool::from(shared)),
+            upload_fallback_key: Arc::new(AtomicBool::from(upload_fallback_key)),
         })
     }
 
@@ -216,13 +260,9 @@ impl Account {
             .await
             .create_inbound_session_from(their_identity_key, message)?;
 
-        self.inner
-            .lock()
-            .await
-            .remove_one_time_keys(&session)
-            .expect(
-            "Session was successfully created but the account doesn't hold a matching one-time key",
-        );
+        // Sessions that were created using our fallback key don't use up a
+        // one-time key, there's nothing to remove in that case.
+        let _ = self.inner.lock().await.remove_one_time_keys(&session);
 
         let now = Instant::now();
         let session_id = session.session_id();
@@ -239,7 +279,9 @@ impl Account {
 
 impl PartialEq for Account {
     fn eq(&self, other: &Self) -> bool {
-        self.identity_keys() == other.identity_keys() && self.shared() == other.shared()
+        self.identity_keys() == other.identity_keys()
+            && self.shared() == other.shared()
+            && self.should_upload_fallback_key() == other.should_upload_fallback_key()
     }
 }
 
@@ -431,6 +473,44 @@ impl InboundGroupSession {
         })
     }
 
+    /// Create a new inbound group session from an exported session key.
+    ///
+    /// This is used for sessions that were forwarded to us by another device.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender_key` - The public curve25519 key of the account that
+    /// created the session.
+    ///
+    /// * `signing_key` - The public ed25519 key of the account that
+    /// created the session.
+    ///
+    /// * `room_id` - The id of the room that the session is used in.
+    ///
+    /// * `session_key` - The exported session key.
+    ///
+    /// * `forwarding_chain` - The curve25519 keys of the devices that forwarded
+    /// the session to us.
+    pub fn from_export(
+        sender_key: &str,
+        signing_key: &str,
+        room_id: &RoomId,
+        session_key: GroupSessionKey,
+        forwarding_chain: Vec<String>,
+    ) -> Result<Self, OlmGroupSessionError> {
+        let session = OlmInboundGroupSession::import(&session_key.0)?;
+        let session_id = session.session_id();
+
+        Ok(InboundGroupSession {
+            inner: Arc::new(Mutex::new(session)),
+            session_id: Arc::new(session_id),
+            sender_key: Arc::new(sender_key.to_owned()),
+            signing_key: Arc::new(signing_key.to_owned()),
+            room_id: Arc::new(room_id.clone()),
+            forwarding_chains: Arc::new(Mutex::new(Some(forwarding_chain))),
+        })
+    }
+
     /// Store the group session as a base64 encoded string.
     ///
     /// # Arguments
@@ -490,6 +570,35 @@ impl InboundGroupSession {
         self.inner.lock().await.first_known_index()
     }
 
+    /// Export the session key of this session at its first known message
+    /// index.
+    ///
+    /// The exported key can be forwarded to other devices so they can decrypt
+    /// messages encrypted with this session.
+    pub async fn export(&self) -> GroupSessionKey {
+        let session = self.inner.lock().await;
+        let index = session.first_known_index();
+
+        GroupSessionKey(
+            session
+                .export(index)
+                .expect("Can't export the session at its first known index"),
+        )
+    }
+
+    /// Get the chain of curve25519 keys of the devices that forwarded this
+    /// session to us.
+    ///
+    /// The chain is empty if we received the session directly from its
+    /// creator.
+    pub async fn forwarding_chain(&self) -> Vec<String> {
+        self.forwarding_chains
+            .lock()
+            .await
+            .clone()
+            .unwrap_or_default()
+    }
+
     /// Decrypt the given ciphertext.
     ///
     /// Returns the decrypted plaintext or an `OlmGroupSessionError` if
@@ -518,6 +627,34 @@ impl PartialEq for InboundGroupSession {
     }
 }
 
+const ROTATION_PERIOD: Duration = Duration::from_millis(604_800_000);
+const ROTATION_MESSAGES: u64 = 100;
+
+/// Settings for an encrypted room.
+///
+/// This determines the algorithm and the rotation periods of the group
+/// sessions that are used in the room.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct EncryptionSettings {
+    /// The encryption algorithm that should be used in the room.
+    pub algorithm: Algorithm,
+    /// How long a group session should be used before it is rotated.
+    pub rotation_period: Duration,
+    /// How many messages should be encrypted using a group session before it
+    /// is rotated.
+    pub rotation_period_msgs: u64,
+}
+
+impl Default for EncryptionSettings {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::MegolmV1AesSha2,
+            rotation_period: ROTATION_PERIOD,
+            rotation_period_msgs: ROTATION_MESSAGES,
+        }
+    }
+}
+
 /// Outbound group session.
 ///
 /// Outbound group sessions are used to exchange room messages between a group
@@ -531,6 +668,8 @@ pub struct OutboundGroupSession {
     creation_time: Arc<Instant>,
     message_count: Arc<AtomicUsize>,
     shared: Arc<AtomicBool>,
+    settings: Arc<EncryptionSettings>,
+    shared_with: Arc<Mutex<BTreeMap<UserId, BTreeSet<DeviceId>>>>,
 }
 
 impl OutboundGroupSession {
@@ -541,7 +680,10 @@ impl OutboundGroupSession {
     /// # Arguments
     ///
     /// * `room_id` - The id of the room that the session is used in.
-    pub fn new(room_id: &RoomId) -> Self {
+    ///
+    /// * `settings` - The settings of the room that decide when the session
+    /// should be rotated.
+    pub fn new(room_id: &RoomId, settings: EncryptionSettings) -> Self {
         let session = OlmOutboundGroupSession::new();
         let session_id = session.session_id();
 
@@ -552,9 +694,73 @@ impl OutboundGroupSession {
             creation_time: Arc::new(Instant::now()),
             message_count: Arc::new(AtomicUsize::new(0)),
             shared: Arc::new(AtomicBool::new(false)),
+            settings: Arc::new(settings),
+            shared_with: Arc::new(Mutex::new(BTreeMap::new())),
         }
     }
 
+    /// Store the group session as a base64 encoded string.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle_mode` - The mode that was used to pickle the group session,
+    /// either an unencrypted mode or an encrypted using passphrase.
+    pub async fn pickle(&self, pickle_mode: PicklingMode) -> String {
+        self.inner.lock().await.pickle(pickle_mode)
+    }
+
+    /// Restore an outbound group session from a previously pickled string.
+    ///
+    /// Returns the restored group session or a `OlmGroupSessionError` if there
+    /// was an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `pickle` - The pickled string of the group session.
+    ///
+    /// * `pickle_mode` - The mode that was used to pickle the group session,
+    /// either an unencrypted mode or an encrypted using passphrase.
+    ///
+    /// * `room_id` - The id of the room that the session is used in.
+    ///
+    /// * `settings` - The settings of the room that decide when the session
+    /// should be rotated.
+    ///
+    /// * `creation_time` - The timestamp that marks when the session was
+    /// created.
+    ///
+    /// * `message_count` - The number of messages that were encrypted using
+    /// the session.
+    ///
+    /// * `shared` - Was the session already shared.
+    ///
+    /// * `shared_with` - The devices that received the session.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_pickle(
+        pickle: String,
+        pickle_mode: PicklingMode,
+        room_id: RoomId,
+        settings: EncryptionSettings,
+        creation_time: Instant,
+        message_count: usize,
+        shared: bool,
+        shared_with: BTreeMap<UserId, BTreeSet<DeviceId>>,
+    ) -> Result<Self, OlmGroupSessionError> {
+        let session = OlmOutboundGroupSession::unpickle(pickle, pickle_mode)?;
+        let session_id = session.session_id();
+
+        Ok(OutboundGroupSession {
+            inner: Arc::new(Mutex::new(session)),
+            session_id: Arc::new(session_id),
+            room_id: Arc::new(room_id),
+            creation_time: Arc::new(creation_time),
+            message_count: Arc::new(AtomicUsize::new(message_count)),
+            shared: Arc::new(AtomicBool::new(shared)),
+            settings: Arc::new(settings),
+            shared_with: Arc::new(Mutex::new(shared_with)),
+        })
+    }
+
     /// Encrypt the given plaintext using this session.
     ///
     /// Returns the encrypted ciphertext.
@@ -564,16 +770,25 @@ impl OutboundGroupSession {
     /// * `plaintext` - The plaintext that should be encrypted.
     pub async fn encrypt(&self, plaintext: String) -> String {
         let session = self.inner.lock().await;
+        self.message_count.fetch_add(1, Ordering::SeqCst);
         session.encrypt(plaintext)
     }
 
     /// Check if the session has expired and if it should be rotated.
     ///
     /// A session will expire after some time or if enough messages have been
-    /// encrypted using it.
+    /// encrypted using it, both limits are configured by the
+    /// `EncryptionSettings` of the room.
     pub fn expired(&self) -> bool {
-        // TODO implement this.
-        false
+        let count = self.message_count.load(Ordering::SeqCst) as u64;
+
+        count >= self.settings.rotation_period_msgs
+            || self.creation_time.elapsed() >= self.settings.rotation_period
+    }
+
+    /// Get the settings that decide when the session should be rotated.
+    pub fn settings(&self) -> &EncryptionSettings {
+        &self.settings
     }
 
     /// Mark the session as shared.
@@ -589,6 +804,59 @@ impl OutboundGroupSession {
         self.shared.load(Ordering::Relaxed)
     }
 
+    /// Remember that the session was sent to the given device.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that owns the device.
+    ///
+    /// * `device_id` - The device that received the session.
+    #[allow(clippy::ptr_arg)]
+    pub async fn mark_shared_with(&self, user_id: &UserId, device_id: &DeviceId) {
+        self.shared_with
+            .lock()
+            .await
+            .entry(user_id.clone())
+            .or_insert_with(BTreeSet::new)
+            .insert(device_id.clone());
+    }
+
+    /// Check if the session was sent to the given device.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that owns the device.
+    ///
+    /// * `device_id` - The device that should be checked.
+    #[allow(clippy::ptr_arg)]
+    pub async fn is_shared_with(&self, user_id: &UserId, device_id: &DeviceId) -> bool {
+        self.shared_with
+            .lock()
+            .await
+            .get(user_id)
+            .map_or(false, |d| d.contains(device_id))
+    }
+
+    /// Get all the devices that received the session, grouped by their owner.
+    pub async fn shared_with(&self) -> BTreeMap<UserId, BTreeSet<DeviceId>> {
+        self.shared_with.lock().await.clone()
+    }
+
+    /// Get the number of messages that were encrypted using this session.
+    pub fn message_count(&self) -> usize {
+        self.message_count.load(Ordering::SeqCst)
+    }
+
+    /// Get the point in time when the session was created.
+    pub fn creation_time(&self) -> &Instant {
+        &self.creation_time
+    }
+
+    /// Get the id of the room that the session is used in.
+    pub fn room_id(&self) -> &RoomId {
+        &self.room_id
+    }
+
     /// Get the session key of this session.
     ///
     /// A session key can be used to to create an `InboundGroupSession`.
@@ -620,16 +888,21 @@ impl std::fmt::Debug for OutboundGroupSession {
             .field("room_id", &self.room_id)
             .field("creation_time", &self.creation_time)
             .field("message_count", &self.message_count)
+            .field("settings", &self.settings)
             .finish()
     }
 }
 
 #[cfg(test)]
 pub(crate) mod test {
-    use crate::olm::{Account, InboundGroupSession, OutboundGroupSession, Session};
+    use crate::olm::{
+        Account, EncryptionSettings, InboundGroupSession, OutboundGroupSession, Session,
+    };
     use matrix_sdk_common::api::r0::keys::SignedKey;
-    use matrix_sdk_common::identifiers::RoomId;
+    use matrix_sdk_common::identifiers::{RoomId, UserId};
+    use matrix_sdk_common::instant::Duration;
     use olm_rs::session::OlmMessage;
+    use olm_rs::PicklingMode;
     use std::collections::BTreeMap;
     use std::convert::TryFrom;
 
@@ -762,11 +1035,75 @@ pub(crate) mod test {
         assert_eq!(plaintext, decyrpted);
     }
 
+    #[tokio::test]
+    async fn fallback_key_session_creation() {
+        let alice = Account::new();
+        let bob = Account::new();
+        let alice_keys = alice.identity_keys();
+
+        assert!(alice.fallback_key().await.unwrap().is_empty());
+        assert!(!alice.should_upload_fallback_key());
+        alice.generate_fallback_key().await;
+        assert!(alice.should_upload_fallback_key());
+        let fallback_key = alice.fallback_key().await.unwrap();
+        assert_eq!(fallback_key.len(), 1);
+        alice.mark_keys_as_published().await;
+        alice.mark_fallback_key_as_published();
+        assert!(!alice.should_upload_fallback_key());
+
+        let fallback_key = SignedKey {
+            key: fallback_key.values().next().unwrap().to_owned(),
+            signatures: BTreeMap::new(),
+        };
+
+        let mut bob_session = bob
+            .create_outbound_session(alice_keys.curve25519(), &fallback_key)
+            .await
+            .unwrap();
+
+        let plaintext = "Hello world";
+        let message = bob_session.encrypt(plaintext).await;
+
+        let prekey_message = match message.clone() {
+            OlmMessage::PreKey(m) => m,
+            OlmMessage::Message(_) => panic!("Incorrect message type"),
+        };
+
+        let bob_keys = bob.identity_keys();
+        let mut alice_session = alice
+            .create_inbound_session(bob_keys.curve25519(), prekey_message)
+            .await
+            .unwrap();
+
+        assert_eq!(bob_session.session_id(), alice_session.session_id());
+
+        let decrypted = alice_session.decrypt(message).await.unwrap();
+        assert_eq!(plaintext, decrypted);
+
+        // The fallback key can be used multiple times until it gets rotated.
+        let carol = Account::new();
+        let mut carol_session = carol
+            .create_outbound_session(alice_keys.curve25519(), &fallback_key)
+            .await
+            .unwrap();
+        let message = carol_session.encrypt(plaintext).await;
+
+        let prekey_message = match message {
+            OlmMessage::PreKey(m) => m,
+            OlmMessage::Message(_) => panic!("Incorrect message type"),
+        };
+
+        assert!(alice
+            .create_inbound_session(carol.identity_keys().curve25519(), prekey_message)
+            .await
+            .is_ok());
+    }
+
     #[tokio::test]
     async fn group_session_creation() {
         let room_id = RoomId::try_from("!test:localhost").unwrap();
 
-        let outbound = OutboundGroupSession::new(&room_id);
+        let outbound = OutboundGroupSession::new(&room_id, EncryptionSettings::default());
 
         assert_eq!(0, outbound.message_index().await);
         assert!(!outbound.shared());
@@ -790,4 +1127,73 @@ pub(crate) mod test {
 
         assert_eq!(plaintext, inbound.decrypt(ciphertext).await.unwrap().0);
     }
+
+    #[tokio::test]
+    async fn outbound_group_session_pickling() {
+        let room_id = RoomId::try_from("!test:localhost").unwrap();
+        let user_id = UserId::try_from("@alice:example.org").unwrap();
+        let device_id = "DEVICEID".to_owned();
+
+        let outbound = OutboundGroupSession::new(&room_id, EncryptionSettings::default());
+        outbound.mark_as_shared();
+        outbound.mark_shared_with(&user_id, &device_id).await;
+        outbound.encrypt("Hello".to_owned()).await;
+        assert_eq!(outbound.message_count(), 1);
+
+        let pickle = outbound.pickle(PicklingMode::Unencrypted).await;
+        let restored = OutboundGroupSession::from_pickle(
+            pickle,
+            PicklingMode::Unencrypted,
+            room_id.clone(),
+            outbound.settings().clone(),
+            *outbound.creation_time(),
+            outbound.message_count(),
+            outbound.shared(),
+            outbound.shared_with().await,
+        )
+        .unwrap();
+
+        assert_eq!(outbound.session_id(), restored.session_id());
+        assert_eq!(
+            outbound.message_index().await,
+            restored.message_index().await
+        );
+        assert_eq!(restored.room_id(), &room_id);
+        assert_eq!(restored.message_count(), 1);
+        assert!(restored.shared());
+        assert!(restored.is_shared_with(&user_id, &device_id).await);
+        assert!(
+            !restored
+                .is_shared_with(&user_id, &"OTHERDEVICE".to_owned())
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn outbound_group_session_expiration() {
+        let room_id = RoomId::try_from("!test:localhost").unwrap();
+
+        let settings = EncryptionSettings {
+            rotation_period_msgs: 2,
+            ..Default::default()
+        };
+
+        let outbound = OutboundGroupSession::new(&room_id, settings);
+        assert!(!outbound.expired());
+        outbound.encrypt("Hello".to_owned()).await;
+        assert!(!outbound.expired());
+        outbound.encrypt("Hello".to_owned()).await;
+        assert!(outbound.expired());
+
+        let settings = EncryptionSettings {
+            rotation_period: Duration::from_millis(0),
+            ..Default::default()
+        };
+
+        let outbound = OutboundGroupSession::new(&room_id, settings);
+        assert!(outbound.expired());
+
+        let outbound = OutboundGroupSession::new(&room_id, EncryptionSettings::default());
+        assert!(!outbound.expired());
+    }
 }
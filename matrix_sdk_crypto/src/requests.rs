@@ -0,0 +1,101 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use matrix_sdk_common::api::r0::{
+    keys::{claim_keys, get_keys, upload_keys, DeviceKeys},
+    to_device::send_event_to_device,
+};
+use serde::Serialize;
+
+use crate::identities::KeysQueryCrossSigningKeys;
+use crate::machine::{FallbackKeys, OneTimeKeys};
+
+/// Keys that need to be uploaded to the server.
+///
+/// This is the body of a `POST /keys/upload` request, unlike the request type
+/// of ruma it can carry our fallback key.
+#[derive(Clone, Debug, Serialize)]
+pub struct KeysUploadRequest {
+    /// Our device keys, only set if they weren't uploaded yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_keys: Option<DeviceKeys>,
+    /// New signed one-time keys.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub one_time_keys: Option<OneTimeKeys>,
+    /// Our signed fallback key, only set if a new one needs to be uploaded.
+    #[serde(
+        rename = "org.matrix.msc2732.fallback_keys",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub fallback_keys: Option<FallbackKeys>,
+}
+
+/// The different requests the `OlmMachine` wants the client to send out.
+#[derive(Debug)]
+pub enum OutgoingRequests {
+    /// Upload our device keys, one-time keys and fallback key.
+    KeysUpload(KeysUploadRequest),
+    /// Query the device and cross signing keys of users we track.
+    KeysQuery(get_keys::Request),
+    /// Claim one-time keys to establish new Olm sessions.
+    KeysClaim(claim_keys::Request),
+    /// Send out to-device events, e.g. room keys or verification events.
+    ToDeviceRequest(send_event_to_device::Request),
+}
+
+/// A request that the `OlmMachine` wants the client to send out.
+///
+/// Once the request was sent out the response needs to be passed back to the
+/// `OlmMachine` using `mark_request_as_sent()` together with the id of the
+/// request. Requests stay in the queue of outgoing requests until then.
+#[derive(Clone, Debug)]
+pub struct OutgoingRequest {
+    request_id: String,
+    request: Arc<OutgoingRequests>,
+}
+
+impl OutgoingRequest {
+    pub(crate) fn new(request_id: String, request: OutgoingRequests) -> Self {
+        Self {
+            request_id,
+            request: Arc::new(request),
+        }
+    }
+
+    /// The unique id of the request.
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    /// The request that should be sent out.
+    pub fn request(&self) -> &OutgoingRequests {
+        &self.request
+    }
+}
+
+/// The response of a request that the `OlmMachine` wanted to be sent out.
+#[derive(Debug)]
+pub enum IncomingResponse<'a> {
+    /// The response of a keys upload request.
+    KeysUpload(&'a upload_keys::Response),
+    /// The response of a keys query request together with the cross signing
+    /// keys that are part of the same response.
+    KeysQuery(&'a get_keys::Response, &'a KeysQueryCrossSigningKeys),
+    /// The response of a keys claim request.
+    KeysClaim(&'a claim_keys::Response),
+    /// The response of a to-device request.
+    ToDevice(&'a send_event_to_device::Response),
+}
@@ -12,24 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use async_trait::async_trait;
+use dashmap::DashMap;
 use matrix_sdk_common::locks::Mutex;
 
-use super::{Account, CryptoStore, InboundGroupSession, Result, Session};
+use super::{Account, CryptoStore, InboundGroupSession, OutboundGroupSession, Result, Session};
 use crate::device::Device;
+use crate::identities::{PrivateCrossSigningIdentity, UserIdentity};
 use crate::memory_stores::{DeviceStore, GroupSessionStore, SessionStore, UserDevices};
-use matrix_sdk_common::identifiers::{DeviceId, RoomId, UserId};
+use matrix_sdk_common::identifiers::{DeviceId, EventId, RoomId, UserId};
 
 #[derive(Debug)]
 pub struct MemoryStore {
     sessions: SessionStore,
     inbound_group_sessions: GroupSessionStore,
+    outbound_group_sessions: HashMap<RoomId, OutboundGroupSession>,
+    backed_up_sessions: HashSet<(String, String)>,
+    message_indices: HashMap<(String, u32), (EventId, SystemTime)>,
     tracked_users: HashSet<UserId>,
     users_for_key_query: HashSet<UserId>,
     devices: DeviceStore,
+    identities: DashMap<UserId, UserIdentity>,
 }
 
 impl MemoryStore {
@@ -37,9 +44,13 @@ impl MemoryStore {
         MemoryStore {
             sessions: SessionStore::new(),
             inbound_group_sessions: GroupSessionStore::new(),
+            outbound_group_sessions: HashMap::new(),
+            backed_up_sessions: HashSet::new(),
+            message_indices: HashMap::new(),
             tracked_users: HashSet::new(),
             users_for_key_query: HashSet::new(),
             devices: DeviceStore::new(),
+            identities: DashMap::new(),
         }
     }
 }
@@ -81,6 +92,81 @@ impl CryptoStore for MemoryStore {
             .get(room_id, sender_key, session_id))
     }
 
+    async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
+        Ok(self.inbound_group_sessions.get_all())
+    }
+
+    async fn inbound_group_sessions_for_backup(
+        &mut self,
+        version: &str,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        Ok(self
+            .inbound_group_sessions
+            .get_all()
+            .into_iter()
+            .filter(|s| {
+                !self
+                    .backed_up_sessions
+                    .contains(&(version.to_owned(), s.session_id().to_owned()))
+            })
+            .take(limit)
+            .collect())
+    }
+
+    async fn mark_inbound_group_sessions_as_backed_up(
+        &mut self,
+        version: &str,
+        sessions: &[InboundGroupSession],
+    ) -> Result<()> {
+        for session in sessions {
+            self.backed_up_sessions
+                .insert((version.to_owned(), session.session_id().to_owned()));
+        }
+
+        Ok(())
+    }
+
+    async fn save_outbound_group_session(&mut self, session: OutboundGroupSession) -> Result<()> {
+        self.outbound_group_sessions
+            .insert(session.room_id().to_owned(), session);
+        Ok(())
+    }
+
+    async fn load_outbound_group_sessions(&mut self) -> Result<Vec<OutboundGroupSession>> {
+        Ok(self.outbound_group_sessions.values().cloned().collect())
+    }
+
+    async fn delete_outbound_group_session(&mut self, room_id: &RoomId) -> Result<()> {
+        self.outbound_group_sessions.remove(room_id);
+        Ok(())
+    }
+
+    async fn save_message_index(
+        &mut self,
+        session_id: &str,
+        message_index: u32,
+        event_id: &EventId,
+        origin_server_ts: SystemTime,
+    ) -> Result<()> {
+        self.message_indices.insert(
+            (session_id.to_owned(), message_index),
+            (event_id.clone(), origin_server_ts),
+        );
+        Ok(())
+    }
+
+    async fn get_message_index(
+        &mut self,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<(EventId, SystemTime)>> {
+        Ok(self
+            .message_indices
+            .get(&(session_id.to_owned(), message_index))
+            .cloned())
+    }
+
     fn tracked_users(&self) -> &HashSet<UserId> {
         &self.tracked_users
     }
@@ -99,6 +185,11 @@ impl CryptoStore for MemoryStore {
         Ok(self.tracked_users.insert(user.clone()))
     }
 
+    async fn remove_tracked_user(&mut self, user: &UserId) -> Result<bool> {
+        self.users_for_key_query.remove(user);
+        Ok(self.tracked_users.remove(user))
+    }
+
     #[allow(clippy::ptr_arg)]
     async fn get_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<Device>> {
         Ok(self.devices.get(user_id, device_id))
@@ -120,18 +211,42 @@ impl CryptoStore for MemoryStore {
 
         Ok(())
     }
+
+    async fn save_user_identities(&self, identities: &[UserIdentity]) -> Result<()> {
+        for identity in identities {
+            let _ = self
+                .identities
+                .insert(identity.user_id().clone(), identity.clone());
+        }
+
+        Ok(())
+    }
+
+    async fn get_user_identity(&self, user_id: &UserId) -> Result<Option<UserIdentity>> {
+        Ok(self.identities.get(user_id).map(|i| i.clone()))
+    }
+
+    async fn save_private_identity(&mut self, _: &PrivateCrossSigningIdentity) -> Result<()> {
+        Ok(())
+    }
+
+    async fn load_private_identity(&mut self) -> Result<Option<PrivateCrossSigningIdentity>> {
+        Ok(None)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use std::convert::TryFrom;
+    use std::time::SystemTime;
 
     use crate::device::test::get_device;
+    use crate::identities::PrivateCrossSigningIdentity;
     use crate::olm::test::get_account_and_session;
-    use crate::olm::{InboundGroupSession, OutboundGroupSession};
+    use crate::olm::{EncryptionSettings, InboundGroupSession, OutboundGroupSession};
     use crate::store::memorystore::MemoryStore;
     use crate::store::CryptoStore;
-    use matrix_sdk_common::identifiers::RoomId;
+    use matrix_sdk_common::identifiers::{EventId, RoomId};
 
     #[tokio::test]
     async fn test_session_store() {
@@ -159,7 +274,7 @@ mod test {
     async fn test_group_session_store() {
         let room_id = RoomId::try_from("!test:localhost").unwrap();
 
-        let outbound = OutboundGroupSession::new(&room_id);
+        let outbound = OutboundGroupSession::new(&room_id, EncryptionSettings::default());
         let inbound = InboundGroupSession::new(
             "test_key",
             "test_key",
@@ -180,6 +295,114 @@ mod test {
             .unwrap()
             .unwrap();
         assert_eq!(inbound, loaded_session);
+        assert_eq!(
+            store.get_inbound_group_sessions().await.unwrap(),
+            vec![inbound]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_group_session_backup_tracking() {
+        let room_id = RoomId::try_from("!test:localhost").unwrap();
+
+        let outbound = OutboundGroupSession::new(&room_id, EncryptionSettings::default());
+        let inbound = InboundGroupSession::new(
+            "test_key",
+            "test_key",
+            &room_id,
+            outbound.session_key().await,
+        )
+        .unwrap();
+
+        let mut store = MemoryStore::new();
+        let _ = store
+            .save_inbound_group_session(inbound.clone())
+            .await
+            .unwrap();
+
+        let sessions = store
+            .inbound_group_sessions_for_backup("1", 10)
+            .await
+            .unwrap();
+        assert_eq!(sessions, vec![inbound.clone()]);
+
+        store
+            .mark_inbound_group_sessions_as_backed_up("1", &sessions)
+            .await
+            .unwrap();
+        assert!(store
+            .inbound_group_sessions_for_backup("1", 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            store
+                .inbound_group_sessions_for_backup("2", 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_outbound_group_session_store() {
+        let room_id = RoomId::try_from("!test:localhost").unwrap();
+        let outbound = OutboundGroupSession::new(&room_id, EncryptionSettings::default());
+
+        let mut store = MemoryStore::new();
+        assert!(store
+            .load_outbound_group_sessions()
+            .await
+            .unwrap()
+            .is_empty());
+
+        store
+            .save_outbound_group_session(outbound.clone())
+            .await
+            .unwrap();
+
+        let sessions = store.load_outbound_group_sessions().await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id(), outbound.session_id());
+
+        store.delete_outbound_group_session(&room_id).await.unwrap();
+        assert!(store
+            .load_outbound_group_sessions()
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_message_index_store() {
+        let mut store = MemoryStore::new();
+        let event_id = EventId::try_from("$event:example.org").unwrap();
+        let timestamp = SystemTime::now();
+
+        assert!(store
+            .get_message_index("session_id", 0)
+            .await
+            .unwrap()
+            .is_none());
+
+        store
+            .save_message_index("session_id", 0, &event_id, timestamp)
+            .await
+            .unwrap();
+
+        let (loaded_id, loaded_timestamp) = store
+            .get_message_index("session_id", 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded_id, event_id);
+        assert_eq!(loaded_timestamp, timestamp);
+        assert!(store
+            .get_message_index("session_id", 1)
+            .await
+            .unwrap()
+            .is_none());
     }
 
     #[tokio::test]
@@ -231,5 +454,40 @@ mod test {
         let tracked_users = store.tracked_users();
 
         let _ = tracked_users.contains(device.user_id());
+
+        store
+            .update_tracked_user(device.user_id(), true)
+            .await
+            .unwrap();
+        assert!(store.remove_tracked_user(device.user_id()).await.unwrap());
+        assert!(!store.tracked_users().contains(device.user_id()));
+        assert!(!store.users_for_key_query().contains(device.user_id()));
+        assert!(!store.remove_tracked_user(device.user_id()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_user_identity_store() {
+        let device = get_device();
+        let store = MemoryStore::new();
+
+        assert!(store
+            .get_user_identity(device.user_id())
+            .await
+            .unwrap()
+            .is_none());
+
+        let identity = PrivateCrossSigningIdentity::new(device.user_id().clone()).public_identity();
+        store
+            .save_user_identities(&[identity.clone()])
+            .await
+            .unwrap();
+
+        let loaded_identity = store
+            .get_user_identity(device.user_id())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(identity, loaded_identity);
     }
 }
@@ -16,6 +16,7 @@ use core::fmt::Debug;
 use std::collections::HashSet;
 use std::io::Error as IoError;
 use std::sync::Arc;
+use std::time::SystemTime;
 use url::ParseError;
 
 use async_trait::async_trait;
@@ -24,9 +25,11 @@ use serde_json::Error as SerdeError;
 use thiserror::Error;
 
 use super::device::Device;
+use super::error::CrossSigningError;
+use super::identities::{PrivateCrossSigningIdentity, UserIdentity};
 use super::memory_stores::UserDevices;
-use super::olm::{Account, InboundGroupSession, Session};
-use matrix_sdk_common::identifiers::{DeviceId, RoomId, UserId};
+use super::olm::{Account, InboundGroupSession, OutboundGroupSession, Session};
+use matrix_sdk_common::identifiers::{DeviceId, EventId, RoomId, UserId};
 use olm_rs::errors::{OlmAccountError, OlmGroupSessionError, OlmSessionError};
 
 pub mod memorystore;
@@ -78,6 +81,10 @@ pub enum CryptoStoreError {
     /// An error occurred while parsing an URL.
     #[error(transparent)]
     UrlParse(#[from] ParseError),
+
+    /// The private cross signing keys couldn't be restored.
+    #[error(transparent)]
+    CrossSigning(#[from] CrossSigningError),
 }
 
 pub type Result<T> = std::result::Result<T, CryptoStoreError>;
@@ -135,6 +142,93 @@ pub trait CryptoStore: Debug + Send + Sync {
         session_id: &str,
     ) -> Result<Option<InboundGroupSession>>;
 
+    /// Get all the inbound group sessions we have in the store.
+    async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>>;
+
+    /// Get inbound group sessions that aren't yet backed up in the given
+    /// backup version.
+    ///
+    /// # Arguments
+    ///
+    /// * `version` - The version of the backup.
+    ///
+    /// * `limit` - The maximal number of sessions that should be returned.
+    async fn inbound_group_sessions_for_backup(
+        &mut self,
+        version: &str,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>>;
+
+    /// Mark the given inbound group sessions as backed up in the given backup
+    /// version.
+    ///
+    /// # Arguments
+    ///
+    /// * `version` - The version of the backup.
+    ///
+    /// * `sessions` - The sessions that were uploaded to the backup.
+    async fn mark_inbound_group_sessions_as_backed_up(
+        &mut self,
+        version: &str,
+        sessions: &[InboundGroupSession],
+    ) -> Result<()>;
+
+    /// Save the given outbound group session in the store.
+    ///
+    /// A room can only have a single active outbound group session, a
+    /// previously stored session for the same room will be replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - The session that should be stored.
+    async fn save_outbound_group_session(&mut self, session: OutboundGroupSession) -> Result<()>;
+
+    /// Load all the outbound group sessions that are stored.
+    async fn load_outbound_group_sessions(&mut self) -> Result<Vec<OutboundGroupSession>>;
+
+    /// Delete the outbound group session of the given room from the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room that the session belongs to.
+    async fn delete_outbound_group_session(&mut self, room_id: &RoomId) -> Result<()>;
+
+    /// Remember which event was decrypted using the given message index of an
+    /// inbound group session.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_id` - The unique id of the group session.
+    ///
+    /// * `message_index` - The message index that was used to decrypt the
+    /// event.
+    ///
+    /// * `event_id` - The unique id of the decrypted event.
+    ///
+    /// * `origin_server_ts` - The timestamp of the decrypted event.
+    async fn save_message_index(
+        &mut self,
+        session_id: &str,
+        message_index: u32,
+        event_id: &EventId,
+        origin_server_ts: SystemTime,
+    ) -> Result<()>;
+
+    /// Get the event id and timestamp of the event that was decrypted using
+    /// the given message index of an inbound group session.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_id` - The unique id of the group session.
+    ///
+    /// * `message_index` - The message index that was used to decrypt the
+    /// event.
+    async fn get_message_index(
+        &mut self,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<(EventId, SystemTime)>>;
+
     /// Get the set of tracked users.
     fn tracked_users(&self) -> &HashSet<UserId>;
 
@@ -153,6 +247,15 @@ pub trait CryptoStore: Debug + Send + Sync {
     /// * `dirty` - Should the user be also marked for a key query.
     async fn update_tracked_user(&mut self, user: &UserId, dirty: bool) -> Result<bool>;
 
+    /// Stop tracking the devices of an user.
+    ///
+    /// Returns true if the user was tracked, false otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The user that should not be tracked anymore.
+    async fn remove_tracked_user(&mut self, user: &UserId) -> Result<bool>;
+
     /// Save the given devices in the store.
     ///
     /// # Arguments
@@ -184,4 +287,32 @@ pub trait CryptoStore: Debug + Send + Sync {
     ///
     /// * `user_id` - The user for which we should get all the devices.
     async fn get_user_devices(&self, user_id: &UserId) -> Result<UserDevices>;
+
+    /// Save the given cross signing identities of users in the store.
+    ///
+    /// A previously stored identity of the same user will be replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `identities` - The identities that should be stored.
+    async fn save_user_identities(&self, identities: &[UserIdentity]) -> Result<()>;
+
+    /// Get the cross signing identity of the given user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user the identity belongs to.
+    async fn get_user_identity(&self, user_id: &UserId) -> Result<Option<UserIdentity>>;
+
+    /// Save the private keys of our own cross signing identity.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - Our private cross signing identity.
+    async fn save_private_identity(&mut self, identity: &PrivateCrossSigningIdentity)
+        -> Result<()>;
+
+    /// Load the private keys of our own cross signing identity, if they were
+    /// previously stored.
+    async fn load_private_identity(&mut self) -> Result<Option<PrivateCrossSigningIdentity>>;
 }
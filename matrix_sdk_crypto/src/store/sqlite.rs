@@ -13,26 +13,33 @@
 // limitations under the License.
 
 use matrix_sdk_common::instant::{Duration, Instant};
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::convert::TryFrom;
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use url::Url;
 
 use async_trait::async_trait;
+use dashmap::DashMap;
 use matrix_sdk_common::locks::Mutex;
 use olm_rs::PicklingMode;
 use sqlx::{query, query_as, sqlite::SqliteQueryAs, Connect, Executor, SqliteConnection};
 use zeroize::Zeroizing;
 
-use super::{Account, CryptoStore, CryptoStoreError, InboundGroupSession, Result, Session};
+use super::{
+    Account, CryptoStore, CryptoStoreError, InboundGroupSession, OutboundGroupSession, Result,
+    Session,
+};
+use crate::olm::EncryptionSettings;
 use crate::device::{Device, TrustState};
+use crate::identities::{PickledCrossSigningIdentity, PrivateCrossSigningIdentity, UserIdentity};
 use crate::memory_stores::{DeviceStore, GroupSessionStore, SessionStore, UserDevices};
 use matrix_sdk_common::api::r0::keys::KeyAlgorithm;
 use matrix_sdk_common::events::Algorithm;
-use matrix_sdk_common::identifiers::{DeviceId, RoomId, UserId};
+use matrix_sdk_common::identifiers::{DeviceId, EventId, RoomId, UserId};
 
 /// SQLite based implementation of a `CryptoStore`.
 pub struct SqliteStore {
@@ -44,6 +51,7 @@ pub struct SqliteStore {
     sessions: SessionStore,
     inbound_group_sessions: GroupSessionStore,
     devices: DeviceStore,
+    identities: DashMap<UserId, UserIdentity>,
     tracked_users: HashSet<UserId>,
     users_for_key_query: HashSet<UserId>,
 
@@ -118,6 +126,7 @@ impl SqliteStore {
             sessions: SessionStore::new(),
             inbound_group_sessions: GroupSessionStore::new(),
             devices: DeviceStore::new(),
+            identities: DashMap::new(),
             path: path.as_ref().to_owned(),
             connection: Arc::new(Mutex::new(connection)),
             pickle_passphrase: passphrase,
@@ -139,6 +148,7 @@ impl SqliteStore {
                 "device_id" TEXT NOT NULL,
                 "pickle" BLOB NOT NULL,
                 "shared" INTEGER NOT NULL,
+                "upload_fallback_key" INTEGER NOT NULL DEFAULT 0,
                 UNIQUE(user_id,device_id)
             );
         "#,
@@ -201,6 +211,68 @@ impl SqliteStore {
             )
             .await?;
 
+        connection
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS outbound_group_sessions (
+                "id" INTEGER NOT NULL PRIMARY KEY,
+                "account_id" INTEGER NOT NULL,
+                "room_id" TEXT NOT NULL,
+                "session_id" TEXT NOT NULL,
+                "pickle" BLOB NOT NULL,
+                "settings" TEXT NOT NULL,
+                "creation_time" INTEGER NOT NULL,
+                "message_count" INTEGER NOT NULL,
+                "shared" INTEGER NOT NULL,
+                "shared_with" TEXT NOT NULL,
+                FOREIGN KEY ("account_id") REFERENCES "accounts" ("id")
+                    ON DELETE CASCADE
+                UNIQUE(account_id,room_id)
+            );
+
+            CREATE INDEX IF NOT EXISTS "outbound_group_sessions_account_id" ON "outbound_group_sessions" ("account_id");
+        "#,
+            )
+            .await?;
+
+        connection
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS backed_up_group_sessions (
+                "id" INTEGER NOT NULL PRIMARY KEY,
+                "account_id" INTEGER NOT NULL,
+                "version" TEXT NOT NULL,
+                "session_id" TEXT NOT NULL,
+                FOREIGN KEY ("account_id") REFERENCES "accounts" ("id")
+                    ON DELETE CASCADE
+                UNIQUE(account_id,version,session_id)
+            );
+
+            CREATE INDEX IF NOT EXISTS "backed_up_group_sessions_account_id" ON "backed_up_group_sessions" ("account_id");
+        "#,
+            )
+            .await?;
+
+        connection
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS message_indices (
+                "id" INTEGER NOT NULL PRIMARY KEY,
+                "account_id" INTEGER NOT NULL,
+                "session_id" TEXT NOT NULL,
+                "message_index" INTEGER NOT NULL,
+                "event_id" TEXT NOT NULL,
+                "origin_server_ts" INTEGER NOT NULL,
+                FOREIGN KEY ("account_id") REFERENCES "accounts" ("id")
+                    ON DELETE CASCADE
+                UNIQUE(account_id,session_id,message_index)
+            );
+
+            CREATE INDEX IF NOT EXISTS "message_indices_account_id" ON "message_indices" ("account_id");
+        "#,
+            )
+            .await?;
+
         connection
             .execute(
                 r#"
@@ -256,6 +328,58 @@ impl SqliteStore {
             )
             .await?;
 
+        connection
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS device_signatures (
+                "id" INTEGER NOT NULL PRIMARY KEY,
+                "device_id" INTEGER NOT NULL,
+                "user_id" TEXT NOT NULL,
+                "key_id" TEXT NOT NULL,
+                "signature" TEXT NOT NULL,
+                FOREIGN KEY ("device_id") REFERENCES "devices" ("id")
+                    ON DELETE CASCADE
+                UNIQUE(device_id, user_id, key_id)
+            );
+
+            CREATE INDEX IF NOT EXISTS "device_signatures_device_id" ON "device_signatures" ("device_id");
+        "#,
+            )
+            .await?;
+
+        connection
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS user_identities (
+                "id" INTEGER NOT NULL PRIMARY KEY,
+                "account_id" INTEGER NOT NULL,
+                "user_id" TEXT NOT NULL,
+                "identity" TEXT NOT NULL,
+                FOREIGN KEY ("account_id") REFERENCES "accounts" ("id")
+                    ON DELETE CASCADE
+                UNIQUE(account_id,user_id)
+            );
+
+            CREATE INDEX IF NOT EXISTS "user_identities_account_id" ON "user_identities" ("account_id");
+        "#,
+            )
+            .await?;
+
+        connection
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS private_identities (
+                "id" INTEGER NOT NULL PRIMARY KEY,
+                "account_id" INTEGER NOT NULL,
+                "pickle" TEXT NOT NULL,
+                FOREIGN KEY ("account_id") REFERENCES "accounts" ("id")
+                    ON DELETE CASCADE
+                UNIQUE(account_id)
+            );
+        "#,
+            )
+            .await?;
+
         Ok(())
     }
 
@@ -371,6 +495,23 @@ impl SqliteStore {
         Ok(())
     }
 
+    async fn delete_tracked_user(&self, user: &UserId) -> Result<()> {
+        let account_id = self.account_id.ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        query(
+            "DELETE FROM tracked_users
+             WHERE account_id = ?1 and user_id = ?2
+             ",
+        )
+        .bind(account_id)
+        .bind(user.to_string())
+        .execute(&mut *connection)
+        .await?;
+
+        Ok(())
+    }
+
     async fn load_tracked_users(&self) -> Result<(HashSet<UserId>, HashSet<UserId>)> {
         let account_id = self.account_id.ok_or(CryptoStoreError::AccountUnset)?;
         let mut connection = self.connection.lock().await;
@@ -465,6 +606,28 @@ impl SqliteStore {
                 keys.insert(algorithm, key.to_owned());
             }
 
+            let signature_rows: Vec<(String, String, String)> = query_as(
+                "SELECT user_id, key_id, signature FROM device_signatures WHERE device_id = ?",
+            )
+            .bind(device_row_id)
+            .fetch_all(&mut *connection)
+            .await?;
+
+            let mut signatures: BTreeMap<UserId, BTreeMap<String, String>> = BTreeMap::new();
+
+            for (signer, key_id, signature) in signature_rows {
+                let signer = if let Ok(u) = UserId::try_from(signer.as_str()) {
+                    u
+                } else {
+                    continue;
+                };
+
+                signatures
+                    .entry(signer)
+                    .or_insert_with(BTreeMap::new)
+                    .insert(key_id, signature);
+            }
+
             let device = Device::new(
                 user_id,
                 device_id.to_owned(),
@@ -472,6 +635,7 @@ impl SqliteStore {
                 trust_state,
                 algorithms,
                 keys,
+                signatures,
             );
 
             store.add(device);
@@ -541,9 +705,53 @@ impl SqliteStore {
             .await?;
         }
 
+        // Signatures that aren't part of the device anymore, e.g. because our
+        // cross signing keys changed, need to go away.
+        query("DELETE FROM device_signatures WHERE device_id = ?")
+            .bind(device_row_id)
+            .execute(&mut *connection)
+            .await?;
+
+        for (signer, signatures) in device.signatures() {
+            for (key_id, signature) in signatures {
+                query(
+                    "INSERT INTO device_signatures (
+                        device_id, user_id, key_id, signature
+                     ) VALUES (?1, ?2, ?3, ?4)
+                     ",
+                )
+                .bind(device_row_id)
+                .bind(signer.to_string())
+                .bind(key_id)
+                .bind(signature)
+                .execute(&mut *connection)
+                .await?;
+            }
+        }
+
         Ok(())
     }
 
+    async fn load_user_identities(&self) -> Result<DashMap<UserId, UserIdentity>> {
+        let account_id = self.account_id.ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        let rows: Vec<(String,)> =
+            query_as("SELECT identity FROM user_identities WHERE account_id = ?")
+                .bind(account_id)
+                .fetch_all(&mut *connection)
+                .await?;
+
+        let identities = DashMap::new();
+
+        for row in rows {
+            let identity: UserIdentity = serde_json::from_str(&row.0)?;
+            identities.insert(identity.user_id().clone(), identity);
+        }
+
+        Ok(identities)
+    }
+
     fn get_pickle_mode(&self) -> PicklingMode {
         match &self.pickle_passphrase {
             Some(p) => PicklingMode::Encrypted {
@@ -559,8 +767,8 @@ impl CryptoStore for SqliteStore {
     async fn load_account(&mut self) -> Result<Option<Account>> {
         let mut connection = self.connection.lock().await;
 
-        let row: Option<(i64, String, bool)> = query_as(
-            "SELECT id, pickle, shared FROM accounts
+        let row: Option<(i64, String, bool, bool)> = query_as(
+            "SELECT id, pickle, shared, upload_fallback_key FROM accounts
                       WHERE user_id = ? and device_id = ?",
         )
         .bind(&*self.user_id)
@@ -568,12 +776,13 @@ impl CryptoStore for SqliteStore {
         .fetch_optional(&mut *connection)
         .await?;
 
-        let result = if let Some((id, pickle, shared)) = row {
+        let result = if let Some((id, pickle, shared, upload_fallback_key)) = row {
             self.account_id = Some(id);
             Some(Account::from_pickle(
                 pickle,
                 self.get_pickle_mode(),
                 shared,
+                upload_fallback_key,
             )?)
         } else {
             return Ok(None);
@@ -593,6 +802,9 @@ impl CryptoStore for SqliteStore {
         let devices = self.load_devices().await?;
         mem::replace(&mut self.devices, devices);
 
+        let identities = self.load_user_identities().await?;
+        mem::replace(&mut self.identities, identities);
+
         let (tracked_users, users_for_query) = self.load_tracked_users().await?;
         mem::replace(&mut self.tracked_users, tracked_users);
         mem::replace(&mut self.users_for_key_query, users_for_query);
@@ -606,17 +818,19 @@ impl CryptoStore for SqliteStore {
 
         query(
             "INSERT INTO accounts (
-                user_id, device_id, pickle, shared
-             ) VALUES (?1, ?2, ?3, ?4)
+                user_id, device_id, pickle, shared, upload_fallback_key
+             ) VALUES (?1, ?2, ?3, ?4, ?5)
              ON CONFLICT(user_id, device_id) DO UPDATE SET
                 pickle = excluded.pickle,
-                shared = excluded.shared
+                shared = excluded.shared,
+                upload_fallback_key = excluded.upload_fallback_key
              ",
         )
         .bind(&*self.user_id.to_string())
         .bind(&*self.device_id.to_string())
         .bind(&pickle)
         .bind(account.shared())
+        .bind(account.should_upload_fallback_key())
         .execute(&mut *connection)
         .await?;
 
@@ -707,6 +921,238 @@ impl CryptoStore for SqliteStore {
             .get(room_id, sender_key, session_id))
     }
 
+    async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
+        Ok(self.inbound_group_sessions.get_all())
+    }
+
+    async fn inbound_group_sessions_for_backup(
+        &mut self,
+        version: &str,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        let account_id = self.account_id.ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        let rows: Vec<(String,)> = query_as(
+            "SELECT session_id FROM backed_up_group_sessions
+             WHERE account_id = ? and version = ?",
+        )
+        .bind(account_id)
+        .bind(version)
+        .fetch_all(&mut *connection)
+        .await?;
+
+        let backed_up: HashSet<String> = rows.into_iter().map(|row| row.0).collect();
+
+        Ok(self
+            .inbound_group_sessions
+            .get_all()
+            .into_iter()
+            .filter(|s| !backed_up.contains(s.session_id()))
+            .take(limit)
+            .collect())
+    }
+
+    async fn mark_inbound_group_sessions_as_backed_up(
+        &mut self,
+        version: &str,
+        sessions: &[InboundGroupSession],
+    ) -> Result<()> {
+        let account_id = self.account_id.ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        for session in sessions {
+            query(
+                "INSERT OR IGNORE INTO backed_up_group_sessions (
+                    account_id, version, session_id
+                 ) VALUES (?1, ?2, ?3)",
+            )
+            .bind(account_id)
+            .bind(version)
+            .bind(session.session_id())
+            .execute(&mut *connection)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn save_outbound_group_session(&mut self, session: OutboundGroupSession) -> Result<()> {
+        let account_id = self.account_id.ok_or(CryptoStoreError::AccountUnset)?;
+        let pickle = session.pickle(self.get_pickle_mode()).await;
+
+        // Instants can't be persisted, store the wall clock time of the
+        // session creation so the age of the session survives restarts.
+        let creation_time = SystemTime::now()
+            .checked_sub(session.creation_time().elapsed())
+            .ok_or(CryptoStoreError::SessionTimestampError)?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+
+        let shared_with: Vec<(UserId, DeviceId)> = session
+            .shared_with()
+            .await
+            .into_iter()
+            .flat_map(|(user_id, devices)| devices.into_iter().map(move |d| (user_id.clone(), d)))
+            .collect();
+        let shared_with = serde_json::to_string(&shared_with)?;
+        let settings = serde_json::to_string(session.settings())?;
+
+        let mut connection = self.connection.lock().await;
+
+        query(
+            "INSERT INTO outbound_group_sessions (
+                account_id, room_id, session_id, pickle, settings,
+                creation_time, message_count, shared, shared_with
+             ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+             ON CONFLICT(account_id, room_id) DO UPDATE SET
+                session_id = excluded.session_id,
+                pickle = excluded.pickle,
+                settings = excluded.settings,
+                creation_time = excluded.creation_time,
+                message_count = excluded.message_count,
+                shared = excluded.shared,
+                shared_with = excluded.shared_with
+             ",
+        )
+        .bind(account_id)
+        .bind(session.room_id().to_string())
+        .bind(session.session_id())
+        .bind(&pickle)
+        .bind(&settings)
+        .bind(creation_time)
+        .bind(session.message_count() as i64)
+        .bind(session.shared())
+        .bind(&shared_with)
+        .execute(&mut *connection)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn load_outbound_group_sessions(&mut self) -> Result<Vec<OutboundGroupSession>> {
+        let account_id = self.account_id.ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        let rows: Vec<(String, String, String, i64, i64, bool, String)> = query_as(
+            "SELECT room_id, pickle, settings, creation_time, message_count, shared, shared_with
+             FROM outbound_group_sessions WHERE account_id = ?",
+        )
+        .bind(account_id)
+        .fetch_all(&mut *connection)
+        .await?;
+
+        let now = SystemTime::now();
+
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let room_id = &row.0;
+                let pickle = &row.1;
+                let settings: EncryptionSettings = serde_json::from_str(&row.2)?;
+                let created = UNIX_EPOCH + Duration::from_millis(row.3 as u64);
+                let creation_time = Instant::now()
+                    .checked_sub(now.duration_since(created).unwrap_or_default())
+                    .ok_or(CryptoStoreError::SessionTimestampError)?;
+
+                let devices: Vec<(UserId, DeviceId)> = serde_json::from_str(&row.6)?;
+                let mut shared_with: BTreeMap<UserId, BTreeSet<DeviceId>> = BTreeMap::new();
+
+                for (user_id, device_id) in devices {
+                    shared_with
+                        .entry(user_id)
+                        .or_insert_with(BTreeSet::new)
+                        .insert(device_id);
+                }
+
+                Ok(OutboundGroupSession::from_pickle(
+                    pickle.to_string(),
+                    self.get_pickle_mode(),
+                    RoomId::try_from(room_id.as_str()).unwrap(),
+                    settings,
+                    creation_time,
+                    row.4 as usize,
+                    row.5,
+                    shared_with,
+                )?)
+            })
+            .collect::<Result<Vec<OutboundGroupSession>>>()?)
+    }
+
+    async fn delete_outbound_group_session(&mut self, room_id: &RoomId) -> Result<()> {
+        let account_id = self.account_id.ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        query("DELETE FROM outbound_group_sessions WHERE account_id = ?1 and room_id = ?2")
+            .bind(account_id)
+            .bind(room_id.to_string())
+            .execute(&mut *connection)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn save_message_index(
+        &mut self,
+        session_id: &str,
+        message_index: u32,
+        event_id: &EventId,
+        origin_server_ts: SystemTime,
+    ) -> Result<()> {
+        let account_id = self.account_id.ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        let timestamp = origin_server_ts
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+
+        query(
+            "INSERT INTO message_indices (
+                account_id, session_id, message_index, event_id, origin_server_ts
+             ) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(account_id, session_id, message_index) DO UPDATE SET
+                event_id = excluded.event_id,
+                origin_server_ts = excluded.origin_server_ts
+             ",
+        )
+        .bind(account_id)
+        .bind(session_id)
+        .bind(i64::from(message_index))
+        .bind(event_id.to_string())
+        .bind(timestamp)
+        .execute(&mut *connection)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_message_index(
+        &mut self,
+        session_id: &str,
+        message_index: u32,
+    ) -> Result<Option<(EventId, SystemTime)>> {
+        let account_id = self.account_id.ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        let row: Option<(String, i64)> = query_as(
+            "SELECT event_id, origin_server_ts FROM message_indices
+             WHERE account_id = ? and session_id = ? and message_index = ?",
+        )
+        .bind(account_id)
+        .bind(session_id)
+        .bind(i64::from(message_index))
+        .fetch_optional(&mut *connection)
+        .await?;
+
+        Ok(row.and_then(|(event_id, timestamp)| {
+            let event_id = EventId::try_from(event_id.as_str()).ok()?;
+            let timestamp = UNIX_EPOCH + Duration::from_millis(timestamp as u64);
+            Some((event_id, timestamp))
+        }))
+    }
+
     fn tracked_users(&self) -> &HashSet<UserId> {
         &self.tracked_users
     }
@@ -729,6 +1175,15 @@ impl CryptoStore for SqliteStore {
         Ok(already_added)
     }
 
+    async fn remove_tracked_user(&mut self, user: &UserId) -> Result<bool> {
+        let was_tracked = self.tracked_users.remove(user);
+        self.users_for_key_query.remove(user);
+
+        self.delete_tracked_user(user).await?;
+
+        Ok(was_tracked)
+    }
+
     async fn save_devices(&self, devices: &[Device]) -> Result<()> {
         // TODO turn this into a bulk transaction.
         for device in devices {
@@ -765,6 +1220,87 @@ impl CryptoStore for SqliteStore {
     async fn get_user_devices(&self, user_id: &UserId) -> Result<UserDevices> {
         Ok(self.devices.user_devices(user_id))
     }
+
+    async fn save_user_identities(&self, identities: &[UserIdentity]) -> Result<()> {
+        let account_id = self.account_id.ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        for identity in identities {
+            query(
+                "INSERT INTO user_identities (
+                    account_id, user_id, identity
+                 ) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(account_id, user_id) DO UPDATE SET
+                    identity = excluded.identity
+                 ",
+            )
+            .bind(account_id)
+            .bind(identity.user_id().to_string())
+            .bind(serde_json::to_string(identity)?)
+            .execute(&mut *connection)
+            .await?;
+
+            self.identities
+                .insert(identity.user_id().clone(), identity.clone());
+        }
+
+        Ok(())
+    }
+
+    async fn get_user_identity(&self, user_id: &UserId) -> Result<Option<UserIdentity>> {
+        Ok(self.identities.get(user_id).map(|i| i.clone()))
+    }
+
+    async fn save_private_identity(
+        &mut self,
+        identity: &PrivateCrossSigningIdentity,
+    ) -> Result<()> {
+        let account_id = self.account_id.ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        let pickle = Zeroizing::new(serde_json::to_string(
+            &identity.pickle(self.get_pickle_mode()),
+        )?);
+
+        query(
+            "INSERT INTO private_identities (
+                account_id, pickle
+             ) VALUES (?1, ?2)
+             ON CONFLICT(account_id) DO UPDATE SET
+                pickle = excluded.pickle
+             ",
+        )
+        .bind(account_id)
+        .bind(&*pickle)
+        .execute(&mut *connection)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn load_private_identity(&mut self) -> Result<Option<PrivateCrossSigningIdentity>> {
+        let account_id = self.account_id.ok_or(CryptoStoreError::AccountUnset)?;
+        let mut connection = self.connection.lock().await;
+
+        let row: Option<(String,)> =
+            query_as("SELECT pickle FROM private_identities WHERE account_id = ?")
+                .bind(account_id)
+                .fetch_optional(&mut *connection)
+                .await?;
+
+        let pickle = if let Some(row) = row {
+            Zeroizing::new(row.0)
+        } else {
+            return Ok(None);
+        };
+
+        let pickle: PickledCrossSigningIdentity = serde_json::from_str(&pickle)?;
+
+        Ok(Some(PrivateCrossSigningIdentity::from_pickle(
+            &pickle,
+            self.get_pickle_mode(),
+        )?))
+    }
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -781,6 +1317,7 @@ impl std::fmt::Debug for SqliteStore {
 #[cfg(test)]
 mod test {
     use crate::device::test::get_device;
+    use crate::identities::PrivateCrossSigningIdentity;
     use crate::olm::GroupSessionKey;
     use matrix_sdk_common::api::r0::keys::SignedKey;
     use olm_rs::outbound_group_session::OlmOutboundGroupSession;
@@ -788,7 +1325,8 @@ mod test {
     use tempfile::tempdir;
 
     use super::{
-        Account, CryptoStore, InboundGroupSession, RoomId, Session, SqliteStore, TryFrom, UserId,
+        Account, CryptoStore, Device, Duration, EncryptionSettings, EventId, InboundGroupSession,
+        OutboundGroupSession, RoomId, Session, SqliteStore, TryFrom, UserId, UNIX_EPOCH,
     };
 
     static USER_ID: &str = "@example:localhost";
@@ -925,6 +1463,7 @@ mod test {
             .expect("Can't save account");
 
         account.mark_as_shared();
+        account.generate_fallback_key().await;
 
         store
             .save_account(account.clone())
@@ -935,6 +1474,7 @@ mod test {
         let loaded_account = loaded_account.unwrap();
 
         assert_eq!(account, loaded_account);
+        assert!(loaded_account.should_upload_fallback_key());
     }
 
     #[tokio::test]
@@ -1058,6 +1598,146 @@ mod test {
             .unwrap()
             .unwrap();
         assert_eq!(session, loaded_session);
+
+        let sessions = store.get_inbound_group_sessions().await.unwrap();
+        assert_eq!(sessions, vec![session]);
+    }
+
+    #[tokio::test]
+    async fn group_session_backup_tracking() {
+        let (account, mut store, dir) = get_loaded_store().await;
+
+        let identity_keys = account.identity_keys();
+        let outbound_session = OlmOutboundGroupSession::new();
+        let session = InboundGroupSession::new(
+            identity_keys.curve25519(),
+            identity_keys.ed25519(),
+            &RoomId::try_from("!test:localhost").unwrap(),
+            GroupSessionKey(outbound_session.session_key()),
+        )
+        .expect("Can't create session");
+
+        store
+            .save_inbound_group_session(session.clone())
+            .await
+            .expect("Can't save group session");
+
+        let sessions = store
+            .inbound_group_sessions_for_backup("1", 10)
+            .await
+            .unwrap();
+        assert_eq!(sessions, vec![session.clone()]);
+
+        store
+            .mark_inbound_group_sessions_as_backed_up("1", &sessions)
+            .await
+            .unwrap();
+
+        drop(store);
+
+        let user_id = UserId::try_from(USER_ID).unwrap();
+        let mut store = SqliteStore::open(&user_id, DEVICE_ID, dir.path())
+            .await
+            .expect("Can't create store");
+        store.load_account().await.unwrap();
+
+        assert!(store
+            .inbound_group_sessions_for_backup("1", 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            store
+                .inbound_group_sessions_for_backup("2", 10)
+                .await
+                .unwrap(),
+            vec![session]
+        );
+    }
+
+    #[tokio::test]
+    async fn outbound_group_session_saving() {
+        let (_account, mut store, dir) = get_loaded_store().await;
+        let room_id = RoomId::try_from("!test:localhost").unwrap();
+        let user_id = UserId::try_from(USER_ID).unwrap();
+        let device_id = DEVICE_ID.to_owned();
+
+        let settings = EncryptionSettings {
+            rotation_period_msgs: 10,
+            ..Default::default()
+        };
+        let session = OutboundGroupSession::new(&room_id, settings.clone());
+        session.mark_as_shared();
+        session.mark_shared_with(&user_id, &device_id).await;
+        session.encrypt("Hello".to_owned()).await;
+
+        store
+            .save_outbound_group_session(session.clone())
+            .await
+            .expect("Can't save outbound group session");
+
+        drop(store);
+
+        let mut store = SqliteStore::open(&user_id, DEVICE_ID, dir.path())
+            .await
+            .expect("Can't create store");
+
+        store.load_account().await.unwrap();
+
+        let sessions = store.load_outbound_group_sessions().await.unwrap();
+        assert_eq!(sessions.len(), 1);
+
+        let loaded = &sessions[0];
+        assert_eq!(loaded.session_id(), session.session_id());
+        assert_eq!(loaded.room_id(), &room_id);
+        assert_eq!(loaded.message_count(), 1);
+        assert_eq!(loaded.settings(), &settings);
+        assert_eq!(loaded.message_index().await, session.message_index().await);
+        assert!(loaded.shared());
+        assert!(loaded.is_shared_with(&user_id, &device_id).await);
+
+        store.delete_outbound_group_session(&room_id).await.unwrap();
+        assert!(store
+            .load_outbound_group_sessions()
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn message_index_saving() {
+        let (_account, mut store, dir) = get_loaded_store().await;
+        let event_id = EventId::try_from("$event:example.org").unwrap();
+        let timestamp = UNIX_EPOCH + Duration::from_millis(1_590_000_000_000);
+
+        assert!(store
+            .get_message_index("session_id", 0)
+            .await
+            .unwrap()
+            .is_none());
+
+        store
+            .save_message_index("session_id", 0, &event_id, timestamp)
+            .await
+            .unwrap();
+
+        drop(store);
+
+        let mut store =
+            SqliteStore::open(&UserId::try_from(USER_ID).unwrap(), DEVICE_ID, dir.path())
+                .await
+                .expect("Can't create store");
+
+        store.load_account().await.unwrap();
+
+        let (loaded_id, loaded_timestamp) = store
+            .get_message_index("session_id", 0)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(loaded_id, event_id);
+        assert_eq!(loaded_timestamp, timestamp);
     }
 
     #[tokio::test]
@@ -1110,6 +1790,17 @@ mod test {
         store.load_account().await.unwrap();
 
         assert!(!store.users_for_key_query().contains(device.user_id()));
+        assert!(store.remove_tracked_user(device.user_id()).await.unwrap());
+        assert!(!store.tracked_users().contains(device.user_id()));
+
+        let mut store =
+            SqliteStore::open(&UserId::try_from(USER_ID).unwrap(), DEVICE_ID, dir.path())
+                .await
+                .expect("Can't create store");
+
+        store.load_account().await.unwrap();
+
+        assert!(!store.tracked_users().contains(device.user_id()));
     }
 
     #[tokio::test]
@@ -1141,12 +1832,50 @@ mod test {
         }
         assert_eq!(device.algorithms().len(), loaded_device.algorithms().len());
         assert_eq!(device.keys(), loaded_device.keys());
+        assert_eq!(device.signatures(), loaded_device.signatures());
 
         let user_devices = store.get_user_devices(device.user_id()).await.unwrap();
         assert_eq!(user_devices.keys().nth(0).unwrap(), device.device_id());
         assert_eq!(user_devices.devices().nth(0).unwrap(), &device);
     }
 
+    #[tokio::test]
+    async fn device_signature_removal() {
+        let (_account, store, dir) = get_loaded_store().await;
+        let device = get_device();
+        assert!(!device.signatures().is_empty());
+
+        store.save_devices(&[device.clone()]).await.unwrap();
+
+        let unsigned_device = Device::new(
+            device.user_id().clone(),
+            device.device_id().clone(),
+            device.display_name().clone(),
+            device.trust_state(),
+            device.algorithms().to_vec(),
+            device.keys().clone(),
+            BTreeMap::new(),
+        );
+        store.save_devices(&[unsigned_device]).await.unwrap();
+
+        drop(store);
+
+        let mut store =
+            SqliteStore::open(&UserId::try_from(USER_ID).unwrap(), DEVICE_ID, dir.path())
+                .await
+                .expect("Can't create store");
+
+        store.load_account().await.unwrap();
+
+        let loaded_device = store
+            .get_device(device.user_id(), device.device_id())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(loaded_device.signatures().is_empty());
+    }
+
     #[tokio::test]
     async fn device_deleting() {
         let (_account, store, dir) = get_loaded_store().await;
@@ -1169,4 +1898,41 @@ mod test {
 
         assert!(loaded_device.is_none());
     }
+
+    #[tokio::test]
+    async fn cross_signing_identity_saving() {
+        let (_account, mut store, dir) = get_loaded_store().await;
+        let user_id = UserId::try_from(USER_ID).unwrap();
+
+        assert!(store.load_private_identity().await.unwrap().is_none());
+
+        let private_identity = PrivateCrossSigningIdentity::new(user_id.clone());
+        let identity = private_identity.public_identity();
+
+        store
+            .save_private_identity(&private_identity)
+            .await
+            .unwrap();
+        store
+            .save_user_identities(&[identity.clone()])
+            .await
+            .unwrap();
+
+        drop(store);
+
+        let mut store = SqliteStore::open(&user_id, DEVICE_ID, dir.path())
+            .await
+            .expect("Can't create store");
+
+        store.load_account().await.unwrap();
+
+        let loaded_identity = store.get_user_identity(&user_id).await.unwrap().unwrap();
+        assert_eq!(identity, loaded_identity);
+
+        let loaded_private_identity = store.load_private_identity().await.unwrap().unwrap();
+        assert_eq!(
+            private_identity.master_public_key(),
+            loaded_private_identity.master_public_key()
+        );
+    }
 }
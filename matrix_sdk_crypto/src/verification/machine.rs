@@ -0,0 +1,500 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use tracing::{trace, warn};
+
+use matrix_sdk_common::api::r0::to_device::send_event_to_device::Request as ToDeviceRequest;
+use matrix_sdk_common::events::{
+    key::verification::{
+        cancel::{CancelCode, CancelEventContent},
+        start::StartEventContent,
+        VerificationMethod,
+    },
+    to_device::AnyToDeviceEvent as ToDeviceEvent,
+    EventType,
+};
+use matrix_sdk_common::identifiers::{DeviceId, UserId};
+
+use super::{content_to_request, sas::Sas};
+use crate::device::Device;
+use crate::store::{CryptoStore, Result as StoreResult};
+
+/// Keeps track of all the verification flows of our own device.
+#[derive(Clone, Debug)]
+pub(crate) struct VerificationMachine {
+    user_id: Arc<UserId>,
+    device_id: Arc<DeviceId>,
+    ed25519_key: Arc<String>,
+    verifications: Arc<DashMap<String, Sas>>,
+    requests: Arc<DashMap<String, Device>>,
+    outgoing_to_device_messages: Arc<DashMap<String, ToDeviceRequest>>,
+}
+
+impl VerificationMachine {
+    pub(crate) fn new(user_id: UserId, device_id: DeviceId, ed25519_key: &str) -> Self {
+        Self {
+            user_id: Arc::new(user_id),
+            device_id: Arc::new(device_id),
+            ed25519_key: Arc::new(ed25519_key.to_owned()),
+            verifications: Arc::new(DashMap::new()),
+            requests: Arc::new(DashMap::new()),
+            outgoing_to_device_messages: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Start a new SAS verification flow with the given device.
+    ///
+    /// The start event will be queued up in the outgoing to-device requests.
+    pub(crate) fn start_sas(&self, device: Device) -> Sas {
+        self.start_sas_helper(device, None)
+    }
+
+    fn start_sas_helper(&self, device: Device, flow_id: Option<String>) -> Sas {
+        let (sas, request) = Sas::start(
+            &self.user_id,
+            &self.device_id,
+            &self.ed25519_key,
+            device,
+            flow_id,
+        );
+
+        self.verifications
+            .insert(sas.flow_id().to_owned(), sas.clone());
+        self.queue_up_request(request);
+
+        sas
+    }
+
+    /// Accept a verification request that another device sent us.
+    ///
+    /// This starts a SAS verification flow that uses the transaction id of the
+    /// request, returns None if no such request is known.
+    pub(crate) fn accept_request(&self, flow_id: &str) -> Option<Sas> {
+        let (flow_id, device) = self.requests.remove(flow_id)?;
+        Some(self.start_sas_helper(device, Some(flow_id)))
+    }
+
+    /// Get the verification flow with the given flow id.
+    pub(crate) fn get_sas(&self, flow_id: &str) -> Option<Sas> {
+        self.verifications.get(flow_id).map(|s| s.clone())
+    }
+
+    /// Accept the verification flow with the given flow id.
+    ///
+    /// Returns true if an accept event got queued up.
+    pub(crate) fn accept_sas(&self, flow_id: &str) -> bool {
+        self.get_sas(flow_id)
+            .and_then(|s| s.accept())
+            .map(|r| self.queue_up_request(r))
+            .is_some()
+    }
+
+    /// Cancel the verification flow with the given flow id.
+    ///
+    /// Returns true if a cancel event got queued up.
+    pub(crate) fn cancel_sas(&self, flow_id: &str) -> bool {
+        self.get_sas(flow_id)
+            .and_then(|s| s.cancel())
+            .map(|r| self.queue_up_request(r))
+            .is_some()
+    }
+
+    /// Confirm that the short authentication strings of the given verification
+    /// flow match.
+    ///
+    /// If the flow finishes with the confirmation the verified device will be
+    /// saved in the given store.
+    ///
+    /// Returns true if a MAC event got queued up.
+    pub(crate) async fn confirm_sas(
+        &self,
+        store: &dyn CryptoStore,
+        flow_id: &str,
+    ) -> StoreResult<bool> {
+        let sas = if let Some(s) = self.get_sas(flow_id) {
+            s
+        } else {
+            return Ok(false);
+        };
+
+        let request = if let Some(r) = sas.confirm() {
+            r
+        } else {
+            return Ok(false);
+        };
+
+        self.queue_up_request(request);
+
+        if sas.is_done() {
+            store.save_devices(&[sas.other_device()]).await?;
+        }
+
+        Ok(true)
+    }
+
+    /// Create a `m.key.verification.cancel` request with the
+    /// `m.unexpected_message` code for a flow we don't want to touch.
+    fn unexpected_message(
+        recipient: &UserId,
+        recipient_device: &DeviceId,
+        flow_id: &str,
+    ) -> ToDeviceRequest {
+        let content = CancelEventContent {
+            transaction_id: flow_id.to_owned(),
+            reason: "Unexpected message".to_owned(),
+            code: CancelCode::UnexpectedMessage,
+        };
+
+        content_to_request(
+            recipient,
+            recipient_device,
+            EventType::KeyVerificationCancel,
+            serde_json::to_value(content).expect("Can't serialize the cancel event content"),
+        )
+    }
+
+    fn queue_up_request(&self, request: ToDeviceRequest) {
+        self.outgoing_to_device_messages
+            .insert(request.txn_id.clone(), request);
+    }
+
+    /// Get the to-device requests that need to be sent out.
+    pub(crate) fn outgoing_to_device_requests(&self) -> Vec<ToDeviceRequest> {
+        self.outgoing_to_device_messages
+            .iter()
+            .map(|r| r.value().clone())
+            .collect()
+    }
+
+    /// Mark the to-device request with the given transaction id as sent.
+    pub(crate) fn mark_to_device_request_as_sent(&self, txn_id: &str) {
+        self.outgoing_to_device_messages.remove(txn_id);
+    }
+
+    /// Handle a `m.key.verification.*` to-device event.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - The store that is used to fetch the devices of the event
+    /// senders and to persist freshly verified devices.
+    ///
+    /// * `event` - The verification event.
+    pub(crate) async fn receive_event(
+        &self,
+        store: &dyn CryptoStore,
+        event: &ToDeviceEvent,
+    ) -> StoreResult<()> {
+        match event {
+            ToDeviceEvent::KeyVerificationRequest(e) => {
+                if !e.content.methods.contains(&VerificationMethod::MSasV1) {
+                    warn!(
+                        "Received a verification request from {} without a supported method",
+                        e.sender
+                    );
+                    return Ok(());
+                }
+
+                if let Some(d) = store.get_device(&e.sender, &e.content.from_device).await? {
+                    trace!(
+                        "Received a verification request {} from {} {}",
+                        e.content.transaction_id,
+                        e.sender,
+                        e.content.from_device
+                    );
+                    self.requests.insert(e.content.transaction_id.clone(), d);
+                } else {
+                    warn!(
+                        "Received a verification request from an unknown device {} {}",
+                        e.sender, e.content.from_device
+                    );
+                }
+            }
+            ToDeviceEvent::KeyVerificationStart(e) => {
+                let StartEventContent::MSasV1(content) = &e.content;
+                let from_device = &content.from_device;
+
+                // A start event can't replace a flow that is already in
+                // progress, otherwise any device could reset it.
+                if self.verifications.contains_key(&content.transaction_id) {
+                    warn!(
+                        "Received a start event for the already existing verification flow {} from {} {}",
+                        content.transaction_id, e.sender, from_device
+                    );
+                    self.queue_up_request(Self::unexpected_message(
+                        &e.sender,
+                        from_device,
+                        &content.transaction_id,
+                    ));
+                    return Ok(());
+                }
+
+                let device = if let Some(d) = store.get_device(&e.sender, from_device).await? {
+                    d
+                } else {
+                    warn!(
+                        "Received a verification start event from an unknown device {} {}",
+                        e.sender, from_device
+                    );
+                    return Ok(());
+                };
+
+                match Sas::from_start_event(
+                    &self.user_id,
+                    &self.device_id,
+                    &self.ed25519_key,
+                    device,
+                    &e.content,
+                ) {
+                    Ok(s) => {
+                        self.requests.remove(s.flow_id());
+                        self.verifications.insert(s.flow_id().to_owned(), s);
+                    }
+                    Err(cancel) => self.queue_up_request(cancel),
+                }
+            }
+            ToDeviceEvent::KeyVerificationAccept(e) => {
+                self.receive_flow_event(store, &e.sender, &e.content.transaction_id, event)
+                    .await?
+            }
+            ToDeviceEvent::KeyVerificationKey(e) => {
+                self.receive_flow_event(store, &e.sender, &e.content.transaction_id, event)
+                    .await?
+            }
+            ToDeviceEvent::KeyVerificationMac(e) => {
+                self.receive_flow_event(store, &e.sender, &e.content.transaction_id, event)
+                    .await?
+            }
+            ToDeviceEvent::KeyVerificationCancel(e) => {
+                self.requests.remove(&e.content.transaction_id);
+                self.receive_flow_event(store, &e.sender, &e.content.transaction_id, event)
+                    .await?
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    async fn receive_flow_event(
+        &self,
+        store: &dyn CryptoStore,
+        sender: &UserId,
+        flow_id: &str,
+        event: &ToDeviceEvent,
+    ) -> StoreResult<()> {
+        let sas = if let Some(s) = self.get_sas(flow_id) {
+            s
+        } else {
+            warn!(
+                "Received a verification event for an unknown flow {}",
+                flow_id
+            );
+            return Ok(());
+        };
+
+        if sas.other_device().user_id() != sender {
+            warn!(
+                "Received a verification event for flow {} from an unexpected sender {}",
+                flow_id, sender
+            );
+            return Ok(());
+        }
+
+        // Only the MAC event tells us which device sent it, the MAC has to
+        // cover the ed25519 key of the device we're verifying.
+        if let ToDeviceEvent::KeyVerificationMac(e) = event {
+            let other_device = sas.other_device();
+            let key_id = format!("ed25519:{}", other_device.device_id());
+
+            if !e.content.mac.contains_key(&key_id) {
+                warn!(
+                    "Received a verification MAC for flow {} that doesn't belong to the device {} {}",
+                    flow_id,
+                    sender,
+                    other_device.device_id()
+                );
+                return Ok(());
+            }
+        }
+
+        if let Some(r) = sas.receive_event(event) {
+            self.queue_up_request(r);
+        }
+
+        if sas.is_done() {
+            store.save_devices(&[sas.other_device()]).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use matrix_sdk_common::api::r0::to_device::send_event_to_device::Request as ToDeviceRequest;
+    use matrix_sdk_common::events::{to_device::AnyToDeviceEvent, EventType};
+    use matrix_sdk_common::identifiers::{DeviceId, UserId};
+
+    use super::VerificationMachine;
+    use crate::device::TrustState;
+    use crate::olm::Account;
+    use crate::store::{memorystore::MemoryStore, CryptoStore};
+    use crate::verification::sas::test::{
+        alice_device_id, alice_id, bob_device_id, bob_id, device_for, request_to_event,
+    };
+
+    struct Side {
+        user_id: UserId,
+        machine: VerificationMachine,
+        store: MemoryStore,
+    }
+
+    impl Side {
+        fn new(user_id: UserId, device_id: DeviceId, account: &Account) -> Self {
+            Side {
+                machine: VerificationMachine::new(
+                    user_id.clone(),
+                    device_id,
+                    account.identity_keys().ed25519(),
+                ),
+                user_id,
+                store: MemoryStore::new(),
+            }
+        }
+
+        /// Take the single request that the machine queued up.
+        fn request(&self) -> ToDeviceRequest {
+            let mut requests = self.machine.outgoing_to_device_requests();
+            assert_eq!(requests.len(), 1);
+            let request = requests.remove(0);
+            self.machine.mark_to_device_request_as_sent(&request.txn_id);
+
+            request
+        }
+
+        async fn receive(&self, sender: &Side, request: &ToDeviceRequest) {
+            let event = request_to_event(&sender.user_id, request);
+            self.machine
+                .receive_event(&self.store, &event)
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn setup() -> (Side, Side) {
+        let alice_account = Account::new();
+        let bob_account = Account::new();
+
+        let alice = Side::new(alice_id(), alice_device_id(), &alice_account);
+        let bob = Side::new(bob_id(), bob_device_id(), &bob_account);
+
+        alice
+            .store
+            .save_devices(&[device_for(bob_id(), bob_device_id(), &bob_account)])
+            .await
+            .unwrap();
+        bob.store
+            .save_devices(&[device_for(alice_id(), alice_device_id(), &alice_account)])
+            .await
+            .unwrap();
+
+        (alice, bob)
+    }
+
+    #[tokio::test]
+    async fn verified_device_is_saved() {
+        let (alice, bob) = setup().await;
+
+        let bob_device = alice
+            .store
+            .get_device(&bob_id(), &bob_device_id())
+            .await
+            .unwrap()
+            .unwrap();
+        let flow_id = alice.machine.start_sas(bob_device).flow_id().to_owned();
+        bob.receive(&alice, &alice.request()).await;
+
+        assert!(bob.machine.accept_sas(&flow_id));
+        alice.receive(&bob, &bob.request()).await;
+        bob.receive(&alice, &alice.request()).await;
+        alice.receive(&bob, &bob.request()).await;
+
+        assert!(alice
+            .machine
+            .confirm_sas(&alice.store, &flow_id)
+            .await
+            .unwrap());
+        bob.receive(&alice, &alice.request()).await;
+
+        assert!(bob.machine.confirm_sas(&bob.store, &flow_id).await.unwrap());
+        alice.receive(&bob, &bob.request()).await;
+
+        assert!(alice.machine.get_sas(&flow_id).unwrap().is_done());
+        assert!(bob.machine.get_sas(&flow_id).unwrap().is_done());
+
+        let device = alice
+            .store
+            .get_device(&bob_id(), &bob_device_id())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(device.trust_state(), TrustState::Verified);
+
+        let device = bob
+            .store
+            .get_device(&alice_id(), &alice_device_id())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(device.trust_state(), TrustState::Verified);
+    }
+
+    #[tokio::test]
+    async fn start_event_doesnt_replace_flow() {
+        let (alice, bob) = setup().await;
+
+        let bob_device = alice
+            .store
+            .get_device(&bob_id(), &bob_device_id())
+            .await
+            .unwrap()
+            .unwrap();
+        let flow_id = alice.machine.start_sas(bob_device).flow_id().to_owned();
+        let start = alice.request();
+
+        bob.receive(&alice, &start).await;
+        assert!(bob.machine.accept_sas(&flow_id));
+        bob.request();
+
+        // The same start event again would reset the flow if it replaced the
+        // existing one, an already accepted flow can't be accepted again.
+        bob.receive(&alice, &start).await;
+        let cancel = bob.request();
+        assert_eq!(cancel.event_type, EventType::KeyVerificationCancel);
+
+        match request_to_event(&bob_id(), &cancel) {
+            AnyToDeviceEvent::KeyVerificationCancel(e) => {
+                assert_eq!(e.content.transaction_id, flow_id)
+            }
+            e => panic!("Unexpected event {:?}", e),
+        }
+
+        let sas = bob.machine.get_sas(&flow_id).unwrap();
+        assert!(!sas.is_canceled());
+        assert!(!bob.machine.accept_sas(&flow_id));
+    }
+}
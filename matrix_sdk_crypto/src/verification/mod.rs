@@ -0,0 +1,211 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Interactive device verification using short authentication strings.
+
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use matrix_sdk_common::api::r0::to_device::{
+    send_event_to_device::Request as ToDeviceRequest, DeviceIdOrAllDevices,
+};
+use matrix_sdk_common::events::EventType;
+use matrix_sdk_common::identifiers::{DeviceId, UserId};
+use matrix_sdk_common::uuid::Uuid;
+
+mod machine;
+mod sas;
+
+pub(crate) use machine::VerificationMachine;
+pub use sas::Sas;
+
+/// The list of emojis, together with their description, that the SAS method
+/// uses, indexed by the 6 bit number that represents them.
+const EMOJI_TABLE: [(&str, &str); 64] = [
+    ("🐶", "Dog"),
+    ("🐱", "Cat"),
+    ("🦁", "Lion"),
+    ("🐎", "Horse"),
+    ("🦄", "Unicorn"),
+    ("🐷", "Pig"),
+    ("🐘", "Elephant"),
+    ("🐰", "Rabbit"),
+    ("🐼", "Panda"),
+    ("🐓", "Rooster"),
+    ("🐧", "Penguin"),
+    ("🐢", "Turtle"),
+    ("🐟", "Fish"),
+    ("🐙", "Octopus"),
+    ("🦋", "Butterfly"),
+    ("🌷", "Flower"),
+    ("🌳", "Tree"),
+    ("🌵", "Cactus"),
+    ("🍄", "Mushroom"),
+    ("🌏", "Globe"),
+    ("🌙", "Moon"),
+    ("☁️", "Cloud"),
+    ("🔥", "Fire"),
+    ("🍌", "Banana"),
+    ("🍎", "Apple"),
+    ("🍓", "Strawberry"),
+    ("🌽", "Corn"),
+    ("🍕", "Pizza"),
+    ("🎂", "Cake"),
+    ("❤️", "Heart"),
+    ("😀", "Smiley"),
+    ("🤖", "Robot"),
+    ("🎩", "Hat"),
+    ("👓", "Glasses"),
+    ("🔧", "Spanner"),
+    ("🎅", "Santa"),
+    ("👍", "Thumbs up"),
+    ("☂️", "Umbrella"),
+    ("⌛", "Hourglass"),
+    ("⏰", "Clock"),
+    ("🎁", "Gift"),
+    ("💡", "Light Bulb"),
+    ("📕", "Book"),
+    ("✏️", "Pencil"),
+    ("📎", "Paperclip"),
+    ("✂️", "Scissors"),
+    ("🔒", "Lock"),
+    ("🔑", "Key"),
+    ("🔨", "Hammer"),
+    ("☎️", "Telephone"),
+    ("🏁", "Flag"),
+    ("🚂", "Train"),
+    ("🚲", "Bicycle"),
+    ("✈️", "Aeroplane"),
+    ("🚀", "Rocket"),
+    ("🏆", "Trophy"),
+    ("⚽", "Ball"),
+    ("🎸", "Guitar"),
+    ("🎺", "Trumpet"),
+    ("🔔", "Bell"),
+    ("⚓", "Anchor"),
+    ("🎧", "Headphones"),
+    ("📁", "Folder"),
+    ("📌", "Pin"),
+];
+
+/// Convert the given bytes into seven emojis.
+///
+/// Only the first 42 bits of the 6 given bytes are used, each 6 bit chunk is
+/// used as an index into the emoji table.
+///
+/// # Arguments
+///
+/// * `bytes` - The 6 bytes that were generated by the SAS object.
+///
+/// # Panics
+///
+/// Panics if less than 6 bytes are given.
+fn bytes_to_emoji(bytes: &[u8]) -> Vec<(&'static str, &'static str)> {
+    let number = bytes[..6]
+        .iter()
+        .fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+
+    (0..7)
+        .map(|i| {
+            let index = (number >> (42 - i * 6)) & 0x3F;
+            EMOJI_TABLE[index as usize]
+        })
+        .collect()
+}
+
+/// Convert the given bytes into three decimal numbers.
+///
+/// Only the first 39 bits of the 5 given bytes are used, each 13 bit chunk is
+/// converted into a number between 1000 and 9191.
+///
+/// # Arguments
+///
+/// * `bytes` - The 5 bytes that were generated by the SAS object.
+///
+/// # Panics
+///
+/// Panics if less than 5 bytes are given.
+fn bytes_to_decimal(bytes: &[u8]) -> (u32, u32, u32) {
+    let bytes: Vec<u32> = bytes[..5].iter().map(|b| u32::from(*b)).collect();
+
+    let first = bytes[0] << 5 | bytes[1] >> 3;
+    let second = (bytes[1] & 0x7) << 10 | bytes[2] << 2 | bytes[3] >> 6;
+    let third = (bytes[3] & 0x3F) << 7 | bytes[4] >> 1;
+
+    (first + 1000, second + 1000, third + 1000)
+}
+
+/// Create a to-device request that sends the given content to a single device.
+///
+/// # Arguments
+///
+/// * `recipient` - The user that should receive the event.
+///
+/// * `recipient_device` - The device of the user that should receive the
+/// event.
+///
+/// * `event_type` - The type of the event that should be sent.
+///
+/// * `content` - The content of the event.
+fn content_to_request(
+    recipient: &UserId,
+    recipient_device: &DeviceId,
+    event_type: EventType,
+    content: Value,
+) -> ToDeviceRequest {
+    let mut messages = BTreeMap::new();
+    let mut user_messages = BTreeMap::new();
+
+    user_messages.insert(
+        DeviceIdOrAllDevices::DeviceId(recipient_device.clone()),
+        serde_json::value::to_raw_value(&content).expect("Can't serialize to-device content"),
+    );
+    messages.insert(recipient.clone(), user_messages);
+
+    ToDeviceRequest {
+        event_type,
+        txn_id: Uuid::new_v4().to_string(),
+        messages,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bytes_to_decimal, bytes_to_emoji};
+
+    #[test]
+    fn emoji_generation() {
+        let bytes = [0u8, 0, 0, 0, 0, 0];
+        let emojis = bytes_to_emoji(&bytes);
+        assert_eq!(emojis.len(), 7);
+        assert!(emojis.iter().all(|e| e == &("🐶", "Dog")));
+
+        // 0b111111 followed by 0b000001 and zeroes.
+        let bytes = [0b1111_1100, 0b0001_0000, 0, 0, 0, 0];
+        let emojis = bytes_to_emoji(&bytes);
+        assert_eq!(emojis[0], ("📌", "Pin"));
+        assert_eq!(emojis[1], ("🐱", "Cat"));
+        assert_eq!(emojis[2], ("🐶", "Dog"));
+    }
+
+    #[test]
+    fn decimal_generation() {
+        let bytes = [0u8, 0, 0, 0, 0];
+        assert_eq!(bytes_to_decimal(&bytes), (1000, 1000, 1000));
+
+        let bytes = [0xFFu8, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert_eq!(bytes_to_decimal(&bytes), (9191, 9191, 9191));
+    }
+}
@@ -0,0 +1,933 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use olm_rs::sas::OlmSas;
+use serde_json::Value;
+use tracing::{trace, warn};
+
+use matrix_sdk_common::api::r0::keys::KeyAlgorithm;
+use matrix_sdk_common::api::r0::to_device::send_event_to_device::Request as ToDeviceRequest;
+use matrix_sdk_common::events::{
+    key::verification::{
+        accept::AcceptEventContent,
+        cancel::{CancelCode, CancelEventContent},
+        key::KeyEventContent,
+        mac::MacEventContent,
+        start::{MSasV1Content, MSasV1ContentOptions, StartEventContent},
+        HashAlgorithm, KeyAgreementProtocol, MessageAuthenticationCode, ShortAuthenticationString,
+        VerificationMethod,
+    },
+    to_device::AnyToDeviceEvent as ToDeviceEvent,
+    EventType,
+};
+use matrix_sdk_common::identifiers::{DeviceId, UserId};
+use matrix_sdk_common::uuid::Uuid;
+
+use super::{bytes_to_decimal, bytes_to_emoji, content_to_request};
+use crate::device::{Device, TrustState};
+use crate::olm::OlmUtility;
+
+const KEY_AGREEMENT_PROTOCOLS: &[KeyAgreementProtocol] = &[KeyAgreementProtocol::Curve25519];
+const HASHES: &[HashAlgorithm] = &[HashAlgorithm::Sha256];
+const MACS: &[MessageAuthenticationCode] = &[MessageAuthenticationCode::HkdfHmacSha256];
+const STRINGS: &[ShortAuthenticationString] = &[
+    ShortAuthenticationString::Decimal,
+    ShortAuthenticationString::Emoji,
+];
+
+/// The state of a SAS verification flow.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SasState {
+    /// We started the verification and are waiting for the other side to
+    /// accept it.
+    Created,
+    /// The other side started the verification, we need to accept it.
+    Started,
+    /// The verification was accepted, we're waiting for the public key of
+    /// the other side.
+    Accepted,
+    /// The public keys were exchanged, the short authentication string can be
+    /// presented to the user.
+    KeyReceived,
+    /// The user confirmed that the short authentication strings match, we're
+    /// waiting for the MAC of the other side.
+    Confirmed,
+    /// We received a valid MAC from the other side, we're waiting for the
+    /// user to confirm the short authentication strings.
+    MacReceived,
+    /// The verification successfully finished.
+    Done,
+    /// The verification was canceled.
+    Canceled,
+}
+
+/// The data of a SAS verification flow, guarded by a single lock.
+struct InnerSas {
+    sas: OlmSas,
+    state: SasState,
+    /// Did we send the start event.
+    we_started: bool,
+    /// The content of the start event, used to calculate and check the
+    /// commitment.
+    start_content: Value,
+    /// The commitment the other side sent us in their accept event.
+    commitment: Option<String>,
+    /// The short authentication string methods both sides agreed on.
+    short_auth_strings: Vec<ShortAuthenticationString>,
+    cancel_code: Option<CancelCode>,
+}
+
+/// Short authentication string object.
+///
+/// Tracks a single SAS verification flow between one of our devices and a
+/// device of another user (or another device of our own user).
+#[derive(Clone)]
+pub struct Sas {
+    inner: Arc<Mutex<InnerSas>>,
+    user_id: Arc<UserId>,
+    device_id: Arc<DeviceId>,
+    ed25519_key: Arc<String>,
+    other_device: Device,
+    flow_id: Arc<String>,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Debug for Sas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sas")
+            .field("flow_id", &self.flow_id)
+            .field("other_user_id", self.other_device.user_id())
+            .field("other_device_id", self.other_device.device_id())
+            .field("state", &self.inner.lock().unwrap().state)
+            .finish()
+    }
+}
+
+impl Sas {
+    /// Start a new SAS verification with the given device.
+    ///
+    /// Returns the new `Sas` object and a to-device request containing the
+    /// `m.key.verification.start` event that needs to be sent out.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - Our own user id.
+    ///
+    /// * `device_id` - Our own device id.
+    ///
+    /// * `ed25519_key` - The ed25519 identity key of our own device.
+    ///
+    /// * `other_device` - The device that should be verified.
+    ///
+    /// * `flow_id` - The transaction id of the flow, a new random one will be
+    /// generated if none is given.
+    pub(crate) fn start(
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ed25519_key: &str,
+        other_device: Device,
+        flow_id: Option<String>,
+    ) -> (Sas, ToDeviceRequest) {
+        let flow_id = flow_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        let content = StartEventContent::MSasV1(
+            MSasV1Content::new(MSasV1ContentOptions {
+                from_device: device_id.clone(),
+                transaction_id: flow_id.clone(),
+                hashes: HASHES.to_vec(),
+                key_agreement_protocols: KEY_AGREEMENT_PROTOCOLS.to_vec(),
+                message_authentication_codes: MACS.to_vec(),
+                short_authentication_string: STRINGS.to_vec(),
+            })
+            .expect("Invalid initial SAS protocol definitions"),
+        );
+
+        let start_content =
+            serde_json::to_value(&content).expect("Can't serialize the start event content");
+
+        let sas = Sas::new(
+            user_id,
+            device_id,
+            ed25519_key,
+            other_device,
+            flow_id,
+            SasState::Created,
+            true,
+            start_content.clone(),
+            STRINGS.to_vec(),
+        );
+
+        let request = sas.content_to_request(EventType::KeyVerificationStart, start_content);
+
+        (sas, request)
+    }
+
+    /// Create a new `Sas` object from a `m.key.verification.start` event that
+    /// the other side sent us.
+    ///
+    /// Returns an error containing a cancel request if the start event uses
+    /// methods we don't support.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - Our own user id.
+    ///
+    /// * `device_id` - Our own device id.
+    ///
+    /// * `ed25519_key` - The ed25519 identity key of our own device.
+    ///
+    /// * `other_device` - The device that started the verification.
+    ///
+    /// * `content` - The content of the start event.
+    pub(crate) fn from_start_event(
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ed25519_key: &str,
+        other_device: Device,
+        content: &StartEventContent,
+    ) -> Result<Sas, ToDeviceRequest> {
+        let StartEventContent::MSasV1(c) = content;
+
+        let short_auth_strings: Vec<ShortAuthenticationString> = STRINGS
+            .iter()
+            .filter(|s| c.short_authentication_string.contains(s))
+            .cloned()
+            .collect();
+
+        let supported = c
+            .key_agreement_protocols
+            .contains(&KEY_AGREEMENT_PROTOCOLS[0])
+            && c.hashes.contains(&HASHES[0])
+            && c.message_authentication_codes.contains(&MACS[0])
+            && !short_auth_strings.is_empty();
+
+        let start_content =
+            serde_json::to_value(content).expect("Can't serialize the start event content");
+
+        let sas = Sas::new(
+            user_id,
+            device_id,
+            ed25519_key,
+            other_device,
+            c.transaction_id.clone(),
+            SasState::Started,
+            false,
+            start_content,
+            short_auth_strings,
+        );
+
+        if supported {
+            Ok(sas)
+        } else {
+            Err(sas
+                .cancel_with_code(CancelCode::UnknownMethod)
+                .expect("A newly created SAS can always be canceled"))
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ed25519_key: &str,
+        other_device: Device,
+        flow_id: String,
+        state: SasState,
+        we_started: bool,
+        start_content: Value,
+        short_auth_strings: Vec<ShortAuthenticationString>,
+    ) -> Sas {
+        let inner = InnerSas {
+            sas: OlmSas::new(),
+            state,
+            we_started,
+            start_content,
+            commitment: None,
+            short_auth_strings,
+            cancel_code: None,
+        };
+
+        Sas {
+            inner: Arc::new(Mutex::new(inner)),
+            user_id: Arc::new(user_id.clone()),
+            device_id: Arc::new(device_id.clone()),
+            ed25519_key: Arc::new(ed25519_key.to_owned()),
+            other_device,
+            flow_id: Arc::new(flow_id),
+        }
+    }
+
+    /// Get the unique id that identifies this verification flow.
+    pub fn flow_id(&self) -> &str {
+        &self.flow_id
+    }
+
+    /// Get the device we're verifying.
+    pub fn other_device(&self) -> Device {
+        self.other_device.clone()
+    }
+
+    /// Did we start the verification flow.
+    pub fn we_started(&self) -> bool {
+        self.inner.lock().unwrap().we_started
+    }
+
+    /// Is the verification flow done, e.g. were both MACs exchanged and
+    /// checked.
+    pub fn is_done(&self) -> bool {
+        self.inner.lock().unwrap().state == SasState::Done
+    }
+
+    /// Has the verification flow been canceled, either by us or by the other
+    /// side.
+    pub fn is_canceled(&self) -> bool {
+        self.inner.lock().unwrap().state == SasState::Canceled
+    }
+
+    /// Get the cancel code of the verification flow, if it was canceled.
+    pub fn cancel_code(&self) -> Option<CancelCode> {
+        self.inner.lock().unwrap().cancel_code.clone()
+    }
+
+    /// Can the short authentication string be presented to the user.
+    ///
+    /// This is true once the public keys of both sides have been exchanged.
+    pub fn can_be_presented(&self) -> bool {
+        match self.inner.lock().unwrap().state {
+            SasState::KeyReceived | SasState::Confirmed | SasState::MacReceived => true,
+            _ => false,
+        }
+    }
+
+    /// Accept a verification flow that the other side started.
+    ///
+    /// Returns a to-device request containing the `m.key.verification.accept`
+    /// event that needs to be sent out, None if the flow can't be accepted in
+    /// its current state.
+    pub(crate) fn accept(&self) -> Option<ToDeviceRequest> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.state != SasState::Started {
+            return None;
+        }
+
+        let commitment = Sas::calculate_commitment(&inner.sas.public_key(), &inner.start_content);
+
+        let content = AcceptEventContent {
+            transaction_id: self.flow_id.to_string(),
+            method: VerificationMethod::MSasV1,
+            key_agreement_protocol: KEY_AGREEMENT_PROTOCOLS[0].clone(),
+            hash: HASHES[0].clone(),
+            message_authentication_code: MACS[0].clone(),
+            short_authentication_string: inner.short_auth_strings.clone(),
+            commitment,
+        };
+
+        inner.state = SasState::Accepted;
+
+        Some(self.content_to_request(
+            EventType::KeyVerificationAccept,
+            serde_json::to_value(content).expect("Can't serialize the accept event content"),
+        ))
+    }
+
+    /// Confirm that the short authentication strings match.
+    ///
+    /// This marks the other device as verified once the other side confirmed
+    /// the match as well.
+    ///
+    /// Returns a to-device request containing the `m.key.verification.mac`
+    /// event that needs to be sent out, None if the flow can't be confirmed in
+    /// its current state.
+    pub(crate) fn confirm(&self) -> Option<ToDeviceRequest> {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.state = match inner.state {
+            SasState::KeyReceived => SasState::Confirmed,
+            SasState::MacReceived => {
+                self.mark_device_as_verified();
+                SasState::Done
+            }
+            _ => return None,
+        };
+
+        let content = self.mac_content(&inner.sas);
+
+        Some(self.content_to_request(
+            EventType::KeyVerificationMac,
+            serde_json::to_value(content).expect("Can't serialize the MAC event content"),
+        ))
+    }
+
+    /// Cancel the verification flow.
+    ///
+    /// Returns a to-device request containing the `m.key.verification.cancel`
+    /// event that needs to be sent out, None if the flow is already done or
+    /// canceled.
+    pub(crate) fn cancel(&self) -> Option<ToDeviceRequest> {
+        self.cancel_with_code(CancelCode::User)
+    }
+
+    /// Get the emoji version of the short authentication string.
+    ///
+    /// Returns a list of seven tuples, each containing the emoji and its
+    /// description, None if the emojis can't be presented yet or if the other
+    /// side doesn't support the emoji method.
+    pub fn emoji(&self) -> Option<Vec<(&'static str, &'static str)>> {
+        if !self.can_be_presented() {
+            return None;
+        }
+
+        let inner = self.inner.lock().unwrap();
+
+        if !inner
+            .short_auth_strings
+            .contains(&ShortAuthenticationString::Emoji)
+        {
+            return None;
+        }
+
+        let bytes = inner
+            .sas
+            .generate_bytes(&self.extra_info(inner.we_started), 6)
+            .expect("Can't generate bytes for the emoji SAS");
+
+        Some(bytes_to_emoji(&bytes))
+    }
+
+    /// Get the decimal version of the short authentication string.
+    ///
+    /// Returns a tuple containing three 4 digit numbers, None if the decimals
+    /// can't be presented yet.
+    pub fn decimals(&self) -> Option<(u32, u32, u32)> {
+        if !self.can_be_presented() {
+            return None;
+        }
+
+        let inner = self.inner.lock().unwrap();
+
+        let bytes = inner
+            .sas
+            .generate_bytes(&self.extra_info(inner.we_started), 5)
+            .expect("Can't generate bytes for the decimal SAS");
+
+        Some(bytes_to_decimal(&bytes))
+    }
+
+    /// Receive a verification event that belongs to this flow.
+    ///
+    /// Returns a to-device request that needs to be sent out as a response to
+    /// the event, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The `m.key.verification.*` event the other device sent us.
+    pub(crate) fn receive_event(&self, event: &ToDeviceEvent) -> Option<ToDeviceRequest> {
+        match event {
+            ToDeviceEvent::KeyVerificationAccept(e) => self.receive_accept(&e.content),
+            ToDeviceEvent::KeyVerificationKey(e) => self.receive_key(&e.content),
+            ToDeviceEvent::KeyVerificationMac(e) => self.receive_mac(&e.content),
+            ToDeviceEvent::KeyVerificationCancel(e) => {
+                self.receive_cancel(&e.content);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn receive_accept(&self, content: &AcceptEventContent) -> Option<ToDeviceRequest> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.state != SasState::Created {
+            drop(inner);
+            return self.cancel_with_code(CancelCode::UnexpectedMessage);
+        }
+
+        let short_auth_strings: Vec<ShortAuthenticationString> = STRINGS
+            .iter()
+            .filter(|s| content.short_authentication_string.contains(s))
+            .cloned()
+            .collect();
+
+        if content.method != VerificationMethod::MSasV1
+            || content.key_agreement_protocol != KEY_AGREEMENT_PROTOCOLS[0]
+            || content.hash != HASHES[0]
+            || content.message_authentication_code != MACS[0]
+            || short_auth_strings.is_empty()
+        {
+            drop(inner);
+            return self.cancel_with_code(CancelCode::UnknownMethod);
+        }
+
+        trace!("Verification flow {} got accepted", self.flow_id);
+
+        inner.commitment = Some(content.commitment.clone());
+        inner.short_auth_strings = short_auth_strings;
+        inner.state = SasState::Accepted;
+
+        let content = KeyEventContent {
+            transaction_id: self.flow_id.to_string(),
+            key: inner.sas.public_key(),
+        };
+
+        Some(self.content_to_request(
+            EventType::KeyVerificationKey,
+            serde_json::to_value(content).expect("Can't serialize the key event content"),
+        ))
+    }
+
+    fn receive_key(&self, content: &KeyEventContent) -> Option<ToDeviceRequest> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.state != SasState::Accepted {
+            drop(inner);
+            return self.cancel_with_code(CancelCode::UnexpectedMessage);
+        }
+
+        if inner.we_started {
+            let commitment = Sas::calculate_commitment(&content.key, &inner.start_content);
+
+            if inner.commitment.as_ref() != Some(&commitment) {
+                drop(inner);
+                return self.cancel_with_code(CancelCode::KeyMismatch);
+            }
+        }
+
+        if inner.sas.set_their_public_key(content.key.clone()).is_err() {
+            drop(inner);
+            return self.cancel_with_code(CancelCode::InvalidMessage);
+        }
+
+        inner.state = SasState::KeyReceived;
+
+        if inner.we_started {
+            // We already sent out our key after the accept event.
+            None
+        } else {
+            let content = KeyEventContent {
+                transaction_id: self.flow_id.to_string(),
+                key: inner.sas.public_key(),
+            };
+
+            Some(self.content_to_request(
+                EventType::KeyVerificationKey,
+                serde_json::to_value(content).expect("Can't serialize the key event content"),
+            ))
+        }
+    }
+
+    fn receive_mac(&self, content: &MacEventContent) -> Option<ToDeviceRequest> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let next_state = match inner.state {
+            SasState::KeyReceived => SasState::MacReceived,
+            SasState::Confirmed => SasState::Done,
+            _ => {
+                drop(inner);
+                return self.cancel_with_code(CancelCode::UnexpectedMessage);
+            }
+        };
+
+        if !self.check_mac(&inner.sas, content) {
+            drop(inner);
+            return self.cancel_with_code(CancelCode::KeyMismatch);
+        }
+
+        if next_state == SasState::Done {
+            self.mark_device_as_verified();
+        }
+
+        inner.state = next_state;
+
+        None
+    }
+
+    fn receive_cancel(&self, content: &CancelEventContent) {
+        let mut inner = self.inner.lock().unwrap();
+
+        warn!(
+            "Verification flow {} was canceled by the other side: {}",
+            self.flow_id, content.reason
+        );
+
+        inner.state = SasState::Canceled;
+        inner.cancel_code = Some(content.code.clone());
+    }
+
+    fn cancel_with_code(&self, code: CancelCode) -> Option<ToDeviceRequest> {
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.state {
+            SasState::Done | SasState::Canceled => return None,
+            _ => (),
+        }
+
+        let reason = match code {
+            CancelCode::User => "Canceled by user",
+            CancelCode::Timeout => "Timed out",
+            CancelCode::UnknownMethod => "Unknown method",
+            CancelCode::UnexpectedMessage => "Unexpected message",
+            CancelCode::KeyMismatch => "Key mismatch",
+            CancelCode::UserMismatch => "User mismatch",
+            CancelCode::InvalidMessage => "Invalid message",
+            _ => "Unknown error",
+        };
+
+        inner.state = SasState::Canceled;
+        inner.cancel_code = Some(code.clone());
+
+        let content = CancelEventContent {
+            transaction_id: self.flow_id.to_string(),
+            reason: reason.to_owned(),
+            code,
+        };
+
+        Some(self.content_to_request(
+            EventType::KeyVerificationCancel,
+            serde_json::to_value(content).expect("Can't serialize the cancel event content"),
+        ))
+    }
+
+    /// Calculate the commitment for the given public key and start event
+    /// content.
+    fn calculate_commitment(public_key: &str, start_content: &Value) -> String {
+        let canonical_json = cjson::to_string(start_content)
+            .expect("Can't serialize the start content to canonical JSON");
+
+        OlmUtility::new().sha256_utf8_msg(&format!("{}{}", public_key, canonical_json))
+    }
+
+    /// Get the extra info that is used to generate the SAS bytes.
+    ///
+    /// The info depends on which side started the verification.
+    fn extra_info(&self, we_started: bool) -> String {
+        let (first_user, first_device, second_user, second_device) = if we_started {
+            (
+                &*self.user_id,
+                &*self.device_id,
+                self.other_device.user_id(),
+                self.other_device.device_id(),
+            )
+        } else {
+            (
+                self.other_device.user_id(),
+                self.other_device.device_id(),
+                &*self.user_id,
+                &*self.device_id,
+            )
+        };
+
+        format!(
+            "MATRIX_KEY_VERIFICATION_SAS{first_user}{first_device}{second_user}{second_device}{transaction_id}",
+            first_user = first_user,
+            first_device = first_device,
+            second_user = second_user,
+            second_device = second_device,
+            transaction_id = self.flow_id,
+        )
+    }
+
+    /// Get the extra info that is used to calculate a MAC.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The user that sends the MAC.
+    ///
+    /// * `sender_device` - The device that sends the MAC.
+    ///
+    /// * `receiver` - The user that receives the MAC.
+    ///
+    /// * `receiver_device` - The device that receives the MAC.
+    fn mac_info(
+        &self,
+        sender: &UserId,
+        sender_device: &DeviceId,
+        receiver: &UserId,
+        receiver_device: &DeviceId,
+    ) -> String {
+        format!(
+            "MATRIX_KEY_VERIFICATION_MAC{}{}{}{}{}",
+            sender, sender_device, receiver, receiver_device, self.flow_id
+        )
+    }
+
+    /// Create the content of the MAC event that we need to send out.
+    fn mac_content(&self, sas: &OlmSas) -> MacEventContent {
+        let info = self.mac_info(
+            &self.user_id,
+            &self.device_id,
+            self.other_device.user_id(),
+            self.other_device.device_id(),
+        );
+
+        let key_id = format!("{}:{}", KeyAlgorithm::Ed25519, self.device_id);
+
+        let mut mac = BTreeMap::new();
+        mac.insert(
+            key_id.clone(),
+            sas.calculate_mac(&self.ed25519_key, &format!("{}{}", info, key_id))
+                .expect("Can't calculate the SAS MAC"),
+        );
+
+        let keys = sas
+            .calculate_mac(&key_id, &format!("{}KEY_IDS", info))
+            .expect("Can't calculate the SAS MAC");
+
+        MacEventContent {
+            transaction_id: self.flow_id.to_string(),
+            mac,
+            keys,
+        }
+    }
+
+    /// Check the MAC event the other side sent us.
+    ///
+    /// Returns true if the MAC of the key list and the MAC of the ed25519 key
+    /// of the other device are valid.
+    fn check_mac(&self, sas: &OlmSas, content: &MacEventContent) -> bool {
+        let info = self.mac_info(
+            self.other_device.user_id(),
+            self.other_device.device_id(),
+            &self.user_id,
+            &self.device_id,
+        );
+
+        let mut key_ids: Vec<&str> = content.mac.keys().map(|k| k.as_str()).collect();
+        key_ids.sort();
+
+        let keys_mac = sas
+            .calculate_mac(&key_ids.join(","), &format!("{}KEY_IDS", info))
+            .expect("Can't calculate the SAS MAC");
+
+        if keys_mac != content.keys {
+            return false;
+        }
+
+        let key_id = format!(
+            "{}:{}",
+            KeyAlgorithm::Ed25519,
+            self.other_device.device_id()
+        );
+
+        let their_key = if let Some(k) = self.other_device.get_key(KeyAlgorithm::Ed25519) {
+            k
+        } else {
+            return false;
+        };
+
+        match content.mac.get(&key_id) {
+            Some(mac) => {
+                let expected = sas
+                    .calculate_mac(their_key, &format!("{}{}", info, key_id))
+                    .expect("Can't calculate the SAS MAC");
+                &expected == mac
+            }
+            None => false,
+        }
+    }
+
+    fn mark_device_as_verified(&self) {
+        trace!(
+            "Marking device {} of user {} as verified",
+            self.other_device.device_id(),
+            self.other_device.user_id()
+        );
+        self.other_device.set_trust_state(TrustState::Verified);
+    }
+
+    fn content_to_request(&self, event_type: EventType, content: Value) -> ToDeviceRequest {
+        content_to_request(
+            self.other_device.user_id(),
+            self.other_device.device_id(),
+            event_type,
+            content,
+        )
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+
+    use serde_json::Value;
+
+    use matrix_sdk_common::api::r0::keys::KeyAlgorithm;
+    use matrix_sdk_common::api::r0::to_device::send_event_to_device::Request as ToDeviceRequest;
+    use matrix_sdk_common::events::{to_device::AnyToDeviceEvent, Algorithm, EventJson};
+    use matrix_sdk_common::identifiers::{DeviceId, UserId};
+
+    use super::Sas;
+    use crate::device::{Device, TrustState};
+    use crate::olm::Account;
+
+    pub(crate) fn alice_id() -> UserId {
+        UserId::try_from("@alice:example.org").unwrap()
+    }
+
+    pub(crate) fn alice_device_id() -> DeviceId {
+        "JLAFKJWSCS".to_string()
+    }
+
+    pub(crate) fn bob_id() -> UserId {
+        UserId::try_from("@bob:example.org").unwrap()
+    }
+
+    pub(crate) fn bob_device_id() -> DeviceId {
+        "BOBDEVCIE".to_string()
+    }
+
+    pub(crate) fn device_for(user_id: UserId, device_id: DeviceId, account: &Account) -> Device {
+        let mut keys = BTreeMap::new();
+        keys.insert(
+            KeyAlgorithm::Ed25519,
+            account.identity_keys().ed25519().to_owned(),
+        );
+        keys.insert(
+            KeyAlgorithm::Curve25519,
+            account.identity_keys().curve25519().to_owned(),
+        );
+
+        Device::new(
+            user_id,
+            device_id,
+            None,
+            TrustState::Unset,
+            vec![
+                Algorithm::MegolmV1AesSha2,
+                Algorithm::OlmV1Curve25519AesSha2,
+            ],
+            keys,
+            BTreeMap::new(),
+        )
+    }
+
+    /// Turn the request into the event the other side would receive.
+    pub(crate) fn request_to_event(sender: &UserId, request: &ToDeviceRequest) -> AnyToDeviceEvent {
+        let content: Value = serde_json::from_str(
+            request
+                .messages
+                .values()
+                .next()
+                .unwrap()
+                .values()
+                .next()
+                .unwrap()
+                .get(),
+        )
+        .unwrap();
+
+        let event = serde_json::json!({
+            "sender": sender,
+            "type": request.event_type,
+            "content": content,
+        });
+
+        serde_json::from_value::<EventJson<AnyToDeviceEvent>>(event)
+            .unwrap()
+            .deserialize()
+            .unwrap()
+    }
+
+    fn get_sas_pair() -> (Sas, Sas) {
+        let alice_account = Account::new();
+        let bob_account = Account::new();
+
+        let alice_device = device_for(alice_id(), alice_device_id(), &alice_account);
+        let bob_device = device_for(bob_id(), bob_device_id(), &bob_account);
+
+        let (alice, request) = Sas::start(
+            &alice_id(),
+            &alice_device_id(),
+            alice_account.identity_keys().ed25519(),
+            bob_device,
+            None,
+        );
+
+        let event = request_to_event(&alice_id(), &request);
+
+        let bob = if let AnyToDeviceEvent::KeyVerificationStart(e) = event {
+            Sas::from_start_event(
+                &bob_id(),
+                &bob_device_id(),
+                bob_account.identity_keys().ed25519(),
+                alice_device,
+                &e.content,
+            )
+            .unwrap()
+        } else {
+            panic!("Invalid start event");
+        };
+
+        (alice, bob)
+    }
+
+    #[test]
+    fn sas_full_flow() {
+        let (alice, bob) = get_sas_pair();
+
+        assert_eq!(alice.flow_id(), bob.flow_id());
+        assert!(alice.we_started());
+        assert!(!bob.we_started());
+        assert!(alice.emoji().is_none());
+
+        let request = bob.accept().unwrap();
+        let event = request_to_event(&bob_id(), &request);
+        let request = alice.receive_event(&event).unwrap();
+
+        let event = request_to_event(&alice_id(), &request);
+        let request = bob.receive_event(&event).unwrap();
+        assert!(bob.can_be_presented());
+
+        let event = request_to_event(&bob_id(), &request);
+        assert!(alice.receive_event(&event).is_none());
+        assert!(alice.can_be_presented());
+
+        assert_eq!(alice.emoji().unwrap(), bob.emoji().unwrap());
+        assert_eq!(alice.decimals().unwrap(), bob.decimals().unwrap());
+
+        let request = alice.confirm().unwrap();
+        let event = request_to_event(&alice_id(), &request);
+        assert!(bob.receive_event(&event).is_none());
+        assert!(!bob.is_done());
+
+        let request = bob.confirm().unwrap();
+        assert!(bob.is_done());
+        assert_eq!(bob.other_device().trust_state(), TrustState::Verified);
+
+        let event = request_to_event(&bob_id(), &request);
+        assert!(alice.receive_event(&event).is_none());
+        assert!(alice.is_done());
+        assert_eq!(alice.other_device().trust_state(), TrustState::Verified);
+    }
+
+    #[test]
+    fn sas_cancellation() {
+        let (alice, bob) = get_sas_pair();
+
+        let request = bob.cancel().unwrap();
+        assert!(bob.is_canceled());
+        assert!(bob.cancel().is_none());
+        assert!(bob.accept().is_none());
+
+        let event = request_to_event(&bob_id(), &request);
+        assert!(alice.receive_event(&event).is_none());
+        assert!(alice.is_canceled());
+        assert_eq!(alice.other_device().trust_state(), TrustState::Unset);
+    }
+}
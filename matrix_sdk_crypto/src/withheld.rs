@@ -0,0 +1,173 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use matrix_sdk_common::events::{Algorithm, EventType};
+use matrix_sdk_common::identifiers::{RoomId, UserId};
+
+/// The event type of the `m.room_key.withheld` to-device event.
+pub(crate) const WITHHELD_EVENT_TYPE: &str = "m.room_key.withheld";
+
+/// Get the event type of the `m.room_key.withheld` to-device event.
+pub(crate) fn withheld_event_type() -> EventType {
+    EventType::Custom(WITHHELD_EVENT_TYPE.to_owned())
+}
+
+/// The reason why a room key was withheld from a device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum WithheldCode {
+    /// The device was blacklisted by the sender.
+    #[serde(rename = "m.blacklisted")]
+    Blacklisted,
+    /// The device isn't verified by the sender.
+    #[serde(rename = "m.unverified")]
+    Unverified,
+    /// The device isn't allowed to receive the key, e.g. it requested a key
+    /// it never received.
+    #[serde(rename = "m.unauthorised")]
+    Unauthorised,
+    /// The sender doesn't have the requested key.
+    #[serde(rename = "m.unavailable")]
+    Unavailable,
+    /// The sender wasn't able to establish an Olm session with the device.
+    #[serde(rename = "m.no_olm")]
+    NoOlm,
+}
+
+impl WithheldCode {
+    /// A human readable description of the code.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            WithheldCode::Blacklisted => "The sender has blocked you.",
+            WithheldCode::Unverified => "The sender has disabled encrypting to unverified devices.",
+            WithheldCode::Unauthorised => "You are not authorised to read the message.",
+            WithheldCode::Unavailable => "The requested key was not found.",
+            WithheldCode::NoOlm => "Unable to establish a secure channel.",
+        }
+    }
+}
+
+impl fmt::Display for WithheldCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = match self {
+            WithheldCode::Blacklisted => "m.blacklisted",
+            WithheldCode::Unverified => "m.unverified",
+            WithheldCode::Unauthorised => "m.unauthorised",
+            WithheldCode::Unavailable => "m.unavailable",
+            WithheldCode::NoOlm => "m.no_olm",
+        };
+
+        f.write_str(code)
+    }
+}
+
+/// The content of a `m.room_key.withheld` to-device event.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct RoomKeyWithheldContent {
+    pub(crate) algorithm: Algorithm,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) room_id: Option<RoomId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) session_id: Option<String>,
+    pub(crate) sender_key: String,
+    pub(crate) code: WithheldCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) reason: Option<String>,
+}
+
+impl RoomKeyWithheldContent {
+    /// Create the content of a `m.room_key.withheld` event for one of our
+    /// group sessions.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room the group session belongs to.
+    ///
+    /// * `session_id` - The id of the withheld group session.
+    ///
+    /// * `sender_key` - Our own curve25519 key.
+    ///
+    /// * `code` - The reason why the session is withheld.
+    pub(crate) fn new(
+        room_id: &RoomId,
+        session_id: &str,
+        sender_key: &str,
+        code: WithheldCode,
+    ) -> Self {
+        RoomKeyWithheldContent {
+            algorithm: Algorithm::MegolmV1AesSha2,
+            room_id: Some(room_id.clone()),
+            session_id: Some(session_id.to_owned()),
+            sender_key: sender_key.to_owned(),
+            code,
+            reason: Some(code.reason().to_owned()),
+        }
+    }
+}
+
+/// A `m.room_key.withheld` to-device event.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ToDeviceRoomKeyWithheld {
+    pub(crate) sender: UserId,
+    #[serde(rename = "type")]
+    pub(crate) event_type: String,
+    pub(crate) content: RoomKeyWithheldContent,
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{ToDeviceRoomKeyWithheld, WithheldCode, WITHHELD_EVENT_TYPE};
+
+    #[test]
+    fn withheld_event_parsing() {
+        let event = json!({
+            "sender": "@alice:example.org",
+            "type": "m.room_key.withheld",
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "room_id": "!test:example.org",
+                "session_id": "SESSIONID",
+                "sender_key": "SENDERKEY",
+                "code": "m.unverified",
+                "reason": "Device not verified"
+            }
+        });
+
+        let event: ToDeviceRoomKeyWithheld = serde_json::from_value(event).unwrap();
+
+        assert_eq!(event.event_type, WITHHELD_EVENT_TYPE);
+        assert_eq!(event.content.code, WithheldCode::Unverified);
+        assert_eq!(event.content.session_id.as_deref(), Some("SESSIONID"));
+        assert_eq!(event.content.code.to_string(), "m.unverified");
+
+        let event = json!({
+            "sender": "@alice:example.org",
+            "type": "m.room_key.withheld",
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "sender_key": "SENDERKEY",
+                "code": "m.no_olm",
+            }
+        });
+
+        let event: ToDeviceRoomKeyWithheld = serde_json::from_value(event).unwrap();
+        assert_eq!(event.content.code, WithheldCode::NoOlm);
+        assert!(event.content.room_id.is_none());
+    }
+}